@@ -170,11 +170,14 @@ export async function fetchReportByName(name: string) {
       alert(`Report '${name}' not found`);
       throw new Error(`Report '${name}' not found`);
     }
-    const parts = new Blob([
+    const bytes = new Blob([
       Uint8Array.from(atob(report.data), (c) => c.charCodeAt(0)),
-    ])
-      .stream()
-      .pipeThrough(new DecompressionStream("gzip"));
+    ]).stream();
+    // Reports exported with `--compression none` are not gzipped
+    const parts =
+      report.compressed === false
+        ? bytes
+        : bytes.pipeThrough(new DecompressionStream("gzip"));
     const data = await new Response(parts).text();
 
     return parseJsonProcessReport(name, data);
@@ -189,4 +192,10 @@ export async function fetchReportByName(name: string) {
   return parseJsonProcessReport(name, await response.text());
 }
 
-const BUNDLED_REPORTS: { name: string; data: string }[] = [];
+const BUNDLED_REPORTS: {
+  name: string;
+  data: string;
+  compressed?: boolean;
+  // Headline numbers computed by the exporter, readable without decompressing `data`
+  summary?: { samples: number; peak_memory: number; gil_held_percent: number };
+}[] = [];
@@ -0,0 +1,288 @@
+use crate::export::{Strip, parse_samples, read_report_file};
+use crate::manifest::{Manifest, ManifestError};
+use crate::types::{
+    ALLOCATIONS_FILE_NAME, JsonLine, MANIFEST_FILE_NAME, MARKERS_FILE_NAME, METADATA_FILE_NAME,
+    MetadataHeader, ReportFileName, ReportFormat,
+};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use log::info;
+use snafu::{IntoError, Location, NoneError, ResultExt, Snafu, Whatever};
+use std::io::{ErrorKind, Write};
+use std::path::Path;
+
+#[derive(Debug, Snafu)]
+pub enum ConvertError {
+    #[snafu(display("Error reading data dir `{path}` at {location}"))]
+    ReadDataDir {
+        source: std::io::Error,
+        path: String,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display("Input and output directory are both `{path}` at {location}"))]
+    SameDirectory {
+        path: String,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display("Error reading report `{path}` at {location}"))]
+    ReadReport {
+        source: std::io::Error,
+        path: String,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display("Error parsing report `{path}` at {location}"))]
+    ParseReport {
+        source: Whatever,
+        path: String,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display("Error writing `{path}` at {location}"))]
+    WriteFile {
+        source: std::io::Error,
+        path: String,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display("Error writing the manifest at {location}"))]
+    WriteManifest {
+        source: ManifestError,
+        #[snafu(implicit)]
+        location: Location,
+    },
+}
+
+/// The raw format reports are converted to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ConvertFormat {
+    Jsonl,
+    #[value(name = "jsonl.gz")]
+    JsonlGz,
+    Cbor,
+}
+
+impl ConvertFormat {
+    fn format(self) -> ReportFormat {
+        match self {
+            Self::Jsonl | Self::JsonlGz => ReportFormat::Jsonl,
+            Self::Cbor => ReportFormat::Cbor,
+        }
+    }
+
+    fn compressed(self) -> bool {
+        self == Self::JsonlGz
+    }
+}
+
+/// Rewrites every report file of a data directory in another raw format, keeping the names and
+/// parts of the reports. The metadata is copied and the manifest recomputed, if there was one.
+/// The samples are rewritten without the parts selected by `strip`.
+pub fn run_convert(
+    input_dir: &Path,
+    output_dir: &Path,
+    to: ConvertFormat,
+    strip: Strip,
+) -> Result<(), ConvertError> {
+    let path = input_dir.display().to_string();
+    std::fs::create_dir_all(output_dir).context(WriteFileSnafu {
+        path: output_dir.display().to_string(),
+    })?;
+    let same_dir = std::fs::canonicalize(input_dir).context(ReadDataDirSnafu { path: &path })?
+        == std::fs::canonicalize(output_dir).context(ReadDataDirSnafu { path: &path })?;
+    if same_dir {
+        return Err(SameDirectorySnafu { path }.into_error(NoneError));
+    }
+
+    let mut converted = 0;
+    for entry in std::fs::read_dir(input_dir).context(ReadDataDirSnafu { path: &path })? {
+        let entry = entry.context(ReadDataDirSnafu { path: &path })?;
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        let Some(report_file) = ReportFileName::parse(&file_name) else {
+            continue;
+        };
+        let content = convert_report(&entry.path(), report_file, to, strip)?;
+        let output_path = output_dir.join(converted_file_name(report_file, to));
+        std::fs::write(&output_path, content).context(WriteFileSnafu {
+            path: output_path.display().to_string(),
+        })?;
+        converted += 1;
+    }
+
+    for sidecar in [METADATA_FILE_NAME, ALLOCATIONS_FILE_NAME, MARKERS_FILE_NAME] {
+        match std::fs::copy(input_dir.join(sidecar), output_dir.join(sidecar)) {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => {
+                return Err(e).context(WriteFileSnafu {
+                    path: output_dir.join(sidecar).display().to_string(),
+                });
+            }
+        }
+    }
+    // The checksums change with the format, so the manifest is computed anew
+    if input_dir.join(MANIFEST_FILE_NAME).exists() {
+        Manifest::compute(output_dir)
+            .and_then(|manifest| manifest.write(output_dir))
+            .context(WriteManifestSnafu)?;
+    }
+
+    info!("Converted {converted} report files");
+    Ok(())
+}
+
+/// The name of the converted report file, e.g. `1234.2.cbor` for `1234.2.json.gz`.
+fn converted_file_name(report_file: ReportFileName<'_>, to: ConvertFormat) -> String {
+    let part = report_file
+        .part
+        .map(|part| format!(".{part}"))
+        .unwrap_or_default();
+    let compressed = if to.compressed() { ".gz" } else { "" };
+    format!(
+        "{}{part}.{}{compressed}",
+        report_file.stem,
+        to.format().extension()
+    )
+}
+
+/// Reads a report file in whatever format it has and encodes its samples in the new one.
+fn convert_report(
+    path: &Path,
+    report_file: ReportFileName<'_>,
+    to: ConvertFormat,
+    strip: Strip,
+) -> Result<Vec<u8>, ConvertError> {
+    let display_path = path.display().to_string();
+    let content = read_report_file(path, report_file.compressed).context(ReadReportSnafu {
+        path: &display_path,
+    })?;
+    let mut lines =
+        parse_samples::<JsonLine>(&content, report_file.format, 1).context(ParseReportSnafu {
+            path: &display_path,
+        })?;
+
+    for line in &mut lines {
+        strip.apply(line);
+    }
+
+    // Reports written with `--embed-metadata` keep their header
+    let (metadata, _) = MetadataHeader::split(&content, report_file.format);
+    let mut encoded = metadata
+        .map(|metadata| MetadataHeader::encode(&metadata, to.format()))
+        .unwrap_or_default();
+    encoded.reserve(content.len());
+    for line in &lines {
+        match to.format() {
+            ReportFormat::Jsonl => {
+                serde_json::to_writer(&mut encoded, line).expect("samples are serializable");
+                encoded.push(b'\n');
+            }
+            ReportFormat::Cbor => {
+                ciborium::into_writer(line, &mut encoded).expect("samples are serializable")
+            }
+        }
+    }
+    if !to.compressed() {
+        return Ok(encoded);
+    }
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(&encoded).context(WriteFileSnafu {
+        path: &display_path,
+    })?;
+    encoder
+        .finish()
+        .context(WriteFileSnafu { path: display_path })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Frame, ProcessResources, StackTrace};
+
+    fn sample(index: usize) -> JsonLine {
+        let mut resources = ProcessResources::exited();
+        resources.exited = false;
+        resources.memory = 1024 * index as u64;
+        JsonLine {
+            stacktraces: vec![StackTrace {
+                pid: 1234,
+                thread_id: 1,
+                thread_name: Some("MainThread".to_string()),
+                os_thread_id: Some(1234),
+                active: true,
+                owns_gil: true,
+                frames: vec![Frame {
+                    name: "main".to_string(),
+                    filename: "/app/main.py".to_string(),
+                    module: None,
+                    short_filename: Some("main.py".to_string()),
+                    line: 42,
+                    locals: None,
+                    is_entry: true,
+                }],
+                process_info: None,
+            }],
+            resources,
+            index,
+            time: 1_700_000_000_000 + index as u128,
+            time_micros: Some((1_700_000_000_000 + index as u128) * 1000),
+            sample_duration_micros: 10,
+            report: None,
+            sequence: Some(index as u64),
+        }
+    }
+
+    /// The samples of a converted report, as JSON values to compare them.
+    fn read_converted(path: &Path) -> Vec<serde_json::Value> {
+        let file_name = path.file_name().unwrap().to_string_lossy().to_string();
+        let report_file = ReportFileName::parse(&file_name).unwrap();
+        let content = read_report_file(path, report_file.compressed).unwrap();
+        parse_samples::<JsonLine>(&content, report_file.format, 1)
+            .unwrap()
+            .iter()
+            .map(|line| serde_json::to_value(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn converting_round_trips() {
+        let root = std::env::temp_dir().join(format!(
+            "py-crude-resource-monitor-convert-{}",
+            std::process::id()
+        ));
+        let (jsonl, cbor, back, gz) = (
+            root.join("jsonl"),
+            root.join("cbor"),
+            root.join("back"),
+            root.join("gz"),
+        );
+        std::fs::create_dir_all(&jsonl).unwrap();
+        let samples = (0..3).map(sample).collect::<Vec<_>>();
+        let mut content = Vec::new();
+        for line in &samples {
+            serde_json::to_writer(&mut content, line).unwrap();
+            content.push(b'\n');
+        }
+        std::fs::write(jsonl.join("1234.json"), content).unwrap();
+
+        run_convert(&jsonl, &cbor, ConvertFormat::Cbor, Strip::default()).unwrap();
+        run_convert(&cbor, &back, ConvertFormat::Jsonl, Strip::default()).unwrap();
+        run_convert(&jsonl, &gz, ConvertFormat::JsonlGz, Strip::default()).unwrap();
+        let converted = [
+            read_converted(&cbor.join("1234.cbor")),
+            read_converted(&back.join("1234.json")),
+            read_converted(&gz.join("1234.json.gz")),
+        ];
+        std::fs::remove_dir_all(&root).unwrap();
+
+        let expected = samples
+            .iter()
+            .map(|line| serde_json::to_value(line).unwrap())
+            .collect::<Vec<_>>();
+        for samples in converted {
+            assert_eq!(samples, expected);
+        }
+    }
+}
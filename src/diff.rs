@@ -0,0 +1,267 @@
+use crate::export::{self, FrameFormat, ReadOptions, ReportIdentifier};
+use crate::types::{JsonLine, Metadata, format_labels};
+use serde::Serialize;
+use snafu::{Location, ResultExt, Snafu, Whatever};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+
+#[derive(Debug, Snafu)]
+pub enum DiffError {
+    #[snafu(display("Error reading report `{path}` at {location}"))]
+    ReadReport {
+        source: Whatever,
+        path: String,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display("Error serializing diff at {location}"))]
+    Serialize {
+        source: serde_json::Error,
+        #[snafu(implicit)]
+        location: Location,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum DiffFormat {
+    Text,
+    Json,
+}
+
+/// Aggregated numbers of a single profile.
+#[derive(Debug, Serialize)]
+struct ProfileSummary {
+    /// Number of captured stacktraces over all threads and processes
+    samples: usize,
+    /// Highest memory usage of all processes combined, in bytes
+    peak_memory: u64,
+    /// Peak memory relative to the memory available to the processes, in percent
+    peak_memory_percent: Option<f64>,
+    /// Mean CPU usage of all processes combined, in percent
+    mean_cpu: f64,
+    /// The labels the profile was captured with
+    labels: BTreeMap<String, String>,
+    /// Number of stacktraces containing a frame, keyed by frame label
+    #[serde(skip)]
+    frame_counts: HashMap<String, usize>,
+}
+
+impl ProfileSummary {
+    fn from_report(report: &HashMap<ReportIdentifier, Vec<JsonLine>>, metadata: &Metadata) -> Self {
+        let mut samples = 0;
+        let mut frame_counts = HashMap::new();
+        let frame_format = FrameFormat::default();
+        // memory and CPU of all processes, summed per sample time
+        let mut per_time: BTreeMap<u128, (u64, f64)> = BTreeMap::new();
+
+        for (id, lines) in report {
+            if matches!(id, ReportIdentifier::Global | ReportIdentifier::Profiler) {
+                continue;
+            }
+            for line in lines {
+                let entry = per_time.entry(line.time).or_default();
+                entry.0 += line.resources.memory;
+                entry.1 += line.resources.cpu as f64;
+
+                for stacktrace in &line.stacktraces {
+                    samples += 1;
+                    // Count every frame once per stack, so recursion does not inflate the share
+                    let labels = stacktrace
+                        .frames
+                        .iter()
+                        .map(|frame| frame_format.label(frame))
+                        .collect::<HashSet<_>>();
+                    for label in labels {
+                        *frame_counts.entry(label).or_insert(0) += 1;
+                    }
+                }
+            }
+        }
+
+        let peak_memory = per_time
+            .values()
+            .map(|(memory, _)| *memory)
+            .max()
+            .unwrap_or(0);
+        let mean_cpu = per_time.values().map(|(_, cpu)| cpu).sum::<f64>() / per_time.len() as f64;
+
+        Self {
+            samples,
+            peak_memory,
+            peak_memory_percent: metadata
+                .memory_limit()
+                .map(|limit| peak_memory as f64 / limit as f64 * 100.0),
+            mean_cpu: if mean_cpu.is_nan() { 0.0 } else { mean_cpu },
+            labels: metadata.labels.clone(),
+            frame_counts,
+        }
+    }
+
+    /// The fraction of stacktraces containing the frame
+    fn share(&self, label: &str) -> f64 {
+        if self.samples == 0 {
+            return 0.0;
+        }
+        self.frame_counts.get(label).copied().unwrap_or(0) as f64 / self.samples as f64
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+struct FrameDiff {
+    frame: String,
+    baseline_share: f64,
+    candidate_share: f64,
+    delta: f64,
+}
+
+#[derive(Debug, Serialize)]
+struct ProfileDiff {
+    baseline: ProfileSummary,
+    candidate: ProfileSummary,
+    peak_memory_delta: i128,
+    mean_cpu_delta: f64,
+    increased: Vec<FrameDiff>,
+    decreased: Vec<FrameDiff>,
+}
+
+impl ProfileDiff {
+    fn new(baseline: ProfileSummary, candidate: ProfileSummary, limit: usize) -> Self {
+        let labels = baseline
+            .frame_counts
+            .keys()
+            .chain(candidate.frame_counts.keys())
+            .collect::<HashSet<_>>();
+        let mut frames = labels
+            .into_iter()
+            .map(|label| {
+                let baseline_share = baseline.share(label);
+                let candidate_share = candidate.share(label);
+                FrameDiff {
+                    frame: label.to_string(),
+                    baseline_share,
+                    candidate_share,
+                    delta: candidate_share - baseline_share,
+                }
+            })
+            .collect::<Vec<_>>();
+        // Sort by label first to keep the output deterministic for equal deltas
+        frames.sort_by(|a, b| a.frame.cmp(&b.frame));
+        frames.sort_by(|a, b| b.delta.total_cmp(&a.delta));
+
+        let increased = frames
+            .iter()
+            .filter(|it| it.delta > 0.0)
+            .take(limit)
+            .cloned()
+            .collect();
+        let decreased = frames
+            .iter()
+            .rev()
+            .filter(|it| it.delta < 0.0)
+            .take(limit)
+            .cloned()
+            .collect();
+
+        Self {
+            peak_memory_delta: candidate.peak_memory as i128 - baseline.peak_memory as i128,
+            mean_cpu_delta: candidate.mean_cpu - baseline.mean_cpu,
+            baseline,
+            candidate,
+            increased,
+            decreased,
+        }
+    }
+
+    fn print_text(&self) {
+        if !self.baseline.labels.is_empty() || !self.candidate.labels.is_empty() {
+            println!(
+                "Labels:      {} -> {}",
+                format_labels(&self.baseline.labels),
+                format_labels(&self.candidate.labels)
+            );
+        }
+        println!(
+            "Samples:     {} -> {}",
+            self.baseline.samples, self.candidate.samples
+        );
+        println!(
+            "Peak memory: {} -> {} ({}{})",
+            format_bytes(self.baseline.peak_memory as f64),
+            format_bytes(self.candidate.peak_memory as f64),
+            if self.peak_memory_delta >= 0 {
+                "+"
+            } else {
+                "-"
+            },
+            format_bytes(self.peak_memory_delta.unsigned_abs() as f64),
+        );
+        if let (Some(baseline), Some(candidate)) = (
+            self.baseline.peak_memory_percent,
+            self.candidate.peak_memory_percent,
+        ) {
+            println!(
+                "             {baseline:.1}% -> {candidate:.1}% of available memory ({:+.1}%)",
+                candidate - baseline
+            );
+        }
+        println!(
+            "Mean CPU:    {:.1}% -> {:.1}% ({:+.1}%)",
+            self.baseline.mean_cpu, self.candidate.mean_cpu, self.mean_cpu_delta
+        );
+
+        for (title, frames) in [
+            ("Frames with the largest increase in share", &self.increased),
+            ("Frames with the largest decrease in share", &self.decreased),
+        ] {
+            println!();
+            println!("{title}:");
+            if frames.is_empty() {
+                println!("  (none)");
+            }
+            for frame in frames {
+                println!(
+                    "  {:+7.2}%  {:6.2}% -> {:6.2}%  {}",
+                    frame.delta * 100.0,
+                    frame.baseline_share * 100.0,
+                    frame.candidate_share * 100.0,
+                    frame.frame
+                );
+            }
+        }
+    }
+}
+
+pub(crate) fn format_bytes(bytes: f64) -> String {
+    format!("{:.1} MiB", bytes / 1024.0 / 1024.0)
+}
+
+fn summarize(data_dir: &Path) -> Result<ProfileSummary, DiffError> {
+    let path = data_dir.display().to_string();
+    let report = export::read_report(data_dir, &ReadOptions::default())
+        .context(ReadReportSnafu { path: &path })?;
+    let metadata = export::read_metadata(data_dir).context(ReadReportSnafu { path })?;
+    Ok(ProfileSummary::from_report(&report, &metadata))
+}
+
+/// Compares two captured profiles and prints which frames got hotter or colder, along with the
+/// change in peak memory and mean CPU usage.
+pub fn run_diff(
+    baseline: &Path,
+    candidate: &Path,
+    format: DiffFormat,
+    limit: usize,
+) -> Result<(), DiffError> {
+    let diff = ProfileDiff::new(summarize(baseline)?, summarize(candidate)?, limit);
+
+    match format {
+        DiffFormat::Text => diff.print_text(),
+        DiffFormat::Json => {
+            println!(
+                "{}",
+                serde_json::to_string_pretty(&diff).context(SerializeSnafu)?
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -1,10 +1,30 @@
-use crate::types::JsonLine;
-use snafu::{Location, ResultExt, Snafu, Whatever};
-use std::collections::HashMap;
-use std::path::Path;
+use crate::types::{
+    Frame, JsonLine, MARKERS_FILE_NAME, METADATA_FILE_NAME, METADATA_HEADER_PREFIX, Marker,
+    Metadata, MetadataHeader, MetadataHeaderKind, ReportFileName, ReportFormat, SINGLE_FILE_STEM,
+    is_report_file, split_process_stem,
+};
+use flate2::Compression;
+use flate2::read::MultiGzDecoder;
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{debug, info, warn};
+use regex::Regex;
+use serde::de::{DeserializeOwned, IgnoredAny};
+use serde::{Deserialize, Serialize};
+use snafu::{Location, OptionExt, Report, ResultExt, Snafu, Whatever, ensure};
+use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::{BufRead, BufReader, ErrorKind, IsTerminal, Read};
+use std::num::NonZeroUsize;
+use std::ops::RangeInclusive;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 
+mod chrome;
 mod firefox;
 mod html;
+mod parquet;
 
 #[derive(Debug, Snafu)]
 pub enum ExportError {
@@ -20,64 +40,1635 @@ pub enum ExportError {
         #[snafu(implicit)]
         location: Location,
     },
+    #[snafu(display("Error generating chrome trace at {location}"))]
+    Chrome {
+        source: chrome::ExportError,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display("Error generating parquet file at {location}"))]
+    Parquet {
+        // `self::` as the module shares its name with the `parquet` crate
+        source: self::parquet::ExportError,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display(
+        "No report files found in `{path}`. Did you run `profile` with this output directory? ({location})"
+    ))]
+    EmptyDataDir {
+        path: String,
+        #[snafu(implicit)]
+        location: Location,
+    },
+}
+
+/// Options controlling which samples of a report are read for exporting.
+#[derive(Debug, Clone)]
+pub struct ReadOptions {
+    /// Only keep every nth sample of each process, downsampling the report.
+    pub every_nth: NonZeroUsize,
+    /// Only keep samples taken at least this many milliseconds after the first sample.
+    pub start_millis: Option<u64>,
+    /// Only keep samples taken at most this many milliseconds after the first sample.
+    pub end_millis: Option<u64>,
+    /// Skip report files that can not be read, e.g. because they are corrupt, instead of failing.
+    pub best_effort: bool,
+    /// Only read the processes with these pids, or all processes if empty. The system-wide
+    /// resources and the profiler itself are read regardless.
+    pub only_pids: Vec<u32>,
+    /// The parts of the samples to drop while reading.
+    pub strip: Strip,
+    /// Only keep the samples of a process in which a thread has a frame whose name contains this
+    /// substring. The system-wide resources and the profiler itself are kept whole.
+    pub containing_frame: Option<String>,
+}
+
+impl Default for ReadOptions {
+    fn default() -> Self {
+        Self {
+            every_nth: NonZeroUsize::MIN,
+            start_millis: None,
+            end_millis: None,
+            best_effort: false,
+            only_pids: Vec::new(),
+            strip: Strip::default(),
+            containing_frame: None,
+        }
+    }
+}
+
+/// Parts of the samples to drop, to shrink rich captures and to not leak their details.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Strip {
+    /// Drop the local variables of every frame
+    pub locals: bool,
+    /// Drop the process info of every stacktrace
+    pub process_info: bool,
+}
+
+impl Strip {
+    /// Whether anything is dropped at all.
+    pub(crate) fn is_active(self) -> bool {
+        self.locals || self.process_info
+    }
+
+    pub(crate) fn apply(self, line: &mut JsonLine) {
+        for stacktrace in &mut line.stacktraces {
+            if self.process_info {
+                stacktrace.process_info = None;
+            }
+            if self.locals {
+                for frame in &mut stacktrace.frames {
+                    frame.locals = None;
+                }
+            }
+        }
+    }
+}
+
+impl ReadOptions {
+    /// Whether the report is read at all, see [`ReadOptions::only_pids`].
+    pub(crate) fn includes(&self, id: ReportIdentifier) -> bool {
+        match id {
+            ReportIdentifier::Pid { pid, .. } => {
+                self.only_pids.is_empty() || self.only_pids.contains(&pid)
+            }
+            ReportIdentifier::Global | ReportIdentifier::Profiler => true,
+        }
+    }
+
+    /// Whether the sample is kept by [`ReadOptions::containing_frame`].
+    pub(crate) fn keeps_frames(&self, line: &JsonLine) -> bool {
+        let Some(substring) = &self.containing_frame else {
+            return true;
+        };
+        line.stacktraces.iter().any(|stacktrace| {
+            stacktrace
+                .frames
+                .iter()
+                .any(|frame| frame.name.contains(substring.as_str()))
+        })
+    }
+}
+
+/// The absolute timestamps of samples to keep, if the options restrict them to a time window.
+/// The window is relative to the earliest sample of all reports in the directory.
+pub(crate) fn time_window(
+    data_dir: &Path,
+    options: &ReadOptions,
+) -> std::io::Result<Option<RangeInclusive<u128>>> {
+    if options.start_millis.is_none() && options.end_millis.is_none() {
+        return Ok(None);
+    }
+    let mut first_times = Vec::new();
+    for entry in std::fs::read_dir(data_dir)? {
+        let entry = entry?;
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        let Some(report_file) = ReportFileName::parse(&file_name) else {
+            continue;
+        };
+        // Parts pruned by `--max-file-age` vanish while the capture goes on
+        match first_sample_time(&entry.path(), report_file) {
+            Ok(first_time) => first_times.extend(first_time),
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+    }
+    let capture_start = first_times.into_iter().min().unwrap_or(0);
+
+    let start = capture_start + options.start_millis.unwrap_or(0) as u128;
+    let end = options
+        .end_millis
+        .map(|end| capture_start + end as u128)
+        .unwrap_or(u128::MAX);
+    Ok(Some(start..=end))
+}
+
+/// Only the timestamp of a [`JsonLine`], which is a lot cheaper to deserialize.
+#[derive(Deserialize)]
+struct SampleTime {
+    time: u128,
+}
+
+/// The first record of a CBOR report, which is either a sample or a [`MetadataHeader`].
+#[derive(Deserialize)]
+struct FirstRecord {
+    #[serde(default, rename = "type")]
+    kind: Option<MetadataHeaderKind>,
+    #[serde(default)]
+    time: Option<u128>,
+}
+
+/// The time and memory of a sample, which is a lot cheaper to deserialize than a [`JsonLine`].
+#[derive(Deserialize)]
+struct SampleMemory {
+    time: u128,
+    resources: MemoryResources,
+}
+
+#[derive(Deserialize)]
+struct MemoryResources {
+    memory: u64,
+}
+
+/// The per-interval disk I/O of a sample, which is all that is read of the samples skipped by
+/// [`ReadOptions::every_nth`]. It is added to the next kept sample, so downsampling does not lose
+/// the I/O in between.
+#[derive(Deserialize, Default)]
+pub(crate) struct SampleIo {
+    resources: IoResources,
+}
+
+#[derive(Deserialize, Default)]
+struct IoResources {
+    disk_read_bytes: u64,
+    disk_write_bytes: u64,
+    #[serde(default)]
+    per_disk_io: HashMap<String, (u64, u64)>,
+    #[serde(default)]
+    thread_resources: HashMap<u64, ThreadIo>,
+}
+
+#[derive(Deserialize, Default)]
+struct ThreadIo {
+    disk_read_bytes: u64,
+    disk_write_bytes: u64,
+}
+
+impl SampleIo {
+    fn add(&mut self, other: Self) {
+        let resources = &mut self.resources;
+        resources.disk_read_bytes += other.resources.disk_read_bytes;
+        resources.disk_write_bytes += other.resources.disk_write_bytes;
+        for (disk, (read, written)) in other.resources.per_disk_io {
+            let entry = resources.per_disk_io.entry(disk).or_default();
+            entry.0 += read;
+            entry.1 += written;
+        }
+        for (tid, io) in other.resources.thread_resources {
+            let entry = resources.thread_resources.entry(tid).or_default();
+            entry.disk_read_bytes += io.disk_read_bytes;
+            entry.disk_write_bytes += io.disk_write_bytes;
+        }
+    }
+}
+
+/// A sample type read by [`parse_samples`], which keeps the I/O of the samples skipped by
+/// [`ReadOptions::every_nth`] if it has any.
+pub(crate) trait Downsampled: DeserializeOwned {
+    fn add_skipped(&mut self, _skipped: &SampleIo) {}
+}
+
+impl Downsampled for SampleMemory {}
+
+impl Downsampled for JsonLine {
+    fn add_skipped(&mut self, skipped: &SampleIo) {
+        let resources = &mut self.resources;
+        resources.disk_read_bytes += skipped.resources.disk_read_bytes;
+        resources.disk_write_bytes += skipped.resources.disk_write_bytes;
+        for (disk, (read, written)) in &skipped.resources.per_disk_io {
+            let entry = resources.per_disk_io.entry(disk.clone()).or_default();
+            entry.0 += read;
+            entry.1 += written;
+        }
+        // Threads that are not part of the kept sample have no place to put their I/O, it is still
+        // part of the process totals
+        for (tid, io) in &skipped.resources.thread_resources {
+            if let Some(thread) = resources.thread_resources.get_mut(tid) {
+                thread.disk_read_bytes += io.disk_read_bytes;
+                thread.disk_write_bytes += io.disk_write_bytes;
+            }
+        }
+    }
+}
+
+/// Collects every nth sample, adding the I/O of the skipped ones to the next kept sample. The
+/// I/O of skipped samples after the last kept one is added to it, so the totals stay the same.
+struct Downsampler<T> {
+    parsed: Vec<T>,
+    every_nth: usize,
+    index: usize,
+    skipped: SampleIo,
+}
+
+impl<T: Downsampled> Downsampler<T> {
+    fn new(every_nth: usize) -> Self {
+        Self {
+            parsed: Vec::new(),
+            every_nth,
+            index: 0,
+            skipped: SampleIo::default(),
+        }
+    }
+
+    /// Whether the next sample is kept, otherwise it is passed to [`Downsampler::skip`].
+    fn keeps_next(&self) -> bool {
+        self.index % self.every_nth == 0
+    }
+
+    fn keep(&mut self, mut sample: T) {
+        sample.add_skipped(&std::mem::take(&mut self.skipped));
+        self.parsed.push(sample);
+        self.index += 1;
+    }
+
+    /// Skips a sample, whose I/O is `None` if it could not be parsed.
+    fn skip(&mut self, io: Option<SampleIo>) {
+        if let Some(io) = io {
+            self.skipped.add(io);
+        }
+        self.index += 1;
+    }
+
+    fn finish(mut self) -> Vec<T> {
+        if let Some(last) = self.parsed.last_mut() {
+            last.add_skipped(&self.skipped);
+        }
+        self.parsed
+    }
+}
+
+/// An overview of a report file, e.g. to pick which reports to look at.
+#[derive(Serialize, Debug)]
+pub struct ReportSummary {
+    /// The name the report is served under, see [`crate::view`]
+    pub name: String,
+    pub samples: usize,
+    /// The time of the first sample in milliseconds since the unix epoch
+    pub start_time: Option<u128>,
+    /// The time of the last sample in milliseconds since the unix epoch
+    pub end_time: Option<u128>,
+    pub peak_memory: u64,
+}
+
+/// Summarizes the parts of a report, only deserializing the time and memory of its samples.
+pub(crate) fn summarize_report(
+    stem: &str,
+    parts: &[ReportPart],
+) -> Result<ReportSummary, Whatever> {
+    let mut samples = Vec::new();
+    for part in parts {
+        let content = read_report_file(&part.path, part.compressed)
+            .with_whatever_context(|_| format!("could not read file `{}`", part.path.display()))?;
+        samples.extend(
+            parse_samples::<SampleMemory>(&content, part.format, 1).with_whatever_context(
+                |_| format!("could not deserialize sample in `{}`", part.path.display()),
+            )?,
+        );
+    }
+
+    Ok(ReportSummary {
+        name: format!("{stem}.json"),
+        samples: samples.len(),
+        start_time: samples.iter().map(|it| it.time).min(),
+        end_time: samples.iter().map(|it| it.time).max(),
+        peak_memory: samples
+            .iter()
+            .map(|it| it.resources.memory)
+            .max()
+            .unwrap_or(0),
+    })
+}
+
+/// A file holding (a part of) the samples of a report.
+pub(crate) struct ReportPart {
+    pub path: PathBuf,
+    /// The number of the rotated part, 0 for the first one
+    pub part: u32,
+    pub format: ReportFormat,
+    pub compressed: bool,
+}
+
+impl ReportPart {
+    /// Whether the samples were written with `--json-pretty`, which JSON lines consumers like the
+    /// viewer can not read as is.
+    pub(crate) fn is_pretty_json(&self) -> std::io::Result<bool> {
+        if self.format != ReportFormat::Jsonl {
+            return Ok(false);
+        }
+        let mut reader = BufReader::new(open_report(&self.path, self.compressed)?);
+        let mut start = Vec::new();
+        reader.read_until(b'\n', &mut start)?;
+        // The metadata header is a single line, even before pretty-printed samples
+        if start.starts_with(METADATA_HEADER_PREFIX) {
+            start.clear();
+            reader.read_until(b'\n', &mut start)?;
+        }
+        Ok(is_pretty(&start))
+    }
+
+    /// Whether the part starts with a [`MetadataHeader`], which JSON lines consumers like the
+    /// viewer would mistake for a sample.
+    pub(crate) fn has_metadata_header(&self) -> std::io::Result<bool> {
+        if self.format != ReportFormat::Jsonl {
+            return Ok(false);
+        }
+        let mut start = Vec::new();
+        open_report(&self.path, self.compressed)?
+            .take(METADATA_HEADER_PREFIX.len() as u64)
+            .read_to_end(&mut start)?;
+        Ok(start == METADATA_HEADER_PREFIX)
+    }
+}
+
+/// Groups the report files in the data directory by their stem, with the rotated parts of each
+/// report in the order they were written. The combined `--single-file` report is skipped, as it
+/// does not belong to a single process.
+pub(crate) fn report_parts(data_dir: &Path) -> std::io::Result<BTreeMap<String, Vec<ReportPart>>> {
+    let mut reports = BTreeMap::<String, Vec<ReportPart>>::new();
+    for entry in std::fs::read_dir(data_dir)? {
+        let entry = entry?;
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        let Some(report_file) = ReportFileName::parse(&file_name) else {
+            continue;
+        };
+        if report_file.stem == SINGLE_FILE_STEM {
+            debug!("Skipping `{file_name}`, it combines the samples of all processes");
+            continue;
+        }
+        reports
+            .entry(report_file.stem.to_string())
+            .or_default()
+            .push(ReportPart {
+                path: entry.path(),
+                part: report_file.part.unwrap_or(0),
+                format: report_file.format,
+                compressed: report_file.compressed,
+            });
+    }
+    for parts in reports.values_mut() {
+        parts.sort_by_key(|it| it.part);
+    }
+    Ok(reports)
+}
+
+/// Reads the parts of a report as a single JSON lines file, converting CBOR and pretty-printed
+/// parts.
+pub(crate) fn read_report_json(parts: &[ReportPart]) -> Result<Vec<u8>, Whatever> {
+    let mut json = Vec::new();
+    for part in parts {
+        let content = read_report_file(&part.path, part.compressed)
+            .with_whatever_context(|_| format!("could not read file `{}`", part.path.display()))?;
+        let (_, content) = MetadataHeader::split(&content, part.format);
+        match part.format {
+            ReportFormat::Jsonl if !is_pretty(content) => json.extend_from_slice(content),
+            format => json.extend(to_json_lines(content, format)?),
+        }
+        // A part might end in a truncated line, which must not swallow the next part
+        if json.last().is_some_and(|it| *it != b'\n') {
+            json.push(b'\n');
+        }
+    }
+    Ok(json)
+}
+
+/// The timestamp of the first sample in a report file, without parsing the whole file.
+fn first_sample_time(path: &Path, file: ReportFileName<'_>) -> std::io::Result<Option<u128>> {
+    let mut reader = BufReader::new(open_report(path, file.compressed)?);
+    if file.format == ReportFormat::Cbor {
+        let Ok(first) = ciborium::from_reader::<FirstRecord, _>(&mut reader) else {
+            return Ok(None);
+        };
+        if first.kind.is_none() {
+            return Ok(first.time);
+        }
+        return Ok(ciborium::from_reader::<SampleTime, _>(&mut reader)
+            .ok()
+            .map(|it| it.time));
+    }
+    let mut record = String::new();
+    for line in reader.lines() {
+        let line = line?;
+        let header = line.as_bytes().starts_with(METADATA_HEADER_PREFIX);
+        if (line.trim().is_empty() || header) && record.is_empty() {
+            continue;
+        }
+        // Compact samples span a single line, pretty-printed ones end with a blank line
+        let complete = line.trim().is_empty() || (record.is_empty() && line != "{");
+        record.push_str(&line);
+        if complete {
+            break;
+        }
+    }
+    Ok(serde_json::from_str::<SampleTime>(&record)
+        .ok()
+        .map(|it| it.time))
+}
+
+/// How strongly exported files are gzip-compressed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompressionLevel {
+    /// Skip compression entirely where the format allows it, trading size for speed
+    None,
+    /// A gzip level from 0 (fastest) to 9 (smallest)
+    Level(u32),
+}
+
+impl CompressionLevel {
+    fn gzip(self) -> Compression {
+        match self {
+            Self::None => Compression::none(),
+            Self::Level(level) => Compression::new(level),
+        }
+    }
+}
+
+impl FromStr for CompressionLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "none" {
+            return Ok(Self::None);
+        }
+        match s.parse::<u32>() {
+            Ok(level) if level <= 9 => Ok(Self::Level(level)),
+            _ => Err(format!("expected a level from 0 to 9 or `none`, got `{s}`")),
+        }
+    }
 }
 
 /// Exports the profile data to a self-contained single-file HTML report.
-pub fn export_html(data_dir: &Path, output_file: &Path) -> Result<(), ExportError> {
-    html::export_report(data_dir, output_file).context(HtmlSnafu)
+pub fn export_html(
+    data_dir: &Path,
+    output_file: &Path,
+    options: &ReadOptions,
+    compression: CompressionLevel,
+) -> Result<(), ExportError> {
+    ensure_has_reports(data_dir)?;
+    html::export_report(data_dir, output_file, options, compression).context(HtmlSnafu)
+}
+
+/// Options specific to the Firefox Profiler export.
+#[derive(Debug, Clone)]
+pub struct FirefoxOptions {
+    pub compression: CompressionLevel,
+    /// Show threads of the same name as one track across all processes
+    pub merge_by_thread_name: bool,
+    pub colors: FirefoxColors,
+    /// Only export the threads with these names, or all threads if empty
+    pub threads: Vec<String>,
+    pub frame_format: FrameFormat,
+    /// Merge runs of identical consecutive stacks of a thread into one weighted sample
+    pub coalesce: bool,
+    /// Show child processes right below their parent, named after it
+    pub process_tree: bool,
+    /// Only show this many threads per process, by CPU time, in their own track
+    pub select_threads_by_cpu: Option<usize>,
+    /// The name of the profile shown in the Firefox Profiler, instead of the profiled command
+    pub profile_name: Option<String>,
+    /// Trim the idle samples at the start and end, with at most this CPU usage in percent
+    pub trim_idle: Option<f32>,
+    /// Collapse consecutive calls of the same function into one frame with the recursion depth
+    pub collapse_recursion: bool,
+    /// End the stacks of inactive threads in an `[idle]` frame
+    pub mark_idle: bool,
+    /// Show the threads of a process whose names match this pattern in one track per value of its
+    /// first capture group, e.g. the numbered threads of a pool
+    pub group_threads: Option<Regex>,
+}
+
+impl Default for FirefoxOptions {
+    fn default() -> Self {
+        Self {
+            compression: CompressionLevel::Level(6),
+            merge_by_thread_name: false,
+            colors: FirefoxColors::default(),
+            threads: Vec::new(),
+            frame_format: FrameFormat::default(),
+            coalesce: false,
+            process_tree: false,
+            select_threads_by_cpu: None,
+            profile_name: None,
+            trim_idle: None,
+            collapse_recursion: false,
+            mark_idle: false,
+            group_threads: None,
+        }
+    }
+}
+
+/// The colors of the frame categories and counters in the Firefox Profiler.
+#[derive(Debug, Clone, Copy)]
+pub struct FirefoxColors {
+    pub python: CategoryColorName,
+    pub native: CategoryColorName,
+    pub memory: GraphColorName,
+    pub io: GraphColorName,
+}
+
+impl Default for FirefoxColors {
+    fn default() -> Self {
+        Self {
+            python: CategoryColorName::Blue,
+            native: CategoryColorName::Green,
+            memory: GraphColorName::Orange,
+            io: GraphColorName::Teal,
+        }
+    }
+}
+
+/// The colors the Firefox Profiler offers for frame categories.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum CategoryColorName {
+    Blue,
+    Brown,
+    Green,
+    Grey,
+    LightBlue,
+    Magenta,
+    Orange,
+    Purple,
+    Red,
+    Yellow,
+}
+
+/// The colors the Firefox Profiler offers for counter graphs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum GraphColorName {
+    Blue,
+    Green,
+    Grey,
+    Ink,
+    Magenta,
+    Orange,
+    Purple,
+    Red,
+    Teal,
+    Yellow,
 }
 
 /// Exports the profile data to a Firefox-compatible JSON report.
-pub fn export_firefox(data_dir: &Path, output_file: &Path) -> Result<(), ExportError> {
-    firefox::export_report(data_dir, output_file).context(FirefoxSnafu)
+pub fn export_firefox(
+    data_dir: &Path,
+    output_file: &Path,
+    options: &ReadOptions,
+    firefox_options: &FirefoxOptions,
+) -> Result<(), ExportError> {
+    ensure_has_reports(data_dir)?;
+    firefox::export_report(data_dir, output_file, options, firefox_options).context(FirefoxSnafu)
+}
+
+/// Reads the reports bundled into an HTML file by [`export_html`], returning the decompressed
+/// JSON lines of each report by file name.
+pub fn read_html_reports(html_file: &Path) -> Result<BTreeMap<String, Vec<u8>>, ExportError> {
+    html::read_bundled_reports(html_file).context(HtmlSnafu)
+}
+
+/// Exports the profile data to a Chrome Trace Event JSON file, viewable in Perfetto.
+pub fn export_chrome(
+    data_dir: &Path,
+    output_file: &Path,
+    options: &ReadOptions,
+    frame_format: &FrameFormat,
+    collapse_recursion: bool,
+) -> Result<(), ExportError> {
+    ensure_has_reports(data_dir)?;
+    chrome::export_report(
+        data_dir,
+        output_file,
+        options,
+        frame_format,
+        collapse_recursion,
+    )
+    .context(ChromeSnafu)
+}
+
+/// Exports the resources of every sample to a Parquet file, one row per sample, e.g. to query
+/// many runs with DuckDB. Stacktraces are not exported.
+pub fn export_parquet(
+    data_dir: &Path,
+    output_file: &Path,
+    options: &ReadOptions,
+) -> Result<(), ExportError> {
+    ensure_has_reports(data_dir)?;
+    self::parquet::export_report(data_dir, output_file, options).context(ParquetSnafu)
+}
+
+/// Fails early with a helpful error if the directory does not contain any report files, instead of
+/// failing somewhere deep inside an exporter.
+fn ensure_has_reports(data_dir: &Path) -> Result<(), ExportError> {
+    // Unreadable directories are reported by the exporters themselves
+    let Ok(entries) = std::fs::read_dir(data_dir) else {
+        return Ok(());
+    };
+    let has_reports = entries
+        .flatten()
+        .any(|entry| is_report_file(&entry.file_name().to_string_lossy()));
+    ensure!(
+        has_reports,
+        EmptyDataDirSnafu {
+            path: data_dir.display().to_string()
+        }
+    );
+    Ok(())
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum ReportIdentifier {
-    Pid(u32),
     Global,
+    /// The profiler itself, recorded with `--track-self`
+    Profiler,
+    /// A process, where the generation counts how often its pid was reused by the OS before
+    Pid {
+        pid: u32,
+        generation: u32,
+    },
+}
+
+impl ReportIdentifier {
+    /// Parses the file name of a report without its extension, i.e. `global`, `self`, `<pid>` or
+    /// `<pid>-<generation>`, optionally prefixed by the name of an output name template.
+    pub(crate) fn parse(name: &str) -> Option<Self> {
+        if name == "global" {
+            return Some(ReportIdentifier::Global);
+        }
+        if name == "self" {
+            return Some(ReportIdentifier::Profiler);
+        }
+        let (pid, generation) = split_process_stem(name)?;
+        Some(ReportIdentifier::Pid { pid, generation })
+    }
+
+    /// The pid the process is known by in the [`Metadata`]. Only the first process with a pid is
+    /// recorded there.
+    pub fn metadata_pid(&self) -> Option<u32> {
+        match self {
+            ReportIdentifier::Pid { pid, generation: 0 } => Some(*pid),
+            _ => None,
+        }
+    }
+}
+
+impl Display for ReportIdentifier {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReportIdentifier::Global => write!(f, "global"),
+            ReportIdentifier::Profiler => write!(f, "self"),
+            ReportIdentifier::Pid { pid, generation: 0 } => write!(f, "{pid}"),
+            ReportIdentifier::Pid { pid, generation } => write!(f, "{pid}-{generation}"),
+        }
+    }
 }
 
 /// Reads the report data from the specified directory and returns a mapping of process identifiers
 /// to their respective JSON lines.
-/// This method is not very memory efficient, as it effectively reads all files in the directory
-/// in memory.
-fn read_report(data_dir: &Path) -> Result<HashMap<ReportIdentifier, Vec<JsonLine>>, Whatever> {
+/// This method is not very memory efficient, as it holds the samples of all files in the directory
+/// in memory. JSON report files are at least read one line at a time.
+/// With [`ReadOptions::best_effort`], report files that can not be read are skipped with a warning.
+/// What was read and skipped is summed up at the end, so no data goes missing unnoticed.
+pub(crate) fn read_report(
+    data_dir: &Path,
+    options: &ReadOptions,
+) -> Result<HashMap<ReportIdentifier, Vec<JsonLine>>, Whatever> {
+    let (all_processes, summary) = read_reports(data_dir, options, options.best_effort)?;
+    summary.log();
+    Ok(all_processes)
+}
+
+/// What [`read_reports`] read, and what it skipped or dropped on the way.
+#[derive(Debug, Default)]
+struct ReadSummary {
+    /// The number of report files samples were read from
+    files: usize,
+    /// The report files skipped on purpose, with the reason
+    skipped: Vec<(String, &'static str)>,
+    /// The report files that could not be read, with the error
+    errors: Vec<(PathBuf, String)>,
+    /// Samples dropped because they were truncated or named no report
+    dropped: usize,
+    /// The samples that were read
+    samples: usize,
+}
+
+impl ReadSummary {
+    fn log(&self) {
+        for (file_name, reason) in &self.skipped {
+            info!("Skipped `{file_name}`, {reason}");
+        }
+        for (path, error) in &self.errors {
+            warn!("Skipped `{}`: {error}", path.display());
+        }
+        info!(
+            "Read {} samples from {} report files, skipped {} files and dropped {} samples",
+            self.samples,
+            self.files,
+            self.skipped.len() + self.errors.len(),
+            self.dropped
+        );
+        if !self.errors.is_empty() {
+            warn!(
+                "Could not read {} report files, exporting the others",
+                self.errors.len()
+            );
+        }
+    }
+}
+
+/// Like [`read_report`], but skips report files that can not be read instead of failing. Returns
+/// the processes that could be read, and the error of every file that could not.
+pub fn read_report_lenient(
+    data_dir: &Path,
+    options: &ReadOptions,
+) -> (
+    HashMap<ReportIdentifier, Vec<JsonLine>>,
+    Vec<(PathBuf, String)>,
+) {
+    match read_reports(data_dir, options, true) {
+        Ok((all_processes, summary)) => (all_processes, summary.errors),
+        Err(e) => {
+            let error = Report::from_error(e).to_string();
+            (HashMap::new(), vec![(data_dir.to_path_buf(), error)])
+        }
+    }
+}
+
+/// Reads all report files, collecting the errors of single files instead of failing if
+/// `skip_errors` is set.
+fn read_reports(
+    data_dir: &Path,
+    options: &ReadOptions,
+    skip_errors: bool,
+) -> Result<(HashMap<ReportIdentifier, Vec<JsonLine>>, ReadSummary), Whatever> {
+    let entries = std::fs::read_dir(data_dir)
+        .whatever_context("could not open data dir")?
+        .collect::<Result<Vec<_>, _>>()
+        .whatever_context("could not read data dir entry")?;
+    let window = time_window(data_dir, options).whatever_context("could not read data dir")?;
+    let progress = progress_bar(entries.len(), "Reading report files");
+
     let mut all_processes = HashMap::new();
-    for entry in std::fs::read_dir(data_dir).whatever_context("could not open data dir")? {
-        let entry = entry.whatever_context("could not read data dir entry")?;
-        let name = entry
-            .path()
-            .file_stem()
-            .unwrap()
-            .to_string_lossy()
-            .to_string();
-
-        let content = std::fs::read_to_string(entry.path()).with_whatever_context(|_| {
-            format!("could not read file `{}`", entry.path().display())
+    let mut summary = ReadSummary::default();
+    for entry in entries {
+        progress.inc(1);
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        let Some(report_file) = ReportFileName::parse(&file_name) else {
+            debug!("Skipping `{file_name}`, it is not a report file");
+            continue;
+        };
+        // Numbers too large for a pid still look like a report
+        let pid = ReportIdentifier::parse(report_file.stem);
+        if pid.is_none() && report_file.stem != SINGLE_FILE_STEM {
+            summary
+                .skipped
+                .push((file_name, "it is not named after a process"));
+            continue;
+        }
+        if pid.is_some_and(|pid| !options.includes(pid)) {
+            summary
+                .skipped
+                .push((file_name, "its pid was not selected"));
+            continue;
+        }
+
+        let (mut lines, truncated) = match read_samples(&entry.path(), report_file, options) {
+            Ok(read) => read,
+            Err(_) if !entry.path().exists() => {
+                summary
+                    .skipped
+                    .push((file_name, "it was deleted while reading"));
+                continue;
+            }
+            Err(e) if skip_errors => {
+                summary
+                    .errors
+                    .push((entry.path(), Report::from_error(e).to_string()));
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        summary.dropped += usize::from(truncated);
+        if lines.is_empty() {
+            summary.skipped.push((file_name, "it holds no samples"));
+            continue;
+        }
+        for line in &mut lines {
+            options.strip.apply(line);
+        }
+        if let Some(window) = &window {
+            lines.retain(|line| window.contains(&line.time));
+            if lines.is_empty() {
+                summary
+                    .skipped
+                    .push((file_name, "it holds no samples in the time window"));
+                continue;
+            }
+        }
+
+        summary.files += 1;
+        // Rotated parts are stitched back together by their continuous sample index
+        match pid {
+            Some(pid) => all_processes.entry(pid).or_default().extend(lines),
+            None => summary.dropped += group_by_report(&mut all_processes, lines, options),
+        }
+    }
+    progress.finish_and_clear();
+    if options.containing_frame.is_some() {
+        for (id, lines) in &mut all_processes {
+            if matches!(id, ReportIdentifier::Pid { .. }) {
+                lines.retain(|line| options.keeps_frames(line));
+            }
+        }
+        all_processes.retain(|_, lines| !lines.is_empty());
+    }
+    summary.samples = all_processes.values().map(Vec::len).sum();
+    for lines in all_processes.values_mut() {
+        // The sequence survives transports that reorder samples, the index is only assigned when
+        // writing
+        lines.sort_by_key(|line| (line.sequence, line.index));
+    }
+
+    Ok((all_processes, summary))
+}
+
+/// Sorts the samples of a `--single-file` report into the processes named in each sample. Returns
+/// how many samples were dropped because they name no report.
+fn group_by_report(
+    all_processes: &mut HashMap<ReportIdentifier, Vec<JsonLine>>,
+    lines: Vec<JsonLine>,
+    options: &ReadOptions,
+) -> usize {
+    let mut dropped = 0;
+    for line in lines {
+        let Some(pid) = line.report.as_deref().and_then(ReportIdentifier::parse) else {
+            debug!(
+                "Skipping sample {}, it does not name its report",
+                line.index
+            );
+            dropped += 1;
+            continue;
+        };
+        if options.includes(pid) {
+            all_processes.entry(pid).or_default().push(line);
+        }
+    }
+    dropped
+}
+
+/// Reads and parses the samples of a single report file. JSON reports are streamed, as a single
+/// sample of a process with thousands of threads can be megabytes large. Also returns whether a
+/// truncated last sample was dropped.
+fn read_samples(
+    path: &Path,
+    report_file: ReportFileName<'_>,
+    options: &ReadOptions,
+) -> Result<(Vec<JsonLine>, bool), Whatever> {
+    // Downsampling here keeps it consistent across exporters. The sampling interval derived
+    // from the timestamps grows by the same factor, so CPU deltas stay correct.
+    let every_nth = options.every_nth.get();
+    if report_file.format == ReportFormat::Jsonl {
+        let reader = open_report(path, report_file.compressed)
+            .with_whatever_context(|_| format!("could not read file `{}`", path.display()))?;
+        return stream_lines(BufReader::new(reader), every_nth, path);
+    }
+
+    let content = read_report_file(path, report_file.compressed)
+        .with_whatever_context(|_| format!("could not read file `{}`", path.display()))?;
+    parse_samples_checked::<JsonLine>(&content, report_file.format, every_nth)
+        .with_whatever_context(|_| format!("could not deserialize sample in `{}`", path.display()))
+}
+
+/// Parses every nth sample of a JSON report like [`parse_lines`], but reads it line by line
+/// instead of holding the whole file in memory. A metadata header on the first line is skipped.
+fn stream_lines<T: Downsampled>(
+    reader: impl BufRead,
+    every_nth: usize,
+    path: &Path,
+) -> Result<(Vec<T>, bool), Whatever> {
+    let mut samples = StreamedSamples::new(every_nth);
+    for (line, record) in JsonRecords::new(reader) {
+        let record = record.with_whatever_context(|_| {
+            format!("could not read line {line} of `{}`", path.display())
         })?;
+        samples.push(&record, line, path)?;
+    }
+    Ok(samples.finish())
+}
 
-        let lines: Vec<JsonLine> = content
-            .lines()
-            .map(|line| {
-                serde_json::from_str(line).with_whatever_context(|_| {
-                    format!("could not deserialize line in `{}`", entry.path().display())
-                })
-            })
-            .collect::<Result<_, _>>()?;
-
-        let pid = if name == "global" {
-            // Pid 1 is the init process, pid 0 is not real and used as a global placeholder.
-            ReportIdentifier::Global
+/// The samples of a JSON report read line by line, with the line each one starts on, or the line
+/// that could not be read. A metadata header on the first line is skipped.
+pub(crate) struct JsonRecords<R> {
+    lines: std::iter::Enumerate<std::io::Lines<R>>,
+    pretty: Option<bool>,
+}
+
+impl<R: BufRead> JsonRecords<R> {
+    pub(crate) fn new(reader: R) -> Self {
+        Self {
+            lines: reader.lines().enumerate(),
+            pretty: None,
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for JsonRecords<R> {
+    type Item = (usize, std::io::Result<String>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut record = String::new();
+        let mut start = 0;
+        for (index, line) in self.lines.by_ref() {
+            let number = index + 1;
+            let line = match line {
+                Ok(line) => line,
+                Err(e) => return Some((number, Err(e))),
+            };
+            let blank = line.trim().is_empty();
+            if record.is_empty() {
+                let header = number == 1
+                    && MetadataHeader::split(line.as_bytes(), ReportFormat::Jsonl)
+                        .0
+                        .is_some();
+                if blank || header {
+                    continue;
+                }
+                start = number;
+            }
+            // Pretty-printed samples span several lines and are separated by blank ones
+            if *self.pretty.get_or_insert_with(|| line.trim() == "{") {
+                if !blank {
+                    record.push_str(&line);
+                    record.push('\n');
+                    continue;
+                }
+            } else {
+                record.push_str(&line);
+            }
+            return Some((start, Ok(record)));
+        }
+        (!record.is_empty()).then_some((start, Ok(record)))
+    }
+}
+
+/// The samples parsed so far by [`stream_lines`].
+struct StreamedSamples<T> {
+    samples: Downsampler<T>,
+    /// The line and error of the last sample if it could not be parsed, which is only an error if
+    /// it was not the last sample of the file
+    failed: Option<(usize, serde_json::Error)>,
+}
+
+impl<T: Downsampled> StreamedSamples<T> {
+    fn new(every_nth: usize) -> Self {
+        Self {
+            samples: Downsampler::new(every_nth),
+            failed: None,
+        }
+    }
+
+    fn push(&mut self, record: &str, line: usize, path: &Path) -> Result<(), Whatever> {
+        if let Some((line, e)) = self.failed.take() {
+            return Err(e).with_whatever_context(|_| {
+                format!(
+                    "could not deserialize sample at line {line} of `{}`",
+                    path.display()
+                )
+            });
+        }
+        if self.samples.keeps_next() {
+            match serde_json::from_str(record) {
+                Ok(sample) => self.samples.keep(sample),
+                Err(e) => {
+                    self.failed = Some((line, e));
+                    self.samples.skip(None);
+                }
+            }
+        } else {
+            self.samples.skip(serde_json::from_str(record).ok());
+        }
+        Ok(())
+    }
+
+    /// The parsed samples, dropping an unparsable last one, as it is most likely a write
+    /// interrupted by the profiler being killed. Also returns whether it was dropped.
+    fn finish(self) -> (Vec<T>, bool) {
+        if let Some((line, e)) = &self.failed {
+            warn!("Dropping truncated last line {line} of report: {e}");
+        }
+        let failed = self.failed.is_some();
+        (self.samples.finish(), failed)
+    }
+}
+
+/// Parses every nth sample of a report file in the given format, either as [`JsonLine`] or a
+/// cheaper subset of its fields.
+pub(crate) fn parse_samples<T: Downsampled>(
+    content: &[u8],
+    format: ReportFormat,
+    every_nth: usize,
+) -> Result<Vec<T>, Whatever> {
+    parse_samples_checked(content, format, every_nth).map(|(samples, _)| samples)
+}
+
+/// Like [`parse_samples`], but also returns whether a truncated last sample was dropped.
+fn parse_samples_checked<T: Downsampled>(
+    content: &[u8],
+    format: ReportFormat,
+    every_nth: usize,
+) -> Result<(Vec<T>, bool), Whatever> {
+    let (_, content) = MetadataHeader::split(content, format);
+    match format {
+        ReportFormat::Jsonl => {
+            let content = std::str::from_utf8(content).whatever_context("report is not UTF-8")?;
+            parse_lines(content, every_nth).whatever_context("invalid JSON line")
+        }
+        ReportFormat::Cbor => parse_cbor(content, every_nth).whatever_context("invalid CBOR item"),
+    }
+}
+
+/// Counts the samples in the (decompressed) content of a report file.
+pub(crate) fn count_samples(content: &[u8], format: ReportFormat) -> usize {
+    let (_, content) = MetadataHeader::split(content, format);
+    match format {
+        ReportFormat::Jsonl => json_records(&String::from_utf8_lossy(content)).len(),
+        ReportFormat::Cbor => {
+            let mut reader = content;
+            let mut count = 0;
+            while !reader.is_empty() && ciborium::from_reader::<IgnoredAny, _>(&mut reader).is_ok()
+            {
+                count += 1;
+            }
+            count
+        }
+    }
+}
+
+/// Converts a CBOR or pretty-printed report to JSON lines, for consumers that only understand
+/// those.
+fn to_json_lines(content: &[u8], format: ReportFormat) -> Result<Vec<u8>, Whatever> {
+    let mut json = Vec::with_capacity(content.len() * 2);
+    for line in parse_samples::<JsonLine>(content, format, 1)? {
+        serde_json::to_writer(&mut json, &line).whatever_context("could not serialize sample")?;
+        json.push(b'\n');
+    }
+    Ok(json)
+}
+
+/// Parses every nth item of a CBOR sequence. Like for JSON lines, a truncated last item is
+/// dropped.
+fn parse_cbor<T: Downsampled>(
+    content: &[u8],
+    every_nth: usize,
+) -> Result<(Vec<T>, bool), ciborium::de::Error<std::io::Error>> {
+    let mut reader = content;
+    let mut samples = Downsampler::new(every_nth);
+    while !reader.is_empty() {
+        // Skipped items still need to be decoded to find the start of the next one
+        let result = if samples.keeps_next() {
+            ciborium::from_reader::<T, _>(&mut reader).map(|sample| samples.keep(sample))
         } else {
-            ReportIdentifier::Pid(
-                name.parse::<u32>()
-                    .with_whatever_context(|_| format!("could not parse pid from `{name}`"))?,
-            )
+            ciborium::from_reader::<SampleIo, _>(&mut reader).map(|io| samples.skip(Some(io)))
         };
+        match result {
+            Ok(()) => {}
+            Err(ciborium::de::Error::Io(e)) if e.kind() == ErrorKind::UnexpectedEof => {
+                warn!("Dropping truncated last sample of report: {e}");
+                return Ok((samples.finish(), true));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok((samples.finish(), false))
+}
 
-        all_processes.insert(pid, lines);
+/// Whether the samples were written with `--json-pretty`, instead of one per line.
+fn is_pretty(content: &[u8]) -> bool {
+    content.trim_ascii_start().starts_with(b"{\n")
+}
+
+/// Splits a JSON report into its samples. These are on a line each, or separated by blank lines if
+/// they were pretty-printed.
+fn json_records(content: &str) -> Vec<&str> {
+    if is_pretty(content.as_bytes()) {
+        content
+            .split("\n\n")
+            .filter(|record| !record.trim().is_empty())
+            .collect()
+    } else {
+        content
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .collect()
     }
+}
 
-    Ok(all_processes)
+/// Parses every nth sample of a JSON report. Blank lines are skipped, and an unparsable last
+/// sample is dropped, as it is most likely a write interrupted by the profiler being killed.
+fn parse_lines<T: Downsampled>(
+    content: &str,
+    every_nth: usize,
+) -> Result<(Vec<T>, bool), serde_json::Error> {
+    let lines = json_records(content);
+    let last_index = lines.len().saturating_sub(1);
+
+    let mut samples = Downsampler::new(every_nth);
+    for (index, line) in lines.into_iter().enumerate() {
+        if !samples.keeps_next() {
+            samples.skip(serde_json::from_str(line).ok());
+            continue;
+        }
+        match serde_json::from_str(line) {
+            Ok(line) => samples.keep(line),
+            Err(e) if index == last_index => {
+                warn!("Dropping truncated last line of report: {e}");
+                return Ok((samples.finish(), true));
+            }
+            Err(e) => return Err(e),
+        }
+    }
+    Ok((samples.finish(), false))
+}
+
+/// Reads the metadata of the run. Without the sidecar file, the headers of reports written with
+/// `--embed-metadata` are used, falling back to defaults for reports captured by older versions.
+pub(crate) fn read_metadata(data_dir: &Path) -> Result<Metadata, Whatever> {
+    let path = data_dir.join(METADATA_FILE_NAME);
+    if !path.exists() {
+        return Ok(read_embedded_metadata(data_dir).unwrap_or_default());
+    }
+    let content = std::fs::read_to_string(&path)
+        .with_whatever_context(|_| format!("could not read metadata `{}`", path.display()))?;
+    serde_json::from_str(&content)
+        .with_whatever_context(|_| format!("could not deserialize metadata `{}`", path.display()))
+}
+
+/// Reads the markers appended to [`MARKERS_FILE_NAME`] in the time window of the options, ordered
+/// by time. There are none without the file.
+pub(crate) fn read_markers(
+    data_dir: &Path,
+    options: &ReadOptions,
+) -> Result<Vec<Marker>, Whatever> {
+    let path = data_dir.join(MARKERS_FILE_NAME);
+    let content = match std::fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => {
+            return Err(e)
+                .with_whatever_context(|_| format!("could not read markers `{}`", path.display()));
+        }
+    };
+    let window = time_window(data_dir, options).whatever_context("could not read data dir")?;
+
+    let mut markers = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let marker = match serde_json::from_str::<Marker>(line) {
+            Ok(marker) => marker,
+            // The target might still be appending the last line
+            Err(e) if options.best_effort => {
+                warn!("Skipping invalid marker in line {}: {e}", number + 1);
+                continue;
+            }
+            Err(e) => {
+                return Err(e).with_whatever_context(|_| {
+                    format!("could not deserialize marker in line {}", number + 1)
+                });
+            }
+        };
+        if window
+            .as_ref()
+            .is_none_or(|window| window.contains(&marker.time))
+        {
+            markers.push(marker);
+        }
+    }
+    markers.sort_by_key(|marker| marker.time);
+    Ok(markers)
+}
+
+/// Combines the metadata headers of all report files. Each header holds the metadata from when
+/// its file was created, so the processes are collected from all of them.
+fn read_embedded_metadata(data_dir: &Path) -> Option<Metadata> {
+    let mut paths = std::fs::read_dir(data_dir)
+        .ok()?
+        .flatten()
+        .map(|entry| entry.path())
+        .collect::<Vec<_>>();
+    paths.sort();
+    paths
+        .iter()
+        .filter_map(|path| {
+            let file_name = path.file_name()?.to_string_lossy();
+            let report_file = ReportFileName::parse(&file_name)?;
+            read_metadata_header(path, report_file).ok().flatten()
+        })
+        .reduce(|mut merged, metadata| {
+            merged
+                .process_start_times
+                .extend(metadata.process_start_times);
+            merged
+                .process_command_lines
+                .extend(metadata.process_command_lines);
+            merged
+                .process_python_versions
+                .extend(metadata.process_python_versions);
+            merged
+        })
+}
+
+/// The metadata header of a report file, without reading the samples after it.
+fn read_metadata_header(
+    path: &Path,
+    file: ReportFileName<'_>,
+) -> std::io::Result<Option<Metadata>> {
+    let mut reader = BufReader::new(open_report(path, file.compressed)?);
+    if file.format == ReportFormat::Cbor {
+        return Ok(ciborium::from_reader::<MetadataHeader, _>(&mut reader)
+            .ok()
+            .map(|it| it.metadata));
+    }
+    let mut line = Vec::new();
+    reader.read_until(b'\n', &mut line)?;
+    Ok(MetadataHeader::split(&line, ReportFormat::Jsonl).0)
+}
+
+/// Opens a report file for reading, transparently decompressing gzip-compressed files.
+pub(crate) fn open_report(path: &Path, compressed: bool) -> std::io::Result<Box<dyn Read>> {
+    let file = File::open(path)?;
+    Ok(if compressed {
+        Box::new(TruncatedGz(MultiGzDecoder::new(file)))
+    } else {
+        Box::new(file)
+    })
+}
+
+/// Reads a report file, transparently decompressing gzip-compressed files.
+pub(crate) fn read_report_file(path: &Path, compressed: bool) -> std::io::Result<Vec<u8>> {
+    if !compressed {
+        return std::fs::read(path);
+    }
+    let mut content = Vec::new();
+    TruncatedGz(MultiGzDecoder::new(File::open(path)?)).read_to_end(&mut content)?;
+    Ok(content)
+}
+
+/// Decompresses a gzip report, ending at its truncated end instead of failing. The file sink
+/// flushes the stream after every batch, so the report of a crashed profiler is only missing the
+/// trailer and at most the batch it was writing, whose truncated last sample is dropped when
+/// parsing.
+struct TruncatedGz<R>(MultiGzDecoder<R>);
+
+impl<R: Read> Read for TruncatedGz<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self.0.read(buf) {
+            // A missing trailer is an unexpected EOF, a block cut off in the middle is invalid
+            Err(e) if matches!(e.kind(), ErrorKind::UnexpectedEof | ErrorKind::InvalidInput) => {
+                warn!("Dropping the truncated end of a compressed report: {e}");
+                Ok(0)
+            }
+            result => result,
+        }
+    }
+}
+
+/// Returns the earliest sample time in milliseconds.
+fn start_time(samples: impl Iterator<Item = impl Borrow<JsonLine>>) -> Result<u128, Whatever> {
+    samples
+        .map(|it| it.borrow().time)
+        .min()
+        .whatever_context("no samples found")
+}
+
+/// The interval assumed if the samples are too close to tell them apart
+const MIN_SAMPLING_INTERVAL_MICROS: u64 = 1000;
+
+/// Returns the median delta between consecutive samples in microseconds, which we take as the
+/// intended sampling interval. A zero interval, e.g. if most samples share their millisecond
+/// timestamp, becomes a millisecond, as it would make the exported profiles degenerate.
+fn sampling_interval_micros(
+    samples: impl Iterator<Item = impl Borrow<JsonLine>>,
+) -> Result<u64, Whatever> {
+    let deltas = samples.collect::<Vec<_>>();
+    let mut deltas = deltas
+        .windows(2)
+        .map(|window| {
+            window[1]
+                .borrow()
+                .timestamp_micros()
+                .saturating_sub(window[0].borrow().timestamp_micros())
+        })
+        .collect::<Vec<_>>();
+    deltas.sort_unstable();
+
+    // take the median of the deltas as intended interval
+    let interval_micros = deltas
+        .get(deltas.len() / 2)
+        .copied()
+        .map(|it| it as u64)
+        .whatever_context("no samples found")?;
+    if interval_micros == 0 {
+        warn!(
+            "Most samples share their timestamp, assuming an interval of \
+             {MIN_SAMPLING_INTERVAL_MICROS}µs. Use a lower rate or microsecond timestamps."
+        );
+        return Ok(MIN_SAMPLING_INTERVAL_MICROS);
+    }
+    Ok(interval_micros)
+}
+
+/// Creates a progress bar for a slow export step. It is hidden if stdout is not a terminal, so
+/// it does not end up in logs.
+pub(crate) fn progress_bar(len: usize, message: &'static str) -> ProgressBar {
+    if !std::io::stdout().is_terminal() {
+        return ProgressBar::hidden();
+    }
+    ProgressBar::new(len as u64)
+        .with_message(message)
+        .with_style(
+            ProgressStyle::with_template("{msg} [{bar:40}] {pos}/{len} ({eta} remaining)")
+                .expect("progress template is valid")
+                .progress_chars("=> "),
+        )
+}
+
+/// The default [`FrameFormat`].
+pub const DEFAULT_FRAME_FORMAT: &str = "{name} ({short_filename}:{line})";
+
+/// A template for the labels of frames in exported profiles, e.g. `{name} {module}:{line}`.
+/// Placeholders are replaced by the field of the frame with the same name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FrameFormat {
+    parts: Vec<FrameFormatPart>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum FrameFormatPart {
+    Text(String),
+    Name,
+    Filename,
+    /// Falls back to the filename if py-spy could not shorten it
+    ShortFilename,
+    /// Empty for Python frames, which have no module
+    Module,
+    Line,
+}
+
+impl FrameFormat {
+    pub fn label(&self, frame: &Frame) -> String {
+        let mut label = String::new();
+        for part in &self.parts {
+            match part {
+                FrameFormatPart::Text(text) => label.push_str(text),
+                FrameFormatPart::Name => label.push_str(&frame.name),
+                FrameFormatPart::Filename => label.push_str(&frame.filename),
+                FrameFormatPart::ShortFilename => {
+                    label.push_str(frame.short_filename.as_ref().unwrap_or(&frame.filename))
+                }
+                FrameFormatPart::Module => label.push_str(frame.module.as_deref().unwrap_or("")),
+                FrameFormatPart::Line => label.push_str(&frame.line.to_string()),
+            }
+        }
+        label
+    }
+
+    /// The label of a frame standing for `depth` recursive calls, see [`stack_frames`].
+    pub(crate) fn recursive_label(&self, frame: &Frame, depth: usize) -> String {
+        let label = self.label(frame);
+        if depth > 1 {
+            format!("{label} [recursion depth {depth}]")
+        } else {
+            label
+        }
+    }
+}
+
+/// The frames of a stack, innermost first, with the number of calls each stands for. With
+/// `collapse_recursion`, consecutive calls of the same function (same name and file) are
+/// collapsed into their innermost frame, so deep recursion does not drown the rest of the stack.
+pub(crate) fn stack_frames(frames: &[Frame], collapse_recursion: bool) -> Vec<(&Frame, usize)> {
+    let mut collapsed = Vec::<(&Frame, usize)>::with_capacity(frames.len());
+    for frame in frames {
+        match collapsed.last_mut() {
+            Some((innermost, depth))
+                if collapse_recursion
+                    && innermost.name == frame.name
+                    && innermost.filename == frame.filename =>
+            {
+                *depth += 1;
+            }
+            _ => collapsed.push((frame, 1)),
+        }
+    }
+    collapsed
+}
+
+impl Default for FrameFormat {
+    fn default() -> Self {
+        DEFAULT_FRAME_FORMAT
+            .parse()
+            .expect("default frame format is valid")
+    }
+}
+
+impl FromStr for FrameFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = Vec::new();
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(FrameFormatPart::Text(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("unclosed `{{` in `{s}`"))?;
+            let part = match &rest[start + 1..start + end] {
+                "name" => FrameFormatPart::Name,
+                "filename" => FrameFormatPart::Filename,
+                "short_filename" => FrameFormatPart::ShortFilename,
+                "module" => FrameFormatPart::Module,
+                "line" => FrameFormatPart::Line,
+                field => {
+                    return Err(format!(
+                        "unknown frame field `{field}`, expected `name`, `filename`, \
+                         `short_filename`, `module` or `line`"
+                    ));
+                }
+            };
+            parts.push(part);
+            rest = &rest[start + end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(FrameFormatPart::Text(rest.to_string()));
+        }
+        Ok(Self { parts })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::ProcessResources;
+
+    fn sample(index: usize, time_micros: u128) -> JsonLine {
+        let mut resources = ProcessResources::exited();
+        resources.exited = false;
+        JsonLine {
+            stacktraces: Vec::new(),
+            resources,
+            index,
+            time: time_micros / 1000,
+            time_micros: Some(time_micros),
+            sample_duration_micros: 0,
+            report: None,
+            sequence: None,
+        }
+    }
+
+    fn json_lines(samples: &[JsonLine]) -> String {
+        samples
+            .iter()
+            .map(|sample| serde_json::to_string(sample).unwrap() + "\n")
+            .collect()
+    }
+
+    fn indices(samples: &[JsonLine]) -> Vec<usize> {
+        samples.iter().map(|sample| sample.index).collect()
+    }
+
+    #[test]
+    fn skips_trailing_blank_lines() {
+        let content = json_lines(&[sample(0, 1000), sample(1, 2000)]) + "\n  \n";
+
+        let (samples, truncated) = parse_lines::<JsonLine>(&content, 1).unwrap();
+        assert_eq!(indices(&samples), [0, 1]);
+        assert!(!truncated);
+
+        let (samples, truncated) =
+            stream_lines::<JsonLine>(content.as_bytes(), 1, Path::new("1234.json")).unwrap();
+        assert_eq!(indices(&samples), [0, 1]);
+        assert!(!truncated);
+    }
+
+    #[test]
+    fn drops_half_written_last_sample() {
+        let written = json_lines(&[sample(0, 1000), sample(1, 2000)]);
+        let interrupted = serde_json::to_string(&sample(2, 3000)).unwrap();
+        let content = format!("{written}{}", &interrupted[..interrupted.len() / 2]);
+
+        let (samples, truncated) = parse_lines::<JsonLine>(&content, 1).unwrap();
+        assert_eq!(indices(&samples), [0, 1]);
+        assert!(truncated);
+
+        let (samples, truncated) =
+            stream_lines::<JsonLine>(content.as_bytes(), 1, Path::new("1234.json")).unwrap();
+        assert_eq!(indices(&samples), [0, 1]);
+        assert!(truncated);
+    }
+
+    #[test]
+    fn fails_on_half_written_sample_before_the_last() {
+        let interrupted = serde_json::to_string(&sample(0, 1000)).unwrap();
+        let content = format!(
+            "{}\n{}",
+            &interrupted[..interrupted.len() / 2],
+            json_lines(&[sample(1, 2000)])
+        );
+
+        assert!(parse_lines::<JsonLine>(&content, 1).is_err());
+        assert!(stream_lines::<JsonLine>(content.as_bytes(), 1, Path::new("1234.json")).is_err());
+    }
+
+    #[test]
+    fn skips_files_that_are_no_reports() {
+        let data_dir = std::env::temp_dir().join(format!(
+            "py-crude-resource-monitor-non-reports-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&data_dir).unwrap();
+        let report = json_lines(&[sample(0, 1000), sample(1, 2000)]);
+        std::fs::write(data_dir.join("1234.json"), &report).unwrap();
+        std::fs::write(data_dir.join("global.json"), &report).unwrap();
+        std::fs::write(
+            data_dir.join(".DS_Store"),
+            [0, 0, 0, 1, b'B', b'u', b'd', b'1'],
+        )
+        .unwrap();
+        std::fs::write(data_dir.join("manifest.json"), "{\"files\": {}}").unwrap();
+        std::fs::write(data_dir.join("notes.txt"), "baseline run").unwrap();
+
+        let read = read_reports(&data_dir, &ReadOptions::default(), false);
+        std::fs::remove_dir_all(&data_dir).unwrap();
+
+        let (reports, summary) = read.unwrap();
+        let mut ids = reports.keys().copied().collect::<Vec<_>>();
+        ids.sort();
+        assert_eq!(
+            ids,
+            [
+                ReportIdentifier::Global,
+                ReportIdentifier::Pid {
+                    pid: 1234,
+                    generation: 0
+                }
+            ]
+        );
+        assert_eq!(summary.files, 2);
+        assert!(summary.errors.is_empty());
+    }
+
+    #[test]
+    fn all_equal_timestamps_fall_back_to_the_minimum_interval() {
+        let samples = (0..5)
+            .map(|index| sample(index, 1_000_000))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            sampling_interval_micros(samples.iter()).unwrap(),
+            MIN_SAMPLING_INTERVAL_MICROS
+        );
+    }
+
+    #[test]
+    fn sampling_interval_is_the_median_delta() {
+        let times = [0, 10_000, 20_000, 30_000, 70_000];
+        let samples = times
+            .iter()
+            .enumerate()
+            .map(|(index, time)| sample(index, 1_000_000 + time))
+            .collect::<Vec<_>>();
+        assert_eq!(sampling_interval_micros(samples.iter()).unwrap(), 10_000);
+    }
 }
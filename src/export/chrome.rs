@@ -0,0 +1,430 @@
+use crate::export::{FrameFormat, ReadOptions, ReportIdentifier};
+use crate::types::{JsonLine, Metadata, ProcessResources};
+use log::info;
+use serde_json::{Value, json};
+use snafu::{Location, ResultExt, Snafu, Whatever};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+#[derive(Debug, Snafu)]
+pub enum ExportError {
+    #[snafu(display("Error reading report at {location}"))]
+    ReadReport {
+        source: Whatever,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display("Error generating chrome trace at {location}"))]
+    ChromeTrace {
+        source: Whatever,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display("Error serializing trace at {location}"))]
+    SerializeTrace {
+        source: serde_json::Error,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display("Error writing output file `{path}` at {location}"))]
+    WriteOutput {
+        source: std::io::Error,
+        path: String,
+        #[snafu(implicit)]
+        location: Location,
+    },
+}
+
+/// A frame that is currently on the stack of a thread, waiting for its complete event to be
+/// emitted once it is popped.
+struct OpenFrame {
+    label: String,
+    start_micros: u128,
+}
+
+/// Builds a list of trace events in the
+/// [Trace Event Format](https://docs.google.com/document/d/1CvAClvFfyA5R-PhYUmn5OOQtYMH4h6I0nSsKchNAySU).
+///
+/// Consecutive samples sharing a stack prefix are merged into a single complete (`X`) event per
+/// frame, so the trace shows a flame chart per thread. Resources are emitted as counter (`C`)
+/// events.
+struct TraceBuilder {
+    start_time_millis: u128,
+    interval_micros: u64,
+    frame_format: FrameFormat,
+    /// Collapse consecutive calls of the same function, see [`super::stack_frames`]
+    collapse_recursion: bool,
+    events: Vec<Value>,
+}
+
+impl TraceBuilder {
+    fn new(
+        start_time_millis: u128,
+        interval_micros: u64,
+        frame_format: FrameFormat,
+        collapse_recursion: bool,
+    ) -> Self {
+        Self {
+            start_time_millis,
+            interval_micros,
+            frame_format,
+            collapse_recursion,
+            events: Vec::new(),
+        }
+    }
+
+    /// Converts microseconds since the unix epoch to microseconds since the start of the trace
+    fn micros(&self, time_micros: u128) -> u128 {
+        time_micros - self.start_time_millis * 1000
+    }
+
+    fn add_process(&mut self, pid: u32, samples: Vec<JsonLine>) {
+        self.events.push(json!({
+            "name": "process_name",
+            "ph": "M",
+            "pid": pid,
+            "args": { "name": format!("Process {pid}") },
+        }));
+
+        // Sorted, so the frames of all threads are closed in a deterministic order
+        let mut open_frames: BTreeMap<u64, Vec<OpenFrame>> = BTreeMap::new();
+        let mut thread_names: HashMap<u64, String> = HashMap::new();
+        let mut last_micros = 0;
+
+        for line in samples {
+            let ts = self.micros(line.timestamp_micros());
+            last_micros = ts;
+
+            let mut seen_threads = HashSet::new();
+            for stacktrace in line.stacktraces {
+                seen_threads.insert(stacktrace.thread_id);
+                // thread name might not be set in first line of the file, so we remember the
+                // last one we saw.
+                if let Some(thread_name) = stacktrace.thread_name {
+                    thread_names.insert(stacktrace.thread_id, thread_name);
+                }
+
+                let labels = super::stack_frames(&stacktrace.frames, self.collapse_recursion)
+                    .into_iter()
+                    .rev()
+                    .map(|(frame, depth)| self.frame_format.recursive_label(frame, depth))
+                    .collect::<Vec<_>>();
+                let open = open_frames.entry(stacktrace.thread_id).or_default();
+
+                let common_prefix = open
+                    .iter()
+                    .zip(labels.iter())
+                    .take_while(|(frame, label)| &frame.label == *label)
+                    .count();
+                close_frames(
+                    &mut self.events,
+                    pid,
+                    stacktrace.thread_id,
+                    open,
+                    common_prefix,
+                    ts,
+                );
+                open.extend(
+                    labels
+                        .into_iter()
+                        .skip(common_prefix)
+                        .map(|label| OpenFrame {
+                            label,
+                            start_micros: ts,
+                        }),
+                );
+            }
+
+            // A thread that is no longer sampled exited or, with `--on-cpu-only`, went idle, so
+            // its frames end here instead of stretching to the end of the trace
+            for (tid, open) in &mut open_frames {
+                if !seen_threads.contains(tid) {
+                    close_frames(&mut self.events, pid, *tid, open, 0, ts);
+                }
+            }
+
+            self.add_counters(pid, ts, &line.resources);
+        }
+
+        // The last sample is assumed to last for one sampling interval
+        let end_micros = last_micros + self.interval_micros as u128;
+        for (tid, open) in &mut open_frames {
+            close_frames(&mut self.events, pid, *tid, open, 0, end_micros);
+        }
+
+        for (tid, name) in thread_names {
+            self.events.push(json!({
+                "name": "thread_name",
+                "ph": "M",
+                "pid": pid,
+                "tid": tid,
+                "args": { "name": name },
+            }));
+        }
+    }
+
+    /// Adds a "System" process with the system-wide resources. Pid 0 is not a real process, we
+    /// use it as a placeholder like `read_report` does.
+    fn add_system(&mut self, samples: Vec<JsonLine>) {
+        self.events.push(json!({
+            "name": "process_name",
+            "ph": "M",
+            "pid": 0,
+            "args": { "name": "System" },
+        }));
+        for line in samples {
+            let ts = self.micros(line.timestamp_micros());
+            self.events.push(json!({
+                "name": "CPU",
+                "ph": "C",
+                "ts": ts,
+                "pid": 0,
+                "args": { "cpu": line.resources.cpu },
+            }));
+            if let Some(watts) = line.resources.power_watts {
+                self.events.push(json!({
+                    "name": "Power",
+                    "ph": "C",
+                    "ts": ts,
+                    "pid": 0,
+                    "args": { "watts": watts },
+                }));
+            }
+            self.add_counters(0, ts, &line.resources);
+        }
+    }
+
+    /// Adds the memory and disk bandwidth counters of a sample, like the firefox export shows them.
+    fn add_counters(&mut self, pid: u32, ts: u128, resources: &ProcessResources) {
+        self.events.push(json!({
+            "name": "Memory",
+            "ph": "C",
+            "ts": ts,
+            "pid": pid,
+            "args": { "memory": resources.memory },
+        }));
+        self.events.push(json!({
+            "name": "Bandwidth",
+            "ph": "C",
+            "ts": ts,
+            "pid": pid,
+            "args": { "io": resources.disk_read_bytes + resources.disk_write_bytes },
+        }));
+    }
+
+    fn finish(self, metadata: &Metadata) -> Value {
+        json!({
+            "traceEvents": self.events,
+            "displayTimeUnit": "ms",
+            "otherData": {
+                "mode": if metadata.on_cpu_only { "on-cpu" } else { "wall-clock" },
+                // Timestamps are relative to this, to correlate them with other traces or logs
+                "start_time_unix_millis": self.start_time_millis,
+            },
+        })
+    }
+}
+
+/// Pops all frames above `keep` from the open stack, emitting a complete event for each of them.
+fn close_frames(
+    events: &mut Vec<Value>,
+    pid: u32,
+    tid: u64,
+    open: &mut Vec<OpenFrame>,
+    keep: usize,
+    end_micros: u128,
+) {
+    // Innermost frames are closed first, which keeps the events properly nested
+    while open.len() > keep {
+        let frame = open.pop().expect("open frames are not empty");
+        events.push(json!({
+            "name": frame.label,
+            "cat": "python",
+            "ph": "X",
+            "ts": frame.start_micros,
+            "dur": end_micros - frame.start_micros,
+            "pid": pid,
+            "tid": tid,
+        }));
+    }
+}
+
+pub(super) fn export_report(
+    data_dir: &Path,
+    output_path: &Path,
+    options: &ReadOptions,
+    frame_format: &FrameFormat,
+    collapse_recursion: bool,
+) -> Result<(), ExportError> {
+    let process_to_profile = super::read_report(data_dir, options).context(ReadReportSnafu)?;
+    let metadata = super::read_metadata(data_dir).context(ReadReportSnafu)?;
+
+    let trace = generate_trace(
+        process_to_profile,
+        &metadata,
+        frame_format,
+        collapse_recursion,
+    )
+    .context(ChromeTraceSnafu)?;
+
+    let path = output_path.display().to_string();
+    let output_file = File::create(output_path).context(WriteOutputSnafu { path: &path })?;
+    let mut writer = BufWriter::new(output_file);
+    serde_json::to_writer(&mut writer, &trace).context(SerializeTraceSnafu)?;
+    writer.flush().context(WriteOutputSnafu { path })?;
+
+    info!(
+        "Wrote Chrome trace to {}. Open it in `https://ui.perfetto.dev`.",
+        output_path.display()
+    );
+
+    Ok(())
+}
+
+fn generate_trace(
+    processes: HashMap<ReportIdentifier, Vec<JsonLine>>,
+    metadata: &Metadata,
+    frame_format: &FrameFormat,
+    collapse_recursion: bool,
+) -> Result<Value, Whatever> {
+    let start_time_millis = super::start_time(processes.values().flatten())?;
+    let interval_micros = super::sampling_interval_micros(processes.values().flatten())?;
+    let mut builder = TraceBuilder::new(
+        start_time_millis,
+        interval_micros,
+        frame_format.clone(),
+        collapse_recursion,
+    );
+
+    let mut processes = processes.into_iter().collect::<Vec<_>>();
+    // Ensure the trace is deterministic
+    processes.sort_by_key(|(id, _)| *id);
+
+    for (id, samples) in processes {
+        // Trace events can not tell processes with the same pid apart, so processes reusing a pid
+        // are merged into the process track of the first one
+        match id {
+            ReportIdentifier::Pid { pid, .. } => builder.add_process(pid, samples),
+            ReportIdentifier::Profiler => {
+                builder.add_process(metadata.profiler_pid.unwrap_or(0), samples)
+            }
+            ReportIdentifier::Global => builder.add_system(samples),
+        }
+    }
+
+    Ok(builder.finish(metadata))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Frame, StackTrace};
+
+    fn sample(time_micros: u128, thread_ids: &[u64]) -> JsonLine {
+        let stacktraces = thread_ids
+            .iter()
+            .map(|&thread_id| StackTrace {
+                pid: 1234,
+                thread_id,
+                thread_name: None,
+                os_thread_id: None,
+                active: true,
+                owns_gil: false,
+                frames: vec![Frame {
+                    name: "work".to_string(),
+                    filename: "main.py".to_string(),
+                    module: None,
+                    short_filename: None,
+                    line: 1,
+                    locals: None,
+                    is_entry: true,
+                }],
+                process_info: None,
+            })
+            .collect();
+        let mut resources = ProcessResources::exited();
+        resources.exited = false;
+        JsonLine {
+            stacktraces,
+            resources,
+            index: 0,
+            time: time_micros / 1000,
+            time_micros: Some(time_micros),
+            sample_duration_micros: 0,
+            report: None,
+            sequence: None,
+        }
+    }
+
+    fn events<'a>(trace: &'a Value, phase: &str) -> Vec<&'a Value> {
+        trace["traceEvents"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|event| event["ph"] == phase)
+            .collect()
+    }
+
+    #[test]
+    fn frames_of_vanished_threads_are_closed() {
+        let processes = HashMap::from([(
+            ReportIdentifier::Pid {
+                pid: 1234,
+                generation: 0,
+            },
+            vec![
+                sample(1_000_000, &[1, 2]),
+                sample(1_001_000, &[2]),
+                sample(1_002_000, &[2]),
+            ],
+        )]);
+        let trace = generate_trace(
+            processes,
+            &Metadata::default(),
+            &FrameFormat::default(),
+            false,
+        )
+        .unwrap();
+
+        let durations = events(&trace, "X")
+            .into_iter()
+            .map(|event| {
+                (
+                    event["tid"].as_u64().unwrap(),
+                    event["dur"].as_u64().unwrap(),
+                )
+            })
+            .collect::<HashMap<_, _>>();
+        // The first thread ends with the sample it is missing from, the second one lasts for an
+        // interval after the last sample
+        assert_eq!(durations, HashMap::from([(1, 1000), (2, 3000)]));
+    }
+
+    #[test]
+    fn exports_the_global_counters() {
+        let mut samples = vec![sample(1_000_000, &[]), sample(1_001_000, &[])];
+        for line in &mut samples {
+            line.resources.memory = 42;
+            line.resources.power_watts = Some(10.0);
+        }
+        let processes = HashMap::from([(ReportIdentifier::Global, samples)]);
+        let trace = generate_trace(
+            processes,
+            &Metadata::default(),
+            &FrameFormat::default(),
+            false,
+        )
+        .unwrap();
+
+        let counters = events(&trace, "C");
+        assert!(counters.iter().all(|event| event["pid"] == 0));
+        let memory = counters
+            .iter()
+            .find(|event| event["name"] == "Memory")
+            .unwrap();
+        assert_eq!(memory["args"]["memory"], 42);
+        assert!(counters.iter().any(|event| event["name"] == "Power"));
+    }
+}
@@ -1,23 +1,44 @@
-use crate::export::ReportIdentifier;
-use crate::types::JsonLine;
-use flate2::Compression;
+use crate::export::{
+    CategoryColorName, CompressionLevel, FirefoxColors, FirefoxOptions, FrameFormat,
+    GraphColorName, ReadOptions, ReportIdentifier,
+};
+use crate::types::{
+    self, CpuNormalization, JsonLine, MAIN_THREAD_NAME, Metadata, ProcessResources, StackTrace,
+};
 use flate2::write::GzEncoder;
 use fxprof_processed_profile::{
     CategoryColor, CategoryHandle, CounterHandle, CpuDelta, Frame, FrameFlags, FrameInfo,
-    GraphColor, ProcessHandle, Profile, ReferenceTimestamp, SamplingInterval, ThreadHandle,
-    Timestamp,
+    GraphColor, MarkerFieldFlags, MarkerFieldFormat, MarkerTiming, ProcessHandle, Profile,
+    ReferenceTimestamp, SamplingInterval, StackHandle, StaticSchemaMarker, StaticSchemaMarkerField,
+    StringHandle, ThreadHandle, Timestamp,
 };
-use log::info;
+use log::{debug, info, warn};
+use regex::Regex;
 use snafu::{Location, OptionExt, ResultExt, Snafu, Whatever};
 use std::borrow::Borrow;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs::File;
+use std::io::{BufWriter, Write};
 use std::marker::PhantomData;
 use std::path::Path;
 
-const MAIN_THREAD_NAME: &str = "MainThread";
 const CATEGORY_PYTHON_NAME: &str = "Python";
 const CATEGORY_NATIVE_NAME: &str = "Native";
+const CATEGORY_IDLE_NAME: &str = "Idle";
+/// The leaf frame added to the samples of inactive threads with [`FirefoxOptions::mark_idle`]
+const IDLE_FRAME_LABEL: &str = "[idle]";
+/// The pid of the process holding the threads merged by name, no real process has it
+const MERGED_THREADS_PID: u32 = u32::MAX;
+/// The thread id of the track holding the threads dropped by `--select-threads-by-cpu`, which is
+/// also the key of their summed resources. No real thread has it.
+const OTHER_THREADS_TRACK_ID: u64 = u32::MAX as u64;
+const OTHER_THREADS_TRACK_NAME: &str = "Other threads";
+
+/// The thread id of the track holding the threads of the nth group of `--group-threads`, counting
+/// down from below [`OTHER_THREADS_TRACK_ID`]. No real thread has it.
+fn grouped_threads_track_id(group: usize) -> u64 {
+    OTHER_THREADS_TRACK_ID - 1 - group as u64
+}
 
 #[derive(Debug, Snafu)]
 pub enum ExportError {
@@ -57,95 +78,414 @@ pub enum ExportError {
 
 struct ProfileBuilder {
     start_time_millis: u128,
-    interval_millis: u64,
+    interval_micros: u64,
     profile: Profile,
     category_native: CategoryHandle,
     category_python: CategoryHandle,
+    colors: FirefoxColors,
+    metadata: Metadata,
+    frame_format: FrameFormat,
+    /// Merge runs of identical consecutive stacks of a thread into one weighted sample
+    coalesce: bool,
+    /// Collapse consecutive calls of the same function, see [`super::stack_frames`]
+    collapse_recursion: bool,
+    /// The stacktraces of named threads were moved to the "Merged threads" process, see
+    /// [`take_named_threads`]
+    merge_by_thread_name: bool,
+    /// The leaf frame added to the stacks of inactive threads, see [`ProfileBuilder::mark_idle`]
+    idle_frame: Option<FrameInfo>,
+    /// The ids of the file and function names of the interned frames, so each name is only held
+    /// once
+    name_ids: HashMap<String, u32>,
+    /// The interned frames by the ids of their file and function, line and recursion depth. The
+    /// function tells apart the native frames of a library, which all have line 0. Frame infos do
+    /// not belong to a thread, so all processes share them.
+    frame_infos: HashMap<(u32, u32, i32, usize), FrameInfo>,
+    /// The tracks by pid and the Python and OS thread ids of their stacktraces, to place the
+    /// [`types::Marker`]s on
+    marker_threads: BTreeMap<(u32, u64), ThreadHandle>,
+    /// The start time and main thread of every process by pid
+    main_threads: BTreeMap<u32, (u128, ThreadHandle)>,
 }
 
 impl ProfileBuilder {
     pub fn from_samples<'a, T: Iterator<Item = &'a Vec<JsonLine>>>(
         samples: impl Fn() -> T,
+        metadata: &Metadata,
+        colors: FirefoxColors,
+        profile_name: Option<&str>,
     ) -> Result<Self, Whatever> {
-        let start_time_millis = Self::start_time(samples().flat_map(|lines| lines.iter()))?;
-        let interval_millis = Self::sampling_interval(samples().flat_map(|lines| lines.iter()))?;
-
-        Ok(Self::new(start_time_millis, interval_millis))
-    }
+        let start_time_millis = super::start_time(samples().flat_map(|lines| lines.iter()))?;
+        let interval_micros =
+            super::sampling_interval_micros(samples().flat_map(|lines| lines.iter()))?;
 
-    pub fn start_time(
-        samples: impl Iterator<Item = impl Borrow<JsonLine>>,
-    ) -> Result<u128, Whatever> {
-        samples
-            .map(|it| it.borrow().time)
-            .min()
-            .whatever_context("no samples found")
-    }
-
-    pub fn sampling_interval(
-        samples: impl Iterator<Item = impl Borrow<JsonLine>>,
-    ) -> Result<u64, Whatever> {
-        let deltas = samples.collect::<Vec<_>>();
-        let mut deltas = deltas
-            .windows(2)
-            .map(|window| {
-                window[1]
-                    .borrow()
-                    .time
-                    .saturating_sub(window[0].borrow().time)
+        // The profiled command tells several open profiles apart. The process that started first
+        // is the root, pids say nothing about the order as they wrap around.
+        let root_command = metadata
+            .process_command_lines
+            .iter()
+            .min_by_key(|(pid, _)| {
+                let start_time = metadata.process_start_times.get(pid);
+                (start_time.is_none(), start_time.copied(), **pid)
             })
-            .collect::<Vec<_>>();
-        deltas.sort_unstable();
+            .map(|(_, args)| args)
+            .filter(|args| !args.is_empty());
+        let mut product = match profile_name {
+            Some(name) => name.to_string(),
+            None => root_command.map_or_else(|| "python".to_string(), |args| args.join(" ")),
+        };
+        if metadata.on_cpu_only {
+            product.push_str(" (on-CPU)");
+        }
+        // The profile has no place for arbitrary metadata, but the product is shown in the title
+        if !metadata.labels.is_empty() {
+            product = format!("{product} [{}]", metadata.format_labels());
+        }
 
-        // take the median of the deltas as intended interval
-        deltas
-            .get(deltas.len() / 2)
-            .copied()
-            .map(|it| it as u64)
-            .whatever_context("no samples found")
+        let mut builder = Self::new(&product, start_time_millis, interval_micros, colors);
+        builder.metadata = metadata.clone();
+        Ok(builder)
     }
 
-    pub fn new(start_time_millis: u128, interval_millis: u64) -> Self {
+    pub fn new(
+        product: &str,
+        start_time_millis: u128,
+        interval_micros: u64,
+        colors: FirefoxColors,
+    ) -> Self {
+        // All timestamps are relative to the first sample on the same clock, so the profiler shows
+        // the actual wall-clock time of every sample
         let mut profile = Profile::new(
-            // TODO: Add metadata to original data json files
-            "python",
+            product,
             ReferenceTimestamp::from_millis_since_unix_epoch(start_time_millis as f64),
             // TODO: Add metadata to original data json files
-            SamplingInterval::from_millis(interval_millis),
+            SamplingInterval::from_nanos(interval_micros * 1000),
         );
-        let category_python = profile.add_category(CATEGORY_PYTHON_NAME, CategoryColor::Blue);
-        let category_native = profile.add_category(CATEGORY_NATIVE_NAME, CategoryColor::Green);
+        let category_python = profile.add_category(CATEGORY_PYTHON_NAME, colors.python.into());
+        let category_native = profile.add_category(CATEGORY_NATIVE_NAME, colors.native.into());
 
         Self {
-            interval_millis,
+            interval_micros,
             profile,
             start_time_millis,
             category_native,
             category_python,
+            colors,
+            metadata: Metadata::default(),
+            frame_format: FrameFormat::default(),
+            coalesce: false,
+            collapse_recursion: false,
+            merge_by_thread_name: false,
+            idle_frame: None,
+            name_ids: HashMap::new(),
+            frame_infos: HashMap::new(),
+            marker_threads: BTreeMap::new(),
+            main_threads: BTreeMap::new(),
         }
     }
 
+    /// Ends the stacks of threads that were not active, e.g. waiting for I/O, in an extra frame of
+    /// its own category, to tell blocked from busy time apart.
+    fn mark_idle(&mut self) {
+        let category = self
+            .profile
+            .add_category(CATEGORY_IDLE_NAME, CategoryColor::Grey);
+        self.idle_frame = Some(FrameInfo {
+            frame: Frame::Label(self.profile.intern_string(IDLE_FRAME_LABEL)),
+            category_pair: category.into(),
+            flags: FrameFlags::empty(),
+        });
+    }
+
     fn time(&self, millis: u128) -> Timestamp {
-        Timestamp::from_millis_since_reference((millis - self.start_time_millis) as f64)
+        self.time_micros(millis * 1000)
+    }
+
+    fn time_micros(&self, micros: u128) -> Timestamp {
+        let since_start = micros - self.start_time_millis * 1000;
+        Timestamp::from_nanos_since_reference(since_start as u64 * 1000)
     }
 
-    fn cpu(&self, percent: f32) -> CpuDelta {
-        CpuDelta::from_millis(percent as f64 / 100. * self.interval_millis as f64)
+    /// Converts a CPU usage into the CPU time spent during the interval before a sample.
+    fn cpu(&self, percent: f32, interval_micros: u64) -> CpuDelta {
+        CpuDelta::from_micros(self.cpu_micros(percent, interval_micros))
     }
 
-    fn add_process(&mut self, pid: u32, samples: Vec<JsonLine>) -> Result<(), Whatever> {
+    fn cpu_micros(&self, percent: f32, interval_micros: u64) -> u64 {
+        // The CPU time is per core
+        let cores = match self.metadata.cpu_normalization {
+            CpuNormalization::PerCore => 1.0,
+            CpuNormalization::Total => self.metadata.cpu_cores.unwrap_or(1) as f64,
+        };
+        (percent as f64 * cores / 100. * interval_micros as f64) as u64
+    }
+
+    /// Adds a process to the profile. Processes reusing the pid of an earlier one are added as
+    /// separate processes with the same pid, and without a `metadata_pid`.
+    fn add_process(
+        &mut self,
+        pid: u32,
+        metadata_pid: Option<u32>,
+        parent_pid: Option<u32>,
+        samples: Vec<JsonLine>,
+    ) -> Result<(), Whatever> {
         let Some(first_sample) = samples.first() else {
             return Ok(());
         };
+        // Happens for processes that were never on the CPU when sampling on-CPU only. Processes
+        // whose threads were merged by name are kept for their counters.
+        if !self.merge_by_thread_name && samples.iter().all(|line| line.stacktraces.is_empty()) {
+            info!("Skipping process {pid} without any stacktraces");
+            return Ok(());
+        }
         assert!(first_sample.time >= self.start_time_millis);
 
-        ProfileBuilderProcess::new(self, first_sample.time, pid)
+        // Place the process at its actual start, unless it started before the profiling did
+        let start_time_millis = metadata_pid
+            .and_then(|pid| self.metadata.process_start_times.get(&pid))
+            .map(|start| (*start as u128 * 1000).clamp(self.start_time_millis, first_sample.time))
+            .unwrap_or(first_sample.time);
+
+        let name = metadata_pid
+            .and_then(|pid| self.metadata.process_python_versions.get(&pid))
+            .map(|version| format!("python {version}"))
+            .unwrap_or_else(|| "Process".to_string());
+        let name = match parent_pid {
+            Some(parent) => format!("{name} (child of {parent})"),
+            None => name,
+        };
+
+        ProfileBuilderProcess::new(self, &name, start_time_millis, pid, &samples)
             .add_main_thread(samples.iter())?
             .add_samples(samples)?;
 
         Ok(())
     }
 
+    /// Adds the profiler itself, recorded with `--track-self`. It has no stacktraces, so only its
+    /// CPU usage and resource counters are shown.
+    fn add_profiler(&mut self, samples: &[JsonLine]) {
+        let Some(first_sample) = samples.first() else {
+            return;
+        };
+        let start_timestamp = self.time(first_sample.time);
+        let pid = self.metadata.profiler_pid.unwrap_or(0);
+        let process = self
+            .profile
+            .add_process("py-crude-resource-monitor", pid, start_timestamp);
+        let thread = self.profile.add_thread(process, pid, start_timestamp, true);
+        self.profile.set_thread_name(thread, "Profiler");
+        let mut counters = ProcessCounters::new(
+            &mut self.profile,
+            process,
+            start_timestamp,
+            samples,
+            None,
+            self.colors,
+        );
+
+        let mut last_time = None;
+        for line in samples {
+            let time_micros = line.timestamp_micros();
+            let timestamp = self.time_micros(time_micros);
+            let interval_micros = last_time
+                .map(|last| time_micros.saturating_sub(last) as u64)
+                .unwrap_or(self.interval_micros);
+            last_time = Some(time_micros);
+
+            let cpu_delta = self.cpu(line.resources.cpu, interval_micros);
+            self.profile
+                .add_sample(thread, timestamp, None, cpu_delta, 1);
+            counters.add_sample(&mut self.profile, timestamp, &line.resources);
+        }
+    }
+
+    /// Adds a "System" process showing the power draw, if it was measured, and how long taking
+    /// each sample took. The other global resources are not shown, as they are not specific to
+    /// the profiled processes.
+    fn add_system(&mut self, samples: &[JsonLine]) {
+        let Some(first_sample) = samples.first() else {
+            return;
+        };
+        let has_power = samples
+            .iter()
+            .any(|line| line.resources.power_watts.is_some());
+        // Missing in reports captured by older versions
+        let has_sample_duration = samples.iter().any(|line| line.sample_duration_micros > 0);
+        if !has_power && !has_sample_duration {
+            return;
+        }
+
+        let start_timestamp = self.time(first_sample.time);
+        // Pid 0 is not a real process, we use it as a placeholder like `read_report` does
+        let process = self.profile.add_process("System", 0, start_timestamp);
+        // Counters are only shown below a thread
+        let thread = self.profile.add_thread(process, 0, start_timestamp, true);
+        self.profile.set_thread_name(thread, "System");
+
+        let mut power = has_power.then(|| {
+            ProfileCounter::new(
+                &mut self.profile,
+                process,
+                "power",
+                "Power",
+                "Power draw of the system in watts",
+                GraphColor::Red,
+            )
+            .initialize(&mut self.profile, start_timestamp, 0.)
+        });
+        let mut sample_duration = has_sample_duration.then(|| {
+            ProfileCounter::new(
+                &mut self.profile,
+                process,
+                "sample-duration",
+                "Sampling",
+                "Time taken to refresh the resources and capture the stacktraces in µs",
+                GraphColor::Grey,
+            )
+            .initialize(&mut self.profile, start_timestamp, 0.)
+        });
+        for line in samples {
+            let timestamp = self.time_micros(line.timestamp_micros());
+            if let Some(counter) = &mut power {
+                let watts = line.resources.power_watts.unwrap_or(0.0);
+                counter.add_value(&mut self.profile, timestamp, watts as f64);
+            }
+            if let Some(counter) = &mut sample_duration {
+                let micros = line.sample_duration_micros;
+                counter.add_value(&mut self.profile, timestamp, micros as f64);
+            }
+        }
+    }
+
+    /// Adds a "Merged threads" process with one thread per thread name, see
+    /// [`take_named_threads`]. Samples of threads sharing a name at the same time are shifted by a
+    /// microsecond each, so every sample keeps its own timestamp.
+    fn add_merged_threads(&mut self, samples: Vec<NamedThreadSample>) {
+        let Some(first_sample) = samples.first() else {
+            return;
+        };
+        let start_timestamp = self.time_micros(first_sample.time_micros);
+        let process =
+            self.profile
+                .add_process("Merged threads", MERGED_THREADS_PID, start_timestamp);
+
+        let mut threads: HashMap<String, (ThreadHandle, u128)> = HashMap::new();
+        for sample in samples {
+            let (thread, time_micros, interval_micros) = match threads.get(&sample.name) {
+                Some(&(thread, last_time)) => {
+                    let time_micros = sample.time_micros.max(last_time + 1);
+                    (thread, time_micros, (time_micros - last_time) as u64)
+                }
+                None => {
+                    let tid = threads.len() as u32 + 1;
+                    let timestamp = self.time_micros(sample.time_micros);
+                    let thread =
+                        self.profile
+                            .add_thread(process, tid, timestamp, threads.is_empty());
+                    self.profile.set_thread_name(thread, &sample.name);
+                    (thread, sample.time_micros, self.interval_micros)
+                }
+            };
+            threads.insert(sample.name, (thread, time_micros));
+
+            let stack = self.intern_stack(thread, &sample.frames, sample.active);
+            let cpu_delta = self.cpu(sample.cpu, interval_micros);
+            let timestamp = self.time_micros(time_micros);
+            self.profile
+                .add_sample(thread, timestamp, stack, cpu_delta, 1);
+        }
+    }
+
+    /// Adds the markers written by the target on the track of their thread, or on the main thread
+    /// of their process if it is not shown, e.g. because it was merged or grouped.
+    fn add_markers(&mut self, markers: Vec<types::Marker>) {
+        let first_main_thread = self
+            .main_threads
+            .values()
+            .min_by_key(|(start_time, _)| *start_time)
+            .map(|(_, thread)| *thread);
+        let mut skipped = 0;
+        for marker in markers {
+            let on_thread = marker.thread_id.and_then(|thread_id| match marker.pid {
+                Some(pid) => self.marker_threads.get(&(pid, thread_id)).copied(),
+                None => self
+                    .marker_threads
+                    .iter()
+                    .find(|((_, id), _)| *id == thread_id)
+                    .map(|(_, thread)| *thread),
+            });
+            let on_process = || match marker.pid {
+                Some(pid) => self.main_threads.get(&pid).map(|(_, thread)| *thread),
+                None => first_main_thread,
+            };
+            let thread = on_thread.or_else(on_process);
+            let Some(thread) = thread.filter(|_| marker.time >= self.start_time_millis) else {
+                skipped += 1;
+                continue;
+            };
+            let name = self.profile.intern_string(&marker.name);
+            let timestamp = self.time(marker.time);
+            self.profile.add_marker(
+                thread,
+                MarkerTiming::Instant(timestamp),
+                TargetMarker { name },
+            );
+        }
+        if skipped > 0 {
+            info!(
+                "Skipped {skipped} markers of processes that are not shown or before the first sample"
+            );
+        }
+    }
+
+    /// The id of a file or function name, see [`ProfileBuilder::name_ids`].
+    fn name_id(&mut self, name: &str) -> u32 {
+        if let Some(id) = self.name_ids.get(name) {
+            return *id;
+        }
+        let id = self.name_ids.len() as u32;
+        self.name_ids.insert(name.to_string(), id);
+        id
+    }
+
+    /// Interns the frames of a stacktrace as a stack of the thread, reusing the frame infos of
+    /// earlier stacks of any thread.
+    fn intern_stack(
+        &mut self,
+        thread: ThreadHandle,
+        frames: &[types::Frame],
+        active: bool,
+    ) -> Option<StackHandle> {
+        let frames = super::stack_frames(frames, self.collapse_recursion);
+        let mut stack_frames = Vec::with_capacity(frames.len());
+        for (frame, depth) in frames.into_iter().rev() {
+            let file_id = self.name_id(&frame.filename);
+            let function_id = self.name_id(&frame.name);
+            let frame_info =
+                self.frame_infos
+                    .entry((file_id, function_id, frame.line, depth))
+                    .or_insert_with(|| FrameInfo {
+                        frame: Frame::Label(self.profile.intern_string(
+                            self.frame_format.recursive_label(frame, depth).as_str(),
+                        )),
+                        // Entry frames are where native code calls into the interpreter
+                        category_pair: if frame.is_native() || frame.is_entry {
+                            self.category_native.into()
+                        } else {
+                            self.category_python.into()
+                        },
+                        flags: FrameFlags::empty(),
+                    });
+            stack_frames.push(frame_info.clone());
+        }
+        if let Some(idle_frame) = self.idle_frame.as_ref().filter(|_| !active) {
+            stack_frames.push(idle_frame.clone());
+        }
+        self.profile
+            .intern_stack_frames(thread, stack_frames.into_iter())
+    }
+
     pub fn finish(self) -> Profile {
         self.profile
     }
@@ -160,40 +500,35 @@ struct ProfileBuilderProcess<'a, T> {
     process: ProcessHandle,
     pid: u32,
     start_time_millis: u128,
+    /// The threads by [`ProfileBuilderProcess::thread_key`]
     threads: HashMap<u32, ThreadHandle>,
-    memory_counter: ProfileCounter<Initialized>,
-    io_counter: ProfileCounter<Initialized>,
+    /// The OS thread id of every Python thread id that only ever ran on one OS thread
+    os_thread_ids: HashMap<u64, u64>,
+    counters: ProcessCounters,
     data: T,
 }
 
 impl<'a> ProfileBuilderProcess<'a, ()> {
-    pub fn new(parent: &'a mut ProfileBuilder, start_time_millis: u128, pid: u32) -> Self {
+    pub fn new(
+        parent: &'a mut ProfileBuilder,
+        name: &str,
+        start_time_millis: u128,
+        pid: u32,
+        samples: &[JsonLine],
+    ) -> Self {
         assert!(start_time_millis >= parent.start_time_millis);
 
         let start_timestamp = parent.time(start_time_millis);
-        let process = parent.profile.add_process("Process", pid, start_timestamp);
-
-        // See "renderTrack" for names:
-        // https://github.com/firefox-devtools/profiler/blob/main/src/components/timeline/LocalTrack.js#L102
-        // (at revision 5c7515aa243e8b21de77e0434d9cc0f761e8bafd if broken)
-        let memory_counter = ProfileCounter::new(
+        let process = parent.profile.add_process(name, pid, start_timestamp);
+        let counters = ProcessCounters::new(
             &mut parent.profile,
             process,
-            "malloc",
-            "Memory",
-            "Amount of allocated memory",
-            GraphColor::Orange,
-        )
-        .initialize(&mut parent.profile, start_timestamp, 0.);
-        let io_counter = ProfileCounter::new(
-            &mut parent.profile,
-            process,
-            "io",
-            "Bandwidth",
-            "I/O read/write in bytes",
-            GraphColor::Teal,
-        )
-        .initialize(&mut parent.profile, start_timestamp, 0.);
+            start_timestamp,
+            samples,
+            // Shown as a separate counter if the profile was captured in a cgroup
+            parent.metadata.cgroup_memory_limit,
+            parent.colors,
+        );
 
         Self {
             parent,
@@ -201,8 +536,8 @@ impl<'a> ProfileBuilderProcess<'a, ()> {
             pid,
             start_time_millis,
             threads: HashMap::new(),
-            memory_counter,
-            io_counter,
+            os_thread_ids: unambiguous_os_thread_ids(samples),
+            counters,
             data: (),
         }
     }
@@ -212,52 +547,86 @@ impl<'a> ProfileBuilderProcess<'a, ()> {
         samples: impl Iterator<Item = impl Borrow<JsonLine>>,
     ) -> Result<ProfileBuilderProcess<'a, MainThreadAdded>, Whatever> {
         // adding the main thread first leads to the RAM display corresponding to mainThreadIndex 0 working
-        let mut threads = samples
-            .flat_map(|line| line.borrow().stacktraces.clone())
-            .map(|it| {
-                (
-                    it.thread_id,
-                    it.thread_name.clone().unwrap_or("unnamed".to_string()),
-                )
-            })
-            .collect::<HashSet<_>>()
+        // Threads are often named after startup, so a pass over all samples resolves the latest
+        // name of every thread before choosing the main thread
+        let mut resolved = BTreeMap::new();
+        for line in samples {
+            for trace in &line.borrow().stacktraces {
+                let key = self.thread_key(trace);
+                let (name, os_thread_id) = resolved.entry(key).or_insert((None, None));
+                if trace.thread_name.is_some() {
+                    name.clone_from(&trace.thread_name);
+                }
+                *os_thread_id = trace.os_thread_id.or(*os_thread_id);
+            }
+        }
+        // Sorted by thread id, which ensures the report is deterministic
+        let threads = resolved
             .into_iter()
+            .map(|(id, (name, os_thread_id))| {
+                (id, name.unwrap_or("unnamed".to_string()), os_thread_id)
+            })
             .collect::<Vec<_>>();
-        // Ensure the report is deterministic
-        threads.sort_by(|(a_id, _), (b_id, _)| a_id.cmp(b_id));
 
-        let main_thread = threads.iter().find(|(_, name)| name == MAIN_THREAD_NAME);
+        // On Linux, the OS thread id of the main thread is the pid. This also works if the main
+        // thread was renamed or the interpreter is embedded.
+        let by_os_thread_id = threads
+            .iter()
+            .find(|(_, _, os_thread_id)| *os_thread_id == Some(self.pid as u64));
+        let by_name = || threads.iter().find(|(_, name, _)| name == MAIN_THREAD_NAME);
+        // All stacktraces were moved away by `--merge-by-thread-name`, but the counters are still
+        // shown below an empty main thread
+        let stub = (self.pid, MAIN_THREAD_NAME.to_string(), None);
 
-        let (main_thread_id, _) = match main_thread {
-            Some(thread) => thread,
-            None => {
-                let all_threads = threads
-                    .iter()
-                    .map(|(_, name)| name.as_str())
-                    .collect::<Vec<_>>();
-                info!(
-                    "No main thread found in samples, found threads `{}`.",
-                    all_threads.as_slice().join(", ")
-                );
-                let chosen = threads.first().whatever_context("no threads found")?;
-                info!(
-                    "Using first thread `{}` with id `{}` as main thread.",
-                    chosen.1, chosen.0
-                );
-                chosen
-            }
+        let (main_thread_id, ..) = if let Some(thread) = by_os_thread_id {
+            debug!(
+                "Using thread `{}` of process {} as main thread, its OS thread id is the pid",
+                thread.1, self.pid
+            );
+            thread
+        } else if let Some(thread) = by_name() {
+            debug!(
+                "Using thread `{}` of process {} as main thread, based on its name",
+                thread.1, self.pid
+            );
+            thread
+        } else if threads.is_empty() {
+            debug!(
+                "Using an empty main thread for process {}, its threads were merged by name",
+                self.pid
+            );
+            &stub
+        } else {
+            let all_threads = threads
+                .iter()
+                .map(|(_, name, _)| name.as_str())
+                .collect::<Vec<_>>();
+            info!(
+                "No main thread found in samples, found threads `{}`.",
+                all_threads.as_slice().join(", ")
+            );
+            let chosen = threads.first().whatever_context("no threads found")?;
+            info!(
+                "Using first thread `{}` with id `{}` as main thread.",
+                chosen.1, chosen.0
+            );
+            chosen
         };
         let main_thread_handle = self.parent.profile.add_thread(
             self.process,
-            *main_thread_id as u32,
+            *main_thread_id,
             self.time(self.start_time_millis),
             true,
         );
         self.parent
             .profile
             .set_thread_name(main_thread_handle, MAIN_THREAD_NAME);
-        self.threads
-            .insert(*main_thread_id as u32, main_thread_handle);
+        self.threads.insert(*main_thread_id, main_thread_handle);
+        // Processes reusing a pid keep the markers on the first one
+        self.parent
+            .main_threads
+            .entry(self.pid)
+            .or_insert((self.start_time_millis, main_thread_handle));
 
         Ok(ProfileBuilderProcess {
             parent: self.parent,
@@ -265,8 +634,8 @@ impl<'a> ProfileBuilderProcess<'a, ()> {
             pid: self.pid,
             start_time_millis: self.start_time_millis,
             threads: self.threads,
-            memory_counter: self.memory_counter,
-            io_counter: self.io_counter,
+            os_thread_ids: self.os_thread_ids,
+            counters: self.counters,
             data: MainThreadAdded { main_thread_handle },
         })
     }
@@ -277,21 +646,72 @@ impl<T> ProfileBuilderProcess<'_, T> {
         self.parent.time(millis)
     }
 
-    fn cpu(&self, percent: f32) -> CpuDelta {
-        self.parent.cpu(percent)
+    fn time_micros(&self, micros: u128) -> Timestamp {
+        self.parent.time_micros(micros)
+    }
+
+    fn cpu_micros(&self, percent: f32, interval_micros: u64) -> u64 {
+        self.parent.cpu_micros(percent, interval_micros)
+    }
+
+    /// Identifies the track of a stacktrace. Python thread ids are reused for new threads, so the
+    /// OS thread id is preferred. It is missing in some samples, so it is also looked up by the
+    /// Python thread id if that is unambiguous.
+    fn thread_key(&self, trace: &StackTrace) -> u32 {
+        trace
+            .os_thread_id
+            .or_else(|| self.os_thread_ids.get(&trace.thread_id).copied())
+            .unwrap_or(trace.thread_id) as u32
     }
 }
 
+/// Maps the Python thread ids that were only seen with a single OS thread id to it.
+fn unambiguous_os_thread_ids(samples: &[JsonLine]) -> HashMap<u64, u64> {
+    let mut os_thread_ids = HashMap::<u64, Option<u64>>::new();
+    for trace in samples.iter().flat_map(|line| &line.stacktraces) {
+        let Some(os_thread_id) = trace.os_thread_id else {
+            continue;
+        };
+        os_thread_ids
+            .entry(trace.thread_id)
+            .and_modify(|it| {
+                if *it != Some(os_thread_id) {
+                    *it = None;
+                }
+            })
+            .or_insert(Some(os_thread_id));
+    }
+    os_thread_ids
+        .into_iter()
+        .filter_map(|(thread_id, os_thread_id)| Some((thread_id, os_thread_id?)))
+        .collect()
+}
+
 impl ProfileBuilderProcess<'_, MainThreadAdded> {
     pub fn add_samples(mut self, samples: Vec<JsonLine>) -> Result<Self, Whatever> {
-        let mut all_frames = HashMap::new();
+        let mut last_time = None;
+        // The first sample with the highest memory usage, to mark it
+        let mut peak_memory: Option<(Timestamp, u64)> = None;
+        // The latest sample of each thread, which is only added once its stack changes
+        let mut pending = HashMap::<ThreadHandle, WeightedSample>::new();
 
         for line in samples {
             assert!(line.time >= self.start_time_millis);
-            let timestamp = self.time(line.time);
+            let time_micros = line.timestamp_micros();
+            let timestamp = self.time_micros(time_micros);
+            // The sample rate might vary (e.g. with `--adaptive-rate` or missed samples), so we use
+            // the actual time since the last sample. The first one falls back to the median.
+            let interval_micros = last_time
+                .map(|last| time_micros.saturating_sub(last) as u64)
+                .unwrap_or(self.parent.interval_micros);
+            last_time = Some(time_micros);
 
+            self.counters
+                .add_gil_waiting(&mut self.parent.profile, timestamp, &line.stacktraces);
+            // The CPU usage of each thread, which is taken from the process for the main thread
+            let mut thread_samples = Vec::with_capacity(line.stacktraces.len());
             for stacktrace in line.stacktraces {
-                let thread_id = stacktrace.thread_id as u32;
+                let thread_id = self.thread_key(&stacktrace);
 
                 let &mut thread = self.threads.entry(thread_id).or_insert_with(|| {
                     self.parent
@@ -299,6 +719,14 @@ impl ProfileBuilderProcess<'_, MainThreadAdded> {
                         .add_thread(self.process, thread_id, timestamp, false)
                 });
 
+                let ids = std::iter::once(stacktrace.thread_id).chain(stacktrace.os_thread_id);
+                for id in ids {
+                    self.parent
+                        .marker_threads
+                        .entry((self.pid, id))
+                        .or_insert(thread);
+                }
+
                 // thread name might not be set in first line of the file, so we set it in every
                 // sample we find.
                 if let Some(thread_name) = stacktrace.thread_name {
@@ -307,61 +735,58 @@ impl ProfileBuilderProcess<'_, MainThreadAdded> {
                         .set_thread_name(thread, thread_name.as_str());
                 }
 
-                let mut stack_frames = Vec::with_capacity(stacktrace.frames.len());
-                for frame in stacktrace.frames.iter().rev() {
-                    let frame_info = all_frames
-                        .entry((frame.filename.clone(), frame.line))
-                        .or_insert_with(|| FrameInfo {
-                            frame: Frame::Label(
-                                self.parent.profile.intern_string(
-                                    format!(
-                                        "{} ({}:{})",
-                                        frame.name,
-                                        frame.short_filename.as_ref().unwrap(),
-                                        frame.line
-                                    )
-                                    .as_str(),
-                                ),
-                            ),
-                            category_pair: if frame.is_entry {
-                                self.parent.category_native.into()
-                            } else {
-                                self.parent.category_python.into()
-                            },
-                            flags: FrameFlags::empty(),
-                        });
-                    stack_frames.push(frame_info.clone());
-                }
                 let stack = self
                     .parent
-                    .profile
-                    .intern_stack_frames(thread, stack_frames.into_iter());
-
-                let cpu_delta = if thread == self.data.main_thread_handle {
-                    self.cpu(line.resources.cpu)
-                } else if let Some(os_thread_id) = stacktrace.os_thread_id {
-                    if let Some(resources) = line.resources.thread_resources.get(&os_thread_id) {
-                        self.cpu(resources.cpu)
-                    } else {
-                        CpuDelta::ZERO
-                    }
-                } else {
-                    CpuDelta::ZERO
-                };
-                self.parent
-                    .profile
-                    .add_sample(thread, timestamp, stack, cpu_delta, 1);
+                    .intern_stack(thread, &stacktrace.frames, stacktrace.active);
+
+                let thread_cpu = stacktrace
+                    .os_thread_id
+                    .and_then(|id| line.resources.thread_resources.get(&id))
+                    .map_or(0.0, |resources| resources.cpu);
+                thread_samples.push((thread, stack, thread_cpu));
             }
 
-            self.memory_counter.add_value(
-                &mut self.parent.profile,
-                timestamp,
-                line.resources.memory as f64,
+            reconcile_thread_cpu(
+                line.resources.cpu,
+                self.data.main_thread_handle,
+                &mut thread_samples,
             );
-            self.io_counter.add_value(
-                &mut self.parent.profile,
-                timestamp,
-                (line.resources.disk_read_bytes + line.resources.disk_write_bytes) as f64,
+            for (thread, stack, cpu) in thread_samples {
+                let cpu_micros = self.cpu_micros(cpu, interval_micros);
+                match pending.get_mut(&thread) {
+                    Some(run) if self.parent.coalesce && run.stack == stack => {
+                        run.weight += 1;
+                        run.cpu_micros += cpu_micros;
+                    }
+                    _ => {
+                        let sample = WeightedSample {
+                            timestamp,
+                            stack,
+                            cpu_micros,
+                            weight: 1,
+                        };
+                        if let Some(previous) = pending.insert(thread, sample) {
+                            previous.add_to(&mut self.parent.profile, thread);
+                        }
+                    }
+                }
+            }
+
+            if peak_memory.is_none_or(|(_, peak)| line.resources.memory > peak) {
+                peak_memory = Some((timestamp, line.resources.memory));
+            }
+            self.counters
+                .add_sample(&mut self.parent.profile, timestamp, &line.resources);
+        }
+        for (thread, sample) in pending {
+            sample.add_to(&mut self.parent.profile, thread);
+        }
+
+        if let Some((timestamp, memory)) = peak_memory.filter(|(_, memory)| *memory > 0) {
+            self.parent.profile.add_marker(
+                self.data.main_thread_handle,
+                MarkerTiming::Instant(timestamp),
+                PeakMemoryMarker { memory },
             );
         }
 
@@ -369,7 +794,439 @@ impl ProfileBuilderProcess<'_, MainThreadAdded> {
     }
 }
 
+/// Gives the main thread the remaining CPU usage of the process, so the CPU usages of the threads
+/// of a sample sum up to the process instead of counting the other threads twice.
+fn reconcile_thread_cpu<T: PartialEq, S>(
+    process_cpu: f32,
+    main_thread: T,
+    thread_samples: &mut [(T, S, f32)],
+) {
+    let other_threads_cpu = thread_samples
+        .iter()
+        .filter(|(thread, ..)| *thread != main_thread)
+        .map(|(.., cpu)| cpu)
+        .sum::<f32>();
+    for (thread, _, cpu) in thread_samples.iter_mut() {
+        if *thread == main_thread {
+            *cpu = (process_cpu - other_threads_cpu).max(0.0);
+        }
+    }
+}
+
+/// A run of samples of a thread with the same stack, starting at the timestamp of the first one.
+/// Without coalescing, every run is a single sample.
+struct WeightedSample {
+    timestamp: Timestamp,
+    stack: Option<StackHandle>,
+    cpu_micros: u64,
+    weight: i32,
+}
+
+impl WeightedSample {
+    fn add_to(self, profile: &mut Profile, thread: ThreadHandle) {
+        profile.add_sample(
+            thread,
+            self.timestamp,
+            self.stack,
+            CpuDelta::from_micros(self.cpu_micros),
+            self.weight,
+        );
+    }
+}
+
+/// Marks the sample where a process used the most memory.
+struct PeakMemoryMarker {
+    memory: u64,
+}
+
+impl StaticSchemaMarker for PeakMemoryMarker {
+    const UNIQUE_MARKER_TYPE_NAME: &'static str = "PeakMemory";
+    const CHART_LABEL: Option<&'static str> = Some("Peak memory {marker.data.memory}");
+    const TOOLTIP_LABEL: Option<&'static str> = Some("Peak memory {marker.data.memory}");
+    const TABLE_LABEL: Option<&'static str> = Some("{marker.data.memory}");
+    const FIELDS: &'static [StaticSchemaMarkerField] = &[StaticSchemaMarkerField {
+        key: "memory",
+        label: "Memory",
+        format: MarkerFieldFormat::Bytes,
+        flags: MarkerFieldFlags::empty(),
+    }];
+
+    fn name(&self, profile: &mut Profile) -> StringHandle {
+        profile.intern_string("Peak memory")
+    }
+
+    fn category(&self, _profile: &mut Profile) -> CategoryHandle {
+        CategoryHandle::OTHER
+    }
+
+    fn string_field_value(&self, _field_index: u32) -> StringHandle {
+        unreachable!("the marker has no string fields")
+    }
+
+    fn number_field_value(&self, _field_index: u32) -> f64 {
+        self.memory as f64
+    }
+}
+
+/// A point in time the target marked, see [`types::MARKERS_FILE_NAME`].
+struct TargetMarker {
+    name: StringHandle,
+}
+
+impl StaticSchemaMarker for TargetMarker {
+    const UNIQUE_MARKER_TYPE_NAME: &'static str = "TargetMarker";
+    const CHART_LABEL: Option<&'static str> = Some("{marker.name}");
+    const TOOLTIP_LABEL: Option<&'static str> = Some("{marker.name}");
+    const TABLE_LABEL: Option<&'static str> = Some("{marker.name}");
+    const FIELDS: &'static [StaticSchemaMarkerField] = &[];
+
+    fn name(&self, _profile: &mut Profile) -> StringHandle {
+        self.name
+    }
+
+    fn category(&self, _profile: &mut Profile) -> CategoryHandle {
+        CategoryHandle::OTHER
+    }
+
+    fn string_field_value(&self, _field_index: u32) -> StringHandle {
+        unreachable!("the marker has no fields")
+    }
+
+    fn number_field_value(&self, _field_index: u32) -> f64 {
+        unreachable!("the marker has no fields")
+    }
+}
+
+/// The resource counters shown for a process.
+struct ProcessCounters {
+    memory: ProfileCounter<Initialized>,
+    io: ProfileCounter<Initialized>,
+    /// Only filled for the global resources
+    disk_io: BTreeMap<String, ProfileCounter<Initialized>>,
+    threads: ProfileCounter<Initialized>,
+    memory_limit: Option<(ProfileCounter<Initialized>, u64)>,
+    // Only present if the samples contain the CPU time, i.e. not for the global resources
+    cpu_time: Option<ProfileCounter<Initialized>>,
+    // Only present if the samples contain the data, i.e. they were captured on Linux
+    ctx_switches: Option<CumulativeCounter>,
+    page_faults: Option<CumulativeCounter>,
+    // Only present if the samples were captured with GPU support
+    gpu_util: Option<ProfileCounter<Initialized>>,
+    gpu_memory: Option<ProfileCounter<Initialized>>,
+    // Only present if the samples contain stacktraces, i.e. not for the global resources
+    gil_waiting: Option<ProfileCounter<Initialized>>,
+}
+
+impl ProcessCounters {
+    fn new(
+        profile: &mut Profile,
+        process: ProcessHandle,
+        start_timestamp: Timestamp,
+        samples: &[JsonLine],
+        memory_limit: Option<u64>,
+        colors: FirefoxColors,
+    ) -> Self {
+        // See "renderTrack" for names:
+        // https://github.com/firefox-devtools/profiler/blob/main/src/components/timeline/LocalTrack.js#L102
+        // (at revision 5c7515aa243e8b21de77e0434d9cc0f761e8bafd if broken)
+        let memory = ProfileCounter::new(
+            profile,
+            process,
+            "malloc",
+            "Memory",
+            "Amount of allocated memory",
+            colors.memory.into(),
+        )
+        .initialize(profile, start_timestamp, 0.);
+        let io = ProfileCounter::new(
+            profile,
+            process,
+            "io",
+            "Bandwidth",
+            "I/O read/write in bytes",
+            colors.io.into(),
+        )
+        .initialize(profile, start_timestamp, 0.);
+        let disk_io = samples
+            .iter()
+            .flat_map(|line| line.resources.per_disk_io.keys())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .map(|device| {
+                let counter = ProfileCounter::new(
+                    profile,
+                    process,
+                    "io",
+                    "Bandwidth",
+                    &format!("I/O read/write of `{device}` in bytes"),
+                    colors.io.into(),
+                )
+                .initialize(profile, start_timestamp, 0.);
+                (device.clone(), counter)
+            })
+            .collect();
+        let threads = ProfileCounter::new(
+            profile,
+            process,
+            "threads",
+            "Threads",
+            "Number of threads of the process",
+            GraphColor::Blue,
+        )
+        .initialize(profile, start_timestamp, 0.);
+        let memory_limit = memory_limit.map(|limit| {
+            let counter = ProfileCounter::new(
+                profile,
+                process,
+                "memory-limit",
+                "Memory",
+                "Memory limit of the cgroup",
+                GraphColor::Red,
+            )
+            .initialize(profile, start_timestamp, 0.);
+            (counter, limit)
+        });
+
+        let has_cpu_time = samples.iter().any(|line| line.resources.cpu_time_ms > 0);
+        let cpu_time = has_cpu_time.then(|| {
+            ProfileCounter::new(
+                profile,
+                process,
+                "cpu-time",
+                "CPU",
+                "CPU time used since the process started, in milliseconds",
+                GraphColor::Orange,
+            )
+            .initialize(profile, start_timestamp, 0.)
+        });
+        let has_ctx_switches = samples
+            .iter()
+            .any(|line| line.resources.voluntary_ctx_switches.is_some());
+        let ctx_switches = has_ctx_switches.then(|| {
+            CumulativeCounter::new(
+                ProfileCounter::new(
+                    profile,
+                    process,
+                    "ctx-switches",
+                    "Scheduling",
+                    "Voluntary and involuntary context switches per sample",
+                    GraphColor::Purple,
+                )
+                .initialize(profile, start_timestamp, 0.),
+            )
+        });
+        let has_page_faults = samples
+            .iter()
+            .any(|line| line.resources.minor_faults.is_some());
+        let page_faults = has_page_faults.then(|| {
+            CumulativeCounter::new(
+                ProfileCounter::new(
+                    profile,
+                    process,
+                    "page-faults",
+                    "Paging",
+                    "Major and minor page faults per sample",
+                    GraphColor::Magenta,
+                )
+                .initialize(profile, start_timestamp, 0.),
+            )
+        });
+
+        let has_gpu = samples.iter().any(|line| line.resources.gpu_util.is_some());
+        let gpu_util = has_gpu.then(|| {
+            ProfileCounter::new(
+                profile,
+                process,
+                "gpu-util",
+                "GPU",
+                "GPU utilization in percent",
+                GraphColor::Green,
+            )
+            .initialize(profile, start_timestamp, 0.)
+        });
+        let gpu_memory = has_gpu.then(|| {
+            ProfileCounter::new(
+                profile,
+                process,
+                "gpu-memory",
+                "Memory",
+                "Used GPU memory",
+                GraphColor::Yellow,
+            )
+            .initialize(profile, start_timestamp, 0.)
+        });
+
+        let has_stacktraces = samples.iter().any(|line| !line.stacktraces.is_empty());
+        let gil_waiting = has_stacktraces.then(|| {
+            ProfileCounter::new(
+                profile,
+                process,
+                "gil-waiting",
+                "GIL",
+                "Number of active threads waiting for the GIL, high values indicate contention",
+                GraphColor::Ink,
+            )
+            .initialize(profile, start_timestamp, 0.)
+        });
+
+        Self {
+            memory,
+            io,
+            disk_io,
+            threads,
+            memory_limit,
+            cpu_time,
+            ctx_switches,
+            page_faults,
+            gpu_util,
+            gpu_memory,
+            gil_waiting,
+        }
+    }
+
+    fn add_sample(
+        &mut self,
+        profile: &mut Profile,
+        timestamp: Timestamp,
+        resources: &ProcessResources,
+    ) {
+        self.memory
+            .add_value(profile, timestamp, resources.memory as f64);
+        self.io.add_value(
+            profile,
+            timestamp,
+            (resources.disk_read_bytes + resources.disk_write_bytes) as f64,
+        );
+        for (device, counter) in &mut self.disk_io {
+            let (read, written) = resources
+                .per_disk_io
+                .get(device)
+                .copied()
+                .unwrap_or_default();
+            counter.add_value(profile, timestamp, (read + written) as f64);
+        }
+        self.threads
+            .add_value(profile, timestamp, resources.thread_count() as f64);
+        if let Some((counter, limit)) = &mut self.memory_limit {
+            counter.add_value(profile, timestamp, *limit as f64);
+        }
+        // Exited processes report no CPU time, which must not make the counter go backwards
+        if let Some(counter) = &mut self.cpu_time {
+            let total = (resources.cpu_time_ms as f64).max(counter.last_value);
+            counter.add_value(profile, timestamp, total);
+        }
+        if let Some(counter) = &mut self.ctx_switches {
+            let total = resources
+                .voluntary_ctx_switches
+                .zip(resources.involuntary_ctx_switches)
+                .map(|(voluntary, involuntary)| voluntary + involuntary);
+            counter.add_total(profile, timestamp, total);
+        }
+        if let Some(counter) = &mut self.page_faults {
+            let total = resources
+                .major_faults
+                .zip(resources.minor_faults)
+                .map(|(major, minor)| major + minor);
+            counter.add_total(profile, timestamp, total);
+        }
+        if let Some(counter) = &mut self.gpu_util {
+            let util = resources.gpu_util.unwrap_or(0.0);
+            counter.add_value(profile, timestamp, util as f64);
+        }
+        if let Some(counter) = &mut self.gpu_memory {
+            let memory = resources.gpu_memory.unwrap_or(0);
+            counter.add_value(profile, timestamp, memory as f64);
+        }
+    }
+
+    /// Counts the threads that are running but do not hold the GIL, i.e. are waiting for it.
+    fn add_gil_waiting(
+        &mut self,
+        profile: &mut Profile,
+        timestamp: Timestamp,
+        stacktraces: &[StackTrace],
+    ) {
+        if let Some(counter) = &mut self.gil_waiting {
+            let waiting = stacktraces
+                .iter()
+                .filter(|trace| trace.active && !trace.owns_gil)
+                .count();
+            counter.add_value(profile, timestamp, waiting as f64);
+        }
+    }
+}
+
+/// Displays the increase per sample of a value that only ever grows, like the number of context
+/// switches since process start.
+struct CumulativeCounter {
+    counter: ProfileCounter<Initialized>,
+    last_total: Option<u64>,
+}
+
+impl CumulativeCounter {
+    fn new(counter: ProfileCounter<Initialized>) -> Self {
+        Self {
+            counter,
+            last_total: None,
+        }
+    }
+
+    fn add_total(&mut self, profile: &mut Profile, timestamp: Timestamp, total: Option<u64>) {
+        // Missing values (e.g. the process exited while reading procfs) count as no increase
+        let Some(total) = total else {
+            self.counter.add_value(profile, timestamp, 0.);
+            return;
+        };
+        let increase = self
+            .last_total
+            .map(|last| total.saturating_sub(last))
+            .unwrap_or(0);
+        self.last_total = Some(total);
+        self.counter.add_value(profile, timestamp, increase as f64);
+    }
+}
+
+impl From<CategoryColorName> for CategoryColor {
+    fn from(color: CategoryColorName) -> Self {
+        match color {
+            CategoryColorName::Blue => CategoryColor::Blue,
+            CategoryColorName::Brown => CategoryColor::Brown,
+            CategoryColorName::Green => CategoryColor::Green,
+            CategoryColorName::Grey => CategoryColor::Grey,
+            CategoryColorName::LightBlue => CategoryColor::LightBlue,
+            CategoryColorName::Magenta => CategoryColor::Magenta,
+            CategoryColorName::Orange => CategoryColor::Orange,
+            CategoryColorName::Purple => CategoryColor::Purple,
+            CategoryColorName::Red => CategoryColor::Red,
+            CategoryColorName::Yellow => CategoryColor::Yellow,
+        }
+    }
+}
+
+impl From<GraphColorName> for GraphColor {
+    fn from(color: GraphColorName) -> Self {
+        match color {
+            GraphColorName::Blue => GraphColor::Blue,
+            GraphColorName::Green => GraphColor::Green,
+            GraphColorName::Grey => GraphColor::Grey,
+            GraphColorName::Ink => GraphColor::Ink,
+            GraphColorName::Magenta => GraphColor::Magenta,
+            GraphColorName::Orange => GraphColor::Orange,
+            GraphColorName::Purple => GraphColor::Purple,
+            GraphColorName::Red => GraphColor::Red,
+            GraphColorName::Teal => GraphColor::Teal,
+            GraphColorName::Yellow => GraphColor::Yellow,
+        }
+    }
+}
+
 struct Initialized;
+/// A counter track showing the absolute values passed to [`ProfileCounter::add_value`], e.g. the
+/// memory or the number of threads. The Firefox Profiler sums up the samples of every counter, not
+/// only of memory tracks, so the samples are the change to the previous value. Recording the raw
+/// values instead would show their running sum, which is why there is no such mode. Values that
+/// are already per sample, like the bytes read since the last one, are passed as they are and
+/// show up per sample as well.
 struct ProfileCounter<T> {
     handle: CounterHandle,
     last_value: f64,
@@ -394,7 +1251,7 @@ impl ProfileCounter<()> {
         }
     }
 
-    /// Sets the initial value of the counter. All other values are relative to this value.
+    /// Sets the initial value of the counter, all other values are relative to this value.
     /// Set this to zero if you want to start counting from zero.
     pub fn initialize(
         self,
@@ -413,18 +1270,29 @@ impl ProfileCounter<()> {
 
 impl ProfileCounter<Initialized> {
     pub fn add_value(&mut self, profile: &mut Profile, timestamp: Timestamp, value: f64) {
-        let delta = value - self.last_value;
+        profile.add_counter_sample(self.handle, timestamp, value - self.last_value, 1);
         self.last_value = value;
-        profile.add_counter_sample(self.handle, timestamp, delta, 1);
     }
 }
 
-pub(super) fn export_report(data_dir: &Path, output_path: &Path) -> Result<(), ExportError> {
-    let process_to_profile = super::read_report(data_dir).context(ReadReportSnafu)?;
+pub(super) fn export_report(
+    data_dir: &Path,
+    output_path: &Path,
+    options: &ReadOptions,
+    firefox_options: &FirefoxOptions,
+) -> Result<(), ExportError> {
+    let process_to_profile = super::read_report(data_dir, options).context(ReadReportSnafu)?;
+    let metadata = super::read_metadata(data_dir).context(ReadReportSnafu)?;
+    let markers = super::read_markers(data_dir, options).context(ReadReportSnafu)?;
+    // Downsampling can keep different ticks per report, which then do not line up anyway
+    if metadata.shared_tick_time && options.every_nth.get() == 1 {
+        check_shared_tick_times(&process_to_profile);
+    }
 
-    let profile = generate_fxprof(process_to_profile).context(FirefoxProfileSnafu)?;
+    let profile = generate_fxprof(process_to_profile, &metadata, markers, firefox_options)
+        .context(FirefoxProfileSnafu)?;
 
-    write_profile(output_path, profile)?;
+    write_profile(output_path, profile, firefox_options.compression)?;
 
     info!(
         "Wrote Firefox profile to {}. Open it in `https://profiler.firefox.com`.",
@@ -435,25 +1303,427 @@ pub(super) fn export_report(data_dir: &Path, output_path: &Path) -> Result<(), E
 }
 
 fn generate_fxprof(
-    processes: HashMap<ReportIdentifier, Vec<JsonLine>>,
+    mut processes: HashMap<ReportIdentifier, Vec<JsonLine>>,
+    metadata: &Metadata,
+    markers: Vec<types::Marker>,
+    options: &FirefoxOptions,
 ) -> Result<Profile, Whatever> {
-    let mut builder = ProfileBuilder::from_samples(|| processes.values())?;
+    if !options.threads.is_empty() {
+        retain_threads(&mut processes, &options.threads);
+    }
+    if let Some(pattern) = &options.group_threads {
+        for (id, lines) in processes.iter_mut() {
+            if let ReportIdentifier::Pid { .. } = id {
+                group_threads(lines, pattern);
+            }
+        }
+    }
+    if let Some(max) = options.select_threads_by_cpu {
+        for (id, lines) in processes.iter_mut() {
+            if let ReportIdentifier::Pid { pid, .. } = id {
+                fold_idle_threads(*pid, lines, max);
+            }
+        }
+    }
+    if let Some(max_cpu) = options.trim_idle {
+        trim_idle(&mut processes, max_cpu);
+    }
+    let mut builder = ProfileBuilder::from_samples(
+        || processes.values(),
+        metadata,
+        options.colors,
+        options.profile_name.as_deref(),
+    )?;
+    builder.frame_format = options.frame_format.clone();
+    builder.coalesce = options.coalesce;
+    builder.collapse_recursion = options.collapse_recursion;
+    builder.merge_by_thread_name = options.merge_by_thread_name;
+    if options.mark_idle {
+        builder.mark_idle();
+    }
+    let merged_threads = options
+        .merge_by_thread_name
+        .then(|| take_named_threads(&mut processes));
+
+    let order = if options.process_tree {
+        process_tree_order(&processes)
+    } else {
+        processes.keys().map(|id| (*id, None)).collect()
+    };
 
-    for (pid, samples) in processes {
-        if let ReportIdentifier::Pid(pid) = pid {
-            builder.add_process(pid, samples)?;
+    let progress = super::progress_bar(processes.len(), "Converting processes");
+    for (id, parent_pid) in order {
+        progress.inc(1);
+        let samples = processes.remove(&id).unwrap_or_default();
+        match id {
+            ReportIdentifier::Pid { pid, .. } => {
+                builder.add_process(pid, id.metadata_pid(), parent_pid, samples)?
+            }
+            ReportIdentifier::Global => builder.add_system(&samples),
+            ReportIdentifier::Profiler => builder.add_profiler(&samples),
         }
     }
+    progress.finish_and_clear();
+    if let Some(samples) = merged_threads {
+        builder.add_merged_threads(samples);
+    }
+    builder.add_markers(markers);
 
     Ok(builder.finish())
 }
 
-fn write_profile(output_path: &Path, profile: Profile) -> Result<(), ExportError> {
+/// Checks that the samples of the processes share the time of the global sample of their tick.
+/// Timestamps only depend on that time, so the counters of all tracks then line up exactly. Warns
+/// about and returns how many process samples do not share the time of a global sample.
+fn check_shared_tick_times(processes: &HashMap<ReportIdentifier, Vec<JsonLine>>) -> usize {
+    let Some(global) = processes.get(&ReportIdentifier::Global) else {
+        return 0;
+    };
+    let global_times = global
+        .iter()
+        .map(JsonLine::timestamp_micros)
+        .collect::<HashSet<_>>();
+    let misaligned = processes
+        .iter()
+        .filter(|(id, _)| matches!(id, ReportIdentifier::Pid { .. }))
+        .flat_map(|(_, lines)| lines)
+        .filter(|line| !global_times.contains(&line.timestamp_micros()))
+        .count();
+    if misaligned > 0 {
+        warn!(
+            "{misaligned} process samples do not share the time of a global sample, so their \
+             counters do not line up with the system ones"
+        );
+    }
+    misaligned
+}
+
+/// Orders the processes depth-first below their parents, along with the pid of their parent. The
+/// processed profile format has no notion of a parent process, but the Firefox Profiler shows the
+/// processes in the order they were added. Processes whose parent was not profiled or is unknown
+/// are shown at the top level.
+fn process_tree_order(
+    processes: &HashMap<ReportIdentifier, Vec<JsonLine>>,
+) -> Vec<(ReportIdentifier, Option<u32>)> {
+    let pids = processes
+        .keys()
+        .filter_map(|id| match id {
+            ReportIdentifier::Pid { pid, .. } => Some(*pid),
+            _ => None,
+        })
+        .collect::<HashSet<_>>();
+    let parents = processes
+        .iter()
+        .map(|(id, samples)| (*id, parent_pid(samples).filter(|pid| pids.contains(pid))))
+        .collect::<HashMap<_, _>>();
+    let mut children = BTreeMap::<Option<u32>, Vec<ReportIdentifier>>::new();
+    for (id, parent) in &parents {
+        children.entry(*parent).or_default().push(*id);
+    }
+    for ids in children.values_mut() {
+        ids.sort_unstable_by(|a, b| b.cmp(a));
+    }
+
+    let mut order = Vec::with_capacity(processes.len());
+    let mut visited = HashSet::new();
+    // Reversed, so the smallest id is popped first
+    let mut stack = children.get(&None).cloned().unwrap_or_default();
+    while let Some(id) = stack.pop() {
+        if !visited.insert(id) {
+            continue;
+        }
+        order.push((id, parents[&id]));
+        let child_ids = match id {
+            ReportIdentifier::Pid { pid, .. } => children.get(&Some(pid)),
+            _ => None,
+        };
+        stack.extend(child_ids.into_iter().flatten().copied());
+    }
+    // A reused pid can make processes their own ancestors, which are not reachable from the top
+    let mut unreachable = parents
+        .keys()
+        .filter(|id| !visited.contains(*id))
+        .copied()
+        .collect::<Vec<_>>();
+    unreachable.sort_unstable();
+    order.extend(unreachable.into_iter().map(|id| (id, None)));
+    order
+}
+
+/// The pid of the parent of a process, as seen by py-spy.
+fn parent_pid(samples: &[JsonLine]) -> Option<u32> {
+    samples
+        .iter()
+        .flat_map(|line| &line.stacktraces)
+        .find_map(|trace| trace.process_info.as_ref()?.parent.as_ref())
+        .map(|parent| parent.pid as u32)
+}
+
+/// Drops the stacktraces of all threads whose name is not in the allowlist. Processes without any
+/// of the threads are skipped by [`ProfileBuilder::add_process`].
+fn retain_threads(processes: &mut HashMap<ReportIdentifier, Vec<JsonLine>>, names: &[String]) {
+    for lines in processes.values_mut() {
+        // The name might be missing in the first samples of a thread
+        let allowed = lines
+            .iter()
+            .flat_map(|line| &line.stacktraces)
+            .filter(|it| {
+                it.thread_name
+                    .as_ref()
+                    .is_some_and(|name| names.contains(name))
+            })
+            .map(|it| it.thread_id)
+            .collect::<HashSet<_>>();
+        for line in lines.iter_mut() {
+            line.stacktraces
+                .retain(|it| allowed.contains(&it.thread_id));
+        }
+    }
+}
+
+/// Drops the samples before the first and after the last sample of a process in which a thread
+/// was active or the process used at least `max_cpu` percent CPU. Runs before the start time of
+/// the profile is computed, so the timeline starts at the activity.
+fn trim_idle(processes: &mut HashMap<ReportIdentifier, Vec<JsonLine>>, max_cpu: f32) {
+    let active = processes
+        .iter()
+        .filter(|(id, _)| matches!(id, ReportIdentifier::Pid { .. }))
+        .flat_map(|(_, lines)| lines)
+        .filter(|line| {
+            line.resources.cpu >= max_cpu || line.stacktraces.iter().any(|trace| trace.active)
+        })
+        .map(JsonLine::timestamp_micros);
+    let Some((first, last)) = active.fold(None, |range: Option<(u128, u128)>, time| {
+        Some(range.map_or((time, time), |(first, last)| {
+            (first.min(time), last.max(time))
+        }))
+    }) else {
+        info!("Not trimming idle samples, the processes were idle throughout");
+        return;
+    };
+    for lines in processes.values_mut() {
+        lines.retain(|line| (first..=last).contains(&line.timestamp_micros()));
+    }
+    debug!(
+        "Trimmed the profile to the {}ms of activity",
+        (last - first) / 1000
+    );
+}
+
+/// Moves the stacktraces of the threads whose names match the pattern to one track per group,
+/// named after the first capture group, or the whole match without one. Like the "Other threads"
+/// track, a group gets the summed CPU usage of its threads.
+fn group_threads(lines: &mut [JsonLine], pattern: &Regex) {
+    // The name might be missing in the first samples of a thread
+    let groups = lines
+        .iter()
+        .flat_map(|line| &line.stacktraces)
+        .filter_map(|it| {
+            let captures = pattern.captures(it.thread_name.as_deref()?)?;
+            let group = captures.get(1).or(captures.get(0))?.as_str().to_string();
+            Some((it.thread_id, group))
+        })
+        .collect::<HashMap<_, _>>();
+    // Numbered by name, which ensures the report is deterministic
+    let track_ids = groups
+        .values()
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .enumerate()
+        .map(|(index, group)| (group.clone(), grouped_threads_track_id(index)))
+        .collect::<HashMap<_, _>>();
+    if track_ids.is_empty() {
+        return;
+    }
+
+    for line in lines.iter_mut() {
+        let mut group_cpus = HashMap::<u64, f32>::new();
+        for trace in line.stacktraces.iter_mut() {
+            let Some(group) = groups.get(&trace.thread_id) else {
+                continue;
+            };
+            let track_id = track_ids[group];
+            let cpu = trace
+                .os_thread_id
+                .and_then(|tid| line.resources.thread_resources.get(&tid))
+                .map_or(0.0, |it| it.cpu);
+            // Only one of the stacktraces carries the summed CPU usage, so it is not counted twice
+            trace.os_thread_id = (!group_cpus.contains_key(&track_id)).then_some(track_id);
+            *group_cpus.entry(track_id).or_default() += cpu;
+            trace.thread_id = track_id;
+            trace.thread_name = Some(group.clone());
+        }
+        for (track_id, cpu) in group_cpus {
+            line.resources.thread_resources.insert(
+                track_id,
+                types::ThreadResources {
+                    cpu,
+                    memory: 0,
+                    disk_read_bytes: 0,
+                    disk_write_bytes: 0,
+                },
+            );
+        }
+    }
+}
+
+/// Keeps the `max` threads with the most CPU time, and then the most samples, plus the main
+/// thread. The stacktraces of the others are moved to a single "Other threads" track, which gets
+/// their summed CPU usage, so the process still adds up.
+fn fold_idle_threads(pid: u32, lines: &mut [JsonLine], max: usize) {
+    // The CPU usage and sample count of every thread
+    let mut totals = HashMap::<u64, (f64, usize)>::new();
+    let mut main_threads = HashSet::new();
+    for line in lines.iter() {
+        for trace in &line.stacktraces {
+            let total = totals.entry(trace.thread_id).or_default();
+            total.0 += trace
+                .os_thread_id
+                .and_then(|tid| line.resources.thread_resources.get(&tid))
+                .map_or(0.0, |it| it.cpu as f64);
+            total.1 += 1;
+            // The main thread gets the remaining CPU usage of the process, so it always stays
+            let is_main = trace.os_thread_id == Some(pid as u64)
+                || trace.thread_name.as_deref() == Some(MAIN_THREAD_NAME);
+            if is_main {
+                main_threads.insert(trace.thread_id);
+            }
+        }
+    }
+    if totals.len() <= max {
+        return;
+    }
+
+    let mut ranked = totals
+        .into_iter()
+        .filter(|(id, _)| !main_threads.contains(id))
+        .collect::<Vec<_>>();
+    // Sorted by thread id first, which ensures the report is deterministic
+    ranked.sort_by_key(|(id, _)| *id);
+    ranked.sort_by(|(_, a), (_, b)| b.0.total_cmp(&a.0).then(b.1.cmp(&a.1)));
+    let kept = ranked
+        .iter()
+        .take(max)
+        .map(|(id, _)| *id)
+        .chain(main_threads)
+        .collect::<HashSet<_>>();
+    debug!(
+        "Moving {} threads of process {pid} to \"{OTHER_THREADS_TRACK_NAME}\"",
+        ranked.len().saturating_sub(max)
+    );
+
+    for line in lines.iter_mut() {
+        let mut other_cpu = 0.0;
+        let mut first_other = true;
+        for trace in line.stacktraces.iter_mut() {
+            if kept.contains(&trace.thread_id) {
+                continue;
+            }
+            other_cpu += trace
+                .os_thread_id
+                .and_then(|tid| line.resources.thread_resources.get(&tid))
+                .map_or(0.0, |it| it.cpu);
+            trace.thread_id = OTHER_THREADS_TRACK_ID;
+            trace.thread_name = Some(OTHER_THREADS_TRACK_NAME.to_string());
+            // Only one of the stacktraces carries the summed CPU usage, so it is not counted twice
+            trace.os_thread_id = first_other.then_some(OTHER_THREADS_TRACK_ID);
+            first_other = false;
+        }
+        if !first_other {
+            line.resources.thread_resources.insert(
+                OTHER_THREADS_TRACK_ID,
+                types::ThreadResources {
+                    cpu: other_cpu,
+                    memory: 0,
+                    disk_read_bytes: 0,
+                    disk_write_bytes: 0,
+                },
+            );
+        }
+    }
+}
+
+/// A sample of a thread that is shown together with the threads of the same name of all other
+/// processes, e.g. the incarnations of a restarted worker.
+struct NamedThreadSample {
+    id: ReportIdentifier,
+    time_micros: u128,
+    name: String,
+    cpu: f32,
+    active: bool,
+    frames: Vec<types::Frame>,
+}
+
+/// Removes the samples of all named threads from the processes and returns them ordered by time.
+fn take_named_threads(
+    processes: &mut HashMap<ReportIdentifier, Vec<JsonLine>>,
+) -> Vec<NamedThreadSample> {
+    let mut samples = Vec::new();
+    for (id, lines) in processes.iter_mut() {
+        if matches!(id, ReportIdentifier::Global | ReportIdentifier::Profiler) {
+            continue;
+        }
+        // The name might be missing in the first samples of a thread
+        let names = lines
+            .iter()
+            .flat_map(|line| &line.stacktraces)
+            .filter_map(|it| Some((it.thread_id, it.thread_name.clone()?)))
+            .collect::<HashMap<_, _>>();
+
+        for line in lines.iter_mut() {
+            let time_micros = line.timestamp_micros();
+            let (named, unnamed) = std::mem::take(&mut line.stacktraces)
+                .into_iter()
+                .partition::<Vec<_>, _>(|it| names.contains_key(&it.thread_id));
+            line.stacktraces = unnamed;
+
+            for stacktrace in named {
+                let cpu = stacktrace
+                    .os_thread_id
+                    .and_then(|tid| line.resources.thread_resources.get(&tid))
+                    .map(|it| it.cpu)
+                    .unwrap_or(0.0);
+                samples.push(NamedThreadSample {
+                    id: *id,
+                    time_micros,
+                    name: names[&stacktrace.thread_id].clone(),
+                    cpu,
+                    active: stacktrace.active,
+                    frames: stacktrace.frames,
+                });
+            }
+        }
+    }
+    // Ensure the report is deterministic
+    samples.sort_by(|a, b| (a.time_micros, &a.name, a.id).cmp(&(b.time_micros, &b.name, b.id)));
+    samples
+}
+
+fn write_profile(
+    output_path: &Path,
+    profile: Profile,
+    compression: CompressionLevel,
+) -> Result<(), ExportError> {
     let output_file = File::create(output_path).context(WriteOutputSnafu {
         path: output_path.display().to_string(),
     })?;
 
-    let mut gz = GzEncoder::new(output_file, Compression::default());
+    // The Firefox Profiler accepts both, so the name decides which tools downstream expect
+    if output_path
+        .extension()
+        .is_none_or(|extension| extension != "gz")
+    {
+        info!("Writing uncompressed JSON, as the output file does not end in `.gz`");
+        let mut writer = BufWriter::new(output_file);
+        serde_json::to_writer(&mut writer, &profile).context(SerializeReportsSnafu)?;
+        return writer.flush().context(WriteOutputSnafu {
+            path: output_path.display().to_string(),
+        });
+    }
+    info!("Writing gzip-compressed JSON, as the output file ends in `.gz`");
+
+    // `none` still writes an uncompressed gzip stream, to match the file name
+    let mut gz = GzEncoder::new(output_file, compression.gzip());
 
     // Serialize the data to JSON and write it to the gzipped file
     serde_json::to_writer(&mut gz, &profile).context(SerializeReportsSnafu)?;
@@ -466,3 +1736,244 @@ fn write_profile(output_path: &Path, profile: Profile) -> Result<(), ExportError
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROCESS: ReportIdentifier = ReportIdentifier::Pid {
+        pid: 1234,
+        generation: 0,
+    };
+
+    fn sample(time_micros: u128, stacktraces: Vec<StackTrace>) -> JsonLine {
+        let mut resources = ProcessResources::exited();
+        resources.exited = false;
+        JsonLine {
+            stacktraces,
+            resources,
+            index: 0,
+            time: time_micros / 1000,
+            time_micros: Some(time_micros),
+            sample_duration_micros: 0,
+            report: None,
+            sequence: None,
+        }
+    }
+
+    #[test]
+    fn samples_of_a_tick_share_their_timestamp() {
+        let tick = 1_700_000_000_000_123;
+        let processes = HashMap::from([
+            (
+                ReportIdentifier::Global,
+                vec![sample(tick, vec![]), sample(tick + 1000, vec![])],
+            ),
+            (
+                PROCESS,
+                vec![sample(tick, vec![]), sample(tick + 1000, vec![])],
+            ),
+        ]);
+        assert_eq!(check_shared_tick_times(&processes), 0);
+
+        let builder = ProfileBuilder::from_samples(
+            || processes.values(),
+            &Metadata::default(),
+            FirefoxColors::default(),
+            None,
+        )
+        .unwrap();
+        for (global, process) in processes[&ReportIdentifier::Global]
+            .iter()
+            .zip(&processes[&PROCESS])
+        {
+            assert_eq!(
+                builder.time_micros(global.timestamp_micros()),
+                builder.time_micros(process.timestamp_micros())
+            );
+        }
+    }
+
+    #[test]
+    fn counters_show_the_absolute_values() {
+        let mut profile = Profile::new(
+            "test",
+            ReferenceTimestamp::from_millis_since_unix_epoch(0.0),
+            SamplingInterval::from_nanos(1_000_000),
+        );
+        let start = Timestamp::from_nanos_since_reference(0);
+        let process = profile.add_process("python", 1234, start);
+        let mut counter = ProfileCounter::new(
+            &mut profile,
+            process,
+            "threads",
+            "Threads",
+            "Number of threads",
+            GraphColor::Blue,
+        )
+        .initialize(&mut profile, start, 0.);
+        for (millis, value) in [(1, 4.0), (2, 7.0), (3, 5.0)] {
+            let timestamp = Timestamp::from_nanos_since_reference(millis * 1_000_000);
+            counter.add_value(&mut profile, timestamp, value);
+        }
+
+        let json = serde_json::to_value(&profile).unwrap();
+        let sums = json["counters"][0]["samples"]["count"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .scan(0.0, |sum, count| {
+                *sum += count.as_f64().unwrap();
+                Some(*sum)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(sums, [0.0, 4.0, 7.0, 5.0]);
+    }
+
+    #[test]
+    fn native_frames_of_a_library_are_told_apart_by_their_function() {
+        let processes = HashMap::from([(PROCESS, vec![sample(1000, vec![])])]);
+        let mut builder = ProfileBuilder::from_samples(
+            || processes.values(),
+            &Metadata::default(),
+            FirefoxColors::default(),
+            None,
+        )
+        .unwrap();
+        let start = builder.time(1);
+        let process = builder.profile.add_process("python", 1234, start);
+        let thread = builder.profile.add_thread(process, 1234, start, true);
+
+        let native = |name: &str| types::Frame {
+            name: name.to_string(),
+            filename: "/usr/lib/libfoo.so".to_string(),
+            module: Some("libfoo.so".to_string()),
+            short_filename: None,
+            line: 0,
+            locals: None,
+            is_entry: false,
+        };
+        builder.intern_stack(thread, &[native("foo_read")], true);
+        builder.intern_stack(thread, &[native("foo_write")], true);
+
+        assert_eq!(builder.frame_infos.len(), 2);
+    }
+
+    #[test]
+    fn export_checks_the_tick_times() {
+        let data_dir = std::env::temp_dir().join(format!(
+            "py-crude-resource-monitor-tick-times-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&data_dir).unwrap();
+        let metadata = Metadata {
+            shared_tick_time: true,
+            ..Metadata::default()
+        };
+        std::fs::write(
+            data_dir.join(types::METADATA_FILE_NAME),
+            serde_json::to_string(&metadata).unwrap(),
+        )
+        .unwrap();
+        let tick = 1_700_000_000_000_123;
+        let write_report = |name: &str, times: &[u128]| {
+            let lines = times
+                .iter()
+                .map(|time| serde_json::to_string(&sample(*time, vec![])).unwrap() + "\n")
+                .collect::<String>();
+            std::fs::write(data_dir.join(name), lines).unwrap();
+        };
+        write_report("global.json", &[tick, tick + 1000]);
+        write_report("1234.json", &[tick, tick + 500]);
+
+        let processes = super::super::read_report(&data_dir, &ReadOptions::default()).unwrap();
+        let misaligned = check_shared_tick_times(&processes);
+        let exported = export_report(
+            &data_dir,
+            &data_dir.join("profile.json"),
+            &ReadOptions::default(),
+            &FirefoxOptions::default(),
+        );
+        std::fs::remove_dir_all(&data_dir).unwrap();
+
+        assert_eq!(misaligned, 1);
+        assert!(exported.is_ok());
+    }
+
+    #[test]
+    fn counts_process_samples_off_the_tick() {
+        let tick = 1_700_000_000_000_123;
+        let processes = HashMap::from([
+            (ReportIdentifier::Global, vec![sample(tick, vec![])]),
+            (
+                PROCESS,
+                vec![sample(tick, vec![]), sample(tick + 500, vec![])],
+            ),
+        ]);
+        assert_eq!(check_shared_tick_times(&processes), 1);
+    }
+
+    #[test]
+    fn thread_cpu_sums_up_to_the_process() {
+        // The main thread reports its own usage, which is replaced by the remaining usage
+        let mut thread_samples = [(1, (), 40.0), (2, (), 30.0), (3, (), 20.0)];
+        reconcile_thread_cpu(100.0, 1, &mut thread_samples);
+
+        assert_eq!(thread_samples.map(|(.., cpu)| cpu), [50.0, 30.0, 20.0]);
+        let total = thread_samples.iter().map(|(.., cpu)| cpu).sum::<f32>();
+        assert_eq!(total, 100.0);
+    }
+
+    #[test]
+    fn main_thread_cpu_is_never_negative() {
+        // Threads are measured at slightly different times than the process
+        let mut thread_samples = [(1, (), 10.0), (2, (), 60.0), (3, (), 50.0)];
+        reconcile_thread_cpu(100.0, 1, &mut thread_samples);
+
+        assert_eq!(thread_samples.map(|(.., cpu)| cpu), [0.0, 60.0, 50.0]);
+    }
+
+    fn stacktrace(thread_id: u64, os_thread_id: Option<u64>) -> StackTrace {
+        StackTrace {
+            pid: 1234,
+            thread_id,
+            thread_name: None,
+            os_thread_id,
+            active: true,
+            owns_gil: false,
+            frames: Vec::new(),
+            process_info: None,
+        }
+    }
+
+    #[test]
+    fn threads_are_keyed_by_their_os_thread_id() {
+        // The Python thread id changed while the OS thread stayed the same, and a later sample
+        // lacks the OS thread id
+        let samples = vec![
+            sample(1000, vec![stacktrace(1, Some(42))]),
+            sample(2000, vec![stacktrace(2, Some(42))]),
+            sample(3000, vec![stacktrace(2, None)]),
+        ];
+        let mut builder = ProfileBuilder::new("test", 1, 1000, FirefoxColors::default());
+        let process = ProfileBuilderProcess::new(&mut builder, "Process", 1, 1234, &samples);
+
+        let keys = samples
+            .iter()
+            .flat_map(|line| &line.stacktraces)
+            .map(|trace| process.thread_key(trace))
+            .collect::<Vec<_>>();
+        assert_eq!(keys, [42, 42, 42]);
+    }
+
+    #[test]
+    fn ambiguous_python_thread_ids_are_not_mapped() {
+        // The Python thread id was reused for a new OS thread
+        let samples = vec![
+            sample(1000, vec![stacktrace(1, Some(42))]),
+            sample(2000, vec![stacktrace(1, Some(43))]),
+            sample(3000, vec![stacktrace(1, None)]),
+        ];
+        assert!(unambiguous_os_thread_ids(&samples).is_empty());
+    }
+}
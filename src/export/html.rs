@@ -1,10 +1,20 @@
+use crate::export::{
+    CompressionLevel, Downsampler, ReadOptions, ReportIdentifier, SampleIo, SampleTime, Strip,
+};
+use crate::types::JsonLine;
 use base64::Engine;
 use base64::prelude::BASE64_STANDARD;
-use flate2::Compression;
+use flate2::read::MultiGzDecoder;
+use log::warn;
+use rayon::prelude::*;
 use rust_embed::Embed;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
-use snafu::{IntoError, Location, NoneError, ResultExt, Snafu};
-use std::io::Write;
+use snafu::{IntoError, Location, NoneError, OptionExt, ResultExt, Snafu, Whatever};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::ops::RangeInclusive;
 use std::path::Path;
 
 #[derive(Debug, Snafu)]
@@ -22,6 +32,13 @@ pub enum ExportError {
         #[snafu(implicit)]
         location: Location,
     },
+    #[snafu(display("Error converting report `{name}` to JSON at {location}"))]
+    ConvertReport {
+        source: Whatever,
+        name: String,
+        #[snafu(implicit)]
+        location: Location,
+    },
     #[snafu(display("Error serializing reports at {location}"))]
     SerializeReports {
         source: serde_json::Error,
@@ -46,6 +63,32 @@ pub enum ExportError {
         #[snafu(implicit)]
         location: Location,
     },
+    #[snafu(display("Error reading HTML file `{path}` at {location}"))]
+    ReadHtml {
+        source: std::io::Error,
+        path: String,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display("No bundled reports found in `{path}` at {location}"))]
+    BundledReportsMissing {
+        path: String,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display("Error deserializing the bundled reports at {location}"))]
+    DeserializeReports {
+        source: serde_json::Error,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display("Error decoding bundled report `{name}` at {location}"))]
+    DecodeReport {
+        source: base64::DecodeError,
+        name: String,
+        #[snafu(implicit)]
+        location: Location,
+    },
     #[snafu(display("Profile data insertion point not found in bundled index.html at {location}"))]
     InsertionPointMissing {
         #[snafu(implicit)]
@@ -53,46 +96,277 @@ pub enum ExportError {
     },
 }
 
+/// Precedes the reports in the exported HTML file
+const BUNDLED_REPORTS_MARKER: &str = "const BUNDLED_REPORTS = ";
+
 #[derive(Embed)]
 #[folder = "frontend/dist/"]
 struct Asset;
 
-pub(super) fn export_report(data_dir: &Path, output_file: &Path) -> Result<(), ExportError> {
-    let mut reports = Vec::new();
+pub(super) fn export_report(
+    data_dir: &Path,
+    output_file: &Path,
+    options: &ReadOptions,
+    compression: CompressionLevel,
+) -> Result<(), ExportError> {
+    let mut reports = super::report_parts(data_dir).context(OutputDirReadSnafu)?;
+    reports.retain(|stem, _| ReportIdentifier::parse(stem).is_none_or(|id| options.includes(id)));
+    let window = super::time_window(data_dir, options).context(OutputDirReadSnafu)?;
 
-    for entry in std::fs::read_dir(data_dir).context(OutputDirReadSnafu)? {
-        let entry = entry.context(OutputDirReadSnafu)?;
+    let index_html = Asset::get("index.html").ok_or(IndexNotFoundSnafu.into_error(NoneError))?;
+    let index_html = String::from_utf8_lossy(&index_html.data);
+    let (head, tail) = index_html
+        .split_once("const BUNDLED_REPORTS = []")
+        .ok_or(InsertionPointMissingSnafu.into_error(NoneError))?;
 
-        let name = entry.file_name().to_string_lossy().to_string();
-        let content =
-            std::fs::read(entry.path()).context(ReadReportSnafu { name: name.clone() })?;
+    // Keeps the output reproducible: global first, then the processes by pid
+    let mut reports = reports.into_iter().collect::<Vec<_>>();
+    reports.sort_by_cached_key(|(stem, _)| (ReportIdentifier::parse(stem), stem.clone()));
 
-        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), Compression::best());
-        encoder.write_all(&content).context(CompressReportSnafu)?;
-        let data = BASE64_STANDARD.encode(encoder.finish().context(CompressReportSnafu)?);
+    let path = output_file.display().to_string();
+    let file = File::create(output_file).context(WriteOutputSnafu { path: &path })?;
+    let mut writer = BufWriter::new(file);
+    write!(writer, "{head}{BUNDLED_REPORTS_MARKER}[").context(WriteOutputSnafu { path: &path })?;
 
-        reports.push(json!({
-            "name": name,
-            "data": data,
-        }));
+    // Compression dominates the runtime, so we do it for a batch of reports in parallel. Each batch
+    // is written before the next one is encoded, so only a batch is held in memory at a time.
+    let progress = super::progress_bar(reports.len(), "Compressing reports");
+    let mut first = true;
+    for batch in reports.chunks(rayon::current_num_threads().max(1)) {
+        let encoded = batch
+            .par_iter()
+            .map(|(stem, parts)| {
+                let report = encode_report(stem, parts, options, window.as_ref(), compression);
+                progress.inc(1);
+                report
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        for (name, data, summary) in encoded {
+            if !first {
+                writer
+                    .write_all(b",")
+                    .context(WriteOutputSnafu { path: &path })?;
+            }
+            first = false;
+            let report = json!({
+                "name": name,
+                "data": data,
+                "compressed": compression != CompressionLevel::None,
+                "summary": summary,
+            });
+            serde_json::to_writer(&mut writer, &report).context(SerializeReportsSnafu)?;
+        }
     }
+    progress.finish_and_clear();
 
-    let report_json = serde_json::to_string(&reports).context(SerializeReportsSnafu)?;
+    write!(writer, "];{tail}").context(WriteOutputSnafu { path: &path })?;
+    writer.flush().context(WriteOutputSnafu { path })?;
 
-    let index_html = Asset::get("index.html").ok_or(IndexNotFoundSnafu.into_error(NoneError))?;
-    let original_html = String::from_utf8_lossy(&index_html.data);
-    let index_html = original_html.replace(
-        "const BUNDLED_REPORTS = []",
-        &format!("const BUNDLED_REPORTS = {report_json};"),
+    Ok(())
+}
+
+/// A report as embedded into the HTML file by [`export_report`].
+#[derive(Deserialize)]
+struct BundledReport {
+    name: String,
+    data: String,
+    compressed: bool,
+}
+
+/// Extracts the reports embedded by [`export_report`], reversing the encoding.
+pub(super) fn read_bundled_reports(
+    html_file: &Path,
+) -> Result<BTreeMap<String, Vec<u8>>, ExportError> {
+    let path = html_file.display().to_string();
+    let html = std::fs::read_to_string(html_file).context(ReadHtmlSnafu { path: &path })?;
+    let start = html
+        .find(BUNDLED_REPORTS_MARKER)
+        .context(BundledReportsMissingSnafu { path })?;
+    // The array is followed by the rest of the script, so we only parse the first value
+    let reports = serde_json::Deserializer::from_str(&html[start + BUNDLED_REPORTS_MARKER.len()..])
+        .into_iter::<Vec<BundledReport>>()
+        .next()
+        .unwrap_or_else(|| Ok(Vec::new()))
+        .context(DeserializeReportsSnafu)?;
+
+    let mut decoded = BTreeMap::new();
+    for report in reports {
+        let mut content = BASE64_STANDARD
+            .decode(&report.data)
+            .context(DecodeReportSnafu { name: &report.name })?;
+        if report.compressed {
+            let mut decompressed = Vec::new();
+            MultiGzDecoder::new(content.as_slice())
+                .read_to_end(&mut decompressed)
+                .context(ReadReportSnafu { name: &report.name })?;
+            content = decompressed;
+        }
+        decoded.insert(report.name, content);
+    }
+    Ok(decoded)
+}
+
+/// Headline numbers of a report, embedded next to it so the viewer can show them without
+/// decompressing the whole report.
+#[derive(Serialize, Debug, Default)]
+struct ReportSummary {
+    samples: usize,
+    /// Lines that could not be parsed and are not counted, e.g. a truncated last line
+    #[serde(skip)]
+    skipped_lines: usize,
+    peak_memory: u64,
+    /// The share of samples in which a thread of the process held the GIL, in percent
+    gil_held_percent: f64,
+}
+
+impl ReportSummary {
+    fn new(content: &[u8]) -> Self {
+        let mut summary = Self::default();
+        let mut gil_held = 0;
+        for line in content
+            .split(|&b| b == b'\n')
+            .filter(|line| !line.is_empty())
+        {
+            let Ok(line) = serde_json::from_slice::<JsonLine>(line) else {
+                summary.skipped_lines += 1;
+                continue;
+            };
+            summary.samples += 1;
+            summary.peak_memory = summary.peak_memory.max(line.resources.memory);
+            if line.stacktraces.iter().any(|trace| trace.owns_gil) {
+                gil_held += 1;
+            }
+        }
+        summary.gil_held_percent = gil_held as f64 / summary.samples.max(1) as f64 * 100.0;
+        summary
+    }
+}
+
+/// Reads the parts of a report and returns its name, (unless disabled) gzip-compressed,
+/// base64-encoded content and summary.
+fn encode_report(
+    stem: &str,
+    parts: &[super::ReportPart],
+    options: &ReadOptions,
+    window: Option<&RangeInclusive<u128>>,
+    compression: CompressionLevel,
+) -> Result<(String, String, ReportSummary), ExportError> {
+    // The viewer only understands JSON lines, so every report is embedded as a single `.json`
+    let name = format!("{stem}.json");
+    let mut content = super::read_report_json(parts).context(ConvertReportSnafu { name: &name })?;
+    if let Some(window) = window {
+        content = filter_lines(&content, window);
+    }
+    let is_process = matches!(
+        ReportIdentifier::parse(stem),
+        Some(ReportIdentifier::Pid { .. })
     );
+    if options.containing_frame.is_some() && is_process {
+        content = filter_frames(&content, options);
+    }
+    if options.strip.is_active() {
+        content = strip_lines(&content, options.strip);
+    }
+    if options.every_nth.get() > 1 {
+        content = decimate_lines(&content, options.every_nth.get());
+    }
+    let summary = ReportSummary::new(&content);
+    if summary.skipped_lines > 0 {
+        warn!(
+            "Skipped {} unparsable lines of report `{name}` in its summary",
+            summary.skipped_lines
+        );
+    }
 
-    if original_html == index_html {
-        return Err(InsertionPointMissingSnafu.into_error(NoneError));
+    if compression != CompressionLevel::None {
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), compression.gzip());
+        encoder.write_all(&content).context(CompressReportSnafu)?;
+        content = encoder.finish().context(CompressReportSnafu)?;
     }
+    let data = BASE64_STANDARD.encode(content);
 
-    std::fs::write(output_file, index_html).context(WriteOutputSnafu {
-        path: output_file.display().to_string(),
-    })?;
+    Ok((name, data, summary))
+}
 
-    Ok(())
+/// Keeps only the lines with a timestamp in the window, parsing nothing but the timestamp.
+fn filter_lines(content: &[u8], window: &RangeInclusive<u128>) -> Vec<u8> {
+    let mut filtered = Vec::new();
+    for line in content
+        .split(|&b| b == b'\n')
+        .filter(|line| !line.is_empty())
+    {
+        let in_window = serde_json::from_slice::<SampleTime>(line)
+            .is_ok_and(|sample| window.contains(&sample.time));
+        if in_window {
+            filtered.extend_from_slice(line);
+            filtered.push(b'\n');
+        }
+    }
+    filtered
+}
+
+/// Keeps only the lines of samples kept by [`ReadOptions::containing_frame`], which needs the
+/// stacktraces to be parsed.
+fn filter_frames(content: &[u8], options: &ReadOptions) -> Vec<u8> {
+    let mut filtered = Vec::new();
+    for line in content
+        .split(|&b| b == b'\n')
+        .filter(|line| !line.is_empty())
+    {
+        let keep = serde_json::from_slice::<JsonLine>(line)
+            .is_ok_and(|sample| options.keeps_frames(&sample));
+        if keep {
+            filtered.extend_from_slice(line);
+            filtered.push(b'\n');
+        }
+    }
+    filtered
+}
+
+/// Drops the parts selected by `strip` from every line, so they are not embedded into the file.
+/// Lines that can not be parsed are kept as they are, as there is nothing to strip from them.
+fn strip_lines(content: &[u8], strip: Strip) -> Vec<u8> {
+    let mut stripped = Vec::with_capacity(content.len());
+    for line in content
+        .split(|&b| b == b'\n')
+        .filter(|line| !line.is_empty())
+    {
+        match serde_json::from_slice::<JsonLine>(line) {
+            Ok(mut sample) => {
+                strip.apply(&mut sample);
+                serde_json::to_writer(&mut stripped, &sample)
+                    .expect("serializing a sample to memory never fails");
+            }
+            Err(_) => stripped.extend_from_slice(line),
+        }
+        stripped.push(b'\n');
+    }
+    stripped
+}
+
+/// Keeps only every nth line of the raw report. The I/O of the skipped lines is added to the
+/// next kept one like [`super::read_samples`] does, so only the kept lines are parsed in full.
+fn decimate_lines(content: &[u8], every_nth: usize) -> Vec<u8> {
+    let mut decimated = Vec::with_capacity(content.len() / every_nth);
+    let mut samples = Downsampler::new(every_nth);
+    for line in content
+        .split(|&b| b == b'\n')
+        .filter(|line| !line.is_empty())
+    {
+        if !samples.keeps_next() {
+            samples.skip(serde_json::from_slice::<SampleIo>(line).ok());
+            continue;
+        }
+        match serde_json::from_slice::<JsonLine>(line) {
+            Ok(sample) => samples.keep(sample),
+            // E.g. the truncated last line, which the viewer would drop as well
+            Err(_) => samples.skip(None),
+        }
+    }
+    for sample in samples.finish() {
+        serde_json::to_writer(&mut decimated, &sample)
+            .expect("serializing a sample to memory never fails");
+        decimated.push(b'\n');
+    }
+    decimated
 }
@@ -0,0 +1,159 @@
+use crate::export::{ReadOptions, ReportIdentifier};
+use crate::types::{JsonLine, Metadata};
+use arrow_array::builder::{
+    BooleanBuilder, Float32Builder, StringBuilder, TimestampMicrosecondBuilder, UInt32Builder,
+    UInt64Builder,
+};
+use arrow_array::{ArrayRef, RecordBatch};
+use arrow_schema::{ArrowError, DataType, Field, Schema, SchemaRef, TimeUnit};
+use log::info;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::{Compression, ZstdLevel};
+use parquet::errors::ParquetError;
+use parquet::file::properties::WriterProperties;
+use snafu::{Location, ResultExt, Snafu, Whatever};
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+
+#[derive(Debug, Snafu)]
+pub enum ExportError {
+    #[snafu(display("Error reading report at {location}"))]
+    ReadReport {
+        source: Whatever,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display("Error building the samples of report `{report}` at {location}"))]
+    BuildBatch {
+        source: ArrowError,
+        report: String,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display("Error creating output file `{path}` at {location}"))]
+    CreateOutput {
+        source: std::io::Error,
+        path: String,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display("Error writing parquet file `{path}` at {location}"))]
+    WriteParquet {
+        source: ParquetError,
+        path: String,
+        #[snafu(implicit)]
+        location: Location,
+    },
+}
+
+/// The columns of the exported table, one row per sample of a report.
+fn schema() -> SchemaRef {
+    Arc::new(Schema::new(vec![
+        Field::new("report", DataType::Utf8, false),
+        // Missing for the system-wide resources
+        Field::new("pid", DataType::UInt32, true),
+        Field::new(
+            "time",
+            DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into())),
+            false,
+        ),
+        Field::new("cpu", DataType::Float32, false),
+        Field::new("memory", DataType::UInt64, false),
+        Field::new("disk_read_bytes", DataType::UInt64, false),
+        Field::new("disk_write_bytes", DataType::UInt64, false),
+        Field::new("threads", DataType::UInt64, false),
+        Field::new("exited", DataType::Boolean, false),
+    ]))
+}
+
+pub(super) fn export_report(
+    data_dir: &Path,
+    output_path: &Path,
+    options: &ReadOptions,
+) -> Result<(), ExportError> {
+    let processes = super::read_report(data_dir, options).context(ReadReportSnafu)?;
+    let metadata = super::read_metadata(data_dir).context(ReadReportSnafu)?;
+
+    let path = output_path.display().to_string();
+    let output_file = File::create(output_path).context(CreateOutputSnafu { path: &path })?;
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::ZSTD(ZstdLevel::default()))
+        .build();
+    let schema = schema();
+    let mut writer = ArrowWriter::try_new(output_file, schema.clone(), Some(properties))
+        .context(WriteParquetSnafu { path: &path })?;
+
+    let mut processes = processes.into_iter().collect::<Vec<_>>();
+    // Ensure the file is deterministic
+    processes.sort_by_key(|(id, _)| *id);
+    let mut rows = 0;
+    for (id, samples) in processes {
+        rows += samples.len();
+        let batch = resource_batch(&schema, id, &samples, &metadata).context(BuildBatchSnafu {
+            report: id.to_string(),
+        })?;
+        writer
+            .write(&batch)
+            .context(WriteParquetSnafu { path: &path })?;
+    }
+    writer.close().context(WriteParquetSnafu { path })?;
+
+    info!(
+        "Wrote {rows} samples to {}. Query it with e.g. `duckdb -c \"SELECT * FROM '{}'\"`.",
+        output_path.display(),
+        output_path.display()
+    );
+
+    Ok(())
+}
+
+/// The resources of every sample of a report as rows. Stacktraces are not exported.
+fn resource_batch(
+    schema: &SchemaRef,
+    id: ReportIdentifier,
+    samples: &[JsonLine],
+    metadata: &Metadata,
+) -> Result<RecordBatch, ArrowError> {
+    let pid = match id {
+        ReportIdentifier::Pid { pid, .. } => Some(pid),
+        ReportIdentifier::Profiler => metadata.profiler_pid,
+        ReportIdentifier::Global => None,
+    };
+    let report = id.to_string();
+
+    let mut reports = StringBuilder::new();
+    let mut pids = UInt32Builder::with_capacity(samples.len());
+    let mut times = TimestampMicrosecondBuilder::with_capacity(samples.len()).with_timezone("UTC");
+    let mut cpus = Float32Builder::with_capacity(samples.len());
+    let mut memories = UInt64Builder::with_capacity(samples.len());
+    let mut disk_reads = UInt64Builder::with_capacity(samples.len());
+    let mut disk_writes = UInt64Builder::with_capacity(samples.len());
+    let mut threads = UInt64Builder::with_capacity(samples.len());
+    let mut exited = BooleanBuilder::with_capacity(samples.len());
+    for sample in samples {
+        let resources = &sample.resources;
+        reports.append_value(&report);
+        pids.append_option(pid);
+        times.append_value(sample.timestamp_micros() as i64);
+        cpus.append_value(resources.cpu);
+        memories.append_value(resources.memory);
+        disk_reads.append_value(resources.disk_read_bytes);
+        disk_writes.append_value(resources.disk_write_bytes);
+        threads.append_value(resources.thread_count() as u64);
+        exited.append_value(resources.exited);
+    }
+
+    let columns: Vec<ArrayRef> = vec![
+        Arc::new(reports.finish()),
+        Arc::new(pids.finish()),
+        Arc::new(times.finish()),
+        Arc::new(cpus.finish()),
+        Arc::new(memories.finish()),
+        Arc::new(disk_reads.finish()),
+        Arc::new(disk_writes.finish()),
+        Arc::new(threads.finish()),
+        Arc::new(exited.finish()),
+    ];
+    RecordBatch::try_new(schema.clone(), columns)
+}
@@ -0,0 +1,74 @@
+//! A crude resource monitor and profiler for Python processes.
+//!
+//! The [`Tracker`] attaches to a Python process (and its children) using py-spy, and writes
+//! stacktraces correlated with resource usage to a directory every time it is ticked. The
+//! [`export`] module turns such a directory into shareable reports.
+//!
+//! ```no_run
+//! use py_crude_resource_monitor::export::{self, FirefoxOptions, ReadOptions};
+//! use py_crude_resource_monitor::{Tracker, TrackerOptions};
+//! use std::path::Path;
+//! use std::time::Duration;
+//!
+//! # fn main() -> Result<(), Box<dyn std::error::Error>> {
+//! let output_dir = Path::new("profile-data");
+//! std::fs::create_dir_all(output_dir)?;
+//!
+//! let mut tracker =
+//!     Tracker::new_with_retry(&[1234], output_dir.to_path_buf(), &TrackerOptions::default())?;
+//! while tracker.is_still_tracking() {
+//!     tracker.tick();
+//!     std::thread::sleep(Duration::from_millis(100));
+//! }
+//! // Flushes all pending samples to disk
+//! tracker.finish();
+//!
+//! export::export_firefox(
+//!     output_dir,
+//!     Path::new("profile.json.gz"),
+//!     &ReadOptions::default(),
+//!     &FirefoxOptions::default(),
+//! )?;
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod convert;
+pub mod diff;
+pub mod export;
+pub mod lint;
+pub mod manifest;
+pub mod processes;
+mod resources;
+mod sink;
+pub mod snapshot;
+pub mod speedscope;
+mod stacktraces;
+pub mod stats;
+mod tracker;
+pub mod tui;
+pub mod types;
+pub mod view;
+
+pub use resources::SystemMeasurements;
+pub use sink::{FileSink, MemorySink, ResumePoint, SampleSink};
+pub use stacktraces::{PySpyError, SampleCounts, SampleErrorSummary, SpyHelper};
+pub use tracker::{
+    LatestSample, OutputNameTemplate, SampleCallback, Tracker, TrackerError, TrackerOptions,
+    redact_command_line,
+};
+
+// These dependencies are only used by the binary target
+use ctrlc as _;
+use dialoguer as _;
+use env_logger as _;
+use glob as _;
+#[cfg(unix)]
+use nix as _;
+#[cfg(target_os = "macos")]
+use shlex as _;
+#[cfg(unix)]
+use signal_hook as _;
+use toml as _;
+#[cfg(target_os = "macos")]
+use users as _;
@@ -0,0 +1,214 @@
+use crate::export::{JsonRecords, ReportIdentifier, open_report, read_report_file};
+use crate::types::{JsonLine, MetadataHeader, ReportFileName, ReportFormat};
+use snafu::{IntoError, Location, NoneError, ResultExt, Snafu};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::io::BufReader;
+use std::path::Path;
+
+#[derive(Debug, Snafu)]
+pub enum LintError {
+    #[snafu(display("Error reading data dir `{path}` at {location}"))]
+    ReadDataDir {
+        source: std::io::Error,
+        path: String,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display("Error reading report `{path}` at {location}"))]
+    ReadReport {
+        source: std::io::Error,
+        path: String,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display("Found {problems} problems in the report files at {location}"))]
+    Problems {
+        problems: usize,
+        #[snafu(implicit)]
+        location: Location,
+    },
+}
+
+/// Where a sample is in its report file: the line it starts on for JSON reports, or its position
+/// in the sequence for CBOR reports, which have no lines.
+#[derive(Debug, Clone, Copy)]
+enum Position {
+    Line(usize),
+    Item(usize),
+}
+
+impl Display for Position {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Line(line) => write!(f, "line {line}"),
+            Self::Item(item) => write!(f, "item {item}"),
+        }
+    }
+}
+
+/// The last sample seen of a report, to check that the next one follows it.
+struct LastSample {
+    position: Position,
+    index: usize,
+    time_micros: u128,
+}
+
+/// Checks the samples of a single report file. A `--single-file` report holds the samples of all
+/// processes, so the order is checked per report named in the samples.
+struct FileLinter {
+    /// The pid all stacktraces must belong to, if the file is named after a process
+    pid: Option<u32>,
+    last: HashMap<Option<String>, LastSample>,
+    problems: Vec<String>,
+}
+
+impl FileLinter {
+    fn new(report_file: ReportFileName<'_>) -> Self {
+        let pid = match ReportIdentifier::parse(report_file.stem) {
+            Some(ReportIdentifier::Pid { pid, .. }) => Some(pid),
+            _ => None,
+        };
+        Self {
+            pid,
+            last: HashMap::new(),
+            problems: Vec::new(),
+        }
+    }
+
+    fn check(&mut self, position: Position, sample: &JsonLine) {
+        let time_micros = sample.timestamp_micros();
+        if let Some(last) = self.last.get(&sample.report) {
+            if sample.index <= last.index {
+                self.problems.push(format!(
+                    "{position}: index {} does not follow index {} of {}",
+                    sample.index, last.index, last.position
+                ));
+            }
+            // Every tick takes a single sample of a report, so they never share a timestamp
+            if time_micros < last.time_micros {
+                self.problems.push(format!(
+                    "{position}: time goes back by {}µs from {}",
+                    last.time_micros - time_micros,
+                    last.position
+                ));
+            } else if time_micros == last.time_micros {
+                self.problems
+                    .push(format!("{position}: same time as {}", last.position));
+            }
+        }
+        self.last.insert(
+            sample.report.clone(),
+            LastSample {
+                position,
+                index: sample.index,
+                time_micros,
+            },
+        );
+
+        let mut thread_ids = HashSet::new();
+        for stacktrace in &sample.stacktraces {
+            if !thread_ids.insert(stacktrace.thread_id) {
+                self.problems.push(format!(
+                    "{position}: thread {:#x} has multiple stacktraces",
+                    stacktrace.thread_id
+                ));
+            }
+            if self.pid.is_some_and(|pid| pid != stacktrace.pid as u32) {
+                self.problems.push(format!(
+                    "{position}: stacktrace of thread {:#x} belongs to process {}",
+                    stacktrace.thread_id, stacktrace.pid
+                ));
+            }
+        }
+    }
+
+    fn invalid(&mut self, position: Position, error: impl Display) {
+        self.problems
+            .push(format!("{position}: invalid sample: {error}"));
+    }
+}
+
+/// Reads every report file of the directory and prints the samples that break the invariants the
+/// exporters rely on: continuous indices, increasing timestamps and one stacktrace per thread of
+/// the process. Unlike the exporters, nothing is dropped or skipped, not even a truncated last
+/// sample.
+pub fn run_lint(data_dir: &Path) -> Result<(), LintError> {
+    let path = data_dir.display().to_string();
+    let mut file_names = std::fs::read_dir(data_dir)
+        .context(ReadDataDirSnafu { path: &path })?
+        .map(|entry| entry.map(|it| it.file_name().to_string_lossy().to_string()))
+        .collect::<Result<Vec<_>, _>>()
+        .context(ReadDataDirSnafu { path: &path })?;
+    file_names.sort();
+
+    let mut problems = 0;
+    let mut files = 0;
+    for file_name in &file_names {
+        let Some(report_file) = ReportFileName::parse(file_name) else {
+            continue;
+        };
+        files += 1;
+        let file_path = data_dir.join(file_name);
+        let mut linter = FileLinter::new(report_file);
+        match report_file.format {
+            ReportFormat::Jsonl => lint_json(&file_path, report_file, &mut linter)?,
+            ReportFormat::Cbor => lint_cbor(&file_path, report_file, &mut linter)?,
+        }
+        for problem in &linter.problems {
+            println!("{file_name}: {problem}");
+        }
+        problems += linter.problems.len();
+    }
+
+    if problems > 0 {
+        return Err(ProblemsSnafu { problems }.into_error(NoneError));
+    }
+    println!("All {files} report files passed");
+    Ok(())
+}
+
+fn lint_json(
+    path: &Path,
+    report_file: ReportFileName<'_>,
+    linter: &mut FileLinter,
+) -> Result<(), LintError> {
+    let display_path = path.display().to_string();
+    let reader = open_report(path, report_file.compressed).context(ReadReportSnafu {
+        path: &display_path,
+    })?;
+    for (line, record) in JsonRecords::new(BufReader::new(reader)) {
+        let record = record.context(ReadReportSnafu {
+            path: &display_path,
+        })?;
+        match serde_json::from_str::<JsonLine>(&record) {
+            Ok(sample) => linter.check(Position::Line(line), &sample),
+            Err(e) => linter.invalid(Position::Line(line), e),
+        }
+    }
+    Ok(())
+}
+
+fn lint_cbor(
+    path: &Path,
+    report_file: ReportFileName<'_>,
+    linter: &mut FileLinter,
+) -> Result<(), LintError> {
+    let content = read_report_file(path, report_file.compressed).context(ReadReportSnafu {
+        path: path.display().to_string(),
+    })?;
+    let (_, mut reader) = MetadataHeader::split(&content, ReportFormat::Cbor);
+    let mut item = 1;
+    while !reader.is_empty() {
+        match ciborium::from_reader::<JsonLine, _>(&mut reader) {
+            Ok(sample) => linter.check(Position::Item(item), &sample),
+            Err(e) => {
+                // The start of the next item is unknown after an invalid one
+                linter.invalid(Position::Item(item), e);
+                break;
+            }
+        }
+        item += 1;
+    }
+    Ok(())
+}
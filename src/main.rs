@@ -1,24 +1,37 @@
-mod export;
-mod resources;
-mod stacktraces;
-mod tracker;
-mod types;
-mod view;
-
-use crate::tracker::{Tracker, TrackerError};
-use crate::view::ViewError;
+// The binary is a thin wrapper around the library, which uses most dependencies
+#![allow(unused_crate_dependencies)]
+
 use clap::builder::Styles;
 use clap::builder::styling::AnsiColor;
-use clap::{ArgGroup, Parser, Subcommand};
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgGroup, ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
 use dialoguer::Confirm;
 use dialoguer::theme::ColorfulTheme;
 use log::{debug, error, info, warn};
+use py_crude_resource_monitor::convert::{self, ConvertFormat};
+use py_crude_resource_monitor::diff::{self, DiffFormat};
+use py_crude_resource_monitor::export::{
+    CategoryColorName, CompressionLevel, FirefoxColors, FirefoxOptions, FrameFormat, GraphColorName,
+};
+use py_crude_resource_monitor::tui::LiveView;
+use py_crude_resource_monitor::types::{
+    BlockingMode, CpuNormalization, ReportFormat, SIDECAR_FILE_NAMES, is_report_file,
+};
+use py_crude_resource_monitor::view::{self, BasicAuth, TlsFiles, ViewError, ViewOptions};
+use py_crude_resource_monitor::{
+    OutputNameTemplate, Tracker, TrackerError, TrackerOptions, export, redact_command_line,
+};
+use py_crude_resource_monitor::{lint, manifest, processes, snapshot, speedscope, stats};
+use regex::Regex;
 use snafu::{IntoError, Location, NoneError, Report, ResultExt, Snafu, ensure};
+use std::ffi::OsString;
+use std::io::Write;
+use std::num::{NonZeroU32, NonZeroU64, NonZeroUsize};
 use std::path::{Path, PathBuf};
 use std::process::{Child, Command, ExitStatus, Stdio};
 use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::time::{Duration, Instant};
 use std::{env, thread};
 
 const CLAP_STYLE: Styles = Styles::styled()
@@ -33,25 +46,60 @@ const CLAP_STYLE: Styles = Styles::styled()
 struct Args {
     #[command(subcommand)]
     command: Subcommands,
+    /// The format of the log messages
+    #[arg(long, value_enum, global = true, default_value = "text")]
+    log_format: LogFormat,
+    /// Only log warnings and errors
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+    /// Log more details, repeat for even more. `RUST_LOG` takes precedence if set
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug)]
+enum LogFormat {
+    /// Human-readable log lines
+    Text,
+    /// One JSON object per log record, for log aggregators
+    Json,
 }
 
 #[derive(Subcommand, Debug)]
 enum Subcommands {
     /// Profile a Python process
-    #[clap(group(ArgGroup::new("target").required(true).args(&["pid", "command"])))]
+    #[clap(group(ArgGroup::new("target").required(true).args(&["pid", "command", "reattach"])))]
     Profile {
-        /// The PID of the Python process to monitor
+        /// The PID of the Python process to monitor, can be repeated to monitor multiple processes
         #[arg(short, long)]
-        pid: Option<u32>,
+        pid: Vec<u32>,
         /// The command to execute
         #[clap(conflicts_with = "pid")]
         command: Option<Vec<String>>,
         /// output directory
         #[arg(short, long)]
         output_dir: PathBuf,
+        /// profile a `--pid` even if it does not look like a Python process, e.g. because it
+        /// embeds an interpreter
+        #[arg(long, requires = "pid")]
+        force: bool,
+        /// do not delete the files in the output directory matching this glob, can be repeated
+        #[arg(long, value_name = "GLOB")]
+        keep: Vec<glob::Pattern>,
+        /// write to a temporary directory next to the output directory and only move the files into it once profiling finished cleanly
+        #[arg(long)]
+        output_to_tmp: bool,
+        /// name the report files of processes after this template instead of `<pid>.json`, e.g.
+        /// `{command}-{pid}`. The pid must come last
+        #[arg(long, value_name = "TEMPLATE", conflicts_with = "single_file")]
+        output_name_template: Option<OutputNameTemplate>,
         /// ms between samples
         #[arg(short, long)]
         sample_rate: Option<u64>,
+        /// µs between samples, for short captures of fast functions. Below a millisecond the
+        /// profiler itself can use a full core and might not keep up
+        #[arg(long, value_name = "MICROS", conflicts_with = "sample_rate")]
+        sample_rate_micros: Option<u64>,
         #[cfg(feature = "unwind")]
         /// capture native stack traces
         #[arg(long)]
@@ -60,23 +108,280 @@ enum Subcommands {
         /// capture native stack traces (not compiled, enable with `unwind` build feature)
         #[arg(long)]
         native: bool,
+        /// stop with an error if a process is sampled without native frames, instead of falling
+        /// back to Python-only stacks
+        #[arg(long, visible_alias = "require-native", requires = "native")]
+        no_native_fallback: bool,
+        /// write gzip-compressed `.json.gz` (or `.cbor.gz`) files
+        #[arg(long)]
+        compress_output: bool,
+        /// the encoding of the written samples, exports and the viewer accept both
+        #[arg(long, value_enum, default_value = "jsonl")]
+        output_format: ReportFormat,
+        /// only sample threads running on the CPU, instead of all threads (wall-clock)
+        #[arg(long)]
+        on_cpu_only: bool,
+        /// only record the stacktraces of the main thread of every process
+        #[arg(long)]
+        main_thread_only: bool,
+        /// re-attach to processes that exec a new executable, e.g. when started by a launcher
+        #[arg(long)]
+        follow_exec: bool,
+        /// once all tracked processes exited, wait for a new process with this process or
+        /// executable name, e.g. of a restarting service, and continue profiling it into the same
+        /// output directory. Without `--pid`, the first such process is waited for as well
+        #[arg(long, value_name = "PROCESS_NAME", conflicts_with_all = ["command", "ring_buffer", "dry_run"])]
+        reattach: Option<String>,
+        /// re-attach at most N times with `--reattach`
+        #[arg(long, value_name = "N", requires = "reattach")]
+        max_reattach: Option<u32>,
+        /// do not record the command lines of the profiled processes, e.g. if they contain secrets
+        #[arg(long)]
+        hide_command_lines: bool,
+        /// replace parts of command line arguments matching this regex with `***`
+        #[arg(long, value_parser = Regex::new)]
+        redact_args: Option<Regex>,
+        /// do not track (child) processes whose command line matches this regex
+        #[arg(long, value_parser = Regex::new)]
+        exclude: Option<Regex>,
+        /// do not sample the system-wide resources into `global.json`
+        #[arg(long)]
+        no_global: bool,
+        /// also sample the resources of the profiler itself into `self.json`
+        #[arg(long)]
+        track_self: bool,
+        /// pretty-print the samples of `jsonl` reports, separated by blank lines, to read them by hand
+        #[arg(long)]
+        json_pretty: bool,
+        /// also print every sample as a JSON line to stdout, e.g. to pipe it into `jq`
+        #[arg(long)]
+        stdout: bool,
+        /// write the samples of all processes into a single `samples.json`, for exports only
+        #[arg(long)]
+        single_file: bool,
+        /// start every report file with a metadata header instead of writing `metadata.json`, so single files are self-describing
+        #[arg(long)]
+        embed_metadata: bool,
+        /// only record the resources of the N threads with the highest CPU usage per process
+        #[arg(long, value_name = "N")]
+        max_threads_recorded: Option<usize>,
+        /// only sample N processes per tick, cycling through all of them, for many workers
+        #[arg(long, value_name = "N")]
+        max_concurrent_samples: Option<NonZeroUsize>,
+        /// skip a process for a sample if py-spy takes longer than this many ms to sample it
+        #[arg(long, value_name = "MS", default_value_t = 1000)]
+        sample_timeout_ms: u64,
+        /// log repeated identical sampling errors of a process once a minute, with how many were suppressed
+        #[arg(long)]
+        rate_limit_log: bool,
+        /// retry failed writes of a sample this often, e.g. on network filesystems
+        #[arg(long, value_name = "N", default_value_t = 3)]
+        write_retries: u32,
+        /// write the samples of a report in batches of N, at least once a second, to save syscalls at high sample rates
+        #[arg(long, value_name = "N", default_value_t = 1)]
+        writer_batch: usize,
+        /// keep at most this many bytes of a report on disk, in `<pid>.<n>.json` parts, deleting the oldest parts
+        #[arg(long, value_name = "BYTES")]
+        max_output_size: Option<u64>,
+        /// delete the samples older than this many seconds, in parts, keeping a rolling window on disk for continuous monitoring
+        #[arg(long, value_name = "SECONDS")]
+        max_file_age: Option<NonZeroU64>,
+        /// clamp the CPU usage of a process to this many percent, instead of 100 per core
+        #[arg(long, value_name = "PERCENT")]
+        max_cpu_percent: Option<f32>,
+        /// round CPU usages in the reports to this many decimals
+        #[arg(long, value_name = "N", default_value_t = 2)]
+        cpu_decimals: u32,
+        /// what 100% CPU usage means for processes, threads and the system: one fully used core
+        /// (`per-core`) or all cores fully used (`total`)
+        #[arg(long, value_enum, value_name = "MODE", default_value = "per-core")]
+        cpu_normalize: CpuNormalization,
+        /// whether py-spy pauses the processes while sampling them, `off` is less intrusive but may read torn stacks
+        #[arg(long, value_enum, value_name = "MODE", default_value = "auto")]
+        blocking: BlockingMode,
+        /// randomly vary the time between samples by up to this many percent, so sampling does not
+        /// align with periodic work
+        #[arg(long, value_name = "PERCENT")]
+        interval_jitter: Option<f64>,
+        /// sample at most FACTOR times as often as configured on SIGUSR2, rounded down to a power
+        /// of two
+        #[arg(long, default_value = "8", value_name = "FACTOR")]
+        max_speedup: NonZeroU32,
+        /// stop profiling once N stacktraces were captured across all threads and processes
+        #[arg(long, value_name = "N")]
+        target_samples: Option<u64>,
+        /// stop profiling after SECONDS, including the time spent waiting for `--reattach`
+        #[arg(long, value_name = "SECONDS")]
+        duration: Option<u64>,
+        /// only keep the samples of the last SECONDS in memory, writing them if the spawned command
+        /// crashes or on CTRL+C
+        #[arg(long, value_name = "SECONDS")]
+        ring_buffer: Option<u64>,
+        /// discard the samples of the first SECONDS, which are noisy from imports. The first sample
+        /// is always discarded, as the CPU usage is only known from the second one on
+        #[arg(long, default_value = "0", value_name = "SECONDS")]
+        warmup: u64,
+        /// ms to wait for a spawned command to exit after SIGTERM, before it is killed
+        #[arg(long, default_value = "2000", value_name = "MS")]
+        child_grace_ms: u64,
+        /// adapt the time between samples to the CPU usage of the profiled processes
+        #[arg(long, conflicts_with_all = ["sample_rate", "sample_rate_micros"])]
+        adaptive_rate: bool,
+        /// ms between samples when the processes use a full core, with `--adaptive-rate`
+        #[arg(long, default_value = "100", requires = "adaptive_rate")]
+        min_sample_rate: u64,
+        /// ms between samples when the processes are idle, with `--adaptive-rate`
+        #[arg(long, default_value = "5000", requires = "adaptive_rate")]
+        max_sample_rate: u64,
+        /// sample a spawned command quickly at first, then space the samples out to take about
+        /// `--auto-rate-samples` over its expected lifetime
+        #[arg(long, conflicts_with_all = ["sample_rate", "sample_rate_micros", "adaptive_rate", "pid"])]
+        auto_rate: bool,
+        /// the number of samples to aim for with `--auto-rate`
+        #[arg(long, default_value = "500", value_name = "N", requires = "auto_rate")]
+        auto_rate_samples: u32,
+        /// attach and take a single sample to check that profiling works, then exit
+        #[arg(long)]
+        dry_run: bool,
+        /// exit with code 3 if no stacktrace was captured at all, e.g. to fail a CI job whose
+        /// profiling silently broke
+        #[arg(long, conflicts_with = "dry_run")]
+        fail_on_no_samples: bool,
+        /// print the absolute output directory to stdout on startup, e.g. for `OUT=$(...)` in scripts
+        #[arg(long, conflicts_with = "dry_run")]
+        print_path: bool,
+        /// show a `top`-like view of the processes and their hottest frames while profiling
+        #[arg(long, conflicts_with_all = ["dry_run", "stdout"])]
+        tui: bool,
+        /// attach a label to the run, recorded in the metadata. Can be given multiple times
+        #[arg(long, value_name = "KEY=VALUE", value_parser = parse_label)]
+        label: Vec<(String, String)>,
+        /// read the options from this TOML file, e.g. `sample-rate = 100`. Options given on the
+        /// command line win
+        #[arg(long, value_name = "FILE")]
+        config: Option<PathBuf>,
+        /// print the options after resolving `--config` and the defaults as a TOML config file,
+        /// then exit
+        #[arg(long)]
+        print_config: bool,
     },
     /// Host a web server to view the profile data
     View {
         /// output directory
         output_dir: PathBuf,
-        /// The port to listen on
-        #[arg(long, default_value = "3000")]
-        port: u16,
-        /// The interface to listen on
-        #[arg(long, default_value = "0.0.0.0")]
-        interface: String,
+        #[command(flatten)]
+        serve: ServeArgs,
+        /// Export the directory to this self-contained HTML file once the server is stopped with CTRL+C
+        #[arg(long, value_name = "FILE")]
+        export_on_exit: Option<PathBuf>,
+    },
+    /// Host a web server to view the reports bundled in an exported HTML file
+    Replay {
+        /// The HTML file created by `export html`
+        html_file: PathBuf,
+        #[command(flatten)]
+        serve: ServeArgs,
     },
     /// Exports a captured profile to a single, shareable HTML file
     Export {
         #[command(subcommand)]
         export_subcommand: ExportSubcommand,
     },
+    /// Compares two captured profiles, showing which frames got hotter or colder
+    Diff {
+        /// The directory containing the baseline profile data
+        baseline: PathBuf,
+        /// The directory containing the profile data to compare against the baseline
+        candidate: PathBuf,
+        /// The output format
+        #[arg(long, value_enum, default_value = "text")]
+        format: DiffFormat,
+        /// The number of frames to show for increase and decrease each
+        #[arg(long, default_value = "10")]
+        limit: usize,
+    },
+    /// Prints summary statistics for each process of a captured profile
+    Stats {
+        /// The directory containing the profile data
+        output_dir: PathBuf,
+        /// Also print the N functions with the most samples, by self and total samples
+        #[arg(long, value_name = "N")]
+        top: Option<usize>,
+        /// The label of the functions of `--top`, from the fields `{name}`, `{filename}`, `{short_filename}`, `{module}` and `{line}`
+        #[arg(long, default_value = export::DEFAULT_FRAME_FORMAT)]
+        frame_format: FrameFormat,
+    },
+    /// Checks that the report files of a captured profile match its manifest
+    Verify {
+        /// The directory containing the profile data
+        output_dir: PathBuf,
+    },
+    /// Checks the samples of a captured profile for corruption, e.g. indices or timestamps out of order
+    Lint {
+        /// The directory containing the profile data
+        output_dir: PathBuf,
+    },
+    /// Converts a py-spy speedscope file into profile data, with all resources zero
+    Import {
+        /// The speedscope file, e.g. written by `py-spy record --format speedscope`
+        speedscope: PathBuf,
+        /// The directory to write the profile data to
+        output_dir: PathBuf,
+        /// ms between the samples of the file, which has no timestamps. py-spy samples every 10ms
+        /// by default
+        #[arg(short, long, default_value = "10")]
+        sample_rate: u64,
+    },
+    /// Rewrites the reports of profile data in another raw format, e.g. to compress old captures
+    Convert {
+        /// The directory containing the profile data
+        input_dir: PathBuf,
+        /// The directory to write the converted profile data to
+        output_dir: PathBuf,
+        /// The format to convert the reports to
+        #[arg(long, value_enum)]
+        to: ConvertFormat,
+        /// Drop the local variables of the frames
+        #[arg(long)]
+        strip_locals: bool,
+        /// Drop the process info of the stacktraces
+        #[arg(long)]
+        strip_process_info: bool,
+    },
+    /// Measures the overhead of profiling a busy Python process
+    Benchmark {
+        /// ms between samples
+        #[arg(short, long, default_value = "100")]
+        sample_rate: u64,
+        /// How long to profile for, in seconds
+        #[arg(long, default_value = "10")]
+        duration: u64,
+        /// The Python interpreter running the busy loop
+        #[arg(long, default_value = "python3")]
+        python: String,
+    },
+    /// Prints the current stacktraces and resource usage of a Python process once
+    Snapshot {
+        /// The PID of the Python process
+        pid: u32,
+        #[cfg(feature = "unwind")]
+        /// capture native stack traces
+        #[arg(long)]
+        native: bool,
+        #[cfg(not(feature = "unwind"))]
+        /// capture native stack traces (not compiled, enable with `unwind` build feature)
+        #[arg(long)]
+        native: bool,
+        /// print the snapshot as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Lists the running processes, marking the ones that look like Python interpreters
+    ListProcesses {
+        /// only list processes that look like Python interpreters
+        #[arg(long)]
+        python_only: bool,
+    },
 }
 
 #[derive(Subcommand, Debug)]
@@ -87,16 +392,194 @@ enum ExportSubcommand {
         output_dir: PathBuf,
         /// The output file to write the HTML to
         output_file: PathBuf,
+        #[command(flatten)]
+        read: ReadArgs,
+        /// gzip level of the embedded reports, from 0 to 9 or `none`
+        #[arg(long, default_value = "9")]
+        compression: CompressionLevel,
     },
     /// Exports to the Firefox Profiler's processed profile JSON format
     Firefox {
         /// The directory containing the profile data
         output_dir: PathBuf,
-        /// The output file to write the gz-compressed JSON to
+        /// The output file to write the JSON to, gzip-compressed if it ends in `.gz`
+        output_file: PathBuf,
+        #[command(flatten)]
+        read: ReadArgs,
+        /// gzip level of a `.gz` output file, from 0 to 9 or `none`
+        #[arg(long, default_value = "6")]
+        compression: CompressionLevel,
+        /// show threads of the same name as one track across all processes, e.g. restarted workers
+        #[arg(long)]
+        merge_by_thread_name: bool,
+        /// color of the Python frames
+        #[arg(long, value_enum, default_value = "blue")]
+        python_color: CategoryColorName,
+        /// color of the native frames
+        #[arg(long, value_enum, default_value = "green")]
+        native_color: CategoryColorName,
+        /// color of the memory graph
+        #[arg(long, value_enum, default_value = "orange")]
+        memory_color: GraphColorName,
+        /// color of the I/O graphs
+        #[arg(long, value_enum, default_value = "teal")]
+        io_color: GraphColorName,
+        /// only export the threads with this name, can be repeated
+        #[arg(long = "thread", value_name = "NAME")]
+        threads: Vec<String>,
+        /// the label of the frames, from the fields `{name}`, `{filename}`, `{short_filename}`, `{module}` and `{line}`
+        #[arg(long, default_value = export::DEFAULT_FRAME_FORMAT)]
+        frame_format: FrameFormat,
+        /// merge runs of identical consecutive stacks of a thread into one weighted sample, which
+        /// shrinks mostly idle profiles
+        #[arg(long)]
+        coalesce: bool,
+        /// show child processes right below their parent, named after it
+        #[arg(long)]
+        process_tree: bool,
+        /// only show the N threads with the most CPU time of each process, plus the main thread,
+        /// and move the others into an "Other threads" track
+        #[arg(long, value_name = "N")]
+        select_threads_by_cpu: Option<usize>,
+        /// the name of the profile in the Firefox Profiler [default: the profiled command]
+        #[arg(long, value_name = "NAME")]
+        profile_name: Option<String>,
+        /// trim the samples at the start and end in which no thread was active and the processes
+        /// used less than PERCENT CPU
+        #[arg(long, value_name = "PERCENT")]
+        trim_idle: Option<f32>,
+        /// collapse consecutive calls of the same function into one frame annotated with the recursion depth
+        #[arg(long)]
+        collapse_recursion: bool,
+        /// end the stacks of threads that were not active, e.g. waiting for I/O or sleeping, in an
+        /// `[idle]` frame of its own category
+        #[arg(long)]
+        mark_idle: bool,
+        /// show the threads of a process whose names match REGEX in one track per value of its first capture group, e.g. `(ThreadPoolExecutor-\d+)_\d+`
+        #[arg(long, value_name = "REGEX", value_parser = Regex::new)]
+        group_threads: Option<Regex>,
+    },
+    /// Exports to the Chrome Trace Event JSON format, viewable in Perfetto
+    Chrome {
+        /// The directory containing the profile data
+        output_dir: PathBuf,
+        /// The output file to write the JSON to
         output_file: PathBuf,
+        #[command(flatten)]
+        read: ReadArgs,
+        /// the label of the frames, from the fields `{name}`, `{filename}`, `{short_filename}`, `{module}` and `{line}`
+        #[arg(long, default_value = export::DEFAULT_FRAME_FORMAT)]
+        frame_format: FrameFormat,
+        /// collapse consecutive calls of the same function into one frame annotated with the recursion depth
+        #[arg(long)]
+        collapse_recursion: bool,
+    },
+    /// Exports the resources of every sample to a Parquet file, e.g. to query many runs with DuckDB
+    Parquet {
+        /// The directory containing the profile data
+        output_dir: PathBuf,
+        /// The output file to write the Parquet table to
+        output_file: PathBuf,
+        #[command(flatten)]
+        read: ReadArgs,
     },
 }
 
+/// Options shared by all exporters, selecting which samples are exported
+#[derive(clap::Args, Debug)]
+struct ReadArgs {
+    /// only keep every nth sample of each process
+    #[arg(long, default_value = "1")]
+    every_nth: NonZeroUsize,
+    /// only export samples taken at least this many ms after the capture started
+    #[arg(long)]
+    start_ms: Option<u64>,
+    /// only export samples taken at most this many ms after the capture started
+    #[arg(long)]
+    end_ms: Option<u64>,
+    /// skip report files that can not be read, e.g. truncated by a crash, instead of failing
+    #[arg(long)]
+    best_effort: bool,
+    /// only export the process with this pid, can be repeated
+    #[arg(long = "only-pid", value_name = "PID")]
+    only_pids: Vec<u32>,
+    /// drop the local variables of the frames
+    #[arg(long)]
+    strip_locals: bool,
+    /// drop the process info of the stacktraces
+    #[arg(long)]
+    strip_process_info: bool,
+    /// only export the samples of a process in which a thread has a frame whose name contains SUBSTRING
+    #[arg(long, value_name = "SUBSTRING")]
+    containing_frame: Option<String>,
+}
+
+/// Options shared by the commands hosting the viewer
+#[derive(clap::Args, Debug)]
+struct ServeArgs {
+    /// The port to listen on
+    #[arg(long, default_value = "3000")]
+    port: u16,
+    /// The interface to listen on, an IP address or the name of a network interface like
+    /// `tailscale0`. `0.0.0.0` makes the profile data accessible to everyone on the network
+    #[arg(long, default_value = "127.0.0.1")]
+    interface: String,
+    /// Serve the frontend from this directory instead of the bundled one
+    #[arg(long)]
+    frontend_dir: Option<PathBuf>,
+    /// Listen on this unix socket instead of a TCP port
+    #[arg(long, conflicts_with_all = ["port", "interface"])]
+    unix_socket: Option<PathBuf>,
+    /// Serve over HTTPS with this PEM certificate (chain), requires `--tls-key`
+    #[arg(long, requires = "tls_key", conflicts_with = "unix_socket")]
+    tls_cert: Option<PathBuf>,
+    /// The PEM private key of the `--tls-cert` certificate
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    /// Require HTTP basic authentication with this user, requires `--auth-password`
+    #[arg(long, requires = "auth_password")]
+    auth_user: Option<String>,
+    /// The password of the `--auth-user`
+    #[arg(long, requires = "auth_user")]
+    auth_password: Option<String>,
+}
+
+impl From<ServeArgs> for ViewOptions {
+    fn from(args: ServeArgs) -> Self {
+        Self {
+            interface: args.interface,
+            port: args.port,
+            frontend_dir: args.frontend_dir,
+            unix_socket: args.unix_socket,
+            tls: args
+                .tls_cert
+                .zip(args.tls_key)
+                .map(|(cert, key)| TlsFiles { cert, key }),
+            auth: args
+                .auth_user
+                .zip(args.auth_password)
+                .map(|(user, password)| BasicAuth { user, password }),
+        }
+    }
+}
+
+impl From<ReadArgs> for export::ReadOptions {
+    fn from(args: ReadArgs) -> Self {
+        Self {
+            every_nth: args.every_nth,
+            start_millis: args.start_ms,
+            end_millis: args.end_ms,
+            best_effort: args.best_effort,
+            only_pids: args.only_pids,
+            strip: export::Strip {
+                locals: args.strip_locals,
+                process_info: args.strip_process_info,
+            },
+            containing_frame: args.containing_frame,
+        }
+    }
+}
+
 #[derive(Debug, Snafu)]
 enum ApplicationError {
     #[snafu(display("Error running tracker at {location}"))]
@@ -111,6 +594,14 @@ enum ApplicationError {
         #[snafu(implicit)]
         location: Location,
     },
+    #[snafu(display(
+        "The profiled command exited right after starting with {status}, see its output above"
+    ))]
+    ChildExitedImmediately {
+        status: ExitStatus,
+        #[snafu(implicit)]
+        location: Location,
+    },
     #[snafu(display("Error setting up webserver runtime at {location}"))]
     TokioInit {
         source: tokio::io::Error,
@@ -123,6 +614,29 @@ enum ApplicationError {
         #[snafu(implicit)]
         location: Location,
     },
+    #[snafu(display("Error reading config file `{path}` at {location}"))]
+    ReadConfig {
+        source: std::io::Error,
+        path: String,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display("Error parsing config file `{path}` at {location}"))]
+    ParseConfig {
+        source: toml::de::Error,
+        path: String,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display(
+        "Error moving the files from `{path}` into the output directory at {location}"
+    ))]
+    MoveStagedFiles {
+        source: std::io::Error,
+        path: String,
+        #[snafu(implicit)]
+        location: Location,
+    },
     #[snafu(display("Error creating data directory at {location}"))]
     DataDirCreate {
         source: std::io::Error,
@@ -146,11 +660,65 @@ enum ApplicationError {
         #[snafu(implicit)]
         location: Location,
     },
+    #[snafu(display("Could not capture any stacktraces of the processes {pids:?} at {location}"))]
+    NoStacktracesCaptured {
+        pids: Vec<u32>,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display("No stacktraces of the processes {pids:?} were captured at {location}"))]
+    NoSamplesWritten {
+        pids: Vec<u32>,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display(
+        "The minimum sample rate {min}ms must not be larger than the maximum {max}ms at {location}"
+    ))]
+    InvalidSampleRateBounds {
+        min: u64,
+        max: u64,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display("Native frames can not be captured with `--blocking off` at {location}"))]
+    NativeNonBlocking {
+        #[snafu(implicit)]
+        location: Location,
+    },
     #[snafu(display("This binary is missing support for unwinding native frames {location}"))]
     MissingUnwindSupport {
         #[snafu(implicit)]
         location: Location,
     },
+    #[snafu(display(
+        "Process {pid} does not look like a Python process, pass `--force` if it embeds an interpreter at {location}"
+    ))]
+    NotAPythonProcess {
+        pid: u32,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display("Error drawing the live view at {location}"))]
+    LiveView {
+        source: std::io::Error,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display(
+        "Output directory `{path}` vanished and could not be recreated at {location}"
+    ))]
+    OutputDirLost {
+        path: String,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display("Could not capture native stacktraces of process {pid} at {location}"))]
+    NativeCaptureFailed {
+        pid: u32,
+        #[snafu(implicit)]
+        location: Location,
+    },
     #[snafu(display("Error starting target process `{command:?}` at {location}"))]
     TargetCommandStart {
         source: std::io::Error,
@@ -164,6 +732,48 @@ enum ApplicationError {
         #[snafu(implicit)]
         location: Location,
     },
+    #[snafu(display("Error comparing profiles at {location}"))]
+    Diff {
+        source: diff::DiffError,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display("Error printing stats at {location}"))]
+    Stats {
+        source: stats::StatsError,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display("Error verifying profile data at {location}"))]
+    Verify {
+        source: manifest::ManifestError,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display("Error linting profile data at {location}"))]
+    Lint {
+        source: lint::LintError,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display("Error importing speedscope file at {location}"))]
+    Import {
+        source: speedscope::ImportError,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display("Error converting profile data at {location}"))]
+    Convert {
+        source: convert::ConvertError,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display("Error taking snapshot at {location}"))]
+    Snapshot {
+        source: snapshot::SnapshotError,
+        #[snafu(implicit)]
+        location: Location,
+    },
     #[cfg(target_os = "macos")]
     #[snafu(display(
         "Insufficient permissions on macOS. Please restart the program using `sudo {program_command}`"
@@ -173,39 +783,333 @@ enum ApplicationError {
 
 #[snafu::report]
 fn main() -> Result<(), ApplicationError> {
-    env_logger::init_from_env(
-        env_logger::Env::default().filter_or("RUST_LOG", "py_crude_resource_monitor=info"),
-    );
-
-    let args = Args::parse();
+    let (arguments, config_warnings) = apply_config_file(env::args_os().collect())?;
+    let matches = Args::command().get_matches_from(arguments);
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    init_logging(args.log_format, args.quiet, args.verbose);
+    for warning in config_warnings {
+        warn!("{warning}");
+    }
+    if let Some(profile) = matches
+        .subcommand_matches("profile")
+        .filter(|it| it.get_flag("print_config"))
+    {
+        print_config(profile);
+        return Ok(());
+    }
 
     let res = match args.command {
         Subcommands::Profile {
             pid,
             output_dir,
+            force,
+            keep,
+            output_to_tmp,
+            output_name_template,
             sample_rate,
+            sample_rate_micros,
+            interval_jitter,
+            max_speedup,
+            target_samples,
+            duration,
+            child_grace_ms,
+            ring_buffer,
+            warmup,
             native,
+            no_native_fallback,
+            compress_output,
+            output_format,
+            on_cpu_only,
+            main_thread_only,
+            follow_exec,
+            reattach,
+            max_reattach,
+            hide_command_lines,
+            redact_args,
+            exclude,
+            no_global,
+            track_self,
+            json_pretty,
+            stdout,
+            single_file,
+            embed_metadata,
+            max_threads_recorded,
+            max_concurrent_samples,
+            sample_timeout_ms,
+            rate_limit_log,
+            write_retries,
+            writer_batch,
+            max_output_size,
+            max_file_age,
+            max_cpu_percent,
+            cpu_decimals,
+            cpu_normalize,
+            blocking,
+            adaptive_rate,
+            min_sample_rate,
+            max_sample_rate,
+            auto_rate,
+            auto_rate_samples,
+            dry_run,
+            fail_on_no_samples,
+            print_path,
+            tui,
+            label,
+            config: _,
+            print_config: _,
             command,
-        } => run_profile(pid, command, output_dir, sample_rate, native)?,
+        } => {
+            check_python_processes(&pid, force)?;
+            // Logs go to stderr, so the path is all a wrapper script captures
+            if print_path {
+                println!(
+                    "{}",
+                    std::path::absolute(&output_dir)
+                        .context(DataDirCreateSnafu)?
+                        .display()
+                );
+            }
+            let interval = match sample_rate_micros {
+                Some(micros) => Duration::from_micros(micros),
+                None => Duration::from_millis(sample_rate.unwrap_or(1000)),
+            };
+            // Sub-millisecond rates can not be represented in the metadata
+            let fixed_rate = !adaptive_rate && !auto_rate;
+            let sample_interval_millis = (fixed_rate && interval.subsec_micros() % 1000 == 0)
+                .then(|| interval.as_millis() as u64);
+            let result = run_profile(
+                pid,
+                command,
+                OutputDir {
+                    path: output_dir,
+                    keep,
+                    via_tmp: output_to_tmp,
+                },
+                dry_run,
+                Schedule {
+                    sample_rate: if adaptive_rate {
+                        SampleRate::Adaptive {
+                            min_millis: min_sample_rate,
+                            max_millis: max_sample_rate,
+                        }
+                    } else if auto_rate {
+                        SampleRate::Auto {
+                            total_samples: auto_rate_samples.max(1),
+                            started: Instant::now(),
+                        }
+                    } else {
+                        SampleRate::Fixed(interval)
+                    },
+                    interval_jitter,
+                    max_speedup_doublings: max_speedup.get().ilog2(),
+                    target_samples,
+                    duration: duration.map(Duration::from_secs),
+                    fail_on_no_samples,
+                    reattach: reattach.map(|process_name| Reattach {
+                        process_name,
+                        max_count: max_reattach,
+                    }),
+                },
+                Duration::from_millis(child_grace_ms),
+                TrackerOptions {
+                    capture_native: native,
+                    compress_output,
+                    output_format,
+                    on_cpu_only,
+                    main_thread_only,
+                    follow_exec,
+                    hide_command_lines,
+                    redact_args,
+                    exclude,
+                    skip_global: no_global,
+                    track_self,
+                    pretty_json: json_pretty,
+                    stream_to_stdout: stdout,
+                    single_file,
+                    embed_metadata,
+                    sample_interval_millis,
+                    max_threads_recorded,
+                    max_concurrent_samples: max_concurrent_samples.map(NonZeroUsize::get),
+                    sample_timeout: Some(Duration::from_millis(sample_timeout_ms)),
+                    rate_limit_log,
+                    write_retries,
+                    writer_batch,
+                    max_output_size,
+                    max_file_age: max_file_age.map(|age| Duration::from_secs(age.get())),
+                    max_cpu_percent,
+                    cpu_decimals: Some(cpu_decimals),
+                    cpu_normalization: cpu_normalize,
+                    blocking,
+                    ring_buffer: ring_buffer.map(Duration::from_secs),
+                    no_native_fallback,
+                    live_view: tui,
+                    warmup: Some(Duration::from_secs(warmup)),
+                    output_name: output_name_template,
+                    labels: label.into_iter().collect(),
+                },
+            );
+            match result {
+                Err(e @ ApplicationError::NoSamplesWritten { .. }) => {
+                    error!("{e}");
+                    std::process::exit(NO_SAMPLES_EXIT_CODE);
+                }
+                result => result?,
+            }
+        }
         Subcommands::View {
             output_dir,
-            interface,
-            port,
-        } => run_view(output_dir, &interface, port).map(|_| None)?,
+            serve,
+            export_on_exit,
+        } => run_view(output_dir, serve.into(), export_on_exit.as_deref()).map(|_| None)?,
+        Subcommands::Replay { html_file, serve } => {
+            run_replay(&html_file, serve.into()).map(|_| None)?
+        }
         Subcommands::Export { export_subcommand } => match export_subcommand {
             ExportSubcommand::Html {
                 output_dir,
                 output_file,
-            } => export::export_html(&output_dir, &output_file)
+                read,
+                compression,
+            } => export::export_html(&output_dir, &output_file, &read.into(), compression)
                 .context(ExportSnafu)
                 .map(|_| None)?,
             ExportSubcommand::Firefox {
                 output_dir,
                 output_file,
-            } => export::export_firefox(&output_dir, &output_file)
+                read,
+                compression,
+                merge_by_thread_name,
+                python_color,
+                native_color,
+                memory_color,
+                io_color,
+                threads,
+                frame_format,
+                coalesce,
+                process_tree,
+                select_threads_by_cpu,
+                profile_name,
+                trim_idle,
+                collapse_recursion,
+                mark_idle,
+                group_threads,
+            } => export::export_firefox(
+                &output_dir,
+                &output_file,
+                &read.into(),
+                &FirefoxOptions {
+                    compression,
+                    merge_by_thread_name,
+                    colors: FirefoxColors {
+                        python: python_color,
+                        native: native_color,
+                        memory: memory_color,
+                        io: io_color,
+                    },
+                    threads,
+                    frame_format,
+                    coalesce,
+                    process_tree,
+                    select_threads_by_cpu,
+                    profile_name,
+                    trim_idle,
+                    collapse_recursion,
+                    mark_idle,
+                    group_threads,
+                },
+            )
+            .context(ExportSnafu)
+            .map(|_| None)?,
+            ExportSubcommand::Chrome {
+                output_dir,
+                output_file,
+                read,
+                frame_format,
+                collapse_recursion,
+            } => export::export_chrome(
+                &output_dir,
+                &output_file,
+                &read.into(),
+                &frame_format,
+                collapse_recursion,
+            )
+            .context(ExportSnafu)
+            .map(|_| None)?,
+            ExportSubcommand::Parquet {
+                output_dir,
+                output_file,
+                read,
+            } => export::export_parquet(&output_dir, &output_file, &read.into())
                 .context(ExportSnafu)
                 .map(|_| None)?,
         },
+        Subcommands::Diff {
+            baseline,
+            candidate,
+            format,
+            limit,
+        } => diff::run_diff(&baseline, &candidate, format, limit)
+            .context(DiffSnafu)
+            .map(|_| None)?,
+        Subcommands::Stats {
+            output_dir,
+            top,
+            frame_format,
+        } => stats::run_stats(&output_dir, top, &frame_format)
+            .context(StatsSnafu)
+            .map(|_| None)?,
+        Subcommands::Verify { output_dir } => manifest::run_verify(&output_dir)
+            .context(VerifySnafu)
+            .map(|_| None)?,
+        Subcommands::Lint { output_dir } => lint::run_lint(&output_dir)
+            .context(LintSnafu)
+            .map(|_| None)?,
+        Subcommands::Import {
+            speedscope,
+            output_dir,
+            sample_rate,
+        } => speedscope::run_import(&speedscope, &output_dir, sample_rate)
+            .context(ImportSnafu)
+            .map(|_| None)?,
+        Subcommands::Convert {
+            input_dir,
+            output_dir,
+            to,
+            strip_locals,
+            strip_process_info,
+        } => convert::run_convert(
+            &input_dir,
+            &output_dir,
+            to,
+            export::Strip {
+                locals: strip_locals,
+                process_info: strip_process_info,
+            },
+        )
+        .context(ConvertSnafu)
+        .map(|_| None)?,
+        Subcommands::Benchmark {
+            sample_rate,
+            duration,
+            python,
+        } => run_benchmark(sample_rate, Duration::from_secs(duration), python).map(|_| None)?,
+        Subcommands::Snapshot { pid, native, json } => {
+            if native && !cfg!(feature = "unwind") {
+                error!("This binary was compiled without support for capturing native stacktraces");
+                return Err(MissingUnwindSupportSnafu.into_error(NoneError));
+            }
+            let options = TrackerOptions {
+                capture_native: native,
+                ..Default::default()
+            };
+            snapshot::run_snapshot(pid, &options, json)
+                .context(SnapshotSnafu)
+                .map(|_| None)?
+        }
+        Subcommands::ListProcesses { python_only } => {
+            processes::run_list_processes(python_only);
+            None
+        }
     };
 
     if let Some(exit_status) = res {
@@ -237,13 +1141,172 @@ fn main() -> Result<(), ApplicationError> {
     Ok(())
 }
 
-fn run_profile(
-    pid: Option<u32>,
-    command: Option<Vec<String>>,
-    output_dir: PathBuf,
-    sample_rate: Option<u64>,
-    native: bool,
-) -> Result<Option<ExitStatus>, ApplicationError> {
+/// How long to wait between two samples
+#[derive(Debug, Clone, Copy)]
+enum SampleRate {
+    /// A fixed time between samples
+    Fixed(Duration),
+    /// Sample busy processes more often, interpolating linearly between the bounds in ms
+    Adaptive { min_millis: u64, max_millis: u64 },
+    /// Aim for a number of samples over the lifetime of a command, which is unknown upfront
+    Auto {
+        total_samples: u32,
+        started: Instant,
+    },
+}
+
+impl SampleRate {
+    /// The time to sleep until the next sample, given the CPU usage of the last one
+    fn sleep_duration(&self, cpu_usage: f32) -> Duration {
+        match *self {
+            Self::Fixed(interval) => interval,
+            Self::Adaptive {
+                min_millis,
+                max_millis,
+            } => {
+                // 100% is one core, so a single busy thread already gets the highest rate
+                let busyness = (cpu_usage / 100.0).clamp(0.0, 1.0) as f64;
+                let millis = max_millis as f64 - (max_millis - min_millis) as f64 * busyness;
+                Duration::from_millis(millis as u64)
+            }
+            Self::Auto {
+                total_samples,
+                started,
+            } => {
+                let elapsed = started.elapsed();
+                if elapsed < AUTO_RATE_PROBE_PERIOD {
+                    return AUTO_RATE_MIN_INTERVAL;
+                }
+                // A command that ran this long is expected to run about as long again
+                (elapsed * 2 / total_samples).clamp(AUTO_RATE_MIN_INTERVAL, AUTO_RATE_MAX_INTERVAL)
+            }
+        }
+    }
+}
+
+/// Where `run_profile` writes its samples
+#[derive(Debug)]
+struct OutputDir {
+    path: PathBuf,
+    /// The files of earlier runs matching these are not deleted
+    keep: Vec<glob::Pattern>,
+    /// Write to a staging directory first and move the files over once profiling succeeded. The
+    /// files of the earlier run are only deleted then.
+    via_tmp: bool,
+}
+
+/// When `run_profile` takes samples and when it stops
+#[derive(Debug, Clone)]
+struct Schedule {
+    sample_rate: SampleRate,
+    /// Randomly vary the time between samples by up to this many percent
+    interval_jitter: Option<f64>,
+    /// How often SIGUSR2 may halve the time between samples, see [`SignalControls`]
+    max_speedup_doublings: u32,
+    /// Stop once this many stacktraces were captured
+    target_samples: Option<u64>,
+    /// Stop once this much time passed since profiling started
+    duration: Option<Duration>,
+    /// Fail with [`NO_SAMPLES_EXIT_CODE`] if no stacktrace was captured by the end
+    fail_on_no_samples: bool,
+    reattach: Option<Reattach>,
+}
+
+/// Waiting for a restarted process once all tracked processes exited, see `--reattach`
+#[derive(Debug, Clone)]
+struct Reattach {
+    process_name: String,
+    /// How often to re-attach at most, unlimited if not set
+    max_count: Option<u32>,
+}
+
+/// How often `--reattach` looks for a new process with the name
+const REATTACH_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Waits for processes with the name to show up. Returns `None` if quitting was requested or the
+/// deadline passed first.
+fn wait_for_processes(
+    process_name: &str,
+    quit_requested: &AtomicBool,
+    deadline: Option<Instant>,
+) -> Option<Vec<u32>> {
+    loop {
+        let pids = processes::processes_named(process_name);
+        if !pids.is_empty() {
+            return Some(pids);
+        }
+        if quit_requested.load(Ordering::Acquire) || deadline.is_some_and(|it| Instant::now() >= it)
+        {
+            return None;
+        }
+        thread::sleep(REATTACH_POLL_INTERVAL);
+    }
+}
+
+/// The exit code of `--fail-on-no-samples`, distinct from errors and the exit codes of most
+/// profiled commands
+const NO_SAMPLES_EXIT_CODE: i32 = 3;
+
+/// Randomly lengthens or shortens the time between samples by up to the given percentage. The
+/// samples record when they were actually taken, so the exporters are not affected.
+fn jitter(duration: Duration, percent: f64) -> Duration {
+    let factor = 1.0 + (fastrand::f64() * 2.0 - 1.0) * percent / 100.0;
+    duration.mul_f64(factor.max(0.0))
+}
+
+/// How long `--auto-rate` samples as fast as possible, as most short scripts end within it
+const AUTO_RATE_PROBE_PERIOD: Duration = Duration::from_secs(1);
+
+/// The bounds of the time between samples with `--auto-rate`; the upper one is the default rate
+const AUTO_RATE_MIN_INTERVAL: Duration = Duration::from_millis(10);
+const AUTO_RATE_MAX_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long to wait for a spawned command to fail before attaching to it
+const CHILD_STARTUP_GRACE_PERIOD: Duration = Duration::from_millis(100);
+
+/// How often to check whether a spawned command exited after asking it to
+const CHILD_EXIT_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+/// A second CTRL+C within this time after the first one exits immediately
+const FORCE_QUIT_WINDOW: Duration = Duration::from_secs(3);
+
+/// Above this share of failed samples the profile is likely not useful
+const HIGH_SAMPLE_FAILURE_RATE: f64 = 0.1;
+
+/// Logs how many samples failed. Many failures usually mean an unsupported interpreter version or
+/// missing permissions.
+fn log_sample_errors(tracker: &Tracker) {
+    let summary = tracker.sample_error_summary();
+    let failed = summary.failed();
+    if failed == 0 {
+        return;
+    }
+    if failed as f64 / summary.attempted() as f64 > HIGH_SAMPLE_FAILURE_RATE {
+        warn!("{summary}");
+        warn!("Check that the Python version is supported and the permissions suffice");
+    } else {
+        info!("{summary}");
+    }
+}
+
+/// Logs how long the time between samples actually was, and warns if sampling could not keep up
+/// with the requested rate.
+fn log_sample_interval(tracker: &Tracker, requested_millis: f64) {
+    let Some((mean, stddev)) = tracker.tick_interval_stats() else {
+        return;
+    };
+    info!("Effective sample interval: {mean:.1}ms (stddev {stddev:.1}ms)");
+
+    // Sampling itself always takes a bit of time, so only warn about significant deviations
+    if mean > requested_millis * 1.25 {
+        warn!(
+            "Samples were taken every {mean:.1}ms on average, but {requested_millis:.1}ms were \
+             requested. Sampling can not keep up, consider a larger `--sample-rate`"
+        );
+    }
+}
+
+fn check_permissions() -> Result<(), ApplicationError> {
     #[cfg(target_os = "macos")]
     {
         // On macOS, we need to be root to profile processes
@@ -255,51 +1318,613 @@ fn run_profile(
             .into_error(NoneError));
         }
     }
+    Ok(())
+}
+
+/// Spends as much time in Python code as possible, with a few frames on the stack.
+const BENCHMARK_SCRIPT: &str = "\
+def work():
+    return sum(i * i for i in range(10_000))
+
+while True:
+    work()
+";
+
+/// Profiles a busy loop in a temporary directory and prints how much CPU time the profiler used
+/// compared to the target, and how regularly it managed to sample.
+fn run_benchmark(
+    sample_rate_millis: u64,
+    duration: Duration,
+    python: String,
+) -> Result<(), ApplicationError> {
+    check_permissions()?;
+
+    let output_dir = temporary_output_dir("benchmark")?;
+
+    let command = vec![python, "-c".to_string(), BENCHMARK_SCRIPT.to_string()];
+    // The busy loop has nothing to clean up, so it is killed right away
+    let (target_pid, child) = start_profiling_target(command.clone(), command, Duration::ZERO)?;
+    let own_pid = std::process::id();
+
+    let mut tracker = Tracker::new_with_retry(
+        &[target_pid],
+        output_dir.clone(),
+        &TrackerOptions::default(),
+    )
+    .context(TrackerSnafu)?;
+    let mut system = sysinfo::System::new();
+    let own_cpu_at_start = cpu_time_millis(&mut system, own_pid).unwrap_or(0);
+    let target_cpu_at_start = cpu_time_millis(&mut system, target_pid).unwrap_or(0);
+
+    info!("Profiling a busy loop for {}s", duration.as_secs());
+    let start = Instant::now();
+    while start.elapsed() < duration && tracker.is_still_tracking() {
+        tracker.tick();
+        thread::sleep(Duration::from_millis(sample_rate_millis));
+    }
+    let elapsed_millis = start.elapsed().as_millis() as f64;
+    let own_cpu = cpu_time_millis(&mut system, own_pid)
+        .unwrap_or(0)
+        .saturating_sub(own_cpu_at_start);
+    let target_cpu = cpu_time_millis(&mut system, target_pid)
+        .unwrap_or(0)
+        .saturating_sub(target_cpu_at_start);
+    let interval = tracker.tick_interval_stats();
+
+    tracker.finish();
+    drop(child);
+    remove_temporary_output_dir(&output_dir);
+
+    println!(
+        "Profiler CPU time: {:.2}s ({:.1}% of one core)",
+        own_cpu as f64 / 1000.0,
+        own_cpu as f64 / elapsed_millis * 100.0
+    );
+    println!(
+        "Target CPU time:   {:.2}s ({:.1}% of one core)",
+        target_cpu as f64 / 1000.0,
+        target_cpu as f64 / elapsed_millis * 100.0
+    );
+    if target_cpu > 0 {
+        println!(
+            "Overhead:          {:.1}% of the target's CPU time",
+            own_cpu as f64 / target_cpu as f64 * 100.0
+        );
+    }
+    if let Some((mean, stddev)) = interval {
+        println!(
+            "Sample interval:   {mean:.1}ms (stddev {stddev:.1}ms), {sample_rate_millis}ms requested"
+        );
+    }
+
+    Ok(())
+}
+
+/// Fails early for pids that are not Python processes, as py-spy only does so after retrying to
+/// attach for a while. With `force`, this is just a warning.
+fn check_python_processes(pids: &[u32], force: bool) -> Result<(), ApplicationError> {
+    for &pid in pids {
+        if processes::is_python_process(pid) {
+            continue;
+        }
+        ensure!(force, NotAPythonProcessSnafu { pid });
+        warn!("Process {pid} does not look like a Python process, attaching might fail");
+    }
+    Ok(())
+}
+
+/// Attaches to the processes and takes a single sample into a temporary directory, failing if
+/// no stacktraces could be captured.
+fn run_dry_run(
+    pids: Vec<u32>,
+    command: Option<Vec<String>>,
+    child_grace: Duration,
+    tracker_options: &TrackerOptions,
+) -> Result<(), ApplicationError> {
+    let output_dir = temporary_output_dir("dry-run")?;
+    let (pids, child) = start_profiling_target_if_necessary(
+        pids,
+        command,
+        tracker_options.redact_args.as_ref(),
+        child_grace,
+    )?;
+
+    let stacktrace_count = Tracker::new_with_retry(&pids, output_dir.clone(), tracker_options)
+        .context(TrackerSnafu)
+        .map(|mut tracker| {
+            tracker.tick();
+            let count = tracker.last_stacktrace_count();
+            tracker.finish();
+            count
+        });
+    drop(child);
+    remove_temporary_output_dir(&output_dir);
+
+    let stacktrace_count = stacktrace_count?;
+    ensure!(stacktrace_count > 0, NoStacktracesCapturedSnafu { pids });
+    info!("Dry run succeeded, captured {stacktrace_count} stacktraces of processes {pids:?}");
+    Ok(())
+}
+
+/// Parses a `KEY=VALUE` label of `--label`.
+fn parse_label(label: &str) -> Result<(String, String), String> {
+    match label.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("expected KEY=VALUE, got `{label}`")),
+    }
+}
+
+/// The options of `profile` that select what to profile, of which the command line and the config
+/// file may only set one
+const TARGET_OPTIONS: [&str; 3] = ["pid", "command", "reattach"];
+
+/// The options of `profile` that make no sense in a config file
+const NON_CONFIG_OPTIONS: [&str; 2] = ["config", "print_config"];
+
+/// Adds the options of the `--config` file of `profile` to the command line, if there is one.
+/// Returns the new command line and warnings about the keys of the file that were skipped.
+fn apply_config_file(
+    mut arguments: Vec<OsString>,
+) -> Result<(Vec<OsString>, Vec<String>), ApplicationError> {
+    // The file might set required options, so missing ones must not fail this first parse
+    let Ok(matches) = Args::command()
+        .ignore_errors(true)
+        .try_get_matches_from(&arguments)
+    else {
+        return Ok((arguments, Vec::new()));
+    };
+    let Some(profile) = matches.subcommand_matches("profile") else {
+        return Ok((arguments, Vec::new()));
+    };
+    let Some(config_path) = profile.get_one::<PathBuf>("config") else {
+        return Ok((arguments, Vec::new()));
+    };
 
-    if native && !cfg!(feature = "unwind") {
+    let path = config_path.display().to_string();
+    let content = std::fs::read_to_string(config_path).context(ReadConfigSnafu { path: &path })?;
+    let config = content
+        .parse::<toml::Table>()
+        .context(ParseConfigSnafu { path })?;
+
+    let command = Args::command();
+    let profile_command = command
+        .find_subcommand("profile")
+        .expect("profile is a subcommand");
+    let given = |id: &str| profile.value_source(id) == Some(ValueSource::CommandLine);
+    let target_given = TARGET_OPTIONS.iter().any(|id| given(id));
+
+    let mut options = Vec::new();
+    let mut target_command = Vec::new();
+    let mut warnings = Vec::new();
+    for (key, value) in config {
+        let id = key.replace('-', "_");
+        let arg = profile_command
+            .get_arguments()
+            .find(|arg| arg.get_id() == id.as_str())
+            .filter(|_| !NON_CONFIG_OPTIONS.contains(&id.as_str()));
+        let Some(arg) = arg else {
+            warnings.push(format!("Ignoring unknown key `{key}` of the config file"));
+            continue;
+        };
+        if given(&id) || (target_given && TARGET_OPTIONS.contains(&id.as_str())) {
+            continue;
+        }
+        let values = match config_values(value) {
+            Ok(values) => values,
+            Err(kind) => {
+                warnings.push(format!(
+                    "Ignoring key `{key}` of the config file, it can not be a {kind}"
+                ));
+                continue;
+            }
+        };
+        match arg.get_long() {
+            Some(long) => {
+                for value in values {
+                    options.push(OsString::from(format!("--{long}")));
+                    options.extend(value.map(OsString::from));
+                }
+            }
+            // The command is the only positional argument
+            None => target_command.extend(values.into_iter().flatten().map(OsString::from)),
+        }
+    }
+
+    let subcommand = arguments
+        .iter()
+        .position(|it| it == "profile")
+        .expect("profile was parsed");
+    arguments.splice(subcommand + 1..subcommand + 1, options);
+    if !target_command.is_empty() {
+        arguments.push(OsString::from("--"));
+        arguments.extend(target_command);
+    }
+    Ok((arguments, warnings))
+}
+
+/// The values a key of a config file stands for, one per occurrence of the option. `None` is a
+/// flag without a value. Fails with the kind of the value if it can not be an option.
+fn config_values(value: toml::Value) -> Result<Vec<Option<String>>, &'static str> {
+    let scalar = |value: toml::Value| match value {
+        toml::Value::String(it) => Ok(it),
+        toml::Value::Integer(it) => Ok(it.to_string()),
+        toml::Value::Float(it) => Ok(it.to_string()),
+        other => Err(other.type_str()),
+    };
+    match value {
+        toml::Value::Boolean(true) => Ok(vec![None]),
+        toml::Value::Boolean(false) => Ok(Vec::new()),
+        toml::Value::Array(items) => items.into_iter().map(|it| scalar(it).map(Some)).collect(),
+        // Labels can be given as a table instead of `KEY=VALUE` strings
+        toml::Value::Table(table) => table
+            .into_iter()
+            .map(|(key, value)| scalar(value).map(|value| Some(format!("{key}={value}"))))
+            .collect(),
+        other => scalar(other).map(|it| vec![Some(it)]),
+    }
+}
+
+/// Prints the resolved options of `profile` as a config file for `--config`, including the defaults.
+fn print_config(profile: &ArgMatches) {
+    let command = Args::command();
+    let profile_command = command
+        .find_subcommand("profile")
+        .expect("profile is a subcommand");
+    let mut config = toml::Table::new();
+    for arg in profile_command.get_arguments() {
+        let id = arg.get_id().as_str();
+        if NON_CONFIG_OPTIONS.contains(&id) {
+            continue;
+        }
+        let value = match arg.get_action() {
+            ArgAction::SetTrue => toml::Value::Boolean(profile.get_flag(id)),
+            action => {
+                let Some(raw) = profile.get_raw(id) else {
+                    continue;
+                };
+                let mut values = raw
+                    .map(|it| {
+                        let it = it.to_string_lossy();
+                        it.parse::<i64>()
+                            .map(toml::Value::Integer)
+                            .unwrap_or_else(|_| toml::Value::String(it.to_string()))
+                    })
+                    .collect::<Vec<_>>();
+                match action {
+                    ArgAction::Append => toml::Value::Array(values),
+                    _ => values.remove(0),
+                }
+            }
+        };
+        config.insert(id.replace('_', "-"), value);
+    }
+    print!(
+        "{}",
+        toml::to_string(&config).expect("the config is serializable")
+    );
+}
+
+/// Creates a directory for samples that are thrown away afterwards.
+
+fn temporary_output_dir(purpose: &str) -> Result<PathBuf, ApplicationError> {
+    let output_dir = env::temp_dir().join(format!(
+        "py-crude-resource-monitor-{purpose}-{}",
+        std::process::id()
+    ));
+    std::fs::create_dir_all(&output_dir).context(DataDirCreateSnafu)?;
+    Ok(output_dir)
+}
+
+fn remove_temporary_output_dir(output_dir: &Path) {
+    if let Err(e) = std::fs::remove_dir_all(output_dir) {
+        warn!("Could not remove {}: {e}", output_dir.display());
+    }
+}
+
+/// The CPU time the process used since it started, in milliseconds.
+fn cpu_time_millis(system: &mut sysinfo::System, pid: u32) -> Option<u64> {
+    let pid = sysinfo::Pid::from_u32(pid);
+    system.refresh_processes_specifics(
+        sysinfo::ProcessesToUpdate::Some(&[pid]),
+        true,
+        sysinfo::ProcessRefreshKind::default().with_cpu(),
+    );
+    system
+        .process(pid)
+        .map(|process| process.accumulated_cpu_time())
+}
+
+fn run_profile(
+    pids: Vec<u32>,
+    command: Option<Vec<String>>,
+    output: OutputDir,
+    dry_run: bool,
+    schedule: Schedule,
+    child_grace: Duration,
+    tracker_options: TrackerOptions,
+) -> Result<Option<ExitStatus>, ApplicationError> {
+    check_permissions()?;
+    let OutputDir {
+        path: output_dir,
+        keep,
+        via_tmp,
+    } = output;
+    let Schedule {
+        sample_rate,
+        interval_jitter,
+        max_speedup_doublings,
+        target_samples,
+        duration,
+        fail_on_no_samples,
+        reattach,
+    } = schedule;
+
+    if tracker_options.capture_native && !cfg!(feature = "unwind") {
         error!("This binary was compiled without support for capturing native stacktraces");
         return Err(MissingUnwindSupportSnafu.into_error(NoneError));
     }
+    // Unwinding a running thread reads garbage, py-spy refuses this as well
+    if tracker_options.capture_native && tracker_options.blocking == BlockingMode::Off {
+        return Err(NativeNonBlockingSnafu.into_error(NoneError));
+    }
 
-    let sample_sleep_duration = Duration::from_millis(sample_rate.unwrap_or(1000));
+    if let SampleRate::Adaptive {
+        min_millis,
+        max_millis,
+    } = sample_rate
+    {
+        ensure!(
+            min_millis <= max_millis,
+            InvalidSampleRateBoundsSnafu {
+                min: min_millis,
+                max: max_millis
+            }
+        );
+    }
+
+    if dry_run {
+        return run_dry_run(pids, command, child_grace, &tracker_options).map(|_| None);
+    }
 
     std::fs::create_dir_all(&output_dir).context(DataDirCreateSnafu)?;
-    clear_data_dir(&output_dir)?;
+    let old_files = confirm_clear_data_dir(&output_dir, &keep)?;
+    // A crash leaves the staging directory behind instead of a half-written output directory, and
+    // the files of the earlier run are only deleted once the new one is moved into place
+    let write_dir = if via_tmp {
+        let staging = staging_dir(&output_dir).context(DataDirCreateSnafu)?;
+        std::fs::create_dir_all(&staging).context(DataDirCreateSnafu)?;
+        info!("Writing to {} until profiling finished", staging.display());
+        staging
+    } else {
+        remove_old_files(&old_files).context(DataDirClearIoSnafu)?;
+        output_dir.clone()
+    };
 
     let quit_requested = Arc::new(AtomicBool::new(false));
     let quit_requested_clone = quit_requested.clone();
-    if let Err(e) = ctrlc::set_handler(move || quit_requested_clone.store(true, Ordering::Release))
-    {
+    let mut first_press: Option<Instant> = None;
+    if let Err(e) = ctrlc::set_handler(move || {
+        if first_press.is_some_and(|it| it.elapsed() < FORCE_QUIT_WINDOW) {
+            warn!("Force-quitting, the output might be incomplete");
+            std::process::exit(130);
+        }
+        first_press = Some(Instant::now());
+        info!("Shutting down, press CTRL+C again to force-quit");
+        quit_requested_clone.store(true, Ordering::Release);
+    }) {
         warn!(
             "Could not register CTRL+C termination handler: {}",
             Report::from_error(e)
         );
     }
 
-    let (pid, _child) = start_profiling_target_if_necessary(pid, command)?;
-    info!("Monitoring process with PID {pid}");
+    let controls = Arc::new(SignalControls::new(max_speedup_doublings));
+    handle_control_signals(controls.clone());
+
+    let started = Instant::now();
+    let deadline = duration.map(|it| started + it);
+    let pids = match &reattach {
+        Some(reattach) if pids.is_empty() => {
+            info!("Waiting for a `{}` process", reattach.process_name);
+            match wait_for_processes(&reattach.process_name, &quit_requested, deadline) {
+                Some(pids) => pids,
+                None => {
+                    info!("No `{}` process showed up, exiting", reattach.process_name);
+                    return Ok(None);
+                }
+            }
+        }
+        _ => pids,
+    };
+    let (pids, _child) = start_profiling_target_if_necessary(
+        pids,
+        command,
+        tracker_options.redact_args.as_ref(),
+        child_grace,
+    )?;
+    info!("Monitoring processes with PIDs {pids:?}");
 
-    let mut tracker =
-        Tracker::new_with_retry(pid, output_dir.clone(), native).context(TrackerSnafu)?;
+    let mut tracker = Tracker::new_with_retry(&pids, write_dir.clone(), &tracker_options)
+        .context(TrackerSnafu)?;
     info!("Tracking started");
-    while tracker.is_still_tracking() && !quit_requested.load(Ordering::Acquire) {
-        tracker.tick();
-        thread::sleep(sample_sleep_duration);
+    // The sum and count of requested sleeps, to compare them against the achieved interval
+    let mut requested_millis = (0.0, 0);
+    let mut target_reached = false;
+    let mut duration_reached = false;
+    let mut missing_native = None;
+    let mut was_paused = false;
+    // The stacktraces captured by the trackers replaced when re-attaching
+    let mut earlier_stacktraces = 0;
+    let mut reattached = 0;
+    let mut live_view = if tracker_options.live_view {
+        Some(LiveView::start().context(LiveViewSnafu)?)
+    } else {
+        None
+    };
+    loop {
+        if !tracker.is_still_tracking() {
+            let Some(reattach) = &reattach else {
+                break;
+            };
+            if reattach.max_count.is_some_and(|max| reattached >= max) {
+                info!("Re-attached {reattached} times, not waiting for another process");
+                break;
+            }
+            info!(
+                "All processes have exited, waiting for a new `{}` process",
+                reattach.process_name
+            );
+            let Some(pids) = wait_for_processes(&reattach.process_name, &quit_requested, deadline)
+            else {
+                break;
+            };
+            let metadata = tracker.metadata().clone();
+            earlier_stacktraces += tracker.total_stacktrace_count();
+            log_sample_errors(&tracker);
+            tracker.finish();
+            // Reports of processes seen before are continued, so the output directory holds
+            // the whole run
+            tracker = Tracker::new_with_retry(&pids, write_dir.clone(), &tracker_options)
+                .context(TrackerSnafu)?;
+            tracker.continue_metadata(metadata);
+            reattached += 1;
+            info!("Re-attached to processes with PIDs {pids:?}");
+        }
+        if quit_requested.load(Ordering::Acquire) {
+            break;
+        }
+        let paused = controls.paused.load(Ordering::Acquire);
+        // Exports would show the pause as an unexplained gap otherwise
+        if paused != was_paused {
+            tracker.add_marker(if paused {
+                "Sampling paused"
+            } else {
+                "Sampling resumed"
+            });
+            was_paused = paused;
+        }
+        if !paused {
+            tracker.tick();
+        }
+        if let Some(view) = &mut live_view {
+            view.render(tracker.latest_samples())
+                .context(LiveViewSnafu)?;
+        }
+        if tracker_options.no_native_fallback {
+            missing_native = tracker.processes_missing_native_frames().first().copied();
+            if missing_native.is_some() {
+                break;
+            }
+        }
+        if tracker.writer_failed() {
+            break;
+        }
+        let stacktrace_count = earlier_stacktraces + tracker.total_stacktrace_count();
+        if target_samples.is_some_and(|target| stacktrace_count >= target) {
+            target_reached = true;
+            break;
+        }
+        if deadline.is_some_and(|it| Instant::now() >= it) {
+            duration_reached = true;
+            break;
+        }
+        let mut sleep_duration =
+            sample_rate.sleep_duration(tracker.last_cpu_usage()) / controls.speedup();
+        if let Some(percent) = interval_jitter {
+            sleep_duration = jitter(sleep_duration, percent);
+        }
+        requested_millis.0 += sleep_duration.as_secs_f64() * 1000.0;
+        requested_millis.1 += 1;
+        match &live_view {
+            Some(view) => {
+                if view.wait_for_quit(sleep_duration).context(LiveViewSnafu)? {
+                    quit_requested.store(true, Ordering::Release);
+                }
+            }
+            None => thread::sleep(sleep_duration),
+        }
+    }
+    // Restores the terminal, so the following log messages are shown
+    drop(live_view);
+    log_sample_interval(
+        &tracker,
+        requested_millis.0 / requested_millis.1.max(1) as f64,
+    );
+    if let SampleRate::Auto { .. } = sample_rate {
+        let interval = sample_rate.sleep_duration(0.0);
+        info!("Settled on sampling every {}ms", interval.as_millis());
+        tracker.record_chosen_sample_interval(interval);
+    }
+    log_sample_errors(&tracker);
+    let stopped_early =
+        quit_requested.load(Ordering::Acquire) || target_reached || duration_reached;
+    // The command exited on its own, so waiting for it returns right away
+    let exit_status = match &mut _child {
+        Some(child) if !stopped_early => Some(child.0.wait().context(ChildWaitSnafu)?),
+        _ => None,
+    };
+    if let Some(exit_code) = exit_status.and_then(shell_exit_code) {
+        tracker.record_exit_code(exit_code);
+    }
+    if tracker_options.ring_buffer.is_some() {
+        let crashed = match &mut _child {
+            Some(child) => child
+                .0
+                .try_wait()
+                .ok()
+                .flatten()
+                .is_some_and(|status| !status.success()),
+            None => false,
+        };
+        // CTRL+C dumps the buffer on demand
+        if crashed || quit_requested.load(Ordering::Acquire) {
+            tracker.flush_ring_buffer();
+        } else {
+            info!("Discarding the ring buffer, the profiled command did not crash");
+        }
+    }
+    let writer_failed = tracker.writer_failed();
+    let stacktrace_count = earlier_stacktraces + tracker.total_stacktrace_count();
+    tracker.finish();
+    if writer_failed {
+        error!("Stopped profiling as the output directory is gone");
+        let path = write_dir.display().to_string();
+        return Err(OutputDirLostSnafu { path }.into_error(NoneError));
+    }
+    if let Some(pid) = missing_native {
+        error!("Stopped profiling as process {pid} was sampled without native frames");
+        return Err(NativeCaptureFailedSnafu { pid }.into_error(NoneError));
+    }
+    ensure!(
+        !fail_on_no_samples || stacktrace_count > 0,
+        NoSamplesWrittenSnafu { pids }
+    );
+    if via_tmp {
+        move_staged_files(&write_dir, &output_dir, &old_files).context(MoveStagedFilesSnafu {
+            path: write_dir.display().to_string(),
+        })?;
     }
 
-    let exit_code = if quit_requested.load(Ordering::Acquire) {
-        info!("Termination requested, exiting");
+    if stopped_early {
+        if target_reached {
+            info!("Captured the target number of stacktraces, exiting");
+        } else if duration_reached {
+            info!("Profiled for the given duration, exiting");
+        } else {
+            info!("Termination requested, exiting");
+        }
         // Explicitly kill the child now
         drop(_child);
-        None
     } else {
         info!("All processes have exited, exiting");
-        if let Some(mut child) = _child {
-            Some(child.0.wait().context(ChildWaitSnafu)?)
-        } else {
-            None
+    }
+    if let Some(status) = exit_status {
+        match shell_exit_code(status) {
+            _ if status.success() => info!("The profiled command succeeded"),
+            Some(exit_code) => error!("The profiled command failed with exit code {exit_code}"),
+            None => error!("The profiled command failed: {status}"),
         }
-    };
+    }
 
     info!(
         "View the profile data by running `{} view {}`",
@@ -308,45 +1933,116 @@ fn run_profile(
             .unwrap_or("<this executable>".to_string()),
         output_dir.display()
     );
-    Ok(exit_code)
+    Ok(exit_status)
+}
+
+/// The directory `--output-to-tmp` writes to, next to the output directory so it is on the same
+/// filesystem and can usually be renamed into place.
+fn staging_dir(output_dir: &Path) -> std::io::Result<PathBuf> {
+    let output_dir = std::path::absolute(output_dir)?;
+    let name = output_dir
+        .file_name()
+        .map(|it| it.to_string_lossy().to_string())
+        .unwrap_or_default();
+    Ok(output_dir.with_file_name(format!(".{name}.partial-{}", std::process::id())))
+}
+
+/// Moves the files of a finished capture from the staging directory into the output directory,
+/// replacing the `old_files` of the earlier run. The whole directory is renamed if the output
+/// directory is empty then, files are copied if they can not be renamed across filesystems.
+fn move_staged_files(
+    staging: &Path,
+    output_dir: &Path,
+    old_files: &[PathBuf],
+) -> std::io::Result<()> {
+    remove_old_files(old_files)?;
+    // Only removes the output directory if it is empty, i.e. nothing was kept with `--keep`
+    if std::fs::remove_dir(output_dir).is_ok() {
+        if std::fs::rename(staging, output_dir).is_ok() {
+            return Ok(());
+        }
+        std::fs::create_dir_all(output_dir)?;
+    }
+    for entry in std::fs::read_dir(staging)? {
+        let entry = entry?;
+        let target = output_dir.join(entry.file_name());
+        if std::fs::rename(entry.path(), &target).is_err() {
+            std::fs::copy(entry.path(), &target)?;
+            std::fs::remove_file(entry.path())?;
+        }
+    }
+    std::fs::remove_dir(staging)
+}
+
+/// The exit code a shell would report for the status, i.e. 128 plus the signal that killed the
+/// process.
+fn shell_exit_code(status: ExitStatus) -> Option<i32> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() {
+            return Some(128 + signal);
+        }
+    }
+    status.code()
 }
 
 fn start_profiling_target_if_necessary(
-    pid: Option<u32>,
+    pids: Vec<u32>,
     command: Option<Vec<String>>,
-) -> Result<(u32, Option<KillOnDrop>), ApplicationError> {
-    // We are profiling an existing process by pid, so nothing to do here
-    if let Some(pid) = pid {
-        return Ok((pid, None));
+    redact_args: Option<&Regex>,
+    child_grace: Duration,
+) -> Result<(Vec<u32>, Option<KillOnDrop>), ApplicationError> {
+    // We are profiling existing processes by pid, so nothing to do here
+    if !pids.is_empty() {
+        return Ok((pids, None));
     }
 
     let command = command.expect("clap should enforce required pid/cmd");
     // We use the debug display here to correctly chunk arguments with spaces.
     // Alternatively, we would escape and quote these strings ourselves, to allow
     // copy-paste-able arguments.
-    info!("Starting process with command {command:?}");
+    let displayed_command = redact_command_line(&command, redact_args);
+    info!("Starting process with command {displayed_command:?}");
     info!("The output of the process will be displayed below, mixed with profiling log messages");
 
-    start_profiling_target(command)
+    let (pid, mut child) = start_profiling_target(command, displayed_command, child_grace)?;
+    // A command that fails right away, e.g. because of a typo, can not be attached to. Noticing it
+    // here is a lot clearer than failing to attach.
+    thread::sleep(CHILD_STARTUP_GRACE_PERIOD);
+    let exit_status = match &mut child {
+        Some(child) => child.0.try_wait().context(ChildWaitSnafu)?,
+        None => None,
+    };
+    if let Some(status) = exit_status {
+        return Err(ChildExitedImmediatelySnafu { status }.into_error(NoneError));
+    }
+    Ok((vec![pid], child))
 }
 
 #[cfg(not(target_os = "macos"))]
 fn start_profiling_target(
     command: Vec<String>,
+    displayed_command: Vec<String>,
+    child_grace: Duration,
 ) -> Result<(u32, Option<KillOnDrop>), ApplicationError> {
     let child = Command::new(&command[0])
         .args(&command[1..])
         .stderr(Stdio::inherit())
         .stdout(Stdio::inherit())
         .spawn()
-        .context(TargetCommandStartSnafu { command })?;
+        .context(TargetCommandStartSnafu {
+            command: displayed_command,
+        })?;
 
-    Ok((child.id(), Some(KillOnDrop(child))))
+    Ok((child.id(), Some(KillOnDrop(child, child_grace))))
 }
 
 #[cfg(target_os = "macos")]
 fn start_profiling_target(
     command: Vec<String>,
+    displayed_command: Vec<String>,
+    child_grace: Duration,
 ) -> Result<(u32, Option<KillOnDrop>), ApplicationError> {
     use std::os::unix::process::CommandExt;
     let child = {
@@ -370,28 +2066,45 @@ fn start_profiling_target(
             .uid(uid)
             .gid(gid)
             .spawn()
-            .context(TargetCommandStartSnafu { command })?
+            .context(TargetCommandStartSnafu {
+                command: displayed_command,
+            })?
     };
 
-    Ok((child.id(), Some(KillOnDrop(child))))
+    Ok((child.id(), Some(KillOnDrop(child, child_grace))))
 }
 
-fn clear_data_dir(dir: &Path) -> Result<(), ApplicationError> {
+/// Asks the user whether to delete the files of an earlier run and returns them, i.e. all reports
+/// in any format and the sidecar files, except for the ones matching a `keep` pattern. They are
+/// deleted with [`remove_old_files`].
+fn confirm_clear_data_dir(
+    dir: &Path,
+    keep: &[glob::Pattern],
+) -> Result<Vec<PathBuf>, ApplicationError> {
     let mut files = Vec::new();
     for file in std::fs::read_dir(dir).context(DataDirClearIoSnafu)? {
         let file = file.context(DataDirClearIoSnafu)?;
-        if file.file_name().to_string_lossy().ends_with(".json") {
-            files.push(file);
+        let name = file.file_name().to_string_lossy().to_string();
+        let written_by_us = is_report_file(&name)
+            || SIDECAR_FILE_NAMES.contains(&name.as_str())
+            || name.ends_with(".json");
+        if !written_by_us {
+            continue;
         }
+        if keep.iter().any(|pattern| pattern.matches(&name)) {
+            debug!("Keeping old file {:?}", file.path());
+            continue;
+        }
+        files.push(file.path());
     }
 
     let file_names = files
         .iter()
-        .map(|f| f.path().to_string_lossy().to_string())
+        .map(|f| f.to_string_lossy().to_string())
         .collect::<Vec<_>>();
 
     if files.is_empty() {
-        return Ok(());
+        return Ok(files);
     }
 
     let confirm = Confirm::with_theme(&ColorfulTheme::default())
@@ -405,30 +2118,199 @@ fn clear_data_dir(dir: &Path) -> Result<(), ApplicationError> {
 
     ensure!(confirm, DataDirClearCancelSnafu);
 
+    Ok(files)
+}
+
+/// Deletes the files of an earlier run returned by [`confirm_clear_data_dir`].
+fn remove_old_files(files: &[PathBuf]) -> std::io::Result<()> {
     for file in files {
-        debug!("Removing old file {:?}", file.path());
-        std::fs::remove_file(file.path()).context(DataDirClearIoSnafu)?;
+        debug!("Removing old file {file:?}");
+        std::fs::remove_file(file)?;
     }
+    Ok(())
+}
+
+fn init_logging(format: LogFormat, quiet: bool, verbose: u8) {
+    let level = match (quiet, verbose) {
+        (true, _) => "warn",
+        (false, 0) => "info",
+        (false, 1) => "debug",
+        (false, _) => "trace",
+    };
+    let mut builder = env_logger::Builder::from_env(
+        env_logger::Env::default()
+            .filter_or("RUST_LOG", format!("py_crude_resource_monitor={level}")),
+    );
+    if let LogFormat::Json = format {
+        builder.format(|buf, record| {
+            let line = serde_json::json!({
+                "timestamp": buf.timestamp().to_string(),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            writeln!(buf, "{line}")
+        });
+    }
+    builder.init();
+}
 
+/// Serves the directory until CTRL+C, exporting it to an HTML file afterwards if requested.
+fn run_view(
+    output_dir: PathBuf,
+    options: ViewOptions,
+    export_on_exit: Option<&Path>,
+) -> Result<(), ApplicationError> {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .context(TokioInitSnafu)?
+        .block_on(view::run_view(output_dir.clone(), options))
+        .context(ViewSnafu)?;
+
+    let Some(export_file) = export_on_exit else {
+        return Ok(());
+    };
+    export::export_html(
+        &output_dir,
+        export_file,
+        &export::ReadOptions::default(),
+        CompressionLevel::Level(9),
+    )
+    .context(ExportSnafu)?;
+    info!("Exported the viewed profile to {}", export_file.display());
     Ok(())
 }
 
-fn run_view(output_dir: PathBuf, interface: &str, port: u16) -> Result<(), ApplicationError> {
+fn run_replay(html_file: &Path, options: ViewOptions) -> Result<(), ApplicationError> {
+    let reports = export::read_html_reports(html_file).context(ExportSnafu)?;
+    info!(
+        "Read {} reports from {}",
+        reports.len(),
+        html_file.display()
+    );
     tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
         .context(TokioInitSnafu)?
-        .block_on(view::run_view(output_dir, interface, port))
+        .block_on(view::run_replay(reports, options))
         .context(ViewSnafu)
 }
 
-struct KillOnDrop(Child);
+/// A spawned command, which is asked to exit with SIGTERM when dropped. It is killed if it did not
+/// exit within the grace period, or right away on platforms without signals.
+struct KillOnDrop(Child, Duration);
 
 impl Drop for KillOnDrop {
     fn drop(&mut self) {
+        // It might have exited on its own, which is what we waited for when profiling it
+        if let Ok(Some(_)) = self.0.try_wait() {
+            return;
+        }
         info!("Cleaning up spawned process");
+        if terminate(&self.0) {
+            let deadline = Instant::now() + self.1;
+            while Instant::now() < deadline {
+                match self.0.try_wait() {
+                    Ok(Some(_)) => return,
+                    Ok(None) => thread::sleep(CHILD_EXIT_POLL_INTERVAL),
+                    Err(_) => break,
+                }
+            }
+            info!(
+                "Spawned process did not exit within {:?}, killing it",
+                self.1
+            );
+        }
         if let Err(e) = self.0.kill() {
             warn!("Could not kill spawned child process. It might linger around now. Error: {e}")
         }
     }
 }
+
+/// Interactive control of a running capture by signals, see [`handle_control_signals`]
+#[derive(Debug)]
+struct SignalControls {
+    /// Toggled by SIGUSR1, skips sampling during uninteresting phases of a long capture
+    paused: AtomicBool,
+    /// How often SIGUSR2 halved the time between samples, up to `max_doublings`
+    doublings: AtomicU32,
+    /// Set by `--max-speedup`
+    max_doublings: u32,
+}
+
+impl SignalControls {
+    fn new(max_doublings: u32) -> Self {
+        Self {
+            paused: AtomicBool::new(false),
+            doublings: AtomicU32::new(0),
+            max_doublings,
+        }
+    }
+
+    /// The factor the time between samples is divided by.
+    fn speedup(&self) -> u32 {
+        1 << self.doublings.load(Ordering::Acquire)
+    }
+}
+
+/// Pauses and resumes sampling on every SIGUSR1. Every SIGUSR2 halves the time between samples,
+/// for more resolution during an interesting phase, up to `--max-speedup`. The next one after
+/// that starts over at the configured rate, as there is no other free signal to slow down with:
+/// the remaining ones either stop the profiler or are sent by the terminal. The samples record
+/// when they were taken, so the exports are correct at any rate.
+#[cfg(unix)]
+fn handle_control_signals(controls: Arc<SignalControls>) {
+    use signal_hook::consts::{SIGUSR1, SIGUSR2};
+    use signal_hook::iterator::Signals;
+
+    let mut signals = match Signals::new([SIGUSR1, SIGUSR2]) {
+        Ok(signals) => signals,
+        Err(e) => {
+            warn!("Could not register the SIGUSR1 and SIGUSR2 handlers: {e}");
+            return;
+        }
+    };
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            if signal == SIGUSR1 {
+                if controls.paused.fetch_xor(true, Ordering::AcqRel) {
+                    info!("Resuming sampling");
+                } else {
+                    info!("Pausing sampling, send SIGUSR1 again to resume");
+                }
+                continue;
+            }
+            let doublings =
+                (controls.doublings.load(Ordering::Acquire) + 1) % (controls.max_doublings + 1);
+            controls.doublings.store(doublings, Ordering::Release);
+            match doublings {
+                0 => info!("Sampling at the configured rate again"),
+                _ => info!("Sampling {}x as often as configured", controls.speedup()),
+            }
+        }
+    });
+}
+
+#[cfg(not(unix))]
+fn handle_control_signals(_controls: Arc<SignalControls>) {}
+
+/// Sends SIGTERM to the child, so it can run its cleanup. Returns whether the signal was sent.
+#[cfg(unix)]
+fn terminate(child: &Child) -> bool {
+    use nix::sys::signal::{Signal, kill};
+    use nix::unistd::Pid;
+
+    match kill(Pid::from_raw(child.id() as i32), Signal::SIGTERM) {
+        Ok(()) => true,
+        Err(e) => {
+            debug!("Could not send SIGTERM to spawned process: {e}");
+            false
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn terminate(_child: &Child) -> bool {
+    false
+}
@@ -0,0 +1,170 @@
+use crate::export::count_samples;
+use crate::types::{MANIFEST_FILE_NAME, ReportFileName};
+use flate2::read::MultiGzDecoder;
+use serde::{Deserialize, Serialize};
+use snafu::{IntoError, Location, NoneError, ResultExt, Snafu};
+use std::collections::BTreeMap;
+use std::io::Read;
+use std::path::Path;
+use xxhash_rust::xxh3::xxh3_64;
+
+#[derive(Debug, Snafu)]
+pub enum ManifestError {
+    #[snafu(display("Error reading data dir `{path}` at {location}"))]
+    ReadDataDir {
+        source: std::io::Error,
+        path: String,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display("Error reading report `{path}` at {location}"))]
+    ReadReport {
+        source: std::io::Error,
+        path: String,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display("Error reading manifest `{path}` at {location}"))]
+    ReadManifest {
+        source: std::io::Error,
+        path: String,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display("Error deserializing manifest `{path}` at {location}"))]
+    DeserializeManifest {
+        source: serde_json::Error,
+        path: String,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display("Error writing manifest `{path}` at {location}"))]
+    WriteManifest {
+        source: std::io::Error,
+        path: String,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display("{problems} report files do not match the manifest at {location}"))]
+    Mismatch {
+        problems: usize,
+        #[snafu(implicit)]
+        location: Location,
+    },
+}
+
+/// The report files of a data directory, written once profiling finished.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Manifest {
+    pub files: BTreeMap<String, FileEntry>,
+}
+
+/// The number of samples and checksum of a single report file.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct FileEntry {
+    /// The number of samples, i.e. (decompressed) lines for JSON reports
+    pub lines: usize,
+    /// The xxh3 hash of the file as stored on disk
+    pub xxh3: u64,
+}
+
+impl Manifest {
+    /// Computes the manifest of all report files currently in the directory.
+    pub fn compute(data_dir: &Path) -> Result<Self, ManifestError> {
+        let path = data_dir.display().to_string();
+        let mut files = BTreeMap::new();
+        for entry in std::fs::read_dir(data_dir).context(ReadDataDirSnafu { path: &path })? {
+            let entry = entry.context(ReadDataDirSnafu { path: &path })?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            let Some(report_file) = ReportFileName::parse(&name) else {
+                continue;
+            };
+            let file_entry = FileEntry::compute(&entry.path(), report_file)?;
+            files.insert(name, file_entry);
+        }
+        Ok(Self { files })
+    }
+
+    pub fn write(&self, data_dir: &Path) -> Result<(), ManifestError> {
+        let path = data_dir.join(MANIFEST_FILE_NAME);
+        let json = serde_json::to_string_pretty(self).expect("manifest is serializable");
+        std::fs::write(&path, json).context(WriteManifestSnafu {
+            path: path.display().to_string(),
+        })
+    }
+
+    pub fn read(data_dir: &Path) -> Result<Self, ManifestError> {
+        let path = data_dir.join(MANIFEST_FILE_NAME);
+        let display_path = path.display().to_string();
+        let content = std::fs::read_to_string(&path).context(ReadManifestSnafu {
+            path: &display_path,
+        })?;
+        serde_json::from_str(&content).context(DeserializeManifestSnafu { path: display_path })
+    }
+}
+
+impl FileEntry {
+    fn compute(path: &Path, file: ReportFileName<'_>) -> Result<Self, ManifestError> {
+        let display_path = path.display().to_string();
+        let content = std::fs::read(path).context(ReadReportSnafu {
+            path: &display_path,
+        })?;
+        let xxh3 = xxh3_64(&content);
+
+        let lines = if file.compressed {
+            let mut decompressed = Vec::new();
+            MultiGzDecoder::new(content.as_slice())
+                .read_to_end(&mut decompressed)
+                .context(ReadReportSnafu { path: display_path })?;
+            count_samples(&decompressed, file.format)
+        } else {
+            count_samples(&content, file.format)
+        };
+
+        Ok(Self { lines, xxh3 })
+    }
+}
+
+/// Recomputes the manifest of the directory and prints every report file that is missing,
+/// unexpected or differs from the stored manifest.
+pub fn run_verify(data_dir: &Path) -> Result<(), ManifestError> {
+    let expected = Manifest::read(data_dir)?;
+    let actual = Manifest::compute(data_dir)?;
+
+    let mut problems = 0;
+    for (name, expected_entry) in &expected.files {
+        let Some(actual_entry) = actual.files.get(name) else {
+            println!("{name}: missing");
+            problems += 1;
+            continue;
+        };
+        if actual_entry.lines != expected_entry.lines {
+            println!(
+                "{name}: expected {} lines, found {}",
+                expected_entry.lines, actual_entry.lines
+            );
+            problems += 1;
+        } else if actual_entry.xxh3 != expected_entry.xxh3 {
+            println!(
+                "{name}: expected checksum {:016x}, found {:016x}",
+                expected_entry.xxh3, actual_entry.xxh3
+            );
+            problems += 1;
+        }
+    }
+    for name in actual.files.keys() {
+        if !expected.files.contains_key(name) {
+            println!("{name}: not in the manifest");
+            problems += 1;
+        }
+    }
+
+    if problems > 0 {
+        return Err(MismatchSnafu { problems }.into_error(NoneError));
+    }
+    println!(
+        "All {} report files match the manifest",
+        expected.files.len()
+    );
+    Ok(())
+}
@@ -0,0 +1,144 @@
+use crate::diff::format_bytes;
+use sysinfo::{ProcessRefreshKind, ProcessesToUpdate, UpdateKind};
+
+/// A running process as shown by [`run_list_processes`].
+struct ListedProcess {
+    pid: u32,
+    python: bool,
+    cpu: f32,
+    memory: u64,
+    command_line: String,
+}
+
+impl ListedProcess {
+    fn new(pid: sysinfo::Pid, process: &sysinfo::Process) -> Self {
+        let command_line = process
+            .cmd()
+            .iter()
+            .map(|arg| arg.to_string_lossy())
+            .collect::<Vec<_>>()
+            .join(" ");
+        Self {
+            pid: pid.as_u32(),
+            python: looks_like_python(process),
+            cpu: process.cpu_usage(),
+            memory: process.memory(),
+            command_line,
+        }
+    }
+}
+
+/// Whether the process looks like a Python interpreter, judging by the name of its executable.
+/// This misses embedded interpreters, which can still be profiled by their pid.
+fn looks_like_python(process: &sysinfo::Process) -> bool {
+    let exe_name = process
+        .exe()
+        .and_then(|exe| exe.file_name())
+        .map(|name| name.to_string_lossy().to_ascii_lowercase());
+    let name = process.name().to_string_lossy().to_ascii_lowercase();
+    [Some(name), exe_name]
+        .into_iter()
+        .flatten()
+        .any(|name| name.starts_with("python") || name.starts_with("pypy"))
+}
+
+/// Whether the process with the pid looks like a Python interpreter, by its executable or, on
+/// Linux, by a loaded `libpython` for embedded interpreters. Unknown processes are reported as
+/// Python processes, so attaching fails with the actual error.
+pub fn is_python_process(pid: u32) -> bool {
+    let pid = sysinfo::Pid::from_u32(pid);
+    let mut system = sysinfo::System::new();
+    let refresh_kind = ProcessRefreshKind::nothing().with_exe(UpdateKind::OnlyIfNotSet);
+    system.refresh_processes_specifics(ProcessesToUpdate::Some(&[pid]), true, refresh_kind);
+    let Some(process) = system.process(pid) else {
+        return true;
+    };
+    looks_like_python(process) || loads_libpython(pid.as_u32())
+}
+
+#[cfg(target_os = "linux")]
+fn loads_libpython(pid: u32) -> bool {
+    // Reading the maps of processes of other users needs the same permissions as attaching
+    std::fs::read_to_string(format!("/proc/{pid}/maps"))
+        .map(|maps| maps.contains("libpython"))
+        .unwrap_or(true)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn loads_libpython(_pid: u32) -> bool {
+    false
+}
+
+/// The pids of the running processes with the given process or executable name, e.g. to find a
+/// restarted service. Processes whose parent has the name as well are left out, as children are
+/// tracked along with their parent anyway.
+pub fn processes_named(name: &str) -> Vec<u32> {
+    let mut system = sysinfo::System::new();
+    let refresh_kind = ProcessRefreshKind::nothing().with_exe(UpdateKind::OnlyIfNotSet);
+    system.refresh_processes_specifics(ProcessesToUpdate::All, true, refresh_kind);
+
+    let own_pid = sysinfo::Pid::from_u32(std::process::id());
+    let has_name = |process: &sysinfo::Process| {
+        let exe_name = process.exe().and_then(|exe| exe.file_name());
+        process.name() == name || exe_name.is_some_and(|it| it == name)
+    };
+    let matching = system
+        .processes()
+        .iter()
+        // Threads show up as processes on Linux
+        .filter(|(pid, process)| {
+            **pid != own_pid && process.thread_kind().is_none() && has_name(process)
+        })
+        .collect::<Vec<_>>();
+    let mut pids = matching
+        .iter()
+        .filter(|(_, process)| {
+            process
+                .parent()
+                .is_none_or(|parent| !matching.iter().any(|(pid, _)| **pid == parent))
+        })
+        .map(|(pid, _)| pid.as_u32())
+        .collect::<Vec<_>>();
+    pids.sort();
+    pids
+}
+
+/// Prints the running processes with their resource usage, marking the ones that look like
+/// Python interpreters to find the pid to profile.
+pub fn run_list_processes(python_only: bool) {
+    let mut system = sysinfo::System::new();
+    let refresh_kind = ProcessRefreshKind::nothing()
+        .with_cpu()
+        .with_memory()
+        .with_cmd(UpdateKind::OnlyIfNotSet)
+        .with_exe(UpdateKind::OnlyIfNotSet);
+    // CPU usage is computed between two refreshes, so we need to refresh twice
+    system.refresh_processes_specifics(ProcessesToUpdate::All, true, refresh_kind);
+    std::thread::sleep(sysinfo::MINIMUM_CPU_UPDATE_INTERVAL);
+    system.refresh_processes_specifics(ProcessesToUpdate::All, true, refresh_kind);
+
+    let mut processes = system
+        .processes()
+        .iter()
+        // Threads show up as processes on Linux
+        .filter(|(_, process)| process.thread_kind().is_none())
+        .map(|(pid, process)| ListedProcess::new(*pid, process))
+        .filter(|process| !python_only || process.python)
+        .collect::<Vec<_>>();
+    processes.sort_by_key(|it| it.pid);
+
+    println!(
+        "{:>8}  {:>6}  {:>8}  {:>12}  Command",
+        "PID", "Python", "CPU", "Memory"
+    );
+    for process in &processes {
+        println!(
+            "{:>8}  {:>6}  {:>7.1}%  {:>12}  {}",
+            process.pid,
+            if process.python { "yes" } else { "" },
+            process.cpu,
+            format_bytes(process.memory as f64),
+            process.command_line
+        );
+    }
+}
@@ -1,13 +1,31 @@
-use crate::types::{ProcessResources, ThreadResources};
+use crate::types::{CpuNormalization, OTHER_THREADS_ID, ProcessResources, ThreadResources};
+use log::warn;
 use std::collections::HashMap;
+use std::time::Instant;
 use sysinfo::{
     CpuRefreshKind, DiskRefreshKind, MemoryRefreshKind, ProcessRefreshKind, RefreshKind, UpdateKind,
 };
 
+#[cfg(feature = "gpu")]
+mod gpu;
+
 #[derive(Debug)]
 pub struct SystemMeasurements {
     system: sysinfo::System,
     disk: sysinfo::Disks,
+    #[cfg(feature = "gpu")]
+    gpu: Option<gpu::GpuMeasurements>,
+    power: PowerMeter,
+    max_threads_recorded: Option<usize>,
+    max_cpu_percent: Option<f32>,
+    cpu_decimals: Option<u32>,
+    cpu_normalization: CpuNormalization,
+}
+
+impl Default for SystemMeasurements {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl SystemMeasurements {
@@ -15,9 +33,81 @@ impl SystemMeasurements {
         Self {
             system: sysinfo::System::new(),
             disk: sysinfo::Disks::new(),
+            #[cfg(feature = "gpu")]
+            gpu: gpu::GpuMeasurements::new(),
+            power: PowerMeter::default(),
+            max_threads_recorded: None,
+            max_cpu_percent: None,
+            cpu_decimals: None,
+            cpu_normalization: CpuNormalization::PerCore,
         }
     }
 
+    /// Only records the resources of the given number of threads with the highest CPU usage per
+    /// process, summing up the others.
+    pub fn with_max_threads_recorded(mut self, max_threads_recorded: Option<usize>) -> Self {
+        self.max_threads_recorded = max_threads_recorded;
+        self
+    }
+
+    /// Caps the CPU usage of processes and the system at this many percent, instead of 100% per
+    /// core. sysinfo sometimes reports far higher values right after attaching to a process.
+    pub fn with_max_cpu_percent(mut self, max_cpu_percent: Option<f32>) -> Self {
+        self.max_cpu_percent = max_cpu_percent;
+        self
+    }
+
+    /// Rounds CPU usages to this many decimals, as the full `f32` precision only bloats the
+    /// reports.
+    pub fn with_cpu_decimals(mut self, cpu_decimals: Option<u32>) -> Self {
+        self.cpu_decimals = cpu_decimals;
+        self
+    }
+
+    /// sysinfo reports CPU usages per core, with `Total` they are divided by the cores.
+    pub fn with_cpu_normalization(mut self, cpu_normalization: CpuNormalization) -> Self {
+        self.cpu_normalization = cpu_normalization;
+        self
+    }
+
+    /// Returns the number of logical cores of the host.
+    pub fn cpu_count(&mut self) -> usize {
+        self.system.refresh_cpu_list(CpuRefreshKind::nothing());
+        self.system.cpus().len()
+    }
+
+    /// Converts a CPU usage as sysinfo reports it to the configured normalization and precision.
+    fn report_cpu(&self, cpu: f32) -> f32 {
+        let cores = self.system.cpus().len();
+        let cpu = match self.cpu_normalization {
+            CpuNormalization::Total if cores > 0 => cpu / cores as f32,
+            _ => cpu,
+        };
+        match self.cpu_decimals {
+            Some(decimals) => {
+                let factor = 10f32.powi(decimals.min(f32::DIGITS) as i32);
+                (cpu * factor).round() / factor
+            }
+            None => cpu,
+        }
+    }
+
+    /// Clamps implausible CPU usages to the configured ceiling, warning about it.
+    fn clamp_cpu(&self, cpu: f32, source: &str) -> f32 {
+        // The cpus are only known after the first refresh
+        let cores = self.system.cpus().len();
+        let max = match self.max_cpu_percent {
+            Some(max) => max,
+            None if cores > 0 => cores as f32 * 100.0,
+            None => return cpu,
+        };
+        if cpu <= max {
+            return cpu;
+        }
+        warn!("Clamping implausible CPU usage of {cpu:.1}% for {source} to {max:.1}%");
+        max
+    }
+
     pub fn refresh(&mut self) {
         self.system.refresh_specifics(
             RefreshKind::default()
@@ -34,12 +124,59 @@ impl SystemMeasurements {
         );
         self.disk
             .refresh_specifics(true, DiskRefreshKind::nothing().with_io_usage());
+        #[cfg(feature = "gpu")]
+        if let Some(gpu) = &mut self.gpu {
+            gpu.refresh();
+        }
+    }
+
+    /// Returns the total memory of the host in bytes.
+    pub fn total_memory(&mut self) -> u64 {
+        self.system
+            .refresh_memory_specifics(MemoryRefreshKind::nothing().with_ram());
+        self.system.total_memory()
+    }
+
+    /// Returns the cgroup memory limit in bytes (`memory.max` for cgroup v2,
+    /// `memory.limit_in_bytes` for v1), if the current process runs in a cgroup with a limit below
+    /// the host memory.
+    pub fn cgroup_memory_limit(&mut self) -> Option<u64> {
+        let total_memory = self.total_memory();
+        self.system
+            .cgroup_limits()
+            .map(|limits| limits.total_memory)
+            .filter(|limit| *limit < total_memory)
+    }
+
+    /// Returns the start time of a process in seconds since the unix epoch.
+    pub fn process_start_time(&self, pid: sysinfo::Pid) -> Option<u64> {
+        self.system.process(pid).map(|process| process.start_time())
+    }
+
+    /// Whether the process exited, but was not reaped by its parent yet.
+    pub fn is_zombie(&self, pid: sysinfo::Pid) -> bool {
+        self.system
+            .process(pid)
+            .is_some_and(|process| process.status() == sysinfo::ProcessStatus::Zombie)
+    }
+
+    /// Returns the command line of a process, split into arguments.
+    pub fn process_command_line(&self, pid: sysinfo::Pid) -> Option<Vec<String>> {
+        let process = self.system.process(pid)?;
+        Some(
+            process
+                .cmd()
+                .iter()
+                .map(|arg| arg.to_string_lossy().to_string())
+                .collect(),
+        )
     }
 
     pub fn get_process_info(&mut self, pid: sysinfo::Pid) -> Option<ProcessResources> {
         let process = self.system.process(pid)?;
 
-        let cpu_usage = process.cpu_usage();
+        let cpu_usage =
+            self.report_cpu(self.clamp_cpu(process.cpu_usage(), &format!("process {pid}")));
         let memory = process.memory();
 
         let thread_resources = process
@@ -58,35 +195,278 @@ impl SystemMeasurements {
                     },
                 )
             })
-            .collect::<HashMap<u64, _>>();
+            .collect::<Vec<_>>();
+        let total_threads = thread_resources.len();
+        // Converted after summing up the other threads, so the sum is not off by the rounding
+        let mut thread_resources = cap_threads(thread_resources, self.max_threads_recorded);
+        for thread in thread_resources.values_mut() {
+            thread.cpu = self.report_cpu(thread.cpu);
+        }
+
+        let proc_stats = read_proc_stats(pid.as_u32());
+        let gpu_usage = self.gpu_usage(pid.as_u32());
 
         Some(ProcessResources {
             memory,
             cpu: cpu_usage,
+            cpu_time_ms: process.accumulated_cpu_time(),
             disk_read_bytes: process.disk_usage().read_bytes,
             disk_write_bytes: process.disk_usage().written_bytes,
             thread_resources,
+            total_threads: Some(total_threads),
+            voluntary_ctx_switches: proc_stats.voluntary_ctx_switches,
+            involuntary_ctx_switches: proc_stats.involuntary_ctx_switches,
+            major_faults: proc_stats.major_faults,
+            minor_faults: proc_stats.minor_faults,
+            cpu_affinity: proc_stats.cpu_affinity,
+            nice: proc_stats.nice,
+            gpu_util: gpu_usage.map(|it| it.0),
+            gpu_memory: gpu_usage.map(|it| it.1),
+            power_watts: None,
+            per_disk_io: HashMap::new(),
+            exited: false,
         })
     }
 
+    /// Returns the GPU utilization in percent and used GPU memory in bytes, if GPU metrics are
+    /// compiled in and NVML is available.
+    #[cfg(feature = "gpu")]
+    fn gpu_usage(&self, pid: u32) -> Option<(f32, u64)> {
+        let usage = self.gpu.as_ref()?.usage(pid);
+        Some((usage.util, usage.memory))
+    }
+
+    #[cfg(not(feature = "gpu"))]
+    fn gpu_usage(&self, _pid: u32) -> Option<(f32, u64)> {
+        None
+    }
+
     pub fn get_global_info(&mut self) -> ProcessResources {
         let memory = self.system.used_memory() + self.system.used_swap();
         // We want to normalize the cpu usage so that 100% is only one core
-        let cpu = self.system.global_cpu_usage() * self.system.cpus().len() as f32;
-        let (disk_read_bytes, disk_write_bytes) = self
+        let cpu = self.report_cpu(self.clamp_cpu(
+            self.system.global_cpu_usage() * self.system.cpus().len() as f32,
+            "the system",
+        ));
+        // A device mounted multiple times (e.g. btrfs subvolumes) reports the same usage for each
+        // mount point, so keying by name also avoids counting it twice
+        let per_disk_io = self
             .disk
             .iter()
-            .map(|it| it.usage())
-            .fold((0, 0), |(read, written), usage| {
-                (read + usage.read_bytes, written + usage.written_bytes)
+            .map(|disk| {
+                let usage = disk.usage();
+                (
+                    disk.name().to_string_lossy().to_string(),
+                    (usage.read_bytes, usage.written_bytes),
+                )
+            })
+            .collect::<HashMap<_, _>>();
+        let (disk_read_bytes, disk_write_bytes) =
+            per_disk_io.values().fold((0, 0), |(read, written), usage| {
+                (read + usage.0, written + usage.1)
             });
 
         ProcessResources {
             memory,
             cpu,
+            cpu_time_ms: 0,
             disk_read_bytes,
             disk_write_bytes,
             thread_resources: HashMap::new(),
+            total_threads: None,
+            voluntary_ctx_switches: None,
+            involuntary_ctx_switches: None,
+            major_faults: None,
+            minor_faults: None,
+            cpu_affinity: None,
+            nice: None,
+            gpu_util: None,
+            gpu_memory: None,
+            power_watts: self.power.read_watts(),
+            per_disk_io,
+            exited: false,
         }
     }
 }
+
+/// Keeps the threads with the highest CPU usage (and memory, on ties) and sums up the others into
+/// an entry with [`OTHER_THREADS_ID`].
+fn cap_threads(
+    mut threads: Vec<(u64, ThreadResources)>,
+    max: Option<usize>,
+) -> HashMap<u64, ThreadResources> {
+    let Some(max) = max.filter(|max| threads.len() > *max) else {
+        return threads.into_iter().collect();
+    };
+    threads.sort_by(|(_, a), (_, b)| {
+        b.cpu
+            .total_cmp(&a.cpu)
+            .then_with(|| b.memory.cmp(&a.memory))
+    });
+    let others = threads.split_off(max).into_iter().fold(
+        ThreadResources {
+            cpu: 0.0,
+            memory: 0,
+            disk_read_bytes: 0,
+            disk_write_bytes: 0,
+        },
+        |mut acc, (_, thread)| {
+            acc.cpu += thread.cpu;
+            acc.memory += thread.memory;
+            acc.disk_read_bytes += thread.disk_read_bytes;
+            acc.disk_write_bytes += thread.disk_write_bytes;
+            acc
+        },
+    );
+    threads.push((OTHER_THREADS_ID, others));
+    threads.into_iter().collect()
+}
+
+/// Measures the power draw of the system, using RAPL energy counters or, failing that, the
+/// discharge rate of the battery.
+#[derive(Debug, Default)]
+struct PowerMeter {
+    /// The last summed RAPL energy reading in microjoules and when it was taken
+    #[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+    last_energy: Option<(u64, Instant)>,
+}
+
+impl PowerMeter {
+    #[cfg(target_os = "linux")]
+    fn read_watts(&mut self) -> Option<f32> {
+        self.read_rapl_watts().or_else(read_battery_watts)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn read_watts(&mut self) -> Option<f32> {
+        None
+    }
+
+    /// RAPL only reports consumed energy, so the first reading never has a value.
+    #[cfg(target_os = "linux")]
+    fn read_rapl_watts(&mut self) -> Option<f32> {
+        let now = Instant::now();
+        let energy = read_rapl_energy()?;
+        let last = self.last_energy.replace((energy, now));
+        let (last_energy, last_time) = last?;
+        // The counters wrap around, we skip that sample
+        let consumed = energy.checked_sub(last_energy)?;
+        let seconds = now.duration_since(last_time).as_secs_f32();
+        (seconds > 0.0).then(|| consumed as f32 / 1_000_000.0 / seconds)
+    }
+}
+
+/// Sums the energy counters of all packages, in microjoules. They are often only readable as root.
+#[cfg(target_os = "linux")]
+fn read_rapl_energy() -> Option<u64> {
+    let mut total = None;
+    for entry in std::fs::read_dir("/sys/class/powercap").ok()?.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        // Packages are `intel-rapl:0`, their subzones `intel-rapl:0:0`
+        if !name.starts_with("intel-rapl:") || name.matches(':').count() != 1 {
+            continue;
+        }
+        let Ok(energy) = std::fs::read_to_string(entry.path().join("energy_uj")) else {
+            continue;
+        };
+        if let Ok(energy) = energy.trim().parse::<u64>() {
+            total = Some(total.unwrap_or(0) + energy);
+        }
+    }
+    total
+}
+
+/// Reads the power draw of discharging batteries in watts.
+#[cfg(target_os = "linux")]
+fn read_battery_watts() -> Option<f32> {
+    let read = |path: &std::path::Path, file: &str| {
+        std::fs::read_to_string(path.join(file))
+            .ok()
+            .map(|it| it.trim().to_string())
+    };
+    let read_number =
+        |path: &std::path::Path, file: &str| read(path, file).and_then(|it| it.parse::<f32>().ok());
+
+    let mut total = None;
+    for entry in std::fs::read_dir("/sys/class/power_supply").ok()?.flatten() {
+        let path = entry.path();
+        if read(&path, "type").as_deref() != Some("Battery")
+            || read(&path, "status").as_deref() != Some("Discharging")
+        {
+            continue;
+        }
+        // Values are in micro units
+        let watts = read_number(&path, "power_now")
+            .map(|microwatts| microwatts / 1_000_000.0)
+            .or_else(|| {
+                let current = read_number(&path, "current_now")?;
+                let voltage = read_number(&path, "voltage_now")?;
+                Some(current / 1_000_000.0 * voltage / 1_000_000.0)
+            });
+        if let Some(watts) = watts {
+            total = Some(total.unwrap_or(0.0) + watts);
+        }
+    }
+    total
+}
+
+/// Scheduler and paging statistics sysinfo does not expose.
+#[derive(Debug, Default)]
+struct ProcStats {
+    voluntary_ctx_switches: Option<u64>,
+    involuntary_ctx_switches: Option<u64>,
+    major_faults: Option<u64>,
+    minor_faults: Option<u64>,
+    cpu_affinity: Option<Vec<usize>>,
+    nice: Option<i32>,
+}
+
+#[cfg(target_os = "linux")]
+fn read_proc_stats(pid: u32) -> ProcStats {
+    let mut stats = ProcStats::default();
+
+    if let Ok(status) = std::fs::read_to_string(format!("/proc/{pid}/status")) {
+        for line in status.lines() {
+            if let Some(value) = line.strip_prefix("voluntary_ctxt_switches:") {
+                stats.voluntary_ctx_switches = value.trim().parse().ok();
+            } else if let Some(value) = line.strip_prefix("nonvoluntary_ctxt_switches:") {
+                stats.involuntary_ctx_switches = value.trim().parse().ok();
+            } else if let Some(value) = line.strip_prefix("Cpus_allowed_list:") {
+                stats.cpu_affinity = parse_cpu_list(value.trim());
+            }
+        }
+    }
+
+    if let Ok(stat) = std::fs::read_to_string(format!("/proc/{pid}/stat")) {
+        // The command name may contain spaces and parentheses, so we split at the last `)`.
+        // The first field after it is the state, which is field 3 in `man 5 proc`.
+        if let Some((_, rest)) = stat.rsplit_once(')') {
+            let fields = rest.split_whitespace().collect::<Vec<_>>();
+            stats.minor_faults = fields.get(7).and_then(|it| it.parse().ok());
+            stats.major_faults = fields.get(9).and_then(|it| it.parse().ok());
+            stats.nice = fields.get(16).and_then(|it| it.parse().ok());
+        }
+    }
+
+    stats
+}
+
+/// Parses a CPU list like `0-3,8,10-11` from procfs.
+#[cfg(target_os = "linux")]
+fn parse_cpu_list(list: &str) -> Option<Vec<usize>> {
+    let mut cpus = Vec::new();
+    for range in list.split(',').filter(|it| !it.is_empty()) {
+        match range.split_once('-') {
+            Some((start, end)) => {
+                cpus.extend(start.parse::<usize>().ok()?..=end.parse::<usize>().ok()?);
+            }
+            None => cpus.push(range.parse().ok()?),
+        }
+    }
+    Some(cpus)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn read_proc_stats(_pid: u32) -> ProcStats {
+    ProcStats::default()
+}
@@ -0,0 +1,100 @@
+use log::{debug, info};
+use nvml_wrapper::Nvml;
+use nvml_wrapper::enums::device::UsedGpuMemory;
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+
+/// GPU usage of a single process, summed over all devices.
+#[derive(Debug, Clone, Copy, Default)]
+pub(super) struct GpuUsage {
+    /// Streaming multiprocessor utilization in percent, where 100% is one GPU
+    pub util: f32,
+    /// Used GPU memory in bytes
+    pub memory: u64,
+}
+
+pub(super) struct GpuMeasurements {
+    nvml: Nvml,
+    /// Timestamp of the newest utilization sample we have seen, so NVML only reports newer ones
+    last_seen_timestamp: u64,
+    usage: HashMap<u32, GpuUsage>,
+}
+
+impl Debug for GpuMeasurements {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GpuMeasurements")
+            .field("last_seen_timestamp", &self.last_seen_timestamp)
+            .field("usage", &self.usage)
+            .finish_non_exhaustive()
+    }
+}
+
+impl GpuMeasurements {
+    /// Returns `None` if NVML is not available, e.g. because there is no NVIDIA driver.
+    pub fn new() -> Option<Self> {
+        match Nvml::init() {
+            Ok(nvml) => Some(Self {
+                nvml,
+                last_seen_timestamp: 0,
+                usage: HashMap::new(),
+            }),
+            Err(e) => {
+                info!("NVML is not available, GPU metrics are disabled: {e}");
+                None
+            }
+        }
+    }
+
+    pub fn refresh(&mut self) {
+        self.usage.clear();
+
+        let device_count = match self.nvml.device_count() {
+            Ok(count) => count,
+            Err(e) => {
+                debug!("Could not query GPU devices: {e}");
+                return;
+            }
+        };
+
+        let mut newest_timestamp = self.last_seen_timestamp;
+        for index in 0..device_count {
+            let Ok(device) = self.nvml.device_by_index(index) else {
+                continue;
+            };
+
+            for process in device.running_compute_processes().unwrap_or_default() {
+                if let UsedGpuMemory::Used(bytes) = process.used_gpu_memory {
+                    self.usage.entry(process.pid).or_default().memory += bytes;
+                }
+            }
+
+            // Errors if there are no samples newer than the timestamp
+            let samples = device
+                .process_utilization_stats(self.last_seen_timestamp)
+                .unwrap_or_default();
+            // There might be multiple samples per process, we only want the newest one
+            let mut newest_per_pid = HashMap::new();
+            for sample in samples {
+                newest_timestamp = newest_timestamp.max(sample.timestamp);
+                newest_per_pid
+                    .entry(sample.pid)
+                    .and_modify(|(timestamp, util)| {
+                        if sample.timestamp > *timestamp {
+                            *timestamp = sample.timestamp;
+                            *util = sample.sm_util;
+                        }
+                    })
+                    .or_insert((sample.timestamp, sample.sm_util));
+            }
+            for (pid, (_, util)) in newest_per_pid {
+                self.usage.entry(pid).or_default().util += util as f32;
+            }
+        }
+        self.last_seen_timestamp = newest_timestamp;
+    }
+
+    pub fn usage(&self, pid: u32) -> GpuUsage {
+        // Processes not using the GPU are not listed by NVML
+        self.usage.get(&pid).copied().unwrap_or_default()
+    }
+}
@@ -0,0 +1,660 @@
+use crate::export::{parse_samples, read_report_file};
+use crate::tracker::TrackerOptions;
+use crate::types::{JsonLine, Metadata, MetadataHeader, ReportFileName, ReportFormat};
+use flate2::Compression;
+use flate2::write::GzEncoder;
+use log::{debug, warn};
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::fs::{File, OpenOptions};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Where the writer thread of the [`Tracker`](crate::Tracker) puts the samples. [`FileSink`]
+/// writes the report files of the output directory, [`MemorySink`] keeps them in memory.
+pub trait SampleSink: Send {
+    /// Where the index and the sequence numbers of the new samples of the report file continue,
+    /// after the samples of an earlier run. Called before the first sample of every report file
+    /// is written.
+    fn resume(&mut self, _output_path: &Path) -> ResumePoint {
+        ResumePoint::default()
+    }
+
+    /// Writes a sample of the report file, e.g. `1234.json` in the output directory.
+    /// [`JsonLine::report`] is only set with [`TrackerOptions::single_file`].
+    fn write(&mut self, output_path: &Path, line: &JsonLine);
+
+    /// The metadata changed, only called with [`TrackerOptions::embed_metadata`].
+    fn set_metadata(&mut self, _metadata: &Metadata) {}
+
+    /// Called about every [`WRITER_FLUSH_INTERVAL`], to write samples that were held back.
+    fn flush(&mut self) {}
+
+    /// Whether the sink can no longer write, so the caller should stop, see
+    /// [`Tracker::writer_failed`](crate::Tracker::writer_failed).
+    fn failed(&self) -> bool {
+        false
+    }
+
+    /// Writes all pending samples, called once the tracker finishes.
+    fn finish(self: Box<Self>) {}
+}
+
+/// Where a report file continues, see [`SampleSink::resume`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ResumePoint {
+    /// The index of the next sample
+    pub next_index: usize,
+    /// The sequence number of the next sample
+    pub next_sequence: u64,
+}
+
+impl ResumePoint {
+    /// Continues after the sample. Samples captured by older versions have no sequence number,
+    /// which then continues after the index.
+    fn after(line: &JsonLine) -> Self {
+        Self {
+            next_index: line.index + 1,
+            next_sequence: line.sequence.unwrap_or(line.index as u64) + 1,
+        }
+    }
+}
+
+/// Writes the samples to the report files, rotating and compressing them as configured in the
+/// [`TrackerOptions`].
+pub struct FileSink {
+    outputs: HashMap<PathBuf, OutputPart>,
+    files: BatchedFiles,
+    compressed: bool,
+    format: ReportFormat,
+    pretty_json: bool,
+    max_output_size: Option<u64>,
+    max_file_age: Option<Duration>,
+    embed_metadata: bool,
+    /// The metadata every new report file starts with, see [`TrackerOptions::embed_metadata`]
+    header_metadata: Option<Metadata>,
+}
+
+impl FileSink {
+    /// Writes the report files in the format, compression and parts configured by the options.
+    pub fn new(options: &TrackerOptions) -> Self {
+        Self {
+            outputs: HashMap::new(),
+            files: BatchedFiles::new(
+                options.writer_batch,
+                options.compress_output,
+                options.write_retries,
+            ),
+            compressed: options.compress_output,
+            format: options.output_format,
+            pretty_json: options.pretty_json,
+            max_output_size: options.max_output_size,
+            max_file_age: options.max_file_age,
+            embed_metadata: options.embed_metadata,
+            header_metadata: None,
+        }
+    }
+
+    fn serialize(&self, line: &JsonLine) -> Vec<u8> {
+        match self.format {
+            ReportFormat::Jsonl if self.pretty_json => {
+                let mut serialized = serde_json::to_vec_pretty(line).unwrap();
+                serialized.extend_from_slice(b"\n\n");
+                serialized
+            }
+            ReportFormat::Jsonl => {
+                let mut serialized = serde_json::to_vec(line).unwrap();
+                serialized.push(b'\n');
+                serialized
+            }
+            ReportFormat::Cbor => {
+                let mut serialized = Vec::new();
+                ciborium::into_writer(line, &mut serialized).unwrap();
+                serialized
+            }
+        }
+    }
+}
+
+impl SampleSink for FileSink {
+    fn resume(&mut self, output_path: &Path) -> ResumePoint {
+        let (output, resumed) = OutputPart::resume(output_path, self.compressed, self.format);
+        self.outputs.insert(output_path.to_path_buf(), output);
+        resumed
+    }
+
+    fn write(&mut self, output_path: &Path, line: &JsonLine) {
+        let mut serialized = self.serialize(line);
+        let output = self
+            .outputs
+            .entry(output_path.to_path_buf())
+            .or_insert_with(|| OutputPart::resume(output_path, self.compressed, self.format).0);
+
+        let size = serialized.len() as u64;
+        let time_millis = line.time;
+        let part_span = self.max_file_age.map(|age| age / PARTS_PER_FILE_AGE);
+        let too_large = self
+            .max_output_size
+            .map(|max_size| max_size / PARTS_PER_OUTPUT_SIZE)
+            .is_some_and(|part_size| output.bytes > 0 && output.bytes + size > part_size);
+        let too_old = part_span
+            .zip(output.started_millis)
+            .is_some_and(|(span, started)| time_millis >= started + span.as_millis());
+        if too_large || too_old {
+            self.files.finish_file(&part_path(output_path, output.part));
+            output.part += 1;
+            output.bytes = 0;
+            output.started_millis = None;
+            debug!("Continuing {output_path:?} in part {}", output.part);
+            if let Some(max_age) = self.max_file_age {
+                output.prune(output_path, max_age);
+            }
+            if let Some(max_size) = self.max_output_size {
+                output.prune_to_size(output_path, max_size);
+            }
+        }
+        output.started_millis.get_or_insert(time_millis);
+        // Every new part starts with the header
+        if let Some(metadata) = self.header_metadata.as_ref().filter(|_| output.bytes == 0) {
+            let mut with_header = MetadataHeader::encode(metadata, self.format);
+            with_header.append(&mut serialized);
+            serialized = with_header;
+        }
+
+        // The sample counts as written once it is batched, a failed batch is dropped
+        self.files
+            .push(&part_path(output_path, output.part), &serialized);
+        output.bytes += serialized.len() as u64;
+    }
+
+    fn set_metadata(&mut self, metadata: &Metadata) {
+        if self.embed_metadata {
+            self.header_metadata = Some(metadata.clone());
+        }
+    }
+
+    fn flush(&mut self) {
+        self.files.flush_older_than(WRITER_FLUSH_INTERVAL);
+    }
+
+    fn failed(&self) -> bool {
+        self.files.gave_up()
+    }
+
+    fn finish(self: Box<Self>) {
+        self.files.finish();
+    }
+}
+
+/// Keeps the samples in memory instead of writing them, e.g. to test the tracker without touching
+/// the disk. Clones share the samples, so one can be handed to
+/// [`Tracker::with_sink`](crate::Tracker::with_sink) and the other read once the tracker
+/// finished.
+#[derive(Clone, Default)]
+pub struct MemorySink {
+    samples: Arc<Mutex<Vec<(PathBuf, JsonLine)>>>,
+}
+
+impl MemorySink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The samples written so far in order, each with the path of its report file, e.g.
+    /// `1234.json`.
+    pub fn samples(&self) -> Vec<(PathBuf, JsonLine)> {
+        self.samples
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+}
+
+impl SampleSink for MemorySink {
+    fn write(&mut self, output_path: &Path, line: &JsonLine) {
+        self.samples
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push((output_path.to_path_buf(), line.clone()));
+    }
+}
+
+/// The part of a report the file sink currently appends to.
+struct OutputPart {
+    part: u32,
+    /// The uncompressed bytes written to the part so far
+    bytes: u64,
+    /// The oldest part that was not pruned for its age yet
+    first_part: u32,
+    /// The time of the first sample written to the part, in ms since the unix epoch
+    started_millis: Option<u128>,
+}
+
+impl OutputPart {
+    /// Continues after the parts left over from an earlier run and returns where their samples
+    /// end. Parts pruned by `--max-file-age` or `--max-output-size` are missing from the start,
+    /// so the last sample is continued instead of counting the samples on disk.
+    fn resume(path: &Path, compressed: bool, format: ReportFormat) -> (Self, ResumePoint) {
+        let parts = existing_parts(path);
+        let mut resumed = Self {
+            part: 0,
+            bytes: 0,
+            first_part: parts.first().copied().unwrap_or(0),
+            started_millis: None,
+        };
+        let mut next = ResumePoint::default();
+        let mut part = resumed.first_part;
+        while let Ok(content) = read_report_file(&part_path(path, part), compressed) {
+            match parse_samples::<JsonLine>(&content, format, 1) {
+                Ok(samples) => {
+                    if let Some(last) = samples.last() {
+                        next = ResumePoint::after(last);
+                    }
+                }
+                Err(e) => warn!("Could not read the samples of part {part} of {path:?}: {e}"),
+            }
+            resumed.part = part;
+            resumed.bytes = content.len() as u64;
+            part += 1;
+        }
+        (resumed, next)
+    }
+
+    /// Deletes the oldest finished parts until the parts of the report take at most `max_size`
+    /// bytes on disk, counting the current part with the size it may grow to.
+    fn prune_to_size(&mut self, path: &Path, max_size: u64) {
+        let part_sizes = (self.first_part..self.part)
+            .map(|part| {
+                std::fs::metadata(part_path(path, part)).map_or(0, |metadata| metadata.len())
+            })
+            .collect::<Vec<_>>();
+        let mut total = part_sizes.iter().sum::<u64>() + max_size / PARTS_PER_OUTPUT_SIZE;
+        for size in part_sizes {
+            if total <= max_size {
+                break;
+            }
+            let old_path = part_path(path, self.first_part);
+            debug!("Deleting {old_path:?}, the report exceeds the maximum output size");
+            match std::fs::remove_file(&old_path) {
+                Ok(()) => {}
+                // Deleted by someone else already
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => {
+                    warn!("Could not delete {old_path:?}: {e}");
+                    break;
+                }
+            }
+            total -= size;
+            self.first_part += 1;
+        }
+    }
+
+    /// Deletes the finished parts last written more than `max_age` ago, oldest first.
+    fn prune(&mut self, path: &Path, max_age: Duration) {
+        while self.first_part < self.part {
+            let old_path = part_path(path, self.first_part);
+            let age = std::fs::metadata(&old_path)
+                .and_then(|it| it.modified())
+                .map(|modified| modified.elapsed().unwrap_or_default());
+            match age {
+                Ok(age) if age <= max_age => break,
+                Ok(_) => {
+                    debug!("Deleting {old_path:?}, it is older than the maximum file age");
+                    if let Err(e) = std::fs::remove_file(&old_path) {
+                        warn!("Could not delete {old_path:?}: {e}");
+                    }
+                }
+                // Deleted by someone else already
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => {
+                    warn!("Could not read the age of {old_path:?}: {e}");
+                    break;
+                }
+            }
+            self.first_part += 1;
+        }
+    }
+}
+
+/// How many parts a report is split into with `--max-output-size`. The oldest is deleted once
+/// a new part would not fit anymore, so between three and four parts stay on disk.
+const PARTS_PER_OUTPUT_SIZE: u64 = 4;
+
+/// How many parts a report is split into with `--max-file-age`. The oldest is only deleted once
+/// all its samples are too old, so up to a part more than the maximum age stays on disk.
+const PARTS_PER_FILE_AGE: u32 = 4;
+
+/// The parts of the report left over from an earlier run, in order.
+fn existing_parts(path: &Path) -> Vec<u32> {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let Some(report) = ReportFileName::parse(&file_name) else {
+        return Vec::new();
+    };
+    let Ok(entries) = std::fs::read_dir(path.parent().unwrap_or(Path::new("."))) else {
+        return Vec::new();
+    };
+    let mut parts = entries
+        .flatten()
+        .filter_map(|entry| {
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            let other = ReportFileName::parse(&file_name)?;
+            let same_report = other.stem == report.stem
+                && other.format == report.format
+                && other.compressed == report.compressed;
+            same_report.then(|| other.part.unwrap_or(0))
+        })
+        .collect::<Vec<_>>();
+    parts.sort();
+    parts
+}
+
+/// The path of a rotated part of the report, e.g. `1234.2.json` for part 2 of `1234.json`. Part
+/// 0 is the report itself.
+fn part_path(path: &Path, part: u32) -> PathBuf {
+    if part == 0 {
+        return path.to_path_buf();
+    }
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    // Stems never contain a dot, so everything after the first one are the extensions
+    let (stem, extensions) = file_name
+        .split_once('.')
+        .unwrap_or((file_name.as_ref(), ""));
+    path.with_file_name(format!("{stem}.{part}.{extensions}"))
+}
+
+/// The time before the first retry of a failed write, doubled for every further retry.
+const WRITE_RETRY_BACKOFF: Duration = Duration::from_millis(50);
+
+/// How many writes in a row may fail to recreate the vanished output directory before the writer
+/// gives up
+const MAX_OUTPUT_DIR_RECREATION_FAILURES: u32 = 3;
+
+/// How long batched samples wait at most before they are written, see
+/// [`TrackerOptions::writer_batch`]
+pub const WRITER_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A report file kept open by the file sink.
+enum OutputFile<W = File> {
+    Plain(W),
+    /// gzip streams are finalized once the file is finished. Every batch is flushed, so after a
+    /// crash the file is only missing its trailer, which the readers tolerate.
+    Compressed(GzEncoder<W>),
+}
+
+impl OutputFile {
+    fn open(path: &Path, compressed: bool) -> std::io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(if compressed {
+            Self::Compressed(GzEncoder::new(file, Compression::default()))
+        } else {
+            Self::Plain(file)
+        })
+    }
+}
+
+impl<W: Write> OutputFile<W> {
+    /// Writes the data after the `written` bytes and makes it readable, counting the bytes that
+    /// reached the file in `written`. For plain files, that is every byte the file accepted, so a
+    /// retry continues where the failed attempt stopped. The encoder of compressed files buffers
+    /// the accepted bytes, which are lost with it, so a failed batch is written again in full.
+    fn write_batch(&mut self, data: &[u8], written: &mut usize) -> std::io::Result<()> {
+        match self {
+            Self::Plain(_) => {
+                self.write_remaining(data, written)?;
+                self.flush()
+            }
+            Self::Compressed(_) => {
+                let mut accepted = *written;
+                self.write_remaining(data, &mut accepted)?;
+                self.flush()?;
+                *written = accepted;
+                Ok(())
+            }
+        }
+    }
+
+    /// Writes the data after the `written` bytes, counting the newly written bytes in it.
+    fn write_remaining(&mut self, data: &[u8], written: &mut usize) -> std::io::Result<()> {
+        while *written < data.len() {
+            let result = match self {
+                Self::Plain(file) => file.write(&data[*written..]),
+                Self::Compressed(encoder) => encoder.write(&data[*written..]),
+            };
+            match result {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(n) => *written += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes everything written so far readable, see [`OutputFile::Compressed`].
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::Plain(_) => Ok(()),
+            // A sync flush, which ends the compressed data so far on a byte boundary
+            Self::Compressed(encoder) => encoder.flush(),
+        }
+    }
+
+    fn finish(self) -> std::io::Result<()> {
+        match self {
+            Self::Plain(_) => Ok(()),
+            Self::Compressed(encoder) => encoder.finish().map(|_| ()),
+        }
+    }
+}
+
+/// Appends a batch of samples to its report file. On errors, e.g. hiccups of network filesystems,
+/// the file is re-opened and the rest of the batch retried with an exponential backoff. If the output directory
+/// vanished, e.g. with an automount, it is recreated first. `dir_failures` counts the writes in a
+/// row that could not recreate it.
+fn write_with_retry(
+    files: &mut HashMap<PathBuf, OutputFile>,
+    path: &Path,
+    compressed: bool,
+    data: &[u8],
+    retries: u32,
+    dir_failures: &mut u32,
+) -> std::io::Result<()> {
+    let mut recreation_failed = false;
+    let mut backoff = WRITE_RETRY_BACKOFF;
+    let mut attempt = 0;
+    let mut written = 0;
+    loop {
+        let result = match files.entry(path.to_path_buf()) {
+            Entry::Occupied(entry) => Ok(entry.into_mut()),
+            Entry::Vacant(entry) => {
+                OutputFile::open(path, compressed).map(|file| entry.insert(file))
+            }
+        }
+        .and_then(|file| file.write_batch(data, &mut written));
+        let Err(e) = result else {
+            *dir_failures = 0;
+            return Ok(());
+        };
+
+        // The handle might be broken, so the next attempt starts with a fresh one
+        if let Some(file) = files.remove(path) {
+            let _ = file.finish();
+        }
+        match path.parent() {
+            Some(dir) if e.kind() == ErrorKind::NotFound && !dir.exists() => {
+                warn!("Output directory {dir:?} vanished, recreating it");
+                if let Err(e) = std::fs::create_dir_all(dir) {
+                    warn!("Could not recreate output directory {dir:?}: {e}");
+                    recreation_failed = true;
+                }
+            }
+            _ => {}
+        }
+        if attempt >= retries {
+            if recreation_failed {
+                *dir_failures += 1;
+            }
+            return Err(e);
+        }
+        debug!("Error writing to {path:?}, retrying in {backoff:?}: {e}");
+        thread::sleep(backoff);
+        backoff *= 2;
+        attempt += 1;
+    }
+}
+
+/// The samples of a report file that were not written yet.
+struct PendingBatch {
+    data: Vec<u8>,
+    samples: usize,
+    /// When the first sample of the batch was added
+    since: Instant,
+}
+
+/// The report files kept open by the file sink. Samples are collected per file and written
+/// in batches, which keeps them in order and saves syscalls at high sample rates.
+struct BatchedFiles {
+    files: HashMap<PathBuf, OutputFile>,
+    pending: HashMap<PathBuf, PendingBatch>,
+    batch_size: usize,
+    compressed: bool,
+    retries: u32,
+    /// See [`write_with_retry`]
+    dir_failures: u32,
+}
+
+impl BatchedFiles {
+    fn new(batch_size: usize, compressed: bool, retries: u32) -> Self {
+        Self {
+            files: HashMap::new(),
+            pending: HashMap::new(),
+            batch_size: batch_size.max(1),
+            compressed,
+            retries,
+            dir_failures: 0,
+        }
+    }
+
+    /// Adds a serialized sample to the batch of the file, writing it once it is full.
+    fn push(&mut self, path: &Path, data: &[u8]) {
+        let batch = self
+            .pending
+            .entry(path.to_path_buf())
+            .or_insert_with(|| PendingBatch {
+                data: Vec::new(),
+                samples: 0,
+                since: Instant::now(),
+            });
+        batch.data.extend_from_slice(data);
+        batch.samples += 1;
+        if batch.samples >= self.batch_size {
+            self.flush(path);
+        }
+    }
+
+    fn flush(&mut self, path: &Path) {
+        let Some(batch) = self.pending.remove(path) else {
+            return;
+        };
+        let result = write_with_retry(
+            &mut self.files,
+            path,
+            self.compressed,
+            &batch.data,
+            self.retries,
+            &mut self.dir_failures,
+        );
+        if let Err(e) = result {
+            warn!(
+                "Could not write {} samples to {path:?}, dropping them: {e}",
+                batch.samples
+            );
+        }
+    }
+
+    /// Writes the batches whose first sample waited for at least `max_age`.
+    fn flush_older_than(&mut self, max_age: Duration) {
+        let stale = self
+            .pending
+            .iter()
+            .filter(|(_, batch)| batch.since.elapsed() >= max_age)
+            .map(|(path, _)| path.clone())
+            .collect::<Vec<_>>();
+        for path in stale {
+            self.flush(&path);
+        }
+    }
+
+    /// Writes the batch of the file and finishes it, e.g. once it was rotated.
+    fn finish_file(&mut self, path: &Path) {
+        self.flush(path);
+        if let Some(Err(e)) = self.files.remove(path).map(OutputFile::finish) {
+            warn!("Could not finish output file {path:?}: {e}");
+        }
+    }
+
+    /// Whether writing failed to recreate the vanished output directory too often.
+    fn gave_up(&self) -> bool {
+        self.dir_failures >= MAX_OUTPUT_DIR_RECREATION_FAILURES
+    }
+
+    fn finish(mut self) {
+        let paths = self.pending.keys().cloned().collect::<Vec<_>>();
+        for path in paths {
+            self.flush(&path);
+        }
+        for (path, file) in self.files {
+            if let Err(e) = file.finish() {
+                warn!("Could not finish compressed output file {path:?}: {e}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    /// Accepts every write, but fails to flush it, like a file on a vanished network filesystem.
+    struct FailingWriter;
+
+    impl Write for FailingWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Err(ErrorKind::BrokenPipe.into())
+        }
+    }
+
+    #[test]
+    fn failed_compressed_batches_are_written_again_in_full() {
+        let data = b"{\"index\": 0}\n{\"index\": 1}\n";
+        let mut written = 0;
+
+        // The encoder accepts all of the data, but it never reaches the file
+        let mut broken =
+            OutputFile::Compressed(GzEncoder::new(FailingWriter, Compression::default()));
+        assert!(broken.write_batch(data, &mut written).is_err());
+        assert_eq!(written, 0);
+
+        let mut retried =
+            OutputFile::Compressed(GzEncoder::new(Vec::new(), Compression::default()));
+        retried.write_batch(data, &mut written).unwrap();
+        assert_eq!(written, data.len());
+        let OutputFile::Compressed(encoder) = retried else {
+            unreachable!("the file is compressed");
+        };
+        let mut decompressed = Vec::new();
+        GzDecoder::new(encoder.finish().unwrap().as_slice())
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, data);
+    }
+}
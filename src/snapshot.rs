@@ -0,0 +1,142 @@
+use crate::diff::format_bytes;
+use crate::export::FrameFormat;
+use crate::sink::MemorySink;
+use crate::tracker::{Tracker, TrackerError, TrackerOptions};
+use crate::types::{JsonLine, ReportFormat};
+use snafu::{Location, ResultExt, Snafu};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+#[derive(Debug, Snafu)]
+pub enum SnapshotError {
+    #[snafu(display("Error attaching to the process at {location}"))]
+    Attach {
+        source: TrackerError,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display("Error serializing snapshot at {location}"))]
+    Serialize {
+        source: serde_json::Error,
+        #[snafu(implicit)]
+        location: Location,
+    },
+}
+
+/// Captures a single sample of the process and its children and prints it, without writing
+/// anything to disk.
+pub fn run_snapshot(pid: u32, options: &TrackerOptions, json: bool) -> Result<(), SnapshotError> {
+    let options = TrackerOptions {
+        compress_output: false,
+        output_format: ReportFormat::Jsonl,
+        single_file: false,
+        output_name: None,
+        stream_to_stdout: false,
+        ring_buffer: None,
+        skip_global: true,
+        // CPU usage is computed between two refreshes, so the samples of the first tick are
+        // discarded
+        warmup: Some(Duration::ZERO),
+        ..options.clone()
+    };
+    let sink = MemorySink::new();
+    let mut tracker = Tracker::with_sink_and_retry(&[pid], || Box::new(sink.clone()), &options)
+        .context(AttachSnafu)?;
+    tracker.tick();
+    std::thread::sleep(sysinfo::MINIMUM_CPU_UPDATE_INTERVAL);
+    tracker.tick();
+    tracker.finish();
+
+    // The report files are named `<report>.json`, e.g. `1234.json`
+    let samples = sink
+        .samples()
+        .into_iter()
+        .map(|(path, line)| {
+            let report = path
+                .file_name()
+                .map(|name| name.to_string_lossy())
+                .unwrap_or_default()
+                .trim_end_matches(".json")
+                .to_string();
+            (report, line)
+        })
+        .collect::<BTreeMap<_, _>>();
+
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&samples).context(SerializeSnafu)?
+        );
+        return Ok(());
+    }
+
+    let frame_format = FrameFormat::default();
+    for (report, line) in samples {
+        println!("{}", format_process(&report, &line, &frame_format));
+    }
+
+    Ok(())
+}
+
+/// The stacktraces and resources of a process as printed without `--json`.
+fn format_process(report: &str, line: &JsonLine, frame_format: &FrameFormat) -> String {
+    let resources = &line.resources;
+    let mut text = format!(
+        "Process {report}: {:.1}% CPU, {} memory\n",
+        resources.cpu,
+        format_bytes(resources.memory as f64)
+    );
+    for stacktrace in &line.stacktraces {
+        text.push_str(&format!(
+            "  Thread {} ({}){}{}\n",
+            stacktrace.thread_name.as_deref().unwrap_or("<unnamed>"),
+            stacktrace.thread_id,
+            if stacktrace.active { "" } else { " [idle]" },
+            if stacktrace.owns_gil { " [gil]" } else { "" },
+        ));
+        for frame in &stacktrace.frames {
+            text.push_str(&format!("    {}\n", frame_format.label(frame)));
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Frame, ProcessResources, StackTrace};
+
+    #[test]
+    fn frames_without_a_short_filename_show_the_filename() {
+        let line = JsonLine {
+            stacktraces: vec![StackTrace {
+                pid: 1234,
+                thread_id: 1,
+                thread_name: Some("MainThread".to_string()),
+                os_thread_id: None,
+                active: true,
+                owns_gil: true,
+                frames: vec![Frame {
+                    name: "PyObject_Call".to_string(),
+                    filename: "/usr/lib/libpython3.12.so".to_string(),
+                    module: Some("libpython3.12.so".to_string()),
+                    short_filename: None,
+                    line: 0,
+                    locals: None,
+                    is_entry: false,
+                }],
+                process_info: None,
+            }],
+            resources: ProcessResources::exited(),
+            index: 0,
+            time: 1,
+            time_micros: Some(1000),
+            sample_duration_micros: 0,
+            report: None,
+            sequence: None,
+        };
+
+        let text = format_process("1234", &line, &FrameFormat::default());
+        assert!(text.contains("    PyObject_Call (/usr/lib/libpython3.12.so:0)\n"));
+    }
+}
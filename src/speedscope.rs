@@ -0,0 +1,269 @@
+use crate::types::{Frame, JsonLine, METADATA_FILE_NAME, Metadata, ProcessResources, StackTrace};
+use regex::Regex;
+use serde::Deserialize;
+use snafu::{Location, OptionExt, ResultExt, Snafu};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::LazyLock;
+use std::time::SystemTime;
+
+#[derive(Debug, Snafu)]
+pub enum ImportError {
+    #[snafu(display("Error reading speedscope file `{path}` at {location}"))]
+    ReadSpeedscope {
+        source: std::io::Error,
+        path: String,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display("Error deserializing speedscope file `{path}` at {location}"))]
+    DeserializeSpeedscope {
+        source: serde_json::Error,
+        path: String,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display(
+        "Sample of profile `{profile}` refers to unknown frame {frame} at {location}"
+    ))]
+    UnknownFrame {
+        profile: String,
+        frame: usize,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display("Error creating data directory `{path}` at {location}"))]
+    CreateDataDir {
+        source: std::io::Error,
+        path: String,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display("Error writing report `{path}` at {location}"))]
+    WriteReport {
+        source: std::io::Error,
+        path: String,
+        #[snafu(implicit)]
+        location: Location,
+    },
+}
+
+/// The parts of a speedscope file we need, see
+/// <https://www.speedscope.app/file-format-schema.json>. Unknown fields are ignored.
+#[derive(Deserialize, Debug)]
+struct SpeedscopeFile {
+    shared: SpeedscopeShared,
+    profiles: Vec<SpeedscopeProfile>,
+}
+
+#[derive(Deserialize, Debug)]
+struct SpeedscopeShared {
+    frames: Vec<SpeedscopeFrame>,
+}
+
+#[derive(Deserialize, Debug)]
+struct SpeedscopeFrame {
+    name: String,
+    #[serde(default)]
+    file: Option<String>,
+    #[serde(default)]
+    line: Option<i32>,
+}
+
+/// Only sampled profiles are supported, which is what py-spy writes.
+#[derive(Deserialize, Debug)]
+struct SpeedscopeProfile {
+    name: String,
+    #[serde(default)]
+    unit: String,
+    /// The frame indices of every sample, outermost first
+    samples: Vec<Vec<usize>>,
+    weights: Vec<f64>,
+}
+
+/// The process and thread py-spy names its profiles after, e.g. `Process 1234 Thread 0x7F12
+/// "MainThread"`.
+static PROFILE_NAME: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r#"^Process (\d+) Thread (0[xX][0-9a-fA-F]+|\d+)(?: "(.*)")?"#).unwrap()
+});
+
+/// The process and thread a speedscope profile belongs to.
+struct ProfileThread {
+    pid: u32,
+    thread_id: u64,
+    thread_name: Option<String>,
+}
+
+impl ProfileThread {
+    /// Parses py-spy's profile name. Profiles of other tools get pid 0 and are told apart by
+    /// their index, keeping their name as the thread name.
+    fn parse(name: &str, index: usize) -> Self {
+        let parsed = PROFILE_NAME.captures(name).and_then(|captures| {
+            let pid = captures[1].parse().ok()?;
+            let thread_id = match captures[2]
+                .strip_prefix("0x")
+                .or(captures[2].strip_prefix("0X"))
+            {
+                Some(hex) => u64::from_str_radix(hex, 16).ok()?,
+                None => captures[2].parse().ok()?,
+            };
+            Some(Self {
+                pid,
+                thread_id,
+                thread_name: captures.get(3).map(|it| it.as_str().to_string()),
+            })
+        });
+        parsed.unwrap_or_else(|| Self {
+            pid: 0,
+            thread_id: index as u64,
+            thread_name: Some(name.to_string()),
+        })
+    }
+}
+
+/// How many microseconds a weight of one is, or `None` if the weights count samples.
+fn unit_micros(unit: &str) -> Option<f64> {
+    match unit {
+        "nanoseconds" => Some(0.001),
+        "microseconds" => Some(1.0),
+        "milliseconds" => Some(1000.0),
+        "seconds" => Some(1_000_000.0),
+        _ => None,
+    }
+}
+
+/// Converts a speedscope file, e.g. written by `py-spy record --format speedscope`, into a data
+/// directory the viewer and the exporters can read. Speedscope files have no timestamps, so the
+/// samples are spread `interval_millis` apart, starting now. All resources are zero.
+pub fn run_import(
+    speedscope: &Path,
+    output_dir: &Path,
+    interval_millis: u64,
+) -> Result<(), ImportError> {
+    let path = speedscope.display().to_string();
+    let content =
+        std::fs::read_to_string(speedscope).context(ReadSpeedscopeSnafu { path: &path })?;
+    let file = serde_json::from_str::<SpeedscopeFile>(&content)
+        .context(DeserializeSpeedscopeSnafu { path })?;
+
+    let frames = file
+        .shared
+        .frames
+        .into_iter()
+        .map(|frame| Frame {
+            name: frame.name,
+            filename: frame.file.unwrap_or_default(),
+            module: None,
+            short_filename: None,
+            line: frame.line.unwrap_or(0),
+            locals: None,
+            is_entry: false,
+        })
+        .collect::<Vec<_>>();
+
+    let interval_micros = interval_millis.max(1) * 1000;
+    // The stacktraces of every process by the tick they fall into
+    let mut ticks = BTreeMap::<u32, BTreeMap<u64, Vec<StackTrace>>>::new();
+    for (index, profile) in file.profiles.into_iter().enumerate() {
+        let thread = ProfileThread::parse(&profile.name, index);
+        let process_ticks = ticks.entry(thread.pid).or_default();
+        let mut tick = 0;
+        for (sample, weight) in profile.samples.iter().zip(&profile.weights) {
+            // py-spy counts samples, other tools record how long a stack was seen
+            let repeat = match unit_micros(&profile.unit) {
+                Some(micros) => (weight * micros / interval_micros as f64).round() as u64,
+                None => *weight as u64,
+            };
+            if repeat == 0 {
+                continue;
+            }
+            let stack = sample
+                .iter()
+                .rev()
+                .map(|frame| {
+                    frames.get(*frame).cloned().context(UnknownFrameSnafu {
+                        profile: &profile.name,
+                        frame: *frame,
+                    })
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            for _ in 0..repeat {
+                process_ticks.entry(tick).or_default().push(StackTrace {
+                    pid: thread.pid as py_spy::Pid,
+                    thread_id: thread.thread_id,
+                    thread_name: thread.thread_name.clone(),
+                    os_thread_id: None,
+                    active: true,
+                    owns_gil: false,
+                    frames: stack.clone(),
+                    process_info: None,
+                });
+                tick += 1;
+            }
+        }
+    }
+
+    std::fs::create_dir_all(output_dir).context(CreateDataDirSnafu {
+        path: output_dir.display().to_string(),
+    })?;
+    let start_time_micros = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_micros();
+    for (pid, process_ticks) in ticks {
+        let lines = process_ticks
+            .into_iter()
+            .enumerate()
+            .map(|(index, (tick, stacktraces))| {
+                let time_micros = start_time_micros + (tick * interval_micros) as u128;
+                JsonLine {
+                    stacktraces,
+                    resources: ProcessResources {
+                        exited: false,
+                        ..ProcessResources::exited()
+                    },
+                    index,
+                    time: time_micros / 1000,
+                    time_micros: Some(time_micros),
+                    sample_duration_micros: 0,
+                    report: None,
+                    sequence: None,
+                }
+            });
+        write_lines(&output_dir.join(format!("{pid}.json")), lines)?;
+    }
+
+    let metadata = Metadata {
+        start_time: Some(start_time_micros / 1000),
+        sample_interval_millis: Some(interval_millis),
+        ..Default::default()
+    };
+    let metadata_path = output_dir.join(METADATA_FILE_NAME);
+    std::fs::write(&metadata_path, serde_json::to_vec(&metadata).unwrap()).context(
+        WriteReportSnafu {
+            path: metadata_path.display().to_string(),
+        },
+    )?;
+
+    Ok(())
+}
+
+fn write_lines(path: &Path, lines: impl Iterator<Item = JsonLine>) -> Result<(), ImportError> {
+    let display_path = path.display().to_string();
+    let file = File::create(path).context(WriteReportSnafu {
+        path: &display_path,
+    })?;
+    let mut writer = BufWriter::new(file);
+    for line in lines {
+        let mut serialized = serde_json::to_vec(&line).unwrap();
+        serialized.push(b'\n');
+        writer.write_all(&serialized).context(WriteReportSnafu {
+            path: &display_path,
+        })?;
+    }
+    writer
+        .flush()
+        .context(WriteReportSnafu { path: display_path })
+}
@@ -1,7 +1,17 @@
-use log::{debug, info};
-use py_spy::{Config, PythonSpy, StackTrace};
-use snafu::{Location, ResultExt, Snafu};
-use std::collections::HashMap;
+use crate::resources::SystemMeasurements;
+use crate::tracker::TrackerOptions;
+use crate::types::{BlockingMode, MAIN_THREAD_NAME};
+use log::{debug, info, warn};
+use py_spy::config::LockingStrategy;
+use py_spy::{Config, StackTrace};
+use regex::Regex;
+use snafu::{IntoError, Location, NoneError, Snafu};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::time::{Duration, Instant};
+use worker::SpyWorker;
+
+mod worker;
 
 #[derive(Debug, Snafu)]
 pub enum PySpyError {
@@ -11,88 +21,621 @@ pub enum PySpyError {
         #[snafu(implicit)]
         location: Location,
     },
+    #[snafu(display(
+        "py-spy does not support the Python version of process {pid}, retrying will not help. \
+         Profile it with a py-spy build that supports it. ({message}) at {location}"
+    ))]
+    UnsupportedVersion {
+        pid: py_spy::Pid,
+        message: String,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display(
+        "Process {pid} is already traced by process {tracer}, e.g. a debugger or another \
+         profiler, and py-spy can not pause it as well, retrying will not help. Detach the other \
+         tracer or sample without pausing with `--blocking off` at {location}"
+    ))]
+    AlreadyTraced {
+        pid: py_spy::Pid,
+        tracer: u32,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display(
+        "Process {pid} was sampled without native frames right after attaching, e.g. because its \
+         interpreter is stripped, retrying will not help. Profile it without \
+         `--no-native-fallback` to record Python-only stacks at {location}"
+    ))]
+    NativeUnavailable {
+        pid: py_spy::Pid,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display("Error spawning the py-spy thread at {location}"))]
+    SpawnWorker {
+        source: std::io::Error,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display("The py-spy thread of process {pid} exited unexpectedly at {location}"))]
+    WorkerExited {
+        pid: py_spy::Pid,
+        #[snafu(implicit)]
+        location: Location,
+    },
+}
+
+impl PySpyError {
+    /// Whether attaching might succeed on a later attempt. A Python version py-spy does not
+    /// support stays unsupported, other tracers rarely detach on their own, and unwinding a
+    /// process keeps failing.
+    pub fn is_retryable(&self) -> bool {
+        !matches!(
+            self,
+            Self::UnsupportedVersion { .. }
+                | Self::AlreadyTraced { .. }
+                | Self::NativeUnavailable { .. }
+        )
+    }
+}
+
+/// How often attaching to a discovered child process is tried, once per refresh
+const MAX_ATTACH_ATTEMPTS: u32 = 5;
+
+/// How often a repeated sampling error is logged with `--rate-limit-log`
+pub const ERROR_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The last sampling error logged for a process with `--rate-limit-log`.
+struct LoggedError {
+    message: String,
+    logged_at: Instant,
+    /// How many identical errors were not logged since
+    suppressed: usize,
+}
+
+/// How many of the most common sampling errors are listed in a [`SampleErrorSummary`]
+const MOST_COMMON_ERRORS: usize = 3;
+
+/// How many samples were attempted and failed for each process
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SampleCounts {
+    pub attempted: usize,
+    pub failed: usize,
+}
+
+/// The sampling errors of a whole run, see [`SpyHelper::sample_error_summary`].
+#[derive(Debug, Default)]
+pub struct SampleErrorSummary {
+    pub per_pid: BTreeMap<py_spy::Pid, SampleCounts>,
+    /// The most common error messages and how often they occurred, most frequent first
+    pub most_common_errors: Vec<(String, usize)>,
+}
+
+impl SampleErrorSummary {
+    pub fn attempted(&self) -> usize {
+        self.per_pid.values().map(|it| it.attempted).sum()
+    }
+
+    pub fn failed(&self) -> usize {
+        self.per_pid.values().map(|it| it.failed).sum()
+    }
+}
+
+impl Display for SampleErrorSummary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let attempted = self.attempted();
+        let failed = self.failed();
+        write!(
+            f,
+            "{failed} of {attempted} samples failed ({:.1}%)",
+            failed as f64 / attempted.max(1) as f64 * 100.0
+        )?;
+        for (pid, counts) in self.per_pid.iter().filter(|(_, it)| it.failed > 0) {
+            write!(
+                f,
+                "\n  process {pid}: {} of {} failed",
+                counts.failed, counts.attempted
+            )?;
+        }
+        for (message, count) in &self.most_common_errors {
+            write!(f, "\n  {count}x {message}")?;
+        }
+        Ok(())
+    }
 }
 
 pub struct SpyHelper {
-    spies: HashMap<py_spy::Pid, PythonSpy>,
+    spies: HashMap<py_spy::Pid, SpyWorker>,
+    /// The executable of each tracked process at the time it was attached
+    executables: HashMap<py_spy::Pid, String>,
+    /// The start time of each tracked process, to notice when the OS reuses its pid
+    start_times: HashMap<py_spy::Pid, u64>,
+    /// Failed attach attempts of discovered child processes and re-executed processes, which are
+    /// often still initializing
+    pending: HashMap<py_spy::Pid, u32>,
+    /// Child processes we gave up attaching to
+    abandoned: HashSet<py_spy::Pid>,
+    /// The initially tracked processes that did not become a zombie yet
+    live_roots: HashSet<py_spy::Pid>,
+    sample_counts: HashMap<py_spy::Pid, SampleCounts>,
+    /// How often each sampling error message occurred
+    sample_errors: HashMap<String, usize>,
+    /// The last logged sampling error of each process, only kept with `--rate-limit-log`
+    logged_errors: Option<HashMap<py_spy::Pid, LoggedError>>,
+    max_concurrent_samples: Option<usize>,
+    /// The last process sampled in the previous tick, the next tick continues after it
+    last_sampled: Option<py_spy::Pid>,
     py_spy_config: Config,
+    /// How long to wait for py-spy before skipping a process
+    sample_timeout: Option<Duration>,
+    on_cpu_only: bool,
+    main_thread_only: bool,
+    follow_exec: bool,
+    exclude: Option<Regex>,
+    /// The processes whose stacktraces contained native frames, which need no further checks
+    native_verified: HashSet<py_spy::Pid>,
+    /// The processes sampled without native frames although they were requested, as py-spy
+    /// silently falls back to Python frames if unwinding fails
+    missing_native: Vec<u32>,
+    /// Check that a process provides native frames when attaching, see
+    /// [`TrackerOptions::no_native_fallback`]
+    require_native: bool,
+}
+
+fn log_suppressed_errors(pid: py_spy::Pid, logged: &LoggedError) {
+    if logged.suppressed > 0 {
+        info!(
+            "Suppressed {} similar sample errors for pid {pid}: {}",
+            logged.suppressed, logged.message
+        );
+    }
+}
+
+/// The pid of the debugger or profiler tracing the process, if any.
+#[cfg(target_os = "linux")]
+fn tracer_pid(pid: py_spy::Pid) -> Option<u32> {
+    let status = std::fs::read_to_string(format!("/proc/{pid}/status")).ok()?;
+    let tracer = status
+        .lines()
+        .find_map(|line| line.strip_prefix("TracerPid:"))?
+        .trim()
+        .parse()
+        .ok()?;
+    // 0 is not traced
+    (tracer != 0).then_some(tracer)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn tracer_pid(_pid: py_spy::Pid) -> Option<u32> {
+    None
+}
+
+/// Whether any of the stacktraces has a native frame, which every native stack has at least below
+/// the interpreter loop. py-spy only sets the module of native frames, see [`Frame::is_native`].
+///
+/// [`Frame::is_native`]: crate::types::Frame::is_native
+fn has_native_frames(traces: &[StackTrace]) -> bool {
+    traces
+        .iter()
+        .flat_map(|trace| &trace.frames)
+        .any(|frame| frame.module.is_some())
+}
+
+/// Keeps only the stacktrace of the main thread, i.e. the one whose OS thread id is the pid or,
+/// failing that, the one named like the main thread. Without either, nothing is kept.
+fn retain_main_thread(pid: py_spy::Pid, traces: &mut Vec<StackTrace>) {
+    let by_os_thread_id = traces
+        .iter()
+        .position(|trace| trace.os_thread_id == Some(pid as u64));
+    let main_thread = by_os_thread_id.or_else(|| {
+        traces
+            .iter()
+            .position(|trace| trace.thread_name.as_deref() == Some(MAIN_THREAD_NAME))
+    });
+    match main_thread {
+        Some(index) => {
+            let main_thread = traces.swap_remove(index);
+            *traces = vec![main_thread];
+        }
+        None => traces.clear(),
+    }
 }
 
 impl SpyHelper {
-    pub fn new(root: py_spy::Pid, capture_native: bool) -> Result<Self, PySpyError> {
+    pub fn new(roots: &[py_spy::Pid], options: &TrackerOptions) -> Result<Self, PySpyError> {
         let mut helper = Self {
             spies: HashMap::new(),
+            executables: HashMap::new(),
+            start_times: HashMap::new(),
+            pending: HashMap::new(),
+            abandoned: HashSet::new(),
+            live_roots: roots.iter().copied().collect(),
+            sample_counts: HashMap::new(),
+            sample_errors: HashMap::new(),
+            logged_errors: options.rate_limit_log.then(HashMap::new),
+            max_concurrent_samples: options.max_concurrent_samples,
+            last_sampled: None,
             py_spy_config: Config {
-                native: capture_native,
+                native: options.capture_native,
+                blocking: match options.blocking {
+                    BlockingMode::Auto | BlockingMode::On => LockingStrategy::Lock,
+                    BlockingMode::Off => LockingStrategy::NonBlocking,
+                },
                 ..Default::default()
             },
+            sample_timeout: options.sample_timeout,
+            on_cpu_only: options.on_cpu_only,
+            main_thread_only: options.main_thread_only,
+            follow_exec: options.follow_exec,
+            exclude: options.exclude.clone(),
+            native_verified: HashSet::new(),
+            missing_native: Vec::new(),
+            require_native: options.capture_native && options.no_native_fallback,
         };
-        helper.track_process(root)?;
+        for root in roots {
+            helper.track_process(*root)?;
+        }
 
         Ok(helper)
     }
 
+    /// Whether any process is still tracked or about to be re-attached. Once all roots became
+    /// zombies, py-spy might still see their children, but we are done.
     pub fn any_live(&self) -> bool {
-        !self.spies.is_empty()
+        (!self.spies.is_empty() || !self.pending.is_empty()) && !self.live_roots.is_empty()
     }
 
-    pub fn refresh(&mut self) {
+    /// Whether the command line of the process matches the exclude pattern.
+    fn is_excluded(&self, system: &SystemMeasurements, pid: py_spy::Pid) -> bool {
+        let Some(exclude) = &self.exclude else {
+            return false;
+        };
+        system
+            .process_command_line(sysinfo::Pid::from_u32(pid as u32))
+            .is_some_and(|args| exclude.is_match(&args.join(" ")))
+    }
+
+    /// Discovers new child processes and stops tracking exited or excluded ones. The system
+    /// measurements must be refreshed before, as they provide the command lines.
+    pub fn refresh(&mut self, system: &SystemMeasurements) {
         let mut to_remove = Vec::new();
         let mut new_processes = Vec::new();
+        let mut to_reattach = Vec::new();
 
         for spy in self.spies.values() {
-            if let Ok(children) = spy.process.child_processes() {
+            let children = spy.call(self.sample_timeout, |spy| {
+                spy.process.child_processes().ok()
+            });
+            if let Some(children) = children.flatten() {
                 for (child, _) in children {
-                    if self.spies.contains_key(&child) {
+                    if self.spies.contains_key(&child) || self.abandoned.contains(&child) {
+                        continue;
+                    }
+                    if self.is_excluded(system, child) {
+                        debug!("Not tracking excluded process {child}");
                         continue;
                     }
                     new_processes.push(child);
                 }
             }
-            if let Err(e) = spy.process.exe() {
-                info!("Tracked process exited: {e}");
+            if self.is_excluded(system, spy.pid) {
+                info!(
+                    "Tracked process {} is now excluded, no longer tracking it",
+                    spy.pid
+                );
+                to_remove.push(spy.pid);
+                continue;
+            }
+            if system.is_zombie(sysinfo::Pid::from_u32(spy.pid as u32)) {
+                info!(
+                    "Tracked process {} is a zombie, no longer tracking it",
+                    spy.pid
+                );
+                self.live_roots.remove(&spy.pid);
                 to_remove.push(spy.pid);
+                continue;
+            }
+            if self.pid_reused(system, spy.pid) {
+                info!(
+                    "Process {} was replaced by a new process with the same pid, re-attaching",
+                    spy.pid
+                );
+                to_reattach.push(spy.pid);
+                continue;
+            }
+            let exe = spy.call(self.sample_timeout, |spy| {
+                spy.process.exe().map_err(|e| e.to_string())
+            });
+            match exe {
+                // Hanging processes are skipped when sampling, see `get_stacktraces`
+                None => {}
+                Some(Err(e)) => {
+                    info!("Tracked process exited: {e}");
+                    to_remove.push(spy.pid);
+                }
+                Some(Ok(exe))
+                    if self.follow_exec && self.executables.get(&spy.pid) != Some(&exe) =>
+                {
+                    info!("Tracked process {} executed {exe}, re-attaching", spy.pid);
+                    to_reattach.push(spy.pid);
+                }
+                Some(Ok(_)) => {}
             }
         }
 
         // Clean up exited processes
         for pid in to_remove {
             self.spies.remove(&pid);
+            self.executables.remove(&pid);
+            self.start_times.remove(&pid);
+        }
+
+        // The memory layout of a process changes completely on exec, so the old spy is useless
+        for pid in &to_reattach {
+            self.spies.remove(pid);
+            self.executables.remove(pid);
+            self.start_times.remove(pid);
+        }
+
+        // Forget pending processes that are gone. The ones that are no child of a tracked process,
+        // e.g. a root that executed another executable, are retried here.
+        self.pending.retain(|pid, _| {
+            new_processes.contains(pid)
+                || system
+                    .process_start_time(sysinfo::Pid::from_u32(*pid as u32))
+                    .is_some()
+        });
+        let retries = self
+            .pending
+            .keys()
+            .filter(|pid| !new_processes.contains(pid))
+            .copied()
+            .collect::<Vec<_>>();
+
+        // Right after an exec, the new interpreter is usually not initialized yet, so re-attaching
+        // is retried like attaching to a new child
+        for pid in to_reattach.into_iter().chain(retries) {
+            self.attach_child(pid);
         }
 
         // Add new processes
         for pid in new_processes {
-            if let Err(e) = self.track_process(pid) {
-                info!("Error tracking process {pid}: {e}");
+            self.attach_child(pid);
+        }
+
+        // Remember when the processes started, so a later refresh can detect pid reuse
+        for pid in self.spies.keys() {
+            if let Some(start_time) = system.process_start_time(sysinfo::Pid::from_u32(*pid as u32))
+            {
+                self.start_times.entry(*pid).or_insert(start_time);
             }
-            info!("Tracking new process {pid}");
         }
 
         debug!("Tracking {} processes", self.spies.len());
     }
 
+    /// Whether the process with the pid started at a different time than the one we attached to.
+    fn pid_reused(&self, system: &SystemMeasurements, pid: py_spy::Pid) -> bool {
+        let current = system.process_start_time(sysinfo::Pid::from_u32(pid as u32));
+        match (self.start_times.get(&pid), current) {
+            (Some(known), Some(current)) => *known != current,
+            _ => false,
+        }
+    }
+
+    /// Attaches to a discovered child process or re-attaches to one that executed another
+    /// executable. Failures are retried on the next refreshes, as the interpreter of a freshly
+    /// started process might not be ready yet.
+    fn attach_child(&mut self, pid: py_spy::Pid) {
+        match self.track_process(pid) {
+            Ok(()) if self.pending.remove(&pid).is_some() => {
+                info!("Tracking new process {pid} after retrying");
+            }
+            Ok(()) => info!("Tracking new process {pid}"),
+            Err(e) if !e.is_retryable() => {
+                info!("Giving up on process {pid}: {e}");
+                self.pending.remove(&pid);
+                self.abandoned.insert(pid);
+                // Makes the caller stop, as for processes that lose their native frames later
+                if matches!(e, PySpyError::NativeUnavailable { .. }) {
+                    self.missing_native.push(pid as u32);
+                }
+            }
+            Err(e) => {
+                let attempts = self.pending.entry(pid).or_insert(0);
+                *attempts += 1;
+                if *attempts < MAX_ATTACH_ATTEMPTS {
+                    debug!("Could not attach to process {pid} yet, retrying: {e}");
+                    return;
+                }
+                info!("Giving up on process {pid} after {MAX_ATTACH_ATTEMPTS} attempts: {e}");
+                self.pending.remove(&pid);
+                self.abandoned.insert(pid);
+            }
+        }
+    }
+
     fn track_process(&mut self, pid: py_spy::Pid) -> Result<(), PySpyError> {
-        let spy = PythonSpy::new(pid, &self.py_spy_config).context(CreateSnafu)?;
+        // Pausing the process needs ptrace, and a process has at most one tracer. py-spy only
+        // notices this once sampling fails.
+        let pauses = matches!(self.py_spy_config.blocking, LockingStrategy::Lock);
+        if let Some(tracer) = tracer_pid(pid).filter(|_| pauses) {
+            return Err(AlreadyTracedSnafu { pid, tracer }.into_error(NoneError));
+        }
+        let spy = SpyWorker::spawn(pid, &self.py_spy_config)?;
+        if self.require_native {
+            let traces = spy
+                .call(self.sample_timeout, |spy| spy.get_stack_traces().ok())
+                .flatten();
+            // A process without threads yet can not tell, it is checked once it is sampled
+            if traces.is_some_and(|traces| !traces.is_empty() && !has_native_frames(&traces)) {
+                return Err(NativeUnavailableSnafu { pid }.into_error(NoneError));
+            }
+        }
 
+        let exe = spy.call(self.sample_timeout, |spy| spy.process.exe().ok());
+        if let Some(exe) = exe.flatten() {
+            self.executables.insert(pid, exe);
+        }
         self.spies.insert(pid, spy);
 
         Ok(())
     }
 
+    /// The executable of a tracked process at the time it was attached.
+    pub fn executable(&self, pid: py_spy::Pid) -> Option<&str> {
+        self.executables.get(&pid).map(String::as_str)
+    }
+
+    /// The interpreter version of a tracked process, as detected by py-spy when attaching.
+    pub fn python_version(&self, pid: py_spy::Pid) -> Option<&str> {
+        self.spies.get(&pid).map(|spy| spy.version.as_str())
+    }
+
+    /// The processes to sample this tick. With `max_concurrent_samples`, this is the next batch
+    /// in pid order, wrapping around, so that every process is sampled regularly.
+    fn pids_to_sample(&mut self) -> Vec<py_spy::Pid> {
+        let mut pids = self.spies.keys().copied().collect::<Vec<_>>();
+        let Some(max) = self.max_concurrent_samples.filter(|max| *max < pids.len()) else {
+            return pids;
+        };
+        pids.sort_unstable();
+        // Pids are stable across ticks, unlike indices when processes come and go
+        let start = self
+            .last_sampled
+            .map(|last| pids.partition_point(|pid| *pid <= last))
+            .unwrap_or(0);
+        let batch = pids
+            .iter()
+            .cycle()
+            .skip(start)
+            .take(max)
+            .copied()
+            .collect::<Vec<_>>();
+        self.last_sampled = batch.last().copied();
+        batch
+    }
+
     pub fn get_stacktraces(&mut self) -> HashMap<py_spy::Pid, Vec<StackTrace>> {
         let mut all_traces = HashMap::new();
 
-        for spy in self.spies.values_mut() {
-            let process_traces = spy.get_stack_traces();
+        for pid in self.pids_to_sample() {
+            let Some(spy) = self.spies.get(&pid) else {
+                continue;
+            };
+            let process_traces = spy
+                .call(self.sample_timeout, |spy| {
+                    spy.get_stack_traces().map_err(|e| e.to_string())
+                })
+                .unwrap_or_else(|| {
+                    warn!("Sampling process {pid} timed out, skipping it");
+                    Err("sampling timed out".to_string())
+                });
+            let spy_pid = spy.pid;
+            self.log_sample_error(spy_pid, process_traces.as_ref().err());
+            let counts = self.sample_counts.entry(spy_pid).or_default();
+            counts.attempted += 1;
             if let Err(e) = process_traces {
-                info!("Sample error {}: {:?}", spy.pid, e);
+                counts.failed += 1;
+                *self.sample_errors.entry(e).or_default() += 1;
                 // This might cause null values in the output (i.e. we miss a timestep)!
                 // The viewer must account for that.
                 continue;
             }
-            all_traces.insert(spy.pid, process_traces.unwrap());
+            let mut process_traces = process_traces.unwrap();
+            if self.py_spy_config.native {
+                self.check_native_frames(pid, &process_traces);
+            }
+            // py-spy marks threads that are likely idle (waiting, sleeping) as inactive
+            if self.on_cpu_only {
+                process_traces.retain(|trace| trace.active);
+            }
+            if self.main_thread_only {
+                retain_main_thread(pid, &mut process_traces);
+            }
+            all_traces.insert(spy_pid, process_traces);
         }
 
         all_traces
     }
+
+    /// Logs the sampling error of a process, if any. With `--rate-limit-log`, an error identical
+    /// to the last one is only logged once per [`ERROR_LOG_INTERVAL`], and the number of
+    /// suppressed errors is logged before the next one or once sampling succeeds again.
+    fn log_sample_error(&mut self, pid: py_spy::Pid, error: Option<&String>) {
+        let Some(logged_errors) = &mut self.logged_errors else {
+            if let Some(e) = error {
+                info!("Sample error {pid}: {e}");
+            }
+            return;
+        };
+        let Some(e) = error else {
+            if let Some(logged) = logged_errors.remove(&pid) {
+                log_suppressed_errors(pid, &logged);
+            }
+            return;
+        };
+        match logged_errors.get_mut(&pid) {
+            Some(logged)
+                if logged.message == *e && logged.logged_at.elapsed() < ERROR_LOG_INTERVAL =>
+            {
+                logged.suppressed += 1;
+            }
+            logged => {
+                if let Some(logged) = logged {
+                    log_suppressed_errors(pid, logged);
+                }
+                info!("Sample error {pid}: {e}");
+                logged_errors.insert(
+                    pid,
+                    LoggedError {
+                        message: e.clone(),
+                        logged_at: Instant::now(),
+                        suppressed: 0,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Notices processes whose stacktraces lack native frames.
+    fn check_native_frames(&mut self, pid: py_spy::Pid, traces: &[StackTrace]) {
+        if traces.is_empty()
+            || self.native_verified.contains(&pid)
+            || self.missing_native.contains(&(pid as u32))
+        {
+            return;
+        }
+        if has_native_frames(traces) {
+            self.native_verified.insert(pid);
+        } else {
+            warn!(
+                "Process {pid} was sampled without native frames, unwinding it failed and only \
+                Python frames are recorded"
+            );
+            self.missing_native.push(pid as u32);
+        }
+    }
+
+    /// The processes sampled without native frames although they were requested.
+    pub fn missing_native_frames(&self) -> &[u32] {
+        &self.missing_native
+    }
+
+    /// Summarizes how many samples failed over the whole run, and why.
+    pub fn sample_error_summary(&self) -> SampleErrorSummary {
+        let mut most_common_errors = self
+            .sample_errors
+            .iter()
+            .map(|(message, count)| (message.clone(), *count))
+            .collect::<Vec<_>>();
+        most_common_errors.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        most_common_errors.truncate(MOST_COMMON_ERRORS);
+
+        SampleErrorSummary {
+            per_pid: self.sample_counts.iter().map(|(k, v)| (*k, *v)).collect(),
+            most_common_errors,
+        }
+    }
 }
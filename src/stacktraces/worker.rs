@@ -0,0 +1,99 @@
+use super::{
+    CreateSnafu, PySpyError, SpawnWorkerSnafu, UnsupportedVersionSnafu, WorkerExitedSnafu,
+};
+use py_spy::{Config, PythonSpy};
+use snafu::{IntoError, NoneError, ResultExt};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, mpsc};
+use std::thread;
+use std::time::Duration;
+
+type Job = Box<dyn FnOnce(&mut PythonSpy) + Send>;
+
+/// The message py-spy fails with if it can not read the interpreter state of the Python version
+/// of a process, e.g. a release newer than py-spy.
+const UNSUPPORTED_VERSION_MESSAGE: &str = "Unsupported version of Python";
+
+/// The cause of the error if py-spy does not support the Python version of the process.
+fn unsupported_version(error: &anyhow::Error) -> Option<String> {
+    error
+        .chain()
+        .map(|cause| cause.to_string())
+        .find(|cause| cause.contains(UNSUPPORTED_VERSION_MESSAGE))
+}
+
+/// Owns the py-spy instance of a process on a dedicated thread, as it can not be moved between
+/// threads. This allows abandoning calls that take too long, e.g. if the process is in a weird
+/// ptrace state, instead of blocking the whole monitor.
+pub(super) struct SpyWorker {
+    pub pid: py_spy::Pid,
+    /// The interpreter version detected when attaching, e.g. `3.11.6`
+    pub version: String,
+    jobs: mpsc::Sender<Job>,
+    /// Whether a call is still running, possibly after it timed out
+    busy: Arc<AtomicBool>,
+}
+
+impl SpyWorker {
+    /// Attaches py-spy to the process on a new thread, which exits once the worker is dropped.
+    pub fn spawn(pid: py_spy::Pid, config: &Config) -> Result<Self, PySpyError> {
+        let config = config.clone();
+        let (init_tx, init_rx) = mpsc::channel();
+        let (jobs, job_rx) = mpsc::channel::<Job>();
+        thread::Builder::new()
+            .name(format!("py-spy-{pid}"))
+            .spawn(move || {
+                let mut spy = match PythonSpy::new(pid, &config) {
+                    Ok(spy) => spy,
+                    Err(e) => {
+                        let _ = init_tx.send(Err(e));
+                        return;
+                    }
+                };
+                let _ = init_tx.send(Ok(spy.version.to_string()));
+                for job in job_rx {
+                    job(&mut spy);
+                }
+            })
+            .context(SpawnWorkerSnafu)?;
+
+        let version = init_rx
+            .recv()
+            .map_err(|_| WorkerExitedSnafu { pid }.into_error(NoneError))?
+            .map_err(|e| match unsupported_version(&e) {
+                Some(message) => UnsupportedVersionSnafu { pid, message }.into_error(NoneError),
+                None => CreateSnafu.into_error(e),
+            })?;
+        Ok(Self {
+            pid,
+            version,
+            jobs,
+            busy: Arc::new(AtomicBool::new(false)),
+        })
+    }
+
+    /// Runs the function on the worker thread. Returns `None` if it did not finish within the
+    /// timeout, or an earlier call that timed out is still running.
+    pub fn call<T: Send + 'static>(
+        &self,
+        timeout: Option<Duration>,
+        f: impl FnOnce(&mut PythonSpy) -> T + Send + 'static,
+    ) -> Option<T> {
+        if self.busy.swap(true, Ordering::AcqRel) {
+            return None;
+        }
+        let busy = self.busy.clone();
+        let (tx, rx) = mpsc::channel();
+        let job: Job = Box::new(move |spy| {
+            let result = f(spy);
+            busy.store(false, Ordering::Release);
+            let _ = tx.send(result);
+        });
+        self.jobs.send(job).ok()?;
+
+        match timeout {
+            Some(timeout) => rx.recv_timeout(timeout).ok(),
+            None => rx.recv().ok(),
+        }
+    }
+}
@@ -0,0 +1,309 @@
+use crate::diff::format_bytes;
+use crate::export::{self, FrameFormat, ReadOptions, ReportIdentifier};
+use crate::types::{ALLOCATIONS_FILE_NAME, AllocationSnapshot, JsonLine, Metadata};
+use snafu::{Location, ResultExt, Snafu, Whatever};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io::ErrorKind;
+use std::path::Path;
+
+/// The number of allocation sites shown per process
+const TOP_ALLOCATION_SITES: usize = 10;
+
+#[derive(Debug, Snafu)]
+pub enum StatsError {
+    #[snafu(display("Error reading report `{path}` at {location}"))]
+    ReadReport {
+        source: Whatever,
+        path: String,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display("Error reading allocations `{path}` at {location}"))]
+    ReadAllocations {
+        source: std::io::Error,
+        path: String,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display("Error deserializing allocations `{path}` at {location}"))]
+    DeserializeAllocations {
+        source: serde_json::Error,
+        path: String,
+        #[snafu(implicit)]
+        location: Location,
+    },
+}
+
+/// Aggregated numbers of a single process.
+#[derive(Debug)]
+struct ProcessStats {
+    id: ReportIdentifier,
+    samples: usize,
+    peak_memory: u64,
+    mean_cpu: f64,
+    /// The CPU time used since the process started, as of the last sample
+    cpu_time_ms: u64,
+    peak_threads: usize,
+    /// How long the process had been running when it was sampled first, in milliseconds
+    uptime_at_first_sample: Option<u128>,
+    command_line: Option<String>,
+    python_version: Option<String>,
+    /// Whether the CPU affinity or nice value changed during the run
+    affinity_changed: bool,
+    nice_changed: bool,
+}
+
+impl ProcessStats {
+    fn new(id: ReportIdentifier, lines: &[JsonLine], metadata: &Metadata) -> Self {
+        let peak_memory = lines
+            .iter()
+            .map(|line| line.resources.memory)
+            .max()
+            .unwrap_or(0);
+        let mean_cpu = if lines.is_empty() {
+            0.0
+        } else {
+            lines
+                .iter()
+                .map(|line| line.resources.cpu as f64)
+                .sum::<f64>()
+                / lines.len() as f64
+        };
+        // Samples of an exited process have no CPU time, so the largest one is the latest
+        let cpu_time_ms = lines
+            .iter()
+            .map(|line| line.resources.cpu_time_ms)
+            .max()
+            .unwrap_or(0);
+        let peak_threads = lines
+            .iter()
+            .map(|line| line.resources.thread_count())
+            .max()
+            .unwrap_or(0);
+        let uptime_at_first_sample = lines
+            .first()
+            .zip(
+                id.metadata_pid()
+                    .and_then(|pid| metadata.process_start_times.get(&pid)),
+            )
+            .map(|(line, start)| line.time.saturating_sub(*start as u128 * 1000));
+
+        let affinities = lines
+            .iter()
+            .filter_map(|line| line.resources.cpu_affinity.as_ref())
+            .collect::<Vec<_>>();
+        let nice_values = lines
+            .iter()
+            .filter_map(|line| line.resources.nice)
+            .collect::<Vec<_>>();
+
+        Self {
+            id,
+            samples: lines.iter().map(|line| line.stacktraces.len()).sum(),
+            peak_memory,
+            mean_cpu,
+            cpu_time_ms,
+            peak_threads,
+            uptime_at_first_sample,
+            command_line: id
+                .metadata_pid()
+                .and_then(|pid| metadata.process_command_lines.get(&pid))
+                .map(|args| args.join(" ")),
+            python_version: id
+                .metadata_pid()
+                .and_then(|pid| metadata.process_python_versions.get(&pid))
+                .cloned(),
+            affinity_changed: affinities.windows(2).any(|it| it[0] != it[1]),
+            nice_changed: nice_values.windows(2).any(|it| it[0] != it[1]),
+        }
+    }
+}
+
+/// Prints summary statistics for every process of a captured profile, and the `top` functions
+/// with the most samples if requested.
+pub fn run_stats(
+    data_dir: &Path,
+    top: Option<usize>,
+    frame_format: &FrameFormat,
+) -> Result<(), StatsError> {
+    let path = data_dir.display().to_string();
+    let report = export::read_report(data_dir, &ReadOptions::default())
+        .context(ReadReportSnafu { path: &path })?;
+    let metadata = export::read_metadata(data_dir).context(ReadReportSnafu { path })?;
+
+    let mut stats = report
+        .iter()
+        .filter_map(|(id, lines)| match id {
+            ReportIdentifier::Pid { .. } | ReportIdentifier::Profiler => {
+                Some(ProcessStats::new(*id, lines, &metadata))
+            }
+            ReportIdentifier::Global => None,
+        })
+        .collect::<Vec<_>>();
+    stats.sort_by_key(|it| it.id);
+
+    if !metadata.labels.is_empty() {
+        println!("Labels: {}", metadata.format_labels());
+    }
+    if let Some(exit_code) = metadata.exit_code {
+        println!("Exit code: {exit_code}");
+    }
+
+    println!(
+        "{:>8}  {:>8}  {:>12}  {:>8}  {:>10}  {:>12}  {:>22}  {:>8}  Command",
+        "PID",
+        "Samples",
+        "Peak memory",
+        "Mean CPU",
+        "CPU time",
+        "Peak threads",
+        "Uptime at first sample",
+        "Python"
+    );
+    for process in &stats {
+        let uptime = process
+            .uptime_at_first_sample
+            .map(|millis| format!("{:.1}s", millis as f64 / 1000.0))
+            .unwrap_or_else(|| "unknown".to_string());
+        println!(
+            "{:>8}  {:>8}  {:>12}  {:>7.1}%  {:>9.1}s  {:>12}  {:>22}  {:>8}  {}",
+            process.id.to_string(),
+            process.samples,
+            format_bytes(process.peak_memory as f64),
+            process.mean_cpu,
+            process.cpu_time_ms as f64 / 1000.0,
+            process.peak_threads,
+            uptime,
+            process.python_version.as_deref().unwrap_or("unknown"),
+            process.command_line.as_deref().unwrap_or("")
+        );
+    }
+
+    for process in &stats {
+        if process.affinity_changed {
+            println!(
+                "Process {} changed its CPU affinity during the run",
+                process.id
+            );
+        }
+        if process.nice_changed {
+            println!(
+                "Process {} changed its nice value during the run",
+                process.id
+            );
+        }
+    }
+
+    if let Some(limit) = top {
+        print_top_functions(&report, limit, frame_format);
+    }
+
+    print_allocations(data_dir)
+}
+
+/// How often a function was sampled.
+#[derive(Debug, Default, Clone, Copy)]
+struct FunctionCounts {
+    /// Samples with the function at the top of the stack
+    self_samples: usize,
+    /// Samples with the function anywhere in the stack
+    total_samples: usize,
+}
+
+/// Prints the functions with the most samples at the top of the stack over all threads and
+/// processes, like a textual flamegraph.
+fn print_top_functions(
+    report: &HashMap<ReportIdentifier, Vec<JsonLine>>,
+    limit: usize,
+    frame_format: &FrameFormat,
+) {
+    let mut samples = 0;
+    let mut functions = HashMap::<String, FunctionCounts>::new();
+    for stacktrace in report.values().flatten().flat_map(|line| &line.stacktraces) {
+        samples += 1;
+        // The innermost frame comes first
+        if let Some(frame) = stacktrace.frames.first() {
+            let label = frame_format.label(frame);
+            functions.entry(label).or_default().self_samples += 1;
+        }
+        // Count every function once per stack, so recursion does not inflate its total
+        let labels = stacktrace
+            .frames
+            .iter()
+            .map(|frame| frame_format.label(frame))
+            .collect::<HashSet<_>>();
+        for label in labels {
+            functions.entry(label).or_default().total_samples += 1;
+        }
+    }
+
+    let mut functions = functions.into_iter().collect::<Vec<_>>();
+    functions.sort_by(|(a_label, a), (b_label, b)| {
+        (b.self_samples, b.total_samples, a_label).cmp(&(a.self_samples, a.total_samples, b_label))
+    });
+    let percent = |count: usize| count as f64 / samples.max(1) as f64 * 100.0;
+
+    println!();
+    println!("Top functions of {samples} stacktraces");
+    println!(
+        "{:>8}  {:>7}  {:>8}  {:>7}  Function",
+        "Self", "Self %", "Total", "Total %"
+    );
+    for (label, counts) in functions.iter().take(limit) {
+        println!(
+            "{:>8}  {:>6.1}%  {:>8}  {:>6.1}%  {label}",
+            counts.self_samples,
+            percent(counts.self_samples),
+            counts.total_samples,
+            percent(counts.total_samples)
+        );
+    }
+}
+
+/// Prints the top allocation sites of the latest snapshot of every process, if the target wrote
+/// any with tracemalloc.
+fn print_allocations(data_dir: &Path) -> Result<(), StatsError> {
+    let path = data_dir.join(ALLOCATIONS_FILE_NAME);
+    let display_path = path.display().to_string();
+    let content = match std::fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).context(ReadAllocationsSnafu { path: display_path }),
+    };
+
+    // tracemalloc reports the memory allocated right now, so only the latest snapshot matters
+    let mut latest = BTreeMap::new();
+    for line in content.lines().filter(|line| !line.trim().is_empty()) {
+        let snapshot = serde_json::from_str::<AllocationSnapshot>(line).context(
+            DeserializeAllocationsSnafu {
+                path: &display_path,
+            },
+        )?;
+        if latest
+            .get(&snapshot.pid)
+            .is_none_or(|it: &AllocationSnapshot| it.time <= snapshot.time)
+        {
+            latest.insert(snapshot.pid, snapshot);
+        }
+    }
+
+    for (pid, mut snapshot) in latest {
+        snapshot
+            .sites
+            .sort_by_key(|site| std::cmp::Reverse(site.size));
+        println!();
+        println!("Top allocation sites of process {pid}");
+        println!("{:>12}  {:>10}  Location", "Size", "Blocks");
+        for site in snapshot.sites.iter().take(TOP_ALLOCATION_SITES) {
+            println!(
+                "{:>12}  {:>10}  {}:{}",
+                format_bytes(site.size as f64),
+                site.count,
+                site.filename,
+                site.line
+            );
+        }
+    }
+
+    Ok(())
+}
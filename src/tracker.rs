@@ -1,14 +1,26 @@
+use crate::manifest::Manifest;
 use crate::resources::SystemMeasurements;
-use crate::stacktraces::{PySpyError, SpyHelper};
-use crate::types::{JsonLine, ProcessResources};
-use log::{trace, warn};
+use crate::sink::{FileSink, SampleSink, WRITER_FLUSH_INTERVAL};
+use crate::stacktraces::{PySpyError, SampleErrorSummary, SpyHelper};
+use crate::types::{
+    BlockingMode, CpuNormalization, JsonLine, MARKERS_FILE_NAME, METADATA_FILE_NAME, Marker,
+    Metadata, PROFILING_LOCK_FILE_NAME, PROFILING_LOCK_REFRESH_INTERVAL, ProcessInfo,
+    ProcessResources, ReportFormat, SINGLE_FILE_STEM,
+};
+use log::{debug, info, trace, warn};
 use py_spy::StackTrace;
+use regex::Regex;
+use serde::Serialize;
 use snafu::{Location, ResultExt, Snafu};
-use std::collections::HashMap;
-use std::fs::OpenOptions;
-use std::io::Write;
-use std::path::PathBuf;
-use std::time::SystemTime;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant, SystemTime};
 use std::{sync, thread};
 use sync::mpsc;
 
@@ -20,33 +32,385 @@ pub enum TrackerError {
         #[snafu(implicit)]
         location: Location,
     },
+    #[snafu(display("Error writing metadata file `{path}` at {location}"))]
+    WriteMetadata {
+        source: std::io::Error,
+        path: String,
+        #[snafu(implicit)]
+        location: Location,
+    },
+}
+
+impl TrackerError {
+    /// Whether creating the tracker might succeed on a later attempt.
+    fn is_retryable(&self) -> bool {
+        match self {
+            Self::PySpy { source, .. } => source.is_retryable(),
+            _ => true,
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
 struct WriteRequest {
     output_path: PathBuf,
+    /// The name of the report, e.g. `1234` or `global`
+    report: String,
     resources: ProcessResources,
     stacktraces: Vec<StackTrace>,
-    time: u128,
+    /// In microseconds since the unix epoch
+    time_micros: u128,
+    sample_duration_micros: u64,
+    /// See [`JsonLine::sequence`]
+    sequence: u64,
+}
+
+/// A message to the writer thread.
+enum WriterMessage {
+    Sample(WriteRequest),
+    /// Write the samples held in the ring buffer
+    FlushRingBuffer,
+    /// Call the callback for every following sample
+    Subscribe(SampleCallback),
+    /// The metadata changed, used for the headers of the following report files with
+    /// `--embed-metadata`
+    Metadata(Box<Metadata>),
+}
+
+/// A callback registered with [`Tracker::on_sample`].
+pub type SampleCallback = Box<dyn FnMut(&JsonLine) + Send>;
+
+/// The samples of the last few seconds, held in memory with `--ring-buffer` until they are
+/// flushed.
+struct RingBuffer {
+    window_micros: u128,
+    samples: VecDeque<WriteRequest>,
+}
+
+impl RingBuffer {
+    fn push(&mut self, request: WriteRequest) {
+        let newest = request.time_micros;
+        self.samples.push_back(request);
+        while self
+            .samples
+            .front()
+            .is_some_and(|oldest| oldest.time_micros + self.window_micros < newest)
+        {
+            self.samples.pop_front();
+        }
+    }
+}
+
+/// Where the samples of a report file continue, see [`SampleSink::resume`].
+struct ReportIndex {
+    /// The index of the next sample, continuous across all parts
+    next: usize,
+    /// Added to the sequence numbers, so they continue after the samples of an earlier run
+    sequence_offset: u64,
+}
+
+/// A process identified by its pid and start time. Pids reused by the OS get a higher generation,
+/// so the unrelated processes end up in separate reports.
+#[derive(Clone, Copy, Debug)]
+struct ProcessGeneration {
+    start_time: u64,
+    generation: u32,
+}
+
+/// Running statistics of the wall-clock time between ticks, in milliseconds.
+#[derive(Clone, Copy, Debug, Default)]
+struct IntervalStats {
+    count: u64,
+    sum: f64,
+    sum_of_squares: f64,
+}
+
+impl IntervalStats {
+    fn add(&mut self, millis: f64) {
+        self.count += 1;
+        self.sum += millis;
+        self.sum_of_squares += millis * millis;
+    }
+
+    fn mean_and_stddev(&self) -> Option<(f64, f64)> {
+        if self.count == 0 {
+            return None;
+        }
+        let mean = self.sum / self.count as f64;
+        let variance = (self.sum_of_squares / self.count as f64 - mean * mean).max(0.0);
+        Some((mean, variance.sqrt()))
+    }
+}
+
+/// A sample printed to stdout, which needs to say which report it belongs to.
+#[derive(Serialize)]
+struct StreamedSample<'a> {
+    /// The report name, e.g. `1234` or `global`
+    report: &'a str,
+    #[serde(flatten)]
+    line: &'a JsonLine,
+}
+
+/// Prints the sample of the report as a JSON line to stdout, e.g. to pipe it into `jq`. Logs go
+/// to stderr, so they do not interfere.
+fn stream_sample(report: &str, line: &JsonLine) -> std::io::Result<()> {
+    let mut json = serde_json::to_vec(&StreamedSample { report, line })?;
+    json.push(b'\n');
+
+    let mut stdout = std::io::stdout().lock();
+    stdout.write_all(&json)?;
+    stdout.flush()
+}
+
+/// Options controlling what the tracker captures and how it writes its output.
+#[derive(Debug, Clone, Default)]
+pub struct TrackerOptions {
+    /// Capture native stack frames in addition to Python frames
+    pub capture_native: bool,
+    /// Write gzip-compressed `<pid>.json.gz` files instead of plain JSON lines
+    pub compress_output: bool,
+    /// The encoding of the written samples
+    pub output_format: ReportFormat,
+    /// Only sample threads that are running on the CPU
+    pub on_cpu_only: bool,
+    /// Only keep the stacktrace of the main thread of every process
+    pub main_thread_only: bool,
+    /// Re-attach to tracked processes that `exec` a new executable, e.g. when a launcher execs
+    /// into the actual Python interpreter
+    pub follow_exec: bool,
+    /// Do not record the command lines of the tracked processes, as they might contain secrets
+    pub hide_command_lines: bool,
+    /// Replace the parts of command line arguments matching this pattern with `***` before they
+    /// are written anywhere
+    pub redact_args: Option<Regex>,
+    /// Do not track processes whose command line matches this pattern
+    pub exclude: Option<Regex>,
+    /// Do not sample the system-wide resources, which are written to `global.json` otherwise
+    pub skip_global: bool,
+    /// Also sample the resources of the profiler itself into `self.json`
+    pub track_self: bool,
+    /// Pretty-print the samples of JSON reports, separated by blank lines, to read them by hand
+    pub pretty_json: bool,
+    /// Also print every sample as a JSON line to stdout, tagged with the name of its report
+    pub stream_to_stdout: bool,
+    /// Write the samples of all processes into a single `samples.json`, tagged with the name of
+    /// their report, instead of one file per process. Exports group them by that name again, the
+    /// viewer and the HTML export do not support this layout.
+    pub single_file: bool,
+    /// Start every report file with a [`MetadataHeader`](crate::types::MetadataHeader) instead of
+    /// writing the sidecar [`METADATA_FILE_NAME`], so single files are self-describing. A header
+    /// holds the metadata from when its file was created.
+    pub embed_metadata: bool,
+    /// The requested time between samples, which is only recorded in the metadata. `None` if the
+    /// rate is not fixed.
+    pub sample_interval_millis: Option<u64>,
+    /// Only record the resources of this many threads with the highest CPU usage per process,
+    /// summing up the others
+    pub max_threads_recorded: Option<usize>,
+    /// Only sample this many processes per tick, cycling through all tracked processes over
+    /// several ticks
+    pub max_concurrent_samples: Option<usize>,
+    /// Skip a process if py-spy takes longer than this to sample it, e.g. because it is stuck in
+    /// a weird ptrace state. `None` waits forever.
+    pub sample_timeout: Option<Duration>,
+    /// Log repeated identical sampling errors of a process only once per
+    /// [`crate::stacktraces::ERROR_LOG_INTERVAL`], followed by how many were suppressed
+    pub rate_limit_log: bool,
+    /// How often a failed write of a sample is retried before the sample is dropped
+    pub write_retries: u32,
+    /// Write the samples of a report in batches of this many, or once they are
+    /// [`WRITER_FLUSH_INTERVAL`] old. 0 and 1 write every sample right away.
+    pub writer_batch: usize,
+    /// Keep at most this many bytes of a report on disk. It is continued in a new part
+    /// `<pid>.<n>.json` once the current one reached a quarter of this size, and the oldest parts
+    /// are deleted to stay below it. For the current part of compressed output, the uncompressed
+    /// size is counted.
+    pub max_output_size: Option<u64>,
+    /// Continue a report in a new part once the current one spans a quarter of this age, and
+    /// delete the parts whose samples are all older, keeping a rolling window on disk
+    pub max_file_age: Option<Duration>,
+    /// Clamp implausible CPU usages above this many percent, instead of 100% per core
+    pub max_cpu_percent: Option<f32>,
+    /// Round CPU usages to this many decimals, `None` keeps the full precision
+    pub cpu_decimals: Option<u32>,
+    /// What 100% CPU usage means in the reports
+    pub cpu_normalization: CpuNormalization,
+    /// Whether py-spy pauses the processes while sampling them
+    pub blocking: BlockingMode,
+    /// Only keep the samples of this last stretch of time in memory, instead of writing every
+    /// sample. They are written once [`Tracker::flush_ring_buffer`] is called, and dropped
+    /// otherwise.
+    pub ring_buffer: Option<Duration>,
+    /// The caller should stop once a process is sampled without native frames despite
+    /// `capture_native`, see [`Tracker::processes_missing_native_frames`]. Attaching to a process
+    /// fails right away if its first sample lacks them.
+    pub no_native_fallback: bool,
+    /// Keep the latest sample of every process in memory, see [`Tracker::latest_samples`]
+    pub live_view: bool,
+    /// Name the report files of processes after this template instead of `<pid>.json`
+    pub output_name: Option<OutputNameTemplate>,
+    /// Discard the samples of the first tick and of this long after it, as the first CPU usages
+    /// are meaningless and imports are noisy. `None` writes every sample.
+    pub warmup: Option<Duration>,
+    /// Labels recorded in the metadata, e.g. `experiment=baseline`
+    pub labels: BTreeMap<String, String>,
+}
+
+/// Names the report files of processes, e.g. `{command}-{pid}` for `python3-1234.json`. The pid
+/// comes last, so readers can still tell it from the name.
+#[derive(Debug, Clone)]
+pub struct OutputNameTemplate {
+    /// The part before `-{pid}`, possibly containing `{command}`
+    prefix: String,
+}
+
+impl OutputNameTemplate {
+    /// The name of the report file without extensions, where the report is `<pid>` or
+    /// `<pid>-<generation>`.
+    fn file_stem(&self, report: &str, executable: Option<&str>) -> String {
+        let mut command = executable
+            .and_then(|exe| Path::new(exe).file_name())
+            .map(|name| {
+                // Dashes would be mistaken for the separator of the pid, dots for the extension
+                name.to_string_lossy()
+                    .chars()
+                    .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+                    .collect::<String>()
+            })
+            .unwrap_or_else(|| "unknown".to_string());
+        if command.bytes().all(|b| b.is_ascii_digit()) {
+            command.insert(0, '_');
+        }
+        format!("{}-{report}", self.prefix.replace("{command}", &command))
+    }
+}
+
+impl FromStr for OutputNameTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let prefix = s
+            .strip_suffix("-{pid}")
+            .filter(|prefix| !prefix.is_empty())
+            .ok_or_else(|| format!("`{s}` must end in `-{{pid}}`, e.g. `{{command}}-{{pid}}`"))?;
+        let literal = prefix.replace("{command}", "");
+        if literal.contains(['{', '}', '.', '/', '\\']) {
+            return Err(format!(
+                "only `{{command}}` may be used before `-{{pid}}`, and no `.` or `/`, in `{s}`"
+            ));
+        }
+        if prefix
+            .rsplit('-')
+            .next()
+            .is_some_and(|last| last.bytes().all(|b| b.is_ascii_digit()))
+        {
+            return Err(format!(
+                "`{s}` must not have a number right before `-{{pid}}`"
+            ));
+        }
+        Ok(Self {
+            prefix: prefix.to_string(),
+        })
+    }
+}
+
+/// The resources and stacktraces of a process in the last tick.
+#[derive(Debug, Clone)]
+pub struct LatestSample {
+    pub resources: ProcessResources,
+    pub stacktraces: Vec<StackTrace>,
+}
+
+/// Masks all parts of the arguments matching the pattern, if there is one.
+pub fn redact_command_line(args: &[String], pattern: Option<&Regex>) -> Vec<String> {
+    args.iter().map(|arg| redact(arg, pattern)).collect()
+}
+
+fn redact(arg: &str, pattern: Option<&Regex>) -> String {
+    match pattern {
+        Some(pattern) => pattern.replace_all(arg, "***").into_owned(),
+        None => arg.to_string(),
+    }
+}
+
+/// Redacts the command lines py-spy attaches to stacktraces, including the ones of the parents.
+fn redact_process_info(info: &mut Option<Box<ProcessInfo>>, pattern: &Regex) {
+    let mut current = info.as_deref_mut();
+    while let Some(info) = current {
+        info.command_line = redact(&info.command_line, Some(pattern));
+        current = info.parent.as_deref_mut();
+    }
 }
 
 pub struct Tracker {
     spies: SpyHelper,
     system: SystemMeasurements,
-    output_dir: PathBuf,
-    writer_channel: mpsc::SyncSender<WriteRequest>,
+    /// `None` if the samples go to a sink other than the report files
+    output_dir: Option<PathBuf>,
+    compress_output: bool,
+    output_format: ReportFormat,
+    single_file: bool,
+    embed_metadata: bool,
+    record_command_lines: bool,
+    skip_global: bool,
+    track_self: bool,
+    redact_args: Option<Regex>,
+    metadata: Metadata,
+    /// The start time of each pid and how often the OS has reused it for a new process
+    generations: HashMap<u32, ProcessGeneration>,
+    last_cpu_usage: f32,
+    /// Converts the reported CPU usages back to 100% per core
+    cpu_scale: f32,
+    last_stacktrace_count: usize,
+    total_stacktrace_count: u64,
+    last_tick: Option<Instant>,
+    tick_intervals: IntervalStats,
+    output_name: Option<OutputNameTemplate>,
+    /// The names of the report files by report, fixed once a process was first sampled
+    file_stems: HashMap<String, String>,
+    warmup: Option<Duration>,
+    /// When the warmup ends, set in the first tick
+    warmup_end: Option<Instant>,
+    live_view: bool,
+    /// The samples of the last tick by report name, only kept with `live_view`
+    latest_samples: BTreeMap<String, LatestSample>,
+    /// The sequence number of the next sample of every report
+    sequences: HashMap<String, u64>,
+    writer_channel: mpsc::SyncSender<WriterMessage>,
+    writer_thread: JoinHandle<()>,
+    /// Set by the writer thread once it gave up on the output directory
+    writer_failed: Arc<AtomicBool>,
+    /// When the profiling lock was last touched
+    lock_refreshed: Instant,
 }
 
 impl Tracker {
+    /// Attaches to all of the given processes, retrying a few times if that fails. Children of
+    /// the processes are tracked automatically.
     pub fn new_with_retry(
-        pid: u32,
+        pids: &[u32],
         output_dir: PathBuf,
-        capture_native: bool,
+        options: &TrackerOptions,
+    ) -> Result<Self, TrackerError> {
+        Self::retry(|| Self::new(pids, output_dir.clone(), options))
+    }
+
+    /// Like [`Tracker::with_sink`], but retries attaching like [`Tracker::new_with_retry`]. Every
+    /// attempt gets a new sink.
+    pub fn with_sink_and_retry(
+        pids: &[u32],
+        mut sink: impl FnMut() -> Box<dyn SampleSink>,
+        options: &TrackerOptions,
     ) -> Result<Self, TrackerError> {
+        Self::retry(|| Self::with_sink(pids, sink(), options))
+    }
+
+    fn retry(mut attach: impl FnMut() -> Result<Self, TrackerError>) -> Result<Self, TrackerError> {
         let mut last_err = None;
         for _ in 0..5 {
-            match Self::new(pid, output_dir.clone(), capture_native) {
+            match attach() {
                 Ok(tracker) => return Ok(tracker),
+                Err(e) if !e.is_retryable() => return Err(e),
                 Err(e) => {
                     warn!("Got error during attach, will retry. ({e})");
                     last_err = Some(e);
@@ -57,85 +421,810 @@ impl Tracker {
         Err(last_err.unwrap())
     }
 
-    fn new(pid: u32, output_dir: PathBuf, capture_native: bool) -> Result<Self, TrackerError> {
-        let system = SystemMeasurements::new();
-        let spy_helper = SpyHelper::new(pid as py_spy::Pid, capture_native).context(PySpySnafu)?;
+    fn new(
+        pids: &[u32],
+        output_dir: PathBuf,
+        options: &TrackerOptions,
+    ) -> Result<Self, TrackerError> {
+        let sink = Box::new(FileSink::new(options));
+        Self::start(pids, Some(output_dir), sink, options)
+    }
 
-        let (tx, rx) = mpsc::sync_channel::<WriteRequest>(100);
+    /// Attaches to all of the given processes like [`Tracker::new_with_retry`], but hands the
+    /// samples to the sink instead of writing them to an output directory. Without one, no
+    /// metadata file, profiling lock or manifest is written, the report files passed to the sink
+    /// are just file names like `1234.json`.
+    pub fn with_sink(
+        pids: &[u32],
+        sink: Box<dyn SampleSink>,
+        options: &TrackerOptions,
+    ) -> Result<Self, TrackerError> {
+        Self::start(pids, None, sink, options)
+    }
 
-        thread::spawn(move || {
-            let mut file_lines = HashMap::new();
+    fn start(
+        pids: &[u32],
+        output_dir: Option<PathBuf>,
+        mut sink: Box<dyn SampleSink>,
+        options: &TrackerOptions,
+    ) -> Result<Self, TrackerError> {
+        let mut system = SystemMeasurements::new()
+            .with_max_threads_recorded(options.max_threads_recorded)
+            .with_max_cpu_percent(options.max_cpu_percent)
+            .with_cpu_decimals(options.cpu_decimals)
+            .with_cpu_normalization(options.cpu_normalization);
+        let cpu_cores = system.cpu_count();
+        let roots = pids
+            .iter()
+            .map(|pid| *pid as py_spy::Pid)
+            .collect::<Vec<_>>();
+        let spy_helper = SpyHelper::new(&roots, options).context(PySpySnafu)?;
+        let metadata = Metadata {
+            on_cpu_only: options.on_cpu_only,
+            start_time: Some(
+                SystemTime::now()
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap()
+                    .as_millis(),
+            ),
+            host_name: sysinfo::System::host_name(),
+            sample_interval_millis: options.sample_interval_millis,
+            capture_native: options.capture_native,
+            total_memory: Some(system.total_memory()),
+            cgroup_memory_limit: system.cgroup_memory_limit(),
+            process_start_times: BTreeMap::new(),
+            process_command_lines: BTreeMap::new(),
+            process_python_versions: BTreeMap::new(),
+            profiler_pid: options.track_self.then(std::process::id),
+            shared_tick_time: true,
+            chosen_sample_interval_millis: None,
+            labels: options.labels.clone(),
+            cpu_normalization: options.cpu_normalization,
+            cpu_cores: Some(cpu_cores),
+            blocking: options.blocking,
+            exit_code: None,
+        };
+        if let Some(output_dir) = &output_dir {
+            if !options.embed_metadata {
+                Self::write_metadata(output_dir, &metadata)?;
+            }
+            Self::write_lock(output_dir);
+        }
+        if options.embed_metadata {
+            sink.set_metadata(&metadata);
+        }
+
+        let (tx, rx) = mpsc::sync_channel::<WriterMessage>(100);
+        let redact_args = options.redact_args.clone();
+        let mut stream_to_stdout = options.stream_to_stdout;
+        let single_file = options.single_file;
+        let mut ring_buffer = options.ring_buffer.map(|window| RingBuffer {
+            window_micros: window.as_micros(),
+            samples: VecDeque::new(),
+        });
+
+        let writer_failed = Arc::new(AtomicBool::new(false));
+        let failed = writer_failed.clone();
+        let writer_thread = thread::spawn(move || {
+            let mut indices = HashMap::new();
+            let mut subscribers = Vec::<SampleCallback>::new();
 
-            while let Ok(req) = rx.recv() {
+            let mut write = |req: WriteRequest,
+                             subscribers: &mut [SampleCallback],
+                             sink: &mut dyn SampleSink| {
                 let path = req.output_path;
-                let line_index = file_lines.entry(path.clone()).or_insert(0);
+                let index = indices.entry(path.clone()).or_insert_with(|| {
+                    let resumed = sink.resume(&path);
+                    ReportIndex {
+                        next: resumed.next_index,
+                        sequence_offset: resumed.next_sequence,
+                    }
+                });
 
                 trace!("Writing stacktraces to {path:?}");
-                let mut file = OpenOptions::new()
-                    .create(true)
-                    .append(true)
-                    .open(&path)
-                    .unwrap();
-                let line = JsonLine {
-                    stacktraces: req.stacktraces.into_iter().map(|s| s.into()).collect(),
+                let mut stacktraces = req
+                    .stacktraces
+                    .into_iter()
+                    .map(crate::types::StackTrace::from)
+                    .collect::<Vec<_>>();
+                if let Some(pattern) = &redact_args {
+                    for stacktrace in &mut stacktraces {
+                        redact_process_info(&mut stacktrace.process_info, pattern);
+                    }
+                }
+                let mut line = JsonLine {
+                    stacktraces,
                     resources: req.resources,
-                    index: *line_index,
-                    time: req.time,
+                    index: index.next,
+                    time: req.time_micros / 1000,
+                    time_micros: Some(req.time_micros),
+                    sample_duration_micros: req.sample_duration_micros,
+                    report: None,
+                    sequence: Some(index.sequence_offset + req.sequence),
                 };
-                file.write_all(serde_json::to_string(&line).unwrap().as_bytes())
-                    .expect("Write succeeds");
-                file.write_all(b"\n").expect("Write succeeds");
+                // Streamed samples are tagged with their report anyway
+                if let Some(Err(e)) = stream_to_stdout.then(|| stream_sample(&req.report, &line)) {
+                    warn!("Could not print sample to stdout, no longer streaming: {e}");
+                    stream_to_stdout = false;
+                }
+                // Subscribers always get the report, but it is only written with a single file
+                line.report = Some(req.report);
+                for callback in subscribers.iter_mut() {
+                    callback(&line);
+                }
+                if !single_file {
+                    line.report = None;
+                }
+                sink.write(&path, &line);
+                index.next += 1;
+            };
 
-                *line_index += 1;
+            loop {
+                let message = match rx.recv_timeout(WRITER_FLUSH_INTERVAL) {
+                    Ok(message) => Some(message),
+                    Err(mpsc::RecvTimeoutError::Timeout) => None,
+                    Err(mpsc::RecvTimeoutError::Disconnected) => break,
+                };
+                match (message, &mut ring_buffer) {
+                    (None, _) => {}
+                    (Some(WriterMessage::Sample(req)), Some(ring_buffer)) => ring_buffer.push(req),
+                    (Some(WriterMessage::Sample(req)), None) => {
+                        write(req, &mut subscribers, sink.as_mut())
+                    }
+                    (Some(WriterMessage::FlushRingBuffer), Some(ring_buffer)) => {
+                        info!("Writing {} buffered samples", ring_buffer.samples.len());
+                        for req in ring_buffer.samples.drain(..) {
+                            write(req, &mut subscribers, sink.as_mut());
+                        }
+                    }
+                    (Some(WriterMessage::FlushRingBuffer), None) => {}
+                    (Some(WriterMessage::Subscribe(callback)), _) => subscribers.push(callback),
+                    (Some(WriterMessage::Metadata(metadata)), _) => sink.set_metadata(&metadata),
+                }
+                sink.flush();
+                if sink.failed() {
+                    failed.store(true, Ordering::Release);
+                }
             }
+
+            sink.finish();
         });
 
         Ok(Self {
             spies: spy_helper,
             system,
             output_dir,
+            compress_output: options.compress_output,
+            output_format: options.output_format,
+            single_file,
+            embed_metadata: options.embed_metadata,
+            record_command_lines: !options.hide_command_lines,
+            skip_global: options.skip_global,
+            track_self: options.track_self,
+            redact_args: options.redact_args.clone(),
+            metadata,
+            generations: HashMap::new(),
+            last_cpu_usage: 0.0,
+            cpu_scale: match options.cpu_normalization {
+                CpuNormalization::PerCore => 1.0,
+                CpuNormalization::Total => cpu_cores.max(1) as f32,
+            },
+            last_stacktrace_count: 0,
+            total_stacktrace_count: 0,
+            last_tick: None,
+            tick_intervals: IntervalStats::default(),
+            output_name: options.output_name.clone(),
+            file_stems: HashMap::new(),
+            warmup: options.warmup,
+            warmup_end: None,
+            live_view: options.live_view,
+            latest_samples: BTreeMap::new(),
+            sequences: HashMap::new(),
             writer_channel: tx,
+            writer_thread,
+            writer_failed,
+            lock_refreshed: Instant::now(),
+        })
+    }
+
+    /// Creates or touches the profiling lock, holding the pid of the profiler, to tell viewers
+    /// that the samples are still updated.
+    fn write_lock(output_dir: &Path) {
+        let path = output_dir.join(PROFILING_LOCK_FILE_NAME);
+        if let Err(e) = std::fs::write(&path, std::process::id().to_string()) {
+            debug!("Could not write profiling lock {path:?}: {e}");
+        }
+    }
+
+    fn write_metadata(output_dir: &Path, metadata: &Metadata) -> Result<(), TrackerError> {
+        let path = output_dir.join(METADATA_FILE_NAME);
+        let json = serde_json::to_string_pretty(metadata).expect("metadata is serializable");
+        std::fs::write(&path, json).context(WriteMetadataSnafu {
+            path: path.display().to_string(),
         })
     }
 
+    /// Writes the samples held in memory with [`TrackerOptions::ring_buffer`], e.g. because the
+    /// profiled process crashed. Does nothing without a ring buffer.
+    pub fn flush_ring_buffer(&self) {
+        self.writer_channel
+            .send(WriterMessage::FlushRingBuffer)
+            .expect("Send succeeds");
+    }
+
+    /// Calls the callback with every following sample of any report, with [`JsonLine::report`]
+    /// set, right before it is written. The callback runs on the writer thread, so it must be
+    /// `Send`, and writing waits for it. A slow callback thus eventually blocks [`Tracker::tick`]
+    /// once the queue of pending samples is full. With [`TrackerOptions::ring_buffer`], it is only
+    /// called once the samples are flushed.
+    pub fn on_sample(&self, callback: SampleCallback) {
+        self.writer_channel
+            .send(WriterMessage::Subscribe(callback))
+            .expect("Send succeeds");
+    }
+
+    /// Stops the tracker and waits for all pending samples to be written to disk. Afterwards, a
+    /// manifest of the report files is written to verify their integrity later and the profiling
+    /// lock is removed. With [`Tracker::with_sink`], the sink is finished instead.
+    pub fn finish(self) {
+        let Self {
+            writer_channel,
+            writer_thread,
+            output_dir,
+            ..
+        } = self;
+        // Closing the channel makes the writer thread flush its files and exit
+        drop(writer_channel);
+        if writer_thread.join().is_err() {
+            warn!("Writer thread panicked, the output might be incomplete");
+        }
+        let Some(output_dir) = output_dir else {
+            return;
+        };
+
+        if let Err(e) = Manifest::compute(&output_dir).and_then(|it| it.write(&output_dir)) {
+            warn!("Could not write manifest: {e}");
+        }
+        match std::fs::remove_file(output_dir.join(PROFILING_LOCK_FILE_NAME)) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => warn!("Could not remove profiling lock: {e}"),
+        }
+    }
+
+    /// The report file the samples of the report are written to, which is the same for all
+    /// reports with `--single-file`.
+    fn output_path(&self, report: &str) -> PathBuf {
+        let name = if self.single_file {
+            SINGLE_FILE_STEM
+        } else {
+            report
+        };
+        let extension = self.output_format.extension();
+        let output_dir = self.output_dir.as_deref().unwrap_or(Path::new(""));
+        if self.compress_output {
+            output_dir.join(format!("{name}.{extension}.gz"))
+        } else {
+            output_dir.join(format!("{name}.{extension}"))
+        }
+    }
+
+    /// The output path of a process report, named after the output name template if there is
+    /// one. The name stays the same once chosen, even if the process execs another executable.
+    fn process_output_path(&mut self, pid: py_spy::Pid, report: &str) -> PathBuf {
+        let Some(template) = &self.output_name else {
+            return self.output_path(report);
+        };
+        let stem = self
+            .file_stems
+            .entry(report.to_string())
+            .or_insert_with(|| template.file_stem(report, self.spies.executable(pid)))
+            .clone();
+        self.output_path(&stem)
+    }
+
+    /// The name of the report file of the process. The first process with a pid is written to
+    /// `<pid>.json`, later processes reusing the pid to `<pid>-<n>.json`.
+    fn report_name(&self, pid: u32) -> String {
+        match self.generations.get(&pid) {
+            Some(process) if process.generation > 0 => format!("{pid}-{}", process.generation),
+            _ => pid.to_string(),
+        }
+    }
+
+    /// Notices when a pid is reused for a new process and starts a new generation for it.
+    fn update_generations<'a>(&mut self, pids: impl Iterator<Item = &'a py_spy::Pid>) {
+        for pid in pids {
+            let pid = *pid as u32;
+            let Some(start_time) = self.system.process_start_time(sysinfo::Pid::from_u32(pid))
+            else {
+                continue;
+            };
+            match self.generations.entry(pid) {
+                Entry::Vacant(entry) => {
+                    entry.insert(ProcessGeneration {
+                        start_time,
+                        generation: 0,
+                    });
+                }
+                Entry::Occupied(mut entry) if entry.get().start_time != start_time => {
+                    let process = entry.get_mut();
+                    process.start_time = start_time;
+                    process.generation += 1;
+                    info!(
+                        "Pid {pid} was reused by a new process, writing it to a new report ({pid}-{})",
+                        process.generation
+                    );
+                }
+                Entry::Occupied(_) => {}
+            }
+        }
+    }
+
+    /// Remembers the start time, command line and Python version of processes sampled for the
+    /// first time, rewriting the metadata file if there were any.
+    fn record_process_info<'a>(&mut self, pids: impl Iterator<Item = &'a py_spy::Pid>) {
+        let mut changed = false;
+        for pid in pids {
+            let pid = *pid as u32;
+            if self.metadata.process_start_times.contains_key(&pid) {
+                continue;
+            }
+            let sysinfo_pid = sysinfo::Pid::from_u32(pid);
+            let Some(start_time) = self.system.process_start_time(sysinfo_pid) else {
+                continue;
+            };
+            self.metadata.process_start_times.insert(pid, start_time);
+            let command_line = self
+                .record_command_lines
+                .then(|| self.system.process_command_line(sysinfo_pid))
+                .flatten()
+                .map(|args| redact_command_line(&args, self.redact_args.as_ref()));
+            if let Some(command_line) = command_line {
+                self.metadata
+                    .process_command_lines
+                    .insert(pid, command_line);
+            }
+            if let Some(version) = self.spies.python_version(pid as py_spy::Pid) {
+                self.metadata
+                    .process_python_versions
+                    .insert(pid, version.to_string());
+            }
+            changed = true;
+        }
+
+        if changed {
+            self.update_metadata();
+        }
+    }
+
+    /// Rewrites the sidecar metadata file or, with `--embed-metadata`, hands the metadata to the
+    /// writer thread for the headers of the report files created from now on.
+    fn update_metadata(&self) {
+        if self.embed_metadata {
+            self.writer_channel
+                .send(WriterMessage::Metadata(Box::new(self.metadata.clone())))
+                .expect("Send succeeds");
+            return;
+        }
+        let Some(output_dir) = &self.output_dir else {
+            return;
+        };
+        if let Err(e) = Self::write_metadata(output_dir, &self.metadata) {
+            warn!("Could not update metadata: {e}");
+        }
+    }
+
+    /// The metadata of the run so far, e.g. to hand it to [`Tracker::continue_metadata`].
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// Keeps the start time and the processes of an earlier tracker that wrote to the same output
+    /// directory, e.g. before re-attaching to a restarted service, instead of overwriting them.
+    pub fn continue_metadata(&mut self, earlier: Metadata) {
+        self.metadata.start_time = earlier.start_time.or(self.metadata.start_time);
+        for (pid, start_time) in earlier.process_start_times {
+            self.metadata
+                .process_start_times
+                .entry(pid)
+                .or_insert(start_time);
+        }
+        for (pid, command_line) in earlier.process_command_lines {
+            self.metadata
+                .process_command_lines
+                .entry(pid)
+                .or_insert(command_line);
+        }
+        for (pid, version) in earlier.process_python_versions {
+            self.metadata
+                .process_python_versions
+                .entry(pid)
+                .or_insert(version);
+        }
+        self.update_metadata();
+    }
+
+    /// Records the exit code of the profiled command in the metadata.
+    pub fn record_exit_code(&mut self, exit_code: i32) {
+        self.metadata.exit_code = Some(exit_code);
+        self.update_metadata();
+    }
+
+    /// Records the time between samples an automatic sample rate settled on in the metadata.
+    pub fn record_chosen_sample_interval(&mut self, interval: Duration) {
+        self.metadata.chosen_sample_interval_millis = Some(interval.as_millis() as u64);
+        self.update_metadata();
+    }
+
+    /// Appends a marker at the current time to the [`MARKERS_FILE_NAME`] of the output directory,
+    /// shown on the main thread of the process that started first. Does nothing with
+    /// [`Tracker::with_sink`].
+    pub fn add_marker(&self, name: &str) {
+        let Some(output_dir) = &self.output_dir else {
+            return;
+        };
+        let marker = Marker {
+            time: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_millis(),
+            name: name.to_string(),
+            thread_id: None,
+            pid: None,
+        };
+        let mut json = serde_json::to_vec(&marker).expect("markers are serializable");
+        json.push(b'\n');
+
+        let path = output_dir.join(MARKERS_FILE_NAME);
+        let written = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(&json));
+        if let Err(e) = written {
+            warn!("Could not write marker `{name}` to {path:?}: {e}");
+        }
+    }
+
+    /// The mean and standard deviation of the actual time between ticks in milliseconds, which
+    /// includes the time sampling takes. `None` before the second tick.
+    pub fn tick_interval_stats(&self) -> Option<(f64, f64)> {
+        self.tick_intervals.mean_and_stddev()
+    }
+
+    /// The combined CPU usage of all tracked processes in the last tick, where 100% is one core.
+    pub fn last_cpu_usage(&self) -> f32 {
+        self.last_cpu_usage
+    }
+
+    /// How many samples failed so far, and why.
+    pub fn sample_error_summary(&self) -> SampleErrorSummary {
+        self.spies.sample_error_summary()
+    }
+
+    /// The processes whose stacktraces lacked native frames although they were requested, in the
+    /// order they were noticed.
+    pub fn processes_missing_native_frames(&self) -> &[u32] {
+        self.spies.missing_native_frames()
+    }
+
+    /// The samples of every process in the last tick by report name, empty unless
+    /// [`TrackerOptions::live_view`] is set.
+    pub fn latest_samples(&self) -> &BTreeMap<String, LatestSample> {
+        &self.latest_samples
+    }
+
+    /// The number of stacktraces, i.e. sampled threads, captured in the last tick.
+    pub fn last_stacktrace_count(&self) -> usize {
+        self.last_stacktrace_count
+    }
+
+    /// The number of stacktraces captured since the tracker started, summed over all ticks.
+    pub fn total_stacktrace_count(&self) -> u64 {
+        self.total_stacktrace_count
+    }
+
+    /// Whether the output directory vanished and could not be recreated repeatedly. Further
+    /// samples would be dropped, so the caller should stop.
+    pub fn writer_failed(&self) -> bool {
+        self.writer_failed.load(Ordering::Acquire)
+    }
+
     pub fn is_still_tracking(&self) -> bool {
         self.spies.any_live()
     }
 
+    /// Whether the samples of the tick at that time are discarded. Starts the warmup in the first
+    /// tick.
+    fn in_warmup(&mut self, now: Instant) -> bool {
+        let Some(warmup) = self.warmup else {
+            return false;
+        };
+        match self.warmup_end {
+            None => {
+                debug!("Discarding samples for the warmup of {warmup:?}");
+                self.warmup_end = Some(now + warmup);
+                true
+            }
+            Some(end) => now < end,
+        }
+    }
+
+    fn next_sequence(&mut self, report: &str) -> u64 {
+        let next = self.sequences.entry(report.to_string()).or_default();
+        *next += 1;
+        *next - 1
+    }
+
+    /// Sends the samples of the processes to the writer thread. Processes that exited since the
+    /// refresh are still written, see [`ProcessResources::exited`].
+    fn write_process_samples(
+        &mut self,
+        stacktraces: HashMap<py_spy::Pid, Vec<StackTrace>>,
+        time_micros: u128,
+        sample_duration_micros: u64,
+    ) {
+        for (pid, threads) in stacktraces {
+            let report = self.report_name(pid as u32);
+            // The process might have exited since the refresh. We still write its stacktraces,
+            // which is common for short-lived multiprocessing workers.
+            let info = self
+                .system
+                .get_process_info(sysinfo::Pid::from_u32(pid as u32))
+                .unwrap_or_else(|| {
+                    debug!("Process {pid} exited before its resources could be read");
+                    ProcessResources::exited()
+                });
+            self.last_cpu_usage += info.cpu * self.cpu_scale;
+            if self.live_view {
+                let sample = LatestSample {
+                    resources: info.clone(),
+                    stacktraces: threads.clone(),
+                };
+                self.latest_samples.insert(report.clone(), sample);
+            }
+
+            let sequence = self.next_sequence(&report);
+            self.writer_channel
+                .send(WriterMessage::Sample(WriteRequest {
+                    output_path: self.process_output_path(pid, &report),
+                    report,
+                    resources: info,
+                    stacktraces: threads,
+                    time_micros,
+                    sample_duration_micros,
+                    sequence,
+                }))
+                .expect("Send succeeds");
+        }
+    }
+
     pub fn tick(&mut self) {
+        let sample_start = Instant::now();
+        if let Some(last_tick) = self.last_tick.replace(sample_start) {
+            self.tick_intervals
+                .add((sample_start - last_tick).as_secs_f64() * 1000.0);
+        }
+        let refresh_lock = self.lock_refreshed.elapsed() >= PROFILING_LOCK_REFRESH_INTERVAL;
+        if let Some(output_dir) = self.output_dir.as_deref().filter(|_| refresh_lock) {
+            Self::write_lock(output_dir);
+            self.lock_refreshed = sample_start;
+        }
         self.system.refresh();
-        self.spies.refresh();
+        self.spies.refresh(&self.system);
 
+        // Every sample of this tick gets the same time, see `Metadata::shared_tick_time`
         let query_time = SystemTime::now()
             .duration_since(SystemTime::UNIX_EPOCH)
             .unwrap()
-            .as_millis();
+            .as_micros();
 
-        for (pid, threads) in self.spies.get_stacktraces() {
-            let Some(info) = self
-                .system
-                .get_process_info(sysinfo::Pid::from_u32(pid as u32))
-            else {
-                continue;
-            };
+        let stacktraces = self.spies.get_stacktraces();
+        let sample_duration_micros = sample_start.elapsed().as_micros() as u64;
+
+        self.update_generations(stacktraces.keys());
+        self.record_process_info(stacktraces.keys());
+        self.last_cpu_usage = 0.0;
+        self.last_stacktrace_count = stacktraces.values().map(Vec::len).sum();
+        self.latest_samples.clear();
+        if self.in_warmup(sample_start) {
+            return;
+        }
+        self.total_stacktrace_count += self.last_stacktrace_count as u64;
+
+        self.write_process_samples(stacktraces, query_time, sample_duration_micros);
 
+        if self.track_self {
+            let info = self
+                .system
+                .get_process_info(sysinfo::Pid::from_u32(std::process::id()))
+                .unwrap_or_else(ProcessResources::exited);
+            let sequence = self.next_sequence("self");
             self.writer_channel
-                .send(WriteRequest {
-                    output_path: self.output_dir.join(format!("{pid}.json")),
+                .send(WriterMessage::Sample(WriteRequest {
+                    output_path: self.output_path("self"),
+                    report: "self".to_string(),
                     resources: info,
-                    stacktraces: threads.clone(),
-                    time: query_time,
-                })
+                    stacktraces: vec![],
+                    time_micros: query_time,
+                    sample_duration_micros,
+                    sequence,
+                }))
                 .expect("Send succeeds");
         }
 
+        if self.skip_global {
+            return;
+        }
+        let sequence = self.next_sequence("global");
         self.writer_channel
-            .send(WriteRequest {
-                output_path: self.output_dir.join("global.json"),
+            .send(WriterMessage::Sample(WriteRequest {
+                output_path: self.output_path("global"),
+                report: "global".to_string(),
                 resources: self.system.get_global_info(),
                 stacktraces: vec![],
-                time: query_time,
-            })
+                time_micros: query_time,
+                sample_duration_micros,
+                sequence,
+            }))
             .expect("Send succeeds");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sink::MemorySink;
+
+    /// No process has this pid, Linux limits them to 2^22
+    const EXITED_PID: py_spy::Pid = 1 << 30;
+
+    fn stacktrace(pid: py_spy::Pid) -> StackTrace {
+        StackTrace {
+            pid,
+            thread_id: 1,
+            thread_name: Some("MainThread".to_string()),
+            os_thread_id: Some(pid as u64),
+            active: true,
+            owns_gil: true,
+            frames: Vec::new(),
+            process_info: None,
+        }
+    }
+
+    #[test]
+    fn writes_stacktraces_of_exited_processes() {
+        let sink = MemorySink::new();
+        let mut tracker =
+            Tracker::with_sink(&[], Box::new(sink.clone()), &TrackerOptions::default()).unwrap();
+
+        let stacktraces = HashMap::from([(EXITED_PID, vec![stacktrace(EXITED_PID)])]);
+        tracker.write_process_samples(stacktraces, 1_000_000, 0);
+        tracker.finish();
+
+        let samples = sink.samples();
+        assert_eq!(samples.len(), 1);
+        let (path, line) = &samples[0];
+        assert_eq!(path, Path::new(&format!("{EXITED_PID}.json")));
+        assert!(line.resources.exited);
+        assert_eq!(line.resources.memory, 0);
+        assert_eq!(line.stacktraces.len(), 1);
+        assert_eq!(line.stacktraces[0].pid, EXITED_PID);
+    }
+
+    #[test]
+    fn continues_the_index_of_an_existing_report() {
+        let output_dir = std::env::temp_dir().join(format!(
+            "py-crude-resource-monitor-resume-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&output_dir).unwrap();
+        let mut existing = String::new();
+        for index in 0..3 {
+            let line = JsonLine {
+                stacktraces: Vec::new(),
+                resources: ProcessResources::exited(),
+                index,
+                time: index as u128,
+                time_micros: Some(index as u128 * 1000),
+                sample_duration_micros: 0,
+                report: None,
+                sequence: Some(index as u64),
+            };
+            existing.push_str(&serde_json::to_string(&line).unwrap());
+            existing.push('\n');
+        }
+        std::fs::write(output_dir.join(format!("{EXITED_PID}.json")), existing).unwrap();
+
+        let mut tracker =
+            Tracker::new(&[], output_dir.clone(), &TrackerOptions::default()).unwrap();
+        let written = Arc::new(sync::Mutex::new(Vec::new()));
+        let on_sample = written.clone();
+        tracker.on_sample(Box::new(move |line| {
+            on_sample.lock().unwrap().push((line.index, line.sequence));
+        }));
+        let stacktraces = HashMap::from([(EXITED_PID, vec![stacktrace(EXITED_PID)])]);
+        tracker.write_process_samples(stacktraces, 1_000_000, 0);
+        tracker.finish();
+        std::fs::remove_dir_all(&output_dir).unwrap();
+
+        assert_eq!(*written.lock().unwrap(), [(3, Some(3))]);
+    }
+
+    #[test]
+    fn continues_after_the_pruned_parts_of_a_report() {
+        let output_dir = std::env::temp_dir().join(format!(
+            "py-crude-resource-monitor-resume-pruned-{}",
+            std::process::id()
+        ));
+        std::fs::create_dir_all(&output_dir).unwrap();
+        // Every sample ends up in a part of its own, of which only the last few are kept
+        let options = TrackerOptions {
+            max_output_size: Some(400),
+            ..TrackerOptions::default()
+        };
+        let mut tracker = Tracker::new(&[], output_dir.clone(), &options).unwrap();
+        for tick in 0..10 {
+            let stacktraces = HashMap::from([(EXITED_PID, vec![stacktrace(EXITED_PID)])]);
+            tracker.write_process_samples(stacktraces, 1_000_000 + tick * 1000, 0);
+        }
+        tracker.finish();
+        let first_part_pruned = !output_dir.join(format!("{EXITED_PID}.json")).exists();
+
+        let mut tracker = Tracker::new(&[], output_dir.clone(), &options).unwrap();
+        let written = Arc::new(sync::Mutex::new(Vec::new()));
+        let on_sample = written.clone();
+        tracker.on_sample(Box::new(move |line| {
+            on_sample.lock().unwrap().push((line.index, line.sequence));
+        }));
+        let stacktraces = HashMap::from([(EXITED_PID, vec![stacktrace(EXITED_PID)])]);
+        tracker.write_process_samples(stacktraces, 2_000_000, 0);
+        tracker.finish();
+        std::fs::remove_dir_all(&output_dir).unwrap();
+
+        assert!(first_part_pruned);
+        assert_eq!(*written.lock().unwrap(), [(10, Some(10))]);
+    }
+
+    #[test]
+    fn samples_of_a_tick_share_their_time() {
+        let sink = MemorySink::new();
+        let options = TrackerOptions {
+            track_self: true,
+            ..TrackerOptions::default()
+        };
+        let mut tracker = Tracker::with_sink(&[], Box::new(sink.clone()), &options).unwrap();
+        tracker.tick();
+        tracker.finish();
+
+        let samples = sink.samples();
+        let reports = samples
+            .iter()
+            .map(|(path, _)| path.to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(reports, ["self.json", "global.json"]);
+        assert_eq!(samples[0].1.time_micros, samples[1].1.time_micros);
+    }
+
+    #[test]
+    fn ticks_write_to_the_sink() {
+        let sink = MemorySink::new();
+        let mut tracker =
+            Tracker::with_sink(&[], Box::new(sink.clone()), &TrackerOptions::default()).unwrap();
+        tracker.tick();
+        tracker.tick();
+        tracker.finish();
+
+        let samples = sink.samples();
+        assert_eq!(samples.len(), 2);
+        for (expected, (path, line)) in samples.iter().enumerate() {
+            assert_eq!(path, Path::new("global.json"));
+            assert_eq!(line.index, expected);
+            assert_eq!(line.sequence, Some(expected as u64));
+            assert!(line.stacktraces.is_empty());
+            assert!(line.resources.memory > 0);
+        }
+        assert!(samples[0].1.timestamp_micros() <= samples[1].1.timestamp_micros());
+    }
+}
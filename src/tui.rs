@@ -0,0 +1,140 @@
+use crate::diff::format_bytes;
+use crate::export::FrameFormat;
+use crate::tracker::LatestSample;
+use crate::types::Frame as ReportFrame;
+use log::LevelFilter;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::Stylize;
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Row, Table};
+use ratatui::{DefaultTerminal, Frame};
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+/// The number of frames shown in the hottest frames table
+const HOTTEST_FRAMES: usize = 10;
+
+/// A `top`-like full-screen view of the profiled processes, redrawn after every tick.
+pub struct LiveView {
+    terminal: DefaultTerminal,
+    /// The log level before the view started, restored once it is dropped
+    log_level: LevelFilter,
+}
+
+impl LiveView {
+    /// Switches the terminal to the view. Log messages are suppressed until it is dropped, as they
+    /// would garble the screen.
+    pub fn start() -> std::io::Result<Self> {
+        let terminal = ratatui::try_init()?;
+        let log_level = log::max_level();
+        log::set_max_level(LevelFilter::Off);
+        Ok(Self {
+            terminal,
+            log_level,
+        })
+    }
+
+    pub fn render(&mut self, samples: &BTreeMap<String, LatestSample>) -> std::io::Result<()> {
+        self.terminal.draw(|frame| draw(frame, samples))?;
+        Ok(())
+    }
+
+    /// Waits for the timeout, returning `true` early if the user pressed `q` or CTRL+C. The
+    /// terminal is in raw mode, so CTRL+C does not send a signal.
+    pub fn wait_for_quit(&self, timeout: Duration) -> std::io::Result<bool> {
+        let deadline = Instant::now() + timeout;
+        while event::poll(deadline.saturating_duration_since(Instant::now()))? {
+            match event::read()? {
+                Event::Key(key) if key.kind == KeyEventKind::Press && is_quit(key) => {
+                    return Ok(true);
+                }
+                _ => {}
+            }
+        }
+        Ok(false)
+    }
+}
+
+impl Drop for LiveView {
+    fn drop(&mut self) {
+        ratatui::restore();
+        log::set_max_level(self.log_level);
+    }
+}
+
+fn is_quit(key: KeyEvent) -> bool {
+    match key.code {
+        KeyCode::Char('q') => true,
+        KeyCode::Char('c') => key.modifiers.contains(KeyModifiers::CONTROL),
+        _ => false,
+    }
+}
+
+fn draw(frame: &mut Frame<'_>, samples: &BTreeMap<String, LatestSample>) {
+    let [processes_area, frames_area, help_area] = Layout::vertical([
+        Constraint::Min(3),
+        Constraint::Length(HOTTEST_FRAMES as u16 + 3),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+
+    let header = Row::new([
+        "Process",
+        "CPU",
+        "Memory",
+        "Disk read",
+        "Disk written",
+        "Threads",
+    ]);
+    let rows = samples.iter().map(|(report, sample)| {
+        let resources = &sample.resources;
+        Row::new([
+            report.clone(),
+            format!("{:.1}%", resources.cpu),
+            format_bytes(resources.memory as f64),
+            format_bytes(resources.disk_read_bytes as f64),
+            format_bytes(resources.disk_write_bytes as f64),
+            resources.thread_count().to_string(),
+        ])
+    });
+    let processes = Table::new(rows, [Constraint::Length(14); 6])
+        .header(header.bold())
+        .block(Block::bordered().title(" Processes "));
+    frame.render_widget(processes, processes_area);
+
+    let rows = hottest_frames(samples)
+        .into_iter()
+        .map(|(label, count)| Row::new([count.to_string(), label]));
+    let frames = Table::new(rows, [Constraint::Length(8), Constraint::Fill(1)])
+        .header(Row::new(["Threads", "Frame"]).bold())
+        .block(Block::bordered().title(" Hottest frames "));
+    frame.render_widget(frames, frames_area);
+
+    frame.render_widget(
+        Line::from("Press q or CTRL+C to stop profiling").dim(),
+        help_area,
+    );
+}
+
+/// The innermost frames of the active threads of all processes, with the number of threads
+/// currently in them, most common first.
+fn hottest_frames(samples: &BTreeMap<String, LatestSample>) -> Vec<(String, usize)> {
+    let format = FrameFormat::default();
+    let mut counts = HashMap::<String, usize>::new();
+    for trace in samples
+        .values()
+        .flat_map(|sample| &sample.stacktraces)
+        .filter(|trace| trace.active)
+    {
+        if let Some(frame) = trace.frames.first() {
+            let label = format.label(&ReportFrame::from(frame.clone()));
+            *counts.entry(label).or_default() += 1;
+        }
+    }
+
+    let mut counts = counts.into_iter().collect::<Vec<_>>();
+    counts.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    counts.truncate(HOTTEST_FRAMES);
+    counts
+}
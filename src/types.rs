@@ -1,13 +1,395 @@
 use py_spy::Pid;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
 
-#[derive(Serialize, Deserialize, Debug)]
+/// The name of the file in the output directory holding the [`Metadata`] of a run.
+pub const METADATA_FILE_NAME: &str = "metadata.json";
+
+/// The name of the file in the output directory listing the report files and their checksums.
+pub const MANIFEST_FILE_NAME: &str = "manifest.json";
+
+/// The name of the file in the output directory a cooperating target can append
+/// [`AllocationSnapshot`]s to, one JSON object per line. See the README for a tracemalloc snippet.
+pub const ALLOCATIONS_FILE_NAME: &str = "allocations.json";
+
+/// The name of the file in the output directory a cooperating target or an external tool can
+/// append [`Marker`]s to, one JSON object per line. The Firefox export shows them on the timeline.
+pub const MARKERS_FILE_NAME: &str = "markers.json";
+
+/// The name of the file in the output directory that exists while a tracker writes to it. The
+/// tracker touches it regularly, so a stale lock means the profiler died without removing it.
+pub const PROFILING_LOCK_FILE_NAME: &str = ".profiling.lock";
+
+/// How often the tracker touches the [`PROFILING_LOCK_FILE_NAME`] file.
+pub const PROFILING_LOCK_REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The files next to the reports in the output directory, which are never listed as reports even
+/// if their name looked like one.
+pub const SIDECAR_FILE_NAMES: [&str; 5] = [
+    METADATA_FILE_NAME,
+    MANIFEST_FILE_NAME,
+    ALLOCATIONS_FILE_NAME,
+    MARKERS_FILE_NAME,
+    PROFILING_LOCK_FILE_NAME,
+];
+
+/// The stem of the report file holding the samples of all processes, written with
+/// `--single-file`. Every sample names its report in [`JsonLine::report`].
+pub const SINGLE_FILE_STEM: &str = "samples";
+
+/// The name Python gives the main thread. A thread whose OS thread id is the pid is more reliable,
+/// as the main thread might be renamed.
+pub(crate) const MAIN_THREAD_NAME: &str = "MainThread";
+
+/// What 100% CPU usage means in the reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum CpuNormalization {
+    /// 100% is one fully used core, so processes and the system reach 100% times the cores
+    #[default]
+    PerCore,
+    /// 100% is all cores fully used
+    Total,
+}
+
+/// Whether py-spy pauses the processes while reading their stacks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum BlockingMode {
+    /// py-spy's default, which pauses the processes
+    #[default]
+    Auto,
+    /// Pause the processes, so every stack is consistent
+    On,
+    /// Do not pause the processes, which is less intrusive but may read torn stacks
+    Off,
+}
+
+/// How the samples in a report file are encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum ReportFormat {
+    /// One JSON object per line
+    #[default]
+    Jsonl,
+    /// A sequence of CBOR items, which is smaller and faster to write
+    Cbor,
+}
+
+impl ReportFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Jsonl => "json",
+            Self::Cbor => "cbor",
+        }
+    }
+}
+
+/// The parts of the name of a report file, e.g. `1234.cbor.gz` or `1234.2.json`.
+#[derive(Debug, Clone, Copy)]
+pub struct ReportFileName<'a> {
+    /// The name without extensions, i.e. the pid or `global`
+    pub stem: &'a str,
+    /// The number of the part once the report was rotated, `None` for the first part
+    pub part: Option<u32>,
+    pub format: ReportFormat,
+    pub compressed: bool,
+}
+
+impl<'a> ReportFileName<'a> {
+    /// Parses the name of a file in the output directory, returning `None` for files that are not
+    /// reports, e.g. sidecar files like the metadata or stray files like `.DS_Store`.
+    pub fn parse(name: &'a str) -> Option<Self> {
+        if SIDECAR_FILE_NAMES.contains(&name) {
+            return None;
+        }
+        let (name, compressed) = match name.strip_suffix(".gz") {
+            Some(name) => (name, true),
+            None => (name, false),
+        };
+        [ReportFormat::Jsonl, ReportFormat::Cbor]
+            .into_iter()
+            .find_map(|format| {
+                let name = name.strip_suffix(format.extension())?.strip_suffix('.')?;
+                let (stem, part) = match name.split_once('.') {
+                    Some((stem, part)) if is_number(part) => (stem, Some(part.parse().ok()?)),
+                    Some(_) => return None,
+                    None => (name, None),
+                };
+                if !is_report_stem(stem) {
+                    return None;
+                }
+                Some(Self {
+                    stem,
+                    part,
+                    format,
+                    compressed,
+                })
+            })
+    }
+}
+
+/// Whether the name is `global`, `self`, `samples` or the name of a process report, see
+/// [`split_process_stem`].
+fn is_report_stem(stem: &str) -> bool {
+    stem == "global"
+        || stem == "self"
+        || stem == SINGLE_FILE_STEM
+        || split_process_stem(stem).is_some()
+}
+
+/// Splits the name of a process report, `<pid>` or `<pid>-<generation>` optionally prefixed by
+/// `<name>-` from an output name template, into the pid and the generation.
+pub(crate) fn split_process_stem(stem: &str) -> Option<(u32, u32)> {
+    let number = |it: &str| is_number(it).then(|| it.parse::<u32>().ok()).flatten();
+    let mut segments = stem.rsplitn(3, '-');
+    let last = number(segments.next()?)?;
+    match (segments.next(), segments.next()) {
+        (None, _) => Some((last, 0)),
+        (Some(middle), rest) if is_number(middle) && rest.is_none_or(|it| !it.is_empty()) => {
+            Some((number(middle)?, last))
+        }
+        // The name of the template might contain dashes itself
+        (Some(middle), _) if !middle.is_empty() => Some((last, 0)),
+        _ => None,
+    }
+}
+
+fn is_number(it: &str) -> bool {
+    !it.is_empty() && it.bytes().all(|b| b.is_ascii_digit())
+}
+
+/// Whether a file in the output directory holds the samples of a process, i.e. is a (possibly
+/// compressed or rotated) `<pid>.json` or `global.json` file, or their CBOR equivalent.
+pub fn is_report_file(name: &str) -> bool {
+    ReportFileName::parse(name).is_some()
+}
+
+/// The top allocation sites of a process at one point in time, as reported by `tracemalloc`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AllocationSnapshot {
+    pub pid: u32,
+    /// The time of the snapshot in milliseconds since the unix epoch
+    pub time: u128,
+    pub sites: Vec<AllocationSite>,
+}
+
+/// The memory currently allocated from a single line of code.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AllocationSite {
+    pub filename: String,
+    pub line: i32,
+    /// The allocated bytes
+    pub size: u64,
+    /// The number of allocated blocks
+    pub count: u64,
+}
+
+/// A point in time the target marked, e.g. the start of a phase like `epoch 3`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Marker {
+    /// The time of the marker in milliseconds since the unix epoch
+    pub time: u128,
+    pub name: String,
+    /// The Python thread id (`threading.get_ident()`) or OS thread id
+    /// (`threading.get_native_id()`) of the thread the marker belongs to. Without one, the marker
+    /// is shown on the main thread.
+    #[serde(default)]
+    pub thread_id: Option<u64>,
+    /// The process the marker belongs to. Without one, the marker is shown on the process that
+    /// started first, usually the profiled command.
+    #[serde(default)]
+    pub pid: Option<u32>,
+}
+
+/// Information about a profiling run as a whole, stored next to the report files.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Metadata {
+    /// Whether only threads running on the CPU were sampled, i.e. the stacktraces show on-CPU
+    /// instead of wall-clock time.
+    #[serde(default)]
+    pub on_cpu_only: bool,
+    /// Total memory of the host in bytes.
+    #[serde(default)]
+    pub total_memory: Option<u64>,
+    /// When the profiling started, in milliseconds since the unix epoch.
+    #[serde(default)]
+    pub start_time: Option<u128>,
+    /// The name of the host the profile was captured on.
+    #[serde(default)]
+    pub host_name: Option<String>,
+    /// The requested time between samples in milliseconds. Missing for adaptive and
+    /// sub-millisecond sample rates.
+    #[serde(default)]
+    pub sample_interval_millis: Option<u64>,
+    /// Whether native stack frames were captured.
+    #[serde(default)]
+    pub capture_native: bool,
+    /// Memory limit of the cgroup the profiler ran in, if any, e.g. inside a container.
+    #[serde(default)]
+    pub cgroup_memory_limit: Option<u64>,
+    /// Start time of each tracked process in seconds since the unix epoch, recorded when the
+    /// process was first sampled.
+    #[serde(default)]
+    pub process_start_times: BTreeMap<u32, u64>,
+    /// Command line of each tracked process, unless hidden when profiling.
+    #[serde(default)]
+    pub process_command_lines: BTreeMap<u32, Vec<String>>,
+    /// Python version of each tracked process, if py-spy could detect it.
+    #[serde(default)]
+    pub process_python_versions: BTreeMap<u32, String>,
+    /// The pid of the profiler itself, if it recorded its own resources into `self.json`.
+    #[serde(default)]
+    pub profiler_pid: Option<u32>,
+    /// Whether all samples of a tick share the same time, so the samples of the processes line
+    /// up with the global ones. Missing in reports captured by older versions.
+    #[serde(default)]
+    pub shared_tick_time: bool,
+    /// The time between samples in milliseconds that `--auto-rate` settled on by the end.
+    #[serde(default)]
+    pub chosen_sample_interval_millis: Option<u64>,
+    /// Arbitrary labels of the run given with `--label`, e.g. `git_sha=abc123`.
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// What 100% CPU usage means, for processes, threads and the system alike.
+    #[serde(default)]
+    pub cpu_normalization: CpuNormalization,
+    /// The number of logical cores of the host.
+    #[serde(default)]
+    pub cpu_cores: Option<usize>,
+    /// Whether py-spy paused the processes while sampling them.
+    #[serde(default)]
+    pub blocking: BlockingMode,
+    /// The exit code of the profiled command if it exited on its own, 128 plus the signal if it
+    /// was killed by one.
+    #[serde(default)]
+    pub exit_code: Option<i32>,
+}
+
+impl Metadata {
+    /// The memory available to the profiled processes, which is the denominator for memory
+    /// percentages. Inside a container, the host memory is not what the processes can use.
+    pub fn memory_limit(&self) -> Option<u64> {
+        self.cgroup_memory_limit.or(self.total_memory)
+    }
+
+    /// The labels as `key=value` pairs separated by commas, for printing.
+    pub fn format_labels(&self) -> String {
+        format_labels(&self.labels)
+    }
+}
+
+/// The first record of a report file written with `--embed-metadata`, which makes the file
+/// self-describing without the sidecar [`METADATA_FILE_NAME`]. Readers skip it instead of
+/// treating it as a sample.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MetadataHeader {
+    /// Always [`MetadataHeaderKind::Metadata`], which tells the header apart from samples
+    #[serde(rename = "type")]
+    pub kind: MetadataHeaderKind,
+    pub metadata: Metadata,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum MetadataHeaderKind {
+    Metadata,
+}
+
+/// How a JSON report file with a [`MetadataHeader`] starts, which is checked before parsing it.
+pub(crate) const METADATA_HEADER_PREFIX: &[u8] = br#"{"type":"metadata""#;
+
+impl MetadataHeader {
+    /// Encodes the header of the metadata in the format of the report. JSON headers always take
+    /// a single line, even if the samples are pretty-printed.
+    pub(crate) fn encode(metadata: &Metadata, format: ReportFormat) -> Vec<u8> {
+        let header = Self {
+            kind: MetadataHeaderKind::Metadata,
+            metadata: metadata.clone(),
+        };
+        match format {
+            ReportFormat::Jsonl => {
+                let mut encoded = serde_json::to_vec(&header).expect("metadata is serializable");
+                encoded.push(b'\n');
+                encoded
+            }
+            ReportFormat::Cbor => {
+                let mut encoded = Vec::new();
+                ciborium::into_writer(&header, &mut encoded).expect("metadata is serializable");
+                encoded
+            }
+        }
+    }
+
+    /// Splits the header off the (decompressed) content of a report file, if it has one.
+    pub(crate) fn split(content: &[u8], format: ReportFormat) -> (Option<Metadata>, &[u8]) {
+        match format {
+            ReportFormat::Jsonl => {
+                if !content.starts_with(METADATA_HEADER_PREFIX) {
+                    return (None, content);
+                }
+                let end = content
+                    .iter()
+                    .position(|it| *it == b'\n')
+                    .map_or(content.len(), |newline| newline + 1);
+                let (line, rest) = content.split_at(end);
+                match serde_json::from_slice::<Self>(line) {
+                    Ok(header) => (Some(header.metadata), rest),
+                    Err(_) => (None, content),
+                }
+            }
+            ReportFormat::Cbor => {
+                let mut rest = content;
+                match ciborium::from_reader::<Self, _>(&mut rest) {
+                    Ok(header) => (Some(header.metadata), rest),
+                    Err(_) => (None, content),
+                }
+            }
+        }
+    }
+}
+
+/// Formats labels as `key=value` pairs separated by commas.
+pub(crate) fn format_labels(labels: &BTreeMap<String, String>) -> String {
+    labels
+        .iter()
+        .map(|(key, value)| format!("{key}={value}"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct JsonLine {
     pub stacktraces: Vec<StackTrace>,
     pub resources: ProcessResources,
     pub index: usize,
+    /// The time of the sample in milliseconds since the unix epoch
     pub time: u128,
+    /// The same time in microseconds, for sample rates below a millisecond. Missing in reports
+    /// captured by older versions, use [`JsonLine::timestamp_micros`] to read it.
+    #[serde(default)]
+    pub time_micros: Option<u128>,
+    /// How long refreshing the resources and capturing the stacktraces took for this sample.
+    /// Missing in reports captured by older versions.
+    #[serde(default)]
+    pub sample_duration_micros: u64,
+    /// The report the sample belongs to, e.g. `1234` or `global`. Only set in the combined
+    /// `samples.json` written with `--single-file`, and for the callbacks of
+    /// [`Tracker::on_sample`](crate::Tracker::on_sample).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub report: Option<String>,
+    /// Counts the samples of the report from 0 as they are taken, so consumers can detect
+    /// reordered and dropped samples regardless of how they were transported. Missing in reports
+    /// captured by older versions.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sequence: Option<u64>,
+}
+
+impl JsonLine {
+    /// The time of the sample in microseconds since the unix epoch.
+    pub fn timestamp_micros(&self) -> u128 {
+        self.time_micros.unwrap_or(self.time * 1000)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -22,9 +404,85 @@ pub struct ThreadResources {
 pub struct ProcessResources {
     pub memory: u64,
     pub cpu: f32,
+    /// The CPU time used since the process started in milliseconds, zero where unavailable, e.g.
+    /// for the global resources or in reports captured by older versions
+    #[serde(default)]
+    pub cpu_time_ms: u64,
     pub disk_read_bytes: u64,
     pub disk_write_bytes: u64,
     pub thread_resources: HashMap<u64, ThreadResources>,
+    /// The number of threads of the process, which is more than `thread_resources` holds if only
+    /// the busiest threads were recorded. Missing in reports captured by older versions.
+    #[serde(default)]
+    pub total_threads: Option<usize>,
+    // The following are totals since process start, read from procfs and only available on Linux
+    #[serde(default)]
+    pub voluntary_ctx_switches: Option<u64>,
+    #[serde(default)]
+    pub involuntary_ctx_switches: Option<u64>,
+    #[serde(default)]
+    pub major_faults: Option<u64>,
+    #[serde(default)]
+    pub minor_faults: Option<u64>,
+    /// The CPUs the process may run on, only available on Linux
+    #[serde(default)]
+    pub cpu_affinity: Option<Vec<usize>>,
+    /// The nice value of the process, only available on Linux
+    #[serde(default)]
+    pub nice: Option<i32>,
+    /// GPU utilization in percent, only available with the `gpu` feature and an NVIDIA GPU
+    #[serde(default)]
+    pub gpu_util: Option<f32>,
+    /// Used GPU memory in bytes, only available with the `gpu` feature and an NVIDIA GPU
+    #[serde(default)]
+    pub gpu_memory: Option<u64>,
+    /// Power draw of the whole system in watts, only set for the global resources and if RAPL or a
+    /// discharging battery is available
+    #[serde(default)]
+    pub power_watts: Option<f32>,
+    /// Read and written bytes per disk device, only set for the global resources. The totals are
+    /// in `disk_read_bytes` and `disk_write_bytes`.
+    #[serde(default)]
+    pub per_disk_io: HashMap<String, (u64, u64)>,
+    /// Set if the process exited before its resources could be read. The stacktraces of the
+    /// sample are still valid, all resources are zero.
+    #[serde(default)]
+    pub exited: bool,
+}
+
+/// The key in [`ProcessResources::thread_resources`] summing up the threads that were not recorded
+/// individually. No real thread has this id.
+pub const OTHER_THREADS_ID: u64 = 0;
+
+impl ProcessResources {
+    /// The number of threads of the process, including the ones not recorded individually.
+    pub fn thread_count(&self) -> usize {
+        self.total_threads.unwrap_or(self.thread_resources.len())
+    }
+
+    /// Resources for a process that is already gone, so that its last stacktraces are kept.
+    pub fn exited() -> Self {
+        Self {
+            memory: 0,
+            cpu: 0.0,
+            cpu_time_ms: 0,
+            disk_read_bytes: 0,
+            disk_write_bytes: 0,
+            thread_resources: HashMap::new(),
+            total_threads: None,
+            voluntary_ctx_switches: None,
+            involuntary_ctx_switches: None,
+            major_faults: None,
+            minor_faults: None,
+            cpu_affinity: None,
+            nice: None,
+            gpu_util: None,
+            gpu_memory: None,
+            power_watts: None,
+            per_disk_io: HashMap::new(),
+            exited: true,
+        }
+    }
 }
 
 // the following structs are `Deserialize`-able wrappers for py-spy structs
@@ -66,6 +524,16 @@ pub struct Frame {
     pub is_entry: bool,
 }
 
+impl Frame {
+    /// Whether this is a native (C, C++, Rust, ...) frame, only captured with `--native`. py-spy
+    /// sets the module only for native frames, resolving it to the shared library of the frame.
+    /// The file name can not tell them apart, as Python scripts do not need a `.py` suffix, e.g.
+    /// the `bin/` shims of console scripts.
+    pub fn is_native(&self) -> bool {
+        self.module.is_some()
+    }
+}
+
 impl From<py_spy::Frame> for Frame {
     fn from(frame: py_spy::Frame) -> Self {
         Self {
@@ -117,3 +585,35 @@ impl From<py_spy::stack_trace::ProcessInfo> for ProcessInfo {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(filename: &str, module: Option<&str>) -> Frame {
+        Frame {
+            name: "run".to_string(),
+            filename: filename.to_string(),
+            module: module.map(str::to_string),
+            short_filename: None,
+            line: 1,
+            locals: None,
+            is_entry: false,
+        }
+    }
+
+    #[test]
+    fn native_frames_are_told_apart_by_their_module() {
+        let native = frame("src/lib.rs", Some("/usr/lib/libexample.so"));
+        assert!(native.is_native());
+        let without_debug_info = frame("libexample.so", Some("libexample.so"));
+        assert!(without_debug_info.is_native());
+    }
+
+    #[test]
+    fn python_scripts_without_suffix_are_not_native() {
+        assert!(!frame("/usr/local/bin/my-console-script", None).is_native());
+        assert!(!frame("<frozen importlib._bootstrap>", None).is_native());
+        assert!(!frame("/app/main.py", None).is_native());
+    }
+}
@@ -1,16 +1,38 @@
+use crate::export::{read_metadata, read_report_json, report_parts, summarize_report};
+use crate::types::{
+    Metadata, PROFILING_LOCK_FILE_NAME, PROFILING_LOCK_REFRESH_INTERVAL, ReportFileName,
+    ReportFormat,
+};
 use axum::extract::{Request, State};
 use axum::http::{StatusCode, Uri, header};
+use axum::middleware::{self, Next};
 use axum::response::{IntoResponse, Response};
 use axum::routing::get;
 use axum::{Json, Router};
-use log::info;
+use axum_server::tls_rustls::RustlsConfig;
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use log::{debug, info, warn};
 use rust_embed::Embed;
+use serde_json::json;
 use snafu::{Location, ResultExt, Snafu};
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Formatter};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 use tower::ServiceExt;
 use tower_http::cors::CorsLayer;
 use tower_http::services::ServeDir;
 
+/// How long after its last refresh a profiling lock is considered stale. This leaves room for a
+/// few missed refreshes, e.g. while a slow tick runs.
+const PROFILING_LOCK_STALE_AFTER: Duration = PROFILING_LOCK_REFRESH_INTERVAL.saturating_mul(6);
+
+/// How long open HTTPS connections may take to finish once the server shuts down
+const TLS_SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
 #[derive(Debug, Snafu)]
 pub enum ViewError {
     #[snafu(display("Error binding to interface `{interface}` at {location}"))]
@@ -20,6 +42,26 @@ pub enum ViewError {
         #[snafu(implicit)]
         location: Location,
     },
+    #[snafu(display("Error binding to unix socket `{path}` at {location}"))]
+    BindToUnixSocket {
+        source: std::io::Error,
+        path: String,
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display("Unix sockets are not supported on this platform at {location}"))]
+    UnixSocketUnsupported {
+        #[snafu(implicit)]
+        location: Location,
+    },
+    #[snafu(display("Error loading TLS certificate `{cert}` or key `{key}` at {location}"))]
+    LoadTlsConfig {
+        source: std::io::Error,
+        cert: String,
+        key: String,
+        #[snafu(implicit)]
+        location: Location,
+    },
     #[snafu(display("Error running webserver at {location}"))]
     Axum {
         source: std::io::Error,
@@ -32,56 +74,391 @@ pub enum ViewError {
 #[folder = "frontend/dist/"]
 struct Asset;
 
-pub async fn run_view(output_dir: PathBuf, interface: &str, port: u16) -> Result<(), ViewError> {
+/// Options controlling where and how the profile data is served.
+#[derive(Debug, Clone)]
+pub struct ViewOptions {
+    /// The interface to listen on, either an IP address, a host name or the name of a network
+    /// interface like `tailscale0`
+    pub interface: String,
+    /// The port to listen on
+    pub port: u16,
+    /// Serve the frontend from this directory instead of the one embedded in the binary
+    pub frontend_dir: Option<PathBuf>,
+    /// Listen on this unix socket instead of the interface and port
+    pub unix_socket: Option<PathBuf>,
+    /// Serve over HTTPS using these certificate and key files
+    pub tls: Option<TlsFiles>,
+    /// Require HTTP basic authentication with these credentials for all routes
+    pub auth: Option<BasicAuth>,
+}
+
+/// The PEM encoded certificate (chain) and private key to serve HTTPS with.
+#[derive(Debug, Clone)]
+pub struct TlsFiles {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+/// The credentials clients need to send to access the viewer.
+#[derive(Clone)]
+pub struct BasicAuth {
+    pub user: String,
+    pub password: String,
+}
+
+impl Debug for BasicAuth {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BasicAuth")
+            .field("user", &self.user)
+            .field("password", &"***")
+            .finish()
+    }
+}
+
+impl BasicAuth {
+    /// The value of the `Authorization` header sent by clients with the correct credentials.
+    fn expected_header(&self) -> String {
+        let credentials = format!("{}:{}", self.user, self.password);
+        format!("Basic {}", BASE64_STANDARD.encode(credentials))
+    }
+}
+
+pub async fn run_view(output_dir: PathBuf, options: ViewOptions) -> Result<(), ViewError> {
+    let data = Router::new()
+        .route("/metadata.json", get(serve_metadata))
+        .route("/profiles_detailed.json", get(serve_profile_summaries))
+        .route("/status", get(serve_status))
+        .route("/{*file}", get(serve_profile_data))
+        .with_state(output_dir.clone());
+    let health = Router::new()
+        .route("/healthz", get(serve_health))
+        .with_state(output_dir);
+    serve(data, health, options).await
+}
+
+/// Serves reports held in memory, e.g. extracted from an exported HTML file, which does not
+/// contain the metadata.
+pub async fn run_replay(
+    reports: BTreeMap<String, Vec<u8>>,
+    options: ViewOptions,
+) -> Result<(), ViewError> {
+    let data = Router::new()
+        .route(
+            "/metadata.json",
+            get(|| async { Json(Metadata::default()) }),
+        )
+        .route("/status", get(|| async { Json(json!({ "live": false })) }))
+        .route("/{*file}", get(serve_replayed_report))
+        .with_state(Arc::new(reports));
+    // The reports are in memory, so there is nothing that could become unreadable
+    let health = Router::new().route(
+        "/healthz",
+        get(|| async { Json(json!({ "status": "ok", "data_readable": true })) }),
+    );
+    serve(data, health, options).await
+}
+
+/// Serves the frontend and the profile data routes, which are nested below `/view`. The health
+/// routes are merged after authentication, so probes do not need credentials.
+async fn serve(data: Router, health: Router, options: ViewOptions) -> Result<(), ViewError> {
+    let frontend = match options.frontend_dir {
+        Some(frontend_dir) => {
+            info!("Serving frontend from {}", frontend_dir.display());
+            Router::new().fallback_service(ServeDir::new(frontend_dir))
+        }
+        None => Router::new()
+            .route("/", get(|| async { FrontendStaticFile("index.html") }))
+            .route("/{*file}", get(serve_frontend)),
+    };
     let app = Router::new()
         // nest to ensure the prefix is stripped
-        .nest(
-            "/view",
-            Router::new().route("/{*file}", get(serve_profile_data)),
-        )
-        .route("/", get(|| async { FrontendStaticFile("index.html") }))
-        .route("/{*file}", get(serve_frontend))
-        .layer(CorsLayer::very_permissive())
-        .with_state(output_dir);
+        .nest("/view", data)
+        .merge(frontend);
+    let app = match options.auth {
+        Some(auth) => app.layer(middleware::from_fn_with_state(
+            Arc::<str>::from(auth.expected_header()),
+            require_auth,
+        )),
+        None => app,
+    };
+    let app = app.merge(health);
+    // CORS is the outermost layer, so preflight requests do not need credentials
+    let app = app.layer(CorsLayer::very_permissive());
+
+    if let Some(path) = options.unix_socket {
+        return serve_unix_socket(app, &path).await;
+    }
 
-    let listen_address = format!("{interface}:{port}");
+    let port = options.port;
+    let listen_address = listen_address(&options.interface, port);
+    let scheme = if options.tls.is_some() {
+        "https"
+    } else {
+        "http"
+    };
     info!("Listening on {listen_address}");
-    info!("This probably resolves to http://localhost:{port}");
+    info!("This probably resolves to {scheme}://localhost:{port}");
+    let all_interfaces = listen_address
+        .parse::<SocketAddr>()
+        .is_ok_and(|address| address.ip().is_unspecified());
+    if all_interfaces {
+        warn!(
+            "Listening on all interfaces, everyone on the network can read the profile data including the command lines. Leave out `--interface` to only listen on localhost."
+        );
+    }
 
     let listener = tokio::net::TcpListener::bind(listen_address.clone())
         .await
         .context(BindToInterfaceSnafu {
             interface: listen_address,
         })?;
-    axum::serve(listener, app).await.context(AxumSnafu)?;
+    // Shut down gracefully on CTRL+C, so the caller gets the chance to act on it, e.g. to export
+    match options.tls {
+        Some(tls) => serve_tls(app, listener, &tls).await?,
+        None => axum::serve(listener, app)
+            .with_graceful_shutdown(ctrl_c())
+            .await
+            .context(AxumSnafu)?,
+    }
 
     Ok(())
 }
 
+async fn ctrl_c() {
+    let _ = tokio::signal::ctrl_c().await;
+    info!("Shutting down the server");
+}
+
+/// The address to bind to. Names of network interfaces are resolved to their current address,
+/// preferring IPv4, anything else that is not an IP is left to be resolved as a host name.
+fn listen_address(interface: &str, port: u16) -> String {
+    let ip = match interface.parse::<IpAddr>() {
+        Ok(ip) => Some(ip),
+        Err(_) => interface_ip(interface),
+    };
+    match ip {
+        Some(ip) => SocketAddr::new(ip, port).to_string(),
+        None => format!("{interface}:{port}"),
+    }
+}
+
+fn interface_ip(name: &str) -> Option<IpAddr> {
+    let interfaces = match if_addrs::get_if_addrs() {
+        Ok(interfaces) => interfaces,
+        Err(e) => {
+            debug!("Could not list the network interfaces: {e}");
+            return None;
+        }
+    };
+    let mut ips = interfaces
+        .iter()
+        .filter(|it| it.name == name)
+        .map(|it| it.ip())
+        .collect::<Vec<_>>();
+    ips.sort_by_key(IpAddr::is_ipv6);
+    let ip = ips.first().copied()?;
+    info!("Resolved interface `{name}` to {ip}");
+    Some(ip)
+}
+
+async fn serve_tls(
+    app: Router,
+    listener: tokio::net::TcpListener,
+    tls: &TlsFiles,
+) -> Result<(), ViewError> {
+    let config = RustlsConfig::from_pem_file(&tls.cert, &tls.key)
+        .await
+        .context(LoadTlsConfigSnafu {
+            cert: tls.cert.display().to_string(),
+            key: tls.key.display().to_string(),
+        })?;
+    let listener = listener.into_std().context(AxumSnafu)?;
+
+    let handle = axum_server::Handle::new();
+    let shutdown = handle.clone();
+    tokio::spawn(async move {
+        ctrl_c().await;
+        shutdown.graceful_shutdown(Some(TLS_SHUTDOWN_GRACE));
+    });
+    axum_server::from_tcp_rustls(listener, config)
+        .handle(handle)
+        .serve(app.into_make_service())
+        .await
+        .context(AxumSnafu)
+}
+
+#[cfg(unix)]
+async fn serve_unix_socket(app: Router, path: &Path) -> Result<(), ViewError> {
+    let listener = tokio::net::UnixListener::bind(path).context(BindToUnixSocketSnafu {
+        path: path.display().to_string(),
+    })?;
+    info!("Listening on unix socket {}", path.display());
+
+    // Shut down gracefully on CTRL+C, so we get the chance to clean up the socket file
+    let result = axum::serve(listener, app)
+        .with_graceful_shutdown(ctrl_c())
+        .await;
+    if let Err(e) = std::fs::remove_file(path) {
+        warn!("Could not remove unix socket {}: {e}", path.display());
+    }
+    result.context(AxumSnafu)
+}
+
+#[cfg(not(unix))]
+async fn serve_unix_socket(_app: Router, _path: &Path) -> Result<(), ViewError> {
+    UnixSocketUnsupportedSnafu.fail()
+}
+
+async fn require_auth(State(expected): State<Arc<str>>, req: Request, next: Next) -> Response {
+    let authorized = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .is_some_and(|value| constant_time_eq(value.as_bytes(), expected.as_bytes()));
+    if !authorized {
+        return (
+            StatusCode::UNAUTHORIZED,
+            [(
+                header::WWW_AUTHENTICATE,
+                "Basic realm=\"py-crude-resource-monitor\"",
+            )],
+            "401 Unauthorized",
+        )
+            .into_response();
+    }
+    next.run(req).await
+}
+
+/// Compares the bytes without returning early, so the time taken does not reveal how much of the
+/// credentials was correct. Only the length is leaked.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
 async fn serve_frontend(uri: Uri) -> impl IntoResponse {
     FrontendStaticFile(uri.path().trim_start_matches('/').to_string())
 }
 
+/// Reports whether the server is up and the data directory readable, e.g. for load balancers.
+/// It does not need any reports to exist yet.
+async fn serve_health(State(profile_data_directory): State<PathBuf>) -> Response {
+    match std::fs::read_dir(&profile_data_directory) {
+        Ok(_) => Json(json!({ "status": "ok", "data_readable": true })).into_response(),
+        Err(e) => {
+            warn!("Health check could not read the data directory: {e}");
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(json!({ "status": "error", "data_readable": false })),
+            )
+                .into_response()
+        }
+    }
+}
+
+/// Reports whether a tracker is still writing to the data directory, i.e. whether its profiling
+/// lock exists and was touched recently, so the frontend can stop polling once capture ended.
+async fn serve_status(State(profile_data_directory): State<PathBuf>) -> Json<serde_json::Value> {
+    let live = std::fs::metadata(profile_data_directory.join(PROFILING_LOCK_FILE_NAME))
+        .and_then(|it| it.modified())
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .is_some_and(|age| age < PROFILING_LOCK_STALE_AFTER);
+    Json(json!({ "live": live }))
+}
+
+/// Serves the metadata of the run, falling back to defaults for reports of older versions.
+async fn serve_metadata(State(profile_data_directory): State<PathBuf>) -> Result<Response, String> {
+    let metadata = read_metadata(&profile_data_directory).map_err(|e| e.to_string())?;
+    Ok(Json(metadata).into_response())
+}
+
+/// Like `profiles.json`, but with the number of samples, time range and peak memory of each report.
+async fn serve_profile_summaries(
+    State(profile_data_directory): State<PathBuf>,
+) -> Result<Response, String> {
+    let summaries = report_parts(&profile_data_directory)
+        .map_err(|_| "error reading data directory")?
+        .iter()
+        .map(|(stem, parts)| summarize_report(stem, parts).map_err(|e| e.to_string()))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Json(summaries).into_response())
+}
+
+/// Serves the files of the data directory. `profiles.json` lists the reports only, so the
+/// frontend does not try to parse sidecar files like `metadata.json` as samples.
 async fn serve_profile_data(
     State(profile_data_directory): State<PathBuf>,
     req: Request,
 ) -> Result<Response, String> {
     if req.uri().path() == "/profiles.json" {
-        let files = std::fs::read_dir(profile_data_directory)
+        // All reports are served under their plain JSON name, see below
+        let files = report_parts(&profile_data_directory)
             .map_err(|_| "error reading data directory")?
-            .filter_map(|f| f.ok())
-            .filter(|f| f.path().extension().map(|e| e == "json").unwrap_or(false))
-            .map(|f| f.path().file_name().unwrap().to_string_lossy().to_string())
+            .into_keys()
+            .map(|stem| format!("{stem}.json"))
             .collect::<Vec<_>>();
         return Ok(Json(files).into_response());
     }
+    if let Some(response) = serve_converted_report(&profile_data_directory, req.uri().path())? {
+        return Ok(response);
+    }
+    // Browsers accept gzip, so `<pid>.json.gz` is served transparently for `<pid>.json`
     Ok(ServeDir::new(profile_data_directory)
+        .precompressed_gzip()
         .oneshot(req)
         .await
         .map_err(|e| e.to_string())?
         .into_response())
 }
 
+async fn serve_replayed_report(
+    State(reports): State<Arc<BTreeMap<String, Vec<u8>>>>,
+    uri: Uri,
+) -> Response {
+    let file = uri.path().trim_start_matches('/');
+    if file == "profiles.json" {
+        return Json(reports.keys().collect::<Vec<_>>()).into_response();
+    }
+    match reports.get(file) {
+        Some(content) => (
+            [(header::CONTENT_TYPE, "application/json")],
+            content.clone(),
+        )
+            .into_response(),
+        None => (StatusCode::NOT_FOUND, "404 Not Found").into_response(),
+    }
+}
+
+/// Converts CBOR reports to JSON lines and stitches rotated reports together on the fly, as the
+/// frontend only understands single JSON lines files. Returns `None` if the path does not refer to
+/// such a report.
+fn serve_converted_report(data_dir: &Path, path: &str) -> Result<Option<Response>, String> {
+    let Some(stem) = path.trim_start_matches('/').strip_suffix(".json") else {
+        return Ok(None);
+    };
+    // Only reports directly in the data directory, e.g. no path traversal
+    if stem.contains('/') || ReportFileName::parse(&format!("{stem}.json")).is_none() {
+        return Ok(None);
+    }
+    let mut reports = report_parts(data_dir).map_err(|_| "error reading data directory")?;
+    let Some(parts) = reports.remove(stem) else {
+        return Ok(None);
+    };
+    // A single JSON lines file is served as is
+    let pretty = parts[0].is_pretty_json().map_err(|e| e.to_string())?;
+    let header = parts[0].has_metadata_header().map_err(|e| e.to_string())?;
+    if parts.len() == 1 && parts[0].format == ReportFormat::Jsonl && !pretty && !header {
+        return Ok(None);
+    }
+
+    let json = read_report_json(&parts).map_err(|e| e.to_string())?;
+    Ok(Some(
+        ([(header::CONTENT_TYPE, "application/json")], json).into_response(),
+    ))
+}
+
 pub struct FrontendStaticFile<T>(pub T);
 
 impl<T> IntoResponse for FrontendStaticFile<T>